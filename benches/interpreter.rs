@@ -0,0 +1,86 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use criterion::{BatchSize, Criterion, Throughput, criterion_group, criterion_main};
+
+use brainrust::{Dispatch, Fused, Machine, Overflow, parse};
+
+// The bundled examples, smallest to largest by steps run, with their input
+fn programs() -> Vec<(&'static str, String, Vec<u8>)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+    ["hello", "reverse", "alphabet"].iter()
+        .map(|&name| {
+            let src = fs::read_to_string(dir.join(name).with_extension("b")).unwrap();
+            let input = fs::read(dir.join(name).with_extension("in")).unwrap_or_default();
+            (name, src, input)
+        })
+        .collect()
+}
+
+// Sources also grow by repeating an example, which keeps them balanced
+fn sources() -> Vec<(String, String)> {
+    let mut sources: Vec<_> = programs().into_iter().map(|(name, src, _)| (name.to_owned(), src)).collect();
+    let hello = sources[0].1.clone();
+    for copies in [100, 10_000] {
+        sources.push((format!("hello x{}", copies), hello.repeat(copies)));
+    }
+    sources
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for (name, src) in sources() {
+        group.throughput(Throughput::Bytes(src.len() as u64));
+        group.bench_function(&name, |b| b.iter(|| parse(&src).unwrap()));
+    }
+    group.finish();
+}
+
+fn bench_optimize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("optimize");
+    for (name, src) in sources() {
+        let prog = parse(&src).unwrap();
+        group.throughput(Throughput::Elements(prog.len() as u64));
+        group.bench_function(&name, |b| b.iter(|| Fused::new(&prog)));
+    }
+    group.finish();
+}
+
+// Each program run to its halt, stepped under both dispatch strategies,
+// with superoperators, and with checked arithmetic, which should cost
+// nothing until a cell overflows
+fn bench_execute(c: &mut Criterion) {
+    let mut group = c.benchmark_group("execute");
+    for (name, src, input) in programs() {
+        let build = |overflow| Machine::builder()
+            .input(io::Cursor::new(input.clone()))
+            .overflow(overflow)
+            .build(src.clone())
+            .unwrap();
+        let mut machine = build(Overflow::Wrap);
+        machine.run().unwrap();
+        group.throughput(Throughput::Elements(machine.steps));
+        for (engine, dispatch, fusion, overflow) in [
+            ("match", Dispatch::Match, false, Overflow::Wrap),
+            ("table", Dispatch::Table, false, Overflow::Wrap),
+            ("fused", Dispatch::Match, true, Overflow::Wrap),
+            ("checked", Dispatch::Match, false, Overflow::Error),
+        ] {
+            group.bench_function(format!("{}/{}", name, engine), |b| b.iter_batched(
+                || {
+                    let mut machine = build(overflow);
+                    machine.dispatch = dispatch;
+                    machine.fusion = fusion;
+                    machine
+                },
+                |mut machine| machine.run().unwrap(),
+                BatchSize::SmallInput,
+            ));
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse, bench_optimize, bench_execute);
+criterion_main!(benches);
@@ -0,0 +1,11 @@
+The alphabet on 240 lines
+
++++++++++++++++[>++++++++++++++++<-]>   cell 1 counts the lines
+[
+  >++++++++[>++++++++<-]>+               cell 3 holds A
+  <++++++++++++++++++++++++++            cell 2 counts the letters
+  [>.+<-]
+  >[-]<
+  ++++++++++.[-]                         newline
+  <-
+]
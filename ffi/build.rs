@@ -0,0 +1,9 @@
+// Regenerate the C header from the bindings
+fn main() {
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    cbindgen::generate(&crate_dir)
+        .expect("failed to generate bindings")
+        .write_to_file("include/brainrust.h");
+}
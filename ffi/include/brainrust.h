@@ -0,0 +1,112 @@
+#ifndef BRAINRUST_H
+#define BRAINRUST_H
+
+/* Generated by cbindgen from src/lib.rs; do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// How a run or step ended
+typedef enum BrainrustStatus {
+  BRAINRUST_STATUS_HALTED = 0,
+  // The step finished and the program has more to run
+  BRAINRUST_STATUS_RUNNING,
+  BRAINRUST_STATUS_PTR_UNDERFLOW,
+  BRAINRUST_STATUS_PTR_OVERFLOW,
+  BRAINRUST_STATUS_CELL_UNDERFLOW,
+  BRAINRUST_STATUS_CELL_OVERFLOW,
+  BRAINRUST_STATUS_STEP_LIMIT,
+  BRAINRUST_STATUS_OUTPUT_LIMIT,
+  BRAINRUST_STATUS_TAPE_LIMIT,
+  BRAINRUST_STATUS_TIMEOUT,
+  BRAINRUST_STATUS_CANCELLED,
+  BRAINRUST_STATUS_IO,
+  // A null handle was passed
+  BRAINRUST_STATUS_INVALID_ARGUMENT,
+  // An extension instruction's handler failed
+  BRAINRUST_STATUS_EXTENSION,
+  // An `{assert COND}` comment's condition was false
+  BRAINRUST_STATUS_ASSERTION_FAILED,
+  BRAINRUST_STATUS_MEMORY_LIMIT,
+  // A write strayed off the end of the tape onto a canary cell
+  BRAINRUST_STATUS_CANARY_OVERWRITTEN,
+  // A pbrain `:` called a procedure that isn't defined
+  BRAINRUST_STATUS_UNDEFINED_PROCEDURE,
+} BrainrustStatus;
+
+// A machine with its program loaded
+typedef struct BrainrustMachine BrainrustMachine;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Parse `source` and make a machine to run it, reading `input` for `,`.
+// Both are copied. Returns null if the source isn't UTF-8 or doesn't parse.
+//
+// # Safety
+//
+// `source` and `input` must each be null or point to the given number of
+// readable bytes.
+struct BrainrustMachine *brainrust_new(const uint8_t *source,
+                                       size_t source_len,
+                                       const uint8_t *input,
+                                       size_t input_len);
+
+// Run until the program halts or fails. A machine that has halted stays
+// as it is.
+//
+// # Safety
+//
+// `machine` must be null or a live handle from `brainrust_new`.
+enum BrainrustStatus brainrust_run(struct BrainrustMachine *machine);
+
+// Execute one instruction. Returns `Running` while there is more to run.
+//
+// # Safety
+//
+// `machine` must be null or a live handle from `brainrust_new`.
+enum BrainrustStatus brainrust_step(struct BrainrustMachine *machine);
+
+// Store the value of tape cell `index` in `value`. Returns false, leaving
+// `value` alone, if the cell is off the tape.
+//
+// # Safety
+//
+// `machine` must be null or a live handle from `brainrust_new`, and `value`
+// must be valid for a write.
+bool brainrust_read_cell(const struct BrainrustMachine *machine, size_t index, uint32_t *value);
+
+// The cell the data pointer is on, or 0 for a null handle
+//
+// # Safety
+//
+// `machine` must be null or a live handle from `brainrust_new`.
+size_t brainrust_data_ptr(const struct BrainrustMachine *machine);
+
+// Everything the program has written so far. The bytes stay valid until the
+// machine next runs or is freed. A null handle has written nothing: `len` is
+// set to 0 and null returned.
+//
+// # Safety
+//
+// `machine` must be null or a live handle from `brainrust_new`, and `len`
+// must be valid for a write.
+const uint8_t *brainrust_output(const struct BrainrustMachine *machine, size_t *len);
+
+// Release a machine. Null is ignored.
+//
+// # Safety
+//
+// `machine` must be null or a live handle from `brainrust_new`, and is not
+// valid afterwards.
+void brainrust_free(struct BrainrustMachine *machine);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* BRAINRUST_H */
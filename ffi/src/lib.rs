@@ -0,0 +1,212 @@
+//! C bindings for the BrainRust interpreter. Machines are opaque handles,
+//! made by `brainrust_new` and released by `brainrust_free`. With the
+//! `python` feature, this is also the `brainrust` Python module, and with
+//! `wasm`, a WebAssembly module for the browser.
+
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "wasm")]
+mod wasm;
+
+use std::io;
+use std::ptr;
+use std::slice;
+use std::str;
+
+use brainrust::{Machine, RuntimeError};
+
+/// How a run or step ended
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BrainrustStatus {
+    Halted = 0,
+    /// The step finished and the program has more to run
+    Running,
+    PtrUnderflow,
+    PtrOverflow,
+    CellUnderflow,
+    CellOverflow,
+    StepLimit,
+    OutputLimit,
+    TapeLimit,
+    Timeout,
+    Cancelled,
+    Io,
+    /// A null handle was passed
+    InvalidArgument,
+    /// An extension instruction's handler failed
+    Extension,
+    /// An `{assert COND}` comment's condition was false
+    AssertionFailed,
+    MemoryLimit,
+    /// A write strayed off the end of the tape onto a canary cell
+    CanaryOverwritten,
+    /// A pbrain `:` called a procedure that isn't defined
+    UndefinedProcedure,
+}
+
+impl From<RuntimeError> for BrainrustStatus {
+    fn from(err: RuntimeError) -> Self {
+        match err {
+            RuntimeError::PtrUnderflow => BrainrustStatus::PtrUnderflow,
+            RuntimeError::PtrOverflow => BrainrustStatus::PtrOverflow,
+            RuntimeError::CellUnderflow => BrainrustStatus::CellUnderflow,
+            RuntimeError::CellOverflow => BrainrustStatus::CellOverflow,
+            RuntimeError::StepLimit(_) => BrainrustStatus::StepLimit,
+            RuntimeError::OutputLimit(_) => BrainrustStatus::OutputLimit,
+            RuntimeError::TapeLimit(_) => BrainrustStatus::TapeLimit,
+            RuntimeError::Timeout => BrainrustStatus::Timeout,
+            RuntimeError::Cancelled => BrainrustStatus::Cancelled,
+            RuntimeError::Io(_) => BrainrustStatus::Io,
+            RuntimeError::Extension(_) => BrainrustStatus::Extension,
+            RuntimeError::AssertionFailed(_) => BrainrustStatus::AssertionFailed,
+            RuntimeError::MemoryLimit(_) => BrainrustStatus::MemoryLimit,
+            RuntimeError::CanaryOverwritten(_) => BrainrustStatus::CanaryOverwritten,
+            RuntimeError::UndefinedProcedure(_) => BrainrustStatus::UndefinedProcedure,
+        }
+    }
+}
+
+/// A machine with its program loaded
+pub struct BrainrustMachine(Machine);
+
+// Borrow `len` bytes from C, where a null pointer with zero length is empty
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    if data.is_null() {
+        &[]
+    } else {
+        slice::from_raw_parts(data, len)
+    }
+}
+
+/// Parse `source` and make a machine to run it, reading `input` for `,`.
+/// Both are copied. Returns null if the source isn't UTF-8 or doesn't parse.
+///
+/// # Safety
+///
+/// `source` and `input` must each be null or point to the given number of
+/// readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn brainrust_new(
+    source: *const u8,
+    source_len: usize,
+    input: *const u8,
+    input_len: usize,
+) -> *mut BrainrustMachine {
+    let Ok(source) = str::from_utf8(bytes(source, source_len)) else {
+        return ptr::null_mut();
+    };
+    let built = Machine::builder()
+        .input(io::Cursor::new(bytes(input, input_len).to_vec()))
+        .build(source.to_owned());
+    match built {
+        Ok(machine) => Box::into_raw(Box::new(BrainrustMachine(machine))),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Run until the program halts or fails. A machine that has halted stays
+/// as it is.
+///
+/// # Safety
+///
+/// `machine` must be null or a live handle from `brainrust_new`.
+#[no_mangle]
+pub unsafe extern "C" fn brainrust_run(machine: *mut BrainrustMachine) -> BrainrustStatus {
+    let Some(BrainrustMachine(machine)) = machine.as_mut() else {
+        return BrainrustStatus::InvalidArgument;
+    };
+    match machine.run() {
+        Ok(()) => BrainrustStatus::Halted,
+        Err(err) => err.into(),
+    }
+}
+
+/// Execute one instruction. Returns `Running` while there is more to run.
+///
+/// # Safety
+///
+/// `machine` must be null or a live handle from `brainrust_new`.
+#[no_mangle]
+pub unsafe extern "C" fn brainrust_step(machine: *mut BrainrustMachine) -> BrainrustStatus {
+    let Some(BrainrustMachine(machine)) = machine.as_mut() else {
+        return BrainrustStatus::InvalidArgument;
+    };
+    match machine.steps().next() {
+        Some(Err(err)) => err.into(),
+        Some(Ok(_)) if !machine.halted => BrainrustStatus::Running,
+        _ => BrainrustStatus::Halted,
+    }
+}
+
+/// Store the value of tape cell `index` in `value`. Returns false, leaving
+/// `value` alone, if the cell is off the tape.
+///
+/// # Safety
+///
+/// `machine` must be null or a live handle from `brainrust_new`, and `value`
+/// must be valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn brainrust_read_cell(
+    machine: *const BrainrustMachine,
+    index: usize,
+    value: *mut u32,
+) -> bool {
+    let Some(BrainrustMachine(machine)) = machine.as_ref() else {
+        return false;
+    };
+    match machine.cell(index) {
+        Some(cell) => {
+            *value = cell;
+            true
+        },
+        None => false,
+    }
+}
+
+/// The cell the data pointer is on, or 0 for a null handle
+///
+/// # Safety
+///
+/// `machine` must be null or a live handle from `brainrust_new`.
+#[no_mangle]
+pub unsafe extern "C" fn brainrust_data_ptr(machine: *const BrainrustMachine) -> usize {
+    match machine.as_ref() {
+        Some(BrainrustMachine(machine)) => machine.data_ptr,
+        None => 0,
+    }
+}
+
+/// Everything the program has written so far. The bytes stay valid until the
+/// machine next runs or is freed. A null handle has written nothing: `len` is
+/// set to 0 and null returned.
+///
+/// # Safety
+///
+/// `machine` must be null or a live handle from `brainrust_new`, and `len`
+/// must be valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn brainrust_output(
+    machine: *const BrainrustMachine,
+    len: *mut usize,
+) -> *const u8 {
+    let Some(BrainrustMachine(machine)) = machine.as_ref() else {
+        *len = 0;
+        return ptr::null();
+    };
+    *len = machine.output.len();
+    machine.output.as_ptr()
+}
+
+/// Release a machine. Null is ignored.
+///
+/// # Safety
+///
+/// `machine` must be null or a live handle from `brainrust_new`, and is not
+/// valid afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn brainrust_free(machine: *mut BrainrustMachine) {
+    if !machine.is_null() {
+        drop(Box::from_raw(machine));
+    }
+}
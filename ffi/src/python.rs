@@ -0,0 +1,114 @@
+// Python bindings: the `brainrust` module, with a `Machine` class for
+// stepping through programs and a `run` shortcut for whole runs
+
+use std::io;
+
+use pyo3::exceptions::{PyIndexError, PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use brainrust::{Machine, ParseError, RuntimeError};
+
+fn parse_error(err: ParseError) -> PyErr {
+    PyValueError::new_err(err.to_string())
+}
+
+fn runtime_error(machine: &Machine, err: RuntimeError) -> PyErr {
+    PyRuntimeError::new_err(machine.fault(err).to_string())
+}
+
+fn build(source: &str, input: &[u8]) -> PyResult<Machine> {
+    Machine::builder()
+        .input(io::Cursor::new(input.to_vec()))
+        .build(source.to_owned())
+        .map_err(parse_error)
+}
+
+// A machine with its program loaded. Machines hold their hooks and streams
+// without locks, so each stays on the thread that made it.
+#[pyclass(name = "Machine", unsendable)]
+struct PyMachine(Machine);
+
+#[pymethods]
+impl PyMachine {
+    #[new]
+    #[pyo3(signature = (source, input = b"".to_vec()))]
+    fn new(source: &str, input: Vec<u8>) -> PyResult<Self> {
+        build(source, &input).map(PyMachine)
+    }
+
+    // Run until the program halts
+    fn run(&mut self) -> PyResult<()> {
+        let machine = &mut self.0;
+        machine.run().map_err(|err| runtime_error(machine, err))
+    }
+
+    // Execute one instruction, returning whether there is more to run
+    fn step(&mut self) -> PyResult<bool> {
+        let machine = &mut self.0;
+        match machine.steps().next() {
+            Some(Err(err)) => Err(runtime_error(machine, err)),
+            Some(Ok(_)) => Ok(!machine.halted),
+            None => Ok(false),
+        }
+    }
+
+    fn cell(&self, index: usize) -> PyResult<u32> {
+        self.0.cell(index)
+            .ok_or_else(|| PyIndexError::new_err("cell is off the tape"))
+    }
+
+    // The cells from `start` up to `end`, or to the last nonzero one
+    #[pyo3(signature = (start = 0, end = None))]
+    fn tape(&self, start: usize, end: Option<usize>) -> Vec<u32> {
+        let end = end.unwrap_or(self.0.used_cells().len());
+        self.0.cells(start..end)
+    }
+
+    #[getter]
+    fn output<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.0.output)
+    }
+
+    #[getter]
+    fn data_ptr(&self) -> usize {
+        self.0.data_ptr
+    }
+
+    #[getter]
+    fn prog_ctr(&self) -> usize {
+        self.0.prog_ctr
+    }
+
+    #[getter]
+    fn steps(&self) -> u64 {
+        self.0.steps
+    }
+
+    #[getter]
+    fn halted(&self) -> bool {
+        self.0.halted
+    }
+
+    fn __repr__(&self) -> String {
+        format!("<Machine data_ptr={} prog_ctr={} steps={}>",
+                self.0.data_ptr, self.0.prog_ctr, self.0.steps)
+    }
+}
+
+// Run a program to completion and return what it wrote
+#[pyfunction]
+#[pyo3(signature = (source, input = b"".to_vec()))]
+fn run<'py>(py: Python<'py>, source: &str, input: Vec<u8>) -> PyResult<Bound<'py, PyBytes>> {
+    let mut machine = build(source, &input)?;
+    machine.run().map_err(|err| runtime_error(&machine, err))?;
+    Ok(PyBytes::new(py, &machine.output))
+}
+
+#[pymodule]
+#[pyo3(name = "brainrust")]
+fn init(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyMachine>()?;
+    module.add_function(wrap_pyfunction!(run, module)?)?;
+    Ok(())
+}
@@ -0,0 +1,102 @@
+// WebAssembly exports for a browser playground: a `Machine` class that runs
+// in bounded slices, so the page can draw between them, and the state a
+// visual debugger shows
+
+use std::io;
+
+use wasm_bindgen::prelude::*;
+
+use brainrust::{Machine, RuntimeError, parse};
+
+fn runtime_error(machine: &Machine, err: RuntimeError) -> JsError {
+    JsError::new(&machine.fault(err).to_string())
+}
+
+// Check that a program parses, reporting where it doesn't
+#[wasm_bindgen(js_name = parse)]
+pub fn check(source: &str) -> Result<(), JsError> {
+    parse(source).map(|_| ()).map_err(|err| JsError::new(&err.to_string()))
+}
+
+// A machine with its program loaded
+#[wasm_bindgen(js_name = Machine)]
+pub struct WasmMachine(Machine);
+
+#[wasm_bindgen(js_class = Machine)]
+impl WasmMachine {
+    #[wasm_bindgen(constructor)]
+    pub fn new(source: &str, input: &[u8]) -> Result<WasmMachine, JsError> {
+        Machine::builder()
+            .input(io::Cursor::new(input.to_vec()))
+            .build(source.to_owned())
+            .map(WasmMachine)
+            .map_err(|err| JsError::new(&err.to_string()))
+    }
+
+    // Execute one instruction, returning whether there is more to run
+    pub fn step(&mut self) -> Result<bool, JsError> {
+        self.run(1)
+    }
+
+    // Execute up to `max_steps` instructions, returning whether there is
+    // more to run
+    pub fn run(&mut self, max_steps: u32) -> Result<bool, JsError> {
+        let machine = &mut self.0;
+        for step in machine.steps().take(max_steps as usize) {
+            if let Err(err) = step {
+                return Err(runtime_error(machine, err));
+            }
+        }
+        Ok(!machine.halted)
+    }
+
+    pub fn cell(&self, index: usize) -> Option<u32> {
+        self.0.cell(index)
+    }
+
+    // The cells from `start` up to `end`, clipped to the tape
+    pub fn tape(&self, start: usize, end: usize) -> Vec<u32> {
+        self.0.cells(start..end)
+    }
+
+    pub fn output(&self) -> Vec<u8> {
+        self.0.output.clone()
+    }
+
+    #[wasm_bindgen(getter, js_name = dataPtr)]
+    pub fn data_ptr(&self) -> usize {
+        self.0.data_ptr
+    }
+
+    #[wasm_bindgen(getter, js_name = progCtr)]
+    pub fn prog_ctr(&self) -> usize {
+        self.0.prog_ctr
+    }
+
+    // 1-based line and column of the next instruction, for highlighting
+    #[wasm_bindgen(getter)]
+    pub fn line(&self) -> usize {
+        self.pos().1 + 1
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn column(&self) -> usize {
+        self.pos().0 + 1
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn steps(&self) -> u64 {
+        self.0.steps
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn halted(&self) -> bool {
+        self.0.halted
+    }
+}
+
+impl WasmMachine {
+    fn pos(&self) -> (usize, usize) {
+        self.0.prog.positions.get(self.0.prog_ctr).copied().unwrap_or_default()
+    }
+}
@@ -0,0 +1,58 @@
+use std::ptr;
+use std::slice;
+
+use brainrust_ffi::*;
+
+#[test]
+fn runs_through_handles() {
+    let source = b"++++++++[>++++++++<-]>+.,.";
+    unsafe {
+        let machine = brainrust_new(source.as_ptr(), source.len(), b"z".as_ptr(), 1);
+        assert!(!machine.is_null());
+        assert_eq!(brainrust_run(machine), BrainrustStatus::Halted);
+        let mut len = 0;
+        let output = brainrust_output(machine, &mut len);
+        assert_eq!(slice::from_raw_parts(output, len), b"Az");
+        let mut cell = 0;
+        assert!(brainrust_read_cell(machine, 1, &mut cell));
+        assert_eq!(cell, b'z' as u32);
+        assert_eq!(brainrust_data_ptr(machine), 1);
+
+        // Running it again doesn't repeat the last instruction
+        assert_eq!(brainrust_run(machine), BrainrustStatus::Halted);
+        let output = brainrust_output(machine, &mut len);
+        assert_eq!(slice::from_raw_parts(output, len), b"Az");
+        brainrust_free(machine);
+    }
+}
+
+#[test]
+fn steps_until_halted() {
+    let source = b"+>";
+    unsafe {
+        let machine = brainrust_new(source.as_ptr(), source.len(), ptr::null(), 0);
+        assert_eq!(brainrust_step(machine), BrainrustStatus::Running);
+        assert_eq!(brainrust_step(machine), BrainrustStatus::Halted);
+        assert_eq!(brainrust_step(machine), BrainrustStatus::Halted);
+        brainrust_free(machine);
+    }
+}
+
+#[test]
+fn reports_failures() {
+    let source = b"<";
+    unsafe {
+        assert!(brainrust_new(b"[".as_ptr(), 1, ptr::null(), 0).is_null());
+        let machine = brainrust_new(source.as_ptr(), source.len(), ptr::null(), 0);
+        assert_eq!(brainrust_run(machine), BrainrustStatus::PtrUnderflow);
+        let mut cell = 7;
+        assert!(!brainrust_read_cell(machine, usize::MAX, &mut cell));
+        assert_eq!(cell, 7);
+        brainrust_free(machine);
+        assert_eq!(brainrust_run(ptr::null_mut()), BrainrustStatus::InvalidArgument);
+        assert_eq!(brainrust_data_ptr(ptr::null()), 0);
+        let mut len = 7;
+        assert!(brainrust_output(ptr::null(), &mut len).is_null());
+        assert_eq!(len, 0);
+    }
+}
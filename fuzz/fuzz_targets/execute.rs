@@ -0,0 +1,41 @@
+#![no_main]
+
+use std::io;
+
+use brainrust::{CellSize, Dialect, Eof, Limits, Machine, Overflow, PtrBounds, Semantics};
+use libfuzzer_sys::fuzz_target;
+
+const CELL_SIZES: [CellSize; 3] = [CellSize::U8, CellSize::U16, CellSize::U32];
+const OVERFLOWS: [Overflow; 3] = [Overflow::Wrap, Overflow::Saturate, Overflow::Error];
+const EOFS: [Eof; 3] = [Eof::Zero, Eof::Max, Eof::Unchanged];
+const PTR_BOUNDS: [PtrBounds; 3] = [PtrBounds::Error, PtrBounds::Wrap, PtrBounds::Extend];
+
+// Run any program that parses, under every combination of policies and
+// either dialect, for a bounded number of steps. Runtime errors are fine;
+// panics are not.
+fuzz_target!(|data: (u8, u8, &str, &[u8])| {
+    let (policies, tape_size, source, input) = data;
+    let pick = |shift: u8| (policies >> shift) as usize % 3;
+    let semantics = Semantics {
+        cell_size: CELL_SIZES[pick(0)],
+        tape_size: (tape_size & 0x7f) as usize + 1,
+        overflow: OVERFLOWS[pick(2)],
+        eof: EOFS[pick(4)],
+        ptr_bounds: PTR_BOUNDS[pick(6)],
+        dialect: if tape_size & 0x80 != 0 { Dialect::Pbrain } else { Dialect::Brainfuck },
+    };
+    let limits = Limits {
+        max_steps: Some(10_000),
+        max_output: Some(1 << 16),
+        max_tape: Some(1 << 16),
+        ..Limits::default()
+    };
+    let built = Machine::builder()
+        .semantics(semantics)
+        .input(io::Cursor::new(input.to_vec()))
+        .limits(limits)
+        .build(source.to_owned());
+    if let Ok(mut machine) = built {
+        let _ = machine.run();
+    }
+});
@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// The parser must reject bad programs with an error, never a panic, and
+// every bracket it accepts must be matched
+fuzz_target!(|source: &str| {
+    if let Ok(prog) = brainrust::parse(source) {
+        assert!(prog.jumps_match());
+    }
+});
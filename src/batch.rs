@@ -0,0 +1,120 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, Instant};
+
+use brainrust::{FarmRun, FarmSummary, Machine, RunFarm, RuntimeError, Semantics};
+
+use crate::cli::RunAllArgs;
+use crate::{configure, exit};
+
+// How one program in a batch ended
+enum Outcome {
+    Pass,
+    Fail(String),
+    Timeout,
+}
+
+struct BatchResult {
+    path: PathBuf,
+    outcome: Outcome,
+    steps: u64,
+    elapsed: Duration,
+}
+
+// Run every program in a directory headlessly, with no input, and print a
+// summary table. Exits nonzero unless every program halts cleanly.
+pub fn run_all(args: RunAllArgs) {
+    let (_, semantics) = configure(&args.semantics);
+    let paths = programs_in(&args.dir).unwrap_or_else(|err| {
+        eprintln!("Failed to read {}: {}", args.dir.display(), err);
+        process::exit(exit::FAILURE);
+    });
+    let timeout = Duration::from_secs_f32(args.timeout);
+
+    // Programs that can't be read or parsed fail without running; the rest
+    // share the farm, and results keep the directory order.
+    let mut farm = RunFarm::new(args.jobs as usize)
+        .limits(args.limits.to_limits())
+        .timeout(timeout);
+    let mut results: Vec<Option<BatchResult>> = Vec::new();
+    for path in &paths {
+        match load(path, &semantics) {
+            Ok(machine) => {
+                farm.add(machine);
+                results.push(None);
+            },
+            Err(why) => results.push(Some(BatchResult {
+                path: path.clone(), outcome: Outcome::Fail(why), steps: 0, elapsed: Duration::ZERO,
+            })),
+        }
+    }
+    let jobs = farm.jobs();
+    let start = Instant::now();
+    let runs = farm.run();
+    let wall = start.elapsed();
+    let summary = FarmSummary::of(&runs);
+    let threads = jobs.min(runs.len());
+    let mut runs = runs.into_iter();
+    let results: Vec<BatchResult> = results.into_iter()
+        .zip(&paths)
+        .map(|(result, path)| result.unwrap_or_else(|| {
+            let FarmRun { machine, result, elapsed } = runs.next().unwrap();
+            let outcome = match result {
+                Ok(()) => Outcome::Pass,
+                Err(RuntimeError::Timeout) => Outcome::Timeout,
+                Err(err) => Outcome::Fail(format!("runtime error at {}", machine.fault(err))),
+            };
+            BatchResult { path: path.clone(), outcome, steps: machine.steps, elapsed }
+        }))
+        .collect();
+
+    let name_width = results.iter()
+        .map(|r| r.path.display().to_string().len())
+        .chain(Some("FILE".len()))
+        .max()
+        .unwrap_or(0);
+    println!("{:w$}  {:7}  {:>12}  {:>8}", "FILE", "RESULT", "STEPS", "TIME", w = name_width);
+    let (mut passed, mut failed, mut timed_out) = (0, 0, 0);
+    for result in &results {
+        let (label, detail) = match &result.outcome {
+            Outcome::Pass => { passed += 1; ("pass", String::new()) },
+            Outcome::Fail(why) => { failed += 1; ("fail", format!("  {}", why)) },
+            Outcome::Timeout => { timed_out += 1; ("timeout", String::new()) },
+        };
+        println!("{:w$}  {:7}  {:>12}  {:>7.2}s{}",
+                 result.path.display(), label, result.steps,
+                 result.elapsed.as_secs_f32(), detail, w = name_width);
+    }
+    println!("{} programs: {} passed, {} failed, {} timed out",
+             results.len(), passed, failed, timed_out);
+    println!("{} steps in {:.2}s; threads: {}, busy {:.2}s",
+             summary.steps, wall.as_secs_f32(), threads, summary.busy.as_secs_f32());
+    if passed != results.len() {
+        process::exit(exit::FAILURE);
+    }
+}
+
+// The `.b` and `.bf` files directly inside `dir`, sorted by name
+fn programs_in(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_program = path.extension()
+            .is_some_and(|ext| ext == "b" || ext == "bf");
+        if is_program && path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+fn load(path: &Path, semantics: &Semantics) -> Result<Machine, String> {
+    let program = fs::read_to_string(path).map_err(|err| format!("read failed: {}", err))?;
+    Machine::builder()
+        .semantics(semantics.clone())
+        .build(program)
+        .map_err(|err| format!("parse error {}", err))
+}
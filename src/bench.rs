@@ -0,0 +1,78 @@
+use std::io::{self, Read};
+use std::process;
+use std::time::{Duration, Instant};
+
+use brainrust::{Dispatch, Machine, Semantics};
+
+use crate::cli::{BenchArgs, LimitArgs};
+use crate::{configure, exit, exit_code, load_program, open_input};
+
+// Time a program run headlessly, stepping one instruction at a time and
+// then with superoperators, and report the speedup. Or compare the dispatch
+// strategies, stepping.
+pub fn bench(args: BenchArgs) {
+    let (_, semantics) = configure(&args.semantics);
+    let program = load_program(&args.source);
+    let mut input = Vec::new();
+    open_input(&args.input).read_to_end(&mut input).unwrap_or_else(|_| {
+        eprintln!("Input read failed!");
+        process::exit(exit::FAILURE);
+    });
+
+    let time = |dispatch, fusion| {
+        time(&program, &input, &semantics, &args.limits, args.runs, |machine: &mut Machine| {
+            machine.dispatch = dispatch;
+            machine.fusion = fusion;
+        })
+    };
+    if args.dispatch_compare {
+        let (matched, steps) = time(Dispatch::Match, false);
+        let (table, _) = time(Dispatch::Table, false);
+        println!("{} steps, best of {} runs", steps, args.runs);
+        println!("match    {}", rate(matched, steps));
+        println!("table    {}", rate(table, steps));
+        println!("speedup  {:.2}x", matched.as_secs_f64() / table.as_secs_f64());
+    } else {
+        let (stepped, steps) = time(args.dispatch, false);
+        let (fused, _) = time(args.dispatch, true);
+        println!("{} steps, best of {} runs", steps, args.runs);
+        println!("stepped  {}", rate(stepped, steps));
+        println!("fused    {}", rate(fused, steps));
+        println!("speedup  {:.2}x", stepped.as_secs_f64() / fused.as_secs_f64());
+    }
+}
+
+// The shortest of `runs` runs of machines set up by `setup`, and the steps
+// each took
+fn time(program: &str, input: &[u8], semantics: &Semantics, limits: &LimitArgs, runs: u32,
+        setup: impl Fn(&mut Machine)) -> (Duration, u64) {
+    let mut best = Duration::MAX;
+    let mut steps = 0;
+    for _ in 0..runs {
+        let mut machine = Machine::builder()
+            .semantics(semantics.clone())
+            .input(io::Cursor::new(input.to_vec()))
+            .limits(limits.to_limits())
+            .build(program.to_owned())
+            .unwrap_or_else(|err| {
+                eprintln!("Failed to parse program: {}", err);
+                process::exit(exit::PARSE);
+            });
+        setup(&mut machine);
+        let start = Instant::now();
+        let result = machine.run();
+        best = best.min(start.elapsed());
+        if let Err(err) = result {
+            let fault = machine.fault(err);
+            eprintln!("Runtime error at {}", fault);
+            process::exit(exit_code(&fault.error));
+        }
+        steps = machine.steps;
+    }
+    (best, steps)
+}
+
+fn rate(time: Duration, steps: u64) -> String {
+    format!("{:>10.3} ms  {:>8.1}M steps/s",
+            time.as_secs_f64() * 1e3, steps as f64 / time.as_secs_f64() / 1e6)
+}
@@ -0,0 +1,123 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+// A pbrain procedure call not yet returned from
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Call {
+    pub at: usize,       // The `:`
+    pub procedure: u32,
+    pub step: u64,       // Steps taken before it
+}
+
+// The procedures a pbrain program called, from where: each node is a
+// procedure called from its parent, with how often and the steps spent in
+// it, calls it made included. The root is the program outside any call.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CallTree {
+    pub nodes: Vec<CallNode>,
+    path: Vec<usize>,  // Nodes of the calls not yet returned from, outermost first
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CallNode {
+    pub procedure: Option<u32>,  // None for the root
+    pub calls: u64,
+    pub steps: u64,              // In the calls that have returned
+    pub children: Vec<usize>,    // In the order they were first called
+}
+
+impl Default for CallTree {
+    fn default() -> CallTree {
+        let root = CallNode { procedure: None, calls: 1, steps: 0, children: Vec::new() };
+        CallTree { nodes: alloc::vec![root], path: Vec::new() }
+    }
+}
+
+impl CallTree {
+    // The node running now
+    pub fn current(&self) -> usize {
+        self.path.last().copied().unwrap_or(0)
+    }
+
+    // Go into a call of `procedure` from the node running now, counting
+    // the call if `count`
+    pub(crate) fn enter(&mut self, procedure: u32, count: bool) {
+        let parent = self.current();
+        let node = match self.nodes[parent].children.iter().find(|&&child| self.nodes[child].procedure == Some(procedure)) {
+            Some(&node) => node,
+            None => {
+                let node = self.nodes.len();
+                self.nodes.push(CallNode { procedure: Some(procedure), calls: 0, steps: 0, children: Vec::new() });
+                self.nodes[parent].children.push(node);
+                node
+            },
+        };
+        if count {
+            self.nodes[node].calls += 1;
+        }
+        self.path.push(node);
+    }
+
+    // Come back out of the node running now, adding `steps` to it if given
+    pub(crate) fn leave(&mut self, steps: Option<u64>) {
+        if let Some(node) = self.path.pop() {
+            self.nodes[node].steps += steps.unwrap_or(0);
+        }
+    }
+
+    // Go back into the nodes of `calls`, outermost first, without counting
+    // them, as after restoring a machine
+    pub(crate) fn follow(&mut self, calls: &[Call]) {
+        self.path.clear();
+        for call in calls {
+            self.enter(call.procedure, false);
+        }
+    }
+
+    // The steps spent in each node, counting those of the calls still
+    // running, as of `steps` steps with `calls` open
+    pub fn steps(&self, calls: &[Call], steps: u64) -> Vec<u64> {
+        let mut spent: Vec<u64> = self.nodes.iter().map(|node| node.steps).collect();
+        spent[0] = steps;
+        for (&node, call) in self.path.iter().zip(calls) {
+            spent[node] += steps - call.step;
+        }
+        spent
+    }
+
+    // The tree as lines of text, children indented under their parents,
+    // with the node running now marked
+    pub fn lines(&self, calls: &[Call], steps: u64) -> Vec<String> {
+        let spent = self.steps(calls, steps);
+        let mut lines = Vec::new();
+        let mut stack = alloc::vec![(0, 0)];  // Node and depth, next on top
+        while let Some((node, depth)) = stack.pop() {
+            let CallNode { procedure, calls, .. } = &self.nodes[node];
+            let name = match procedure {
+                Some(procedure) => format!("procedure {}", procedure),
+                None => String::from("top level"),
+            };
+            let mark = if node == self.current() { '*' } else { ' ' };
+            lines.push(format!("{}{:indent$}{:<w$} {:>10} calls {:>12} steps", mark, "", name, calls, spent[node],
+                               indent = 2 * depth, w = NAME_WIDTH.saturating_sub(2 * depth)));
+            stack.extend(self.nodes[node].children.iter().rev().map(|&child| (child, depth + 1)));
+        }
+        lines
+    }
+
+    // Roughly the memory it takes, in bytes
+    pub(crate) fn footprint(&self) -> usize {
+        self.nodes.capacity() * mem::size_of::<CallNode>()
+            + self.nodes.iter().map(|node| node.children.capacity() * mem::size_of::<usize>()).sum::<usize>()
+            + self.path.capacity() * mem::size_of::<usize>()
+    }
+}
+
+// Width of the column of names, indentation included
+const NAME_WIDTH: usize = 24;
@@ -0,0 +1,721 @@
+use std::path::PathBuf;
+
+use clap::builder::ArgPredicate;
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
+
+use brainrust::{CellSize, Dialect, Dispatch, Eof, Limits, Overflow, PtrBounds};
+
+// Command-line interface. Running `brainrust FILE` with no subcommand is
+// shorthand for `brainrust run FILE`.
+#[derive(Parser)]
+#[command(name = "brainrust", version, about = "A visual Brainfuck debugger")]
+#[command(args_conflicts_with_subcommands = true)]
+#[command(after_help = "Most options can also be set with the BRAINRUST_* environment \
+                        variables shown, which command-line flags override. \
+                        BRAINRUST_THEME_HIGHLIGHT, BRAINRUST_THEME_OUTPUT and \
+                        BRAINRUST_THEME_CHANGED set the debugger's colors.\n\n\
+                        Exit status: 0 on a clean halt, 1 on I/O or configuration errors, \
+                        2 on usage errors, 3 on parse errors, 4 on runtime errors, \
+                        5 when a step, output or tape limit is exceeded.")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Cmd>,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+#[derive(Subcommand)]
+pub enum Cmd {
+    /// Run a program in the visual debugger
+    Run(Box<RunArgs>),
+    /// Debug a program: `run` by another name, for `debug FILE --console`
+    Debug(Box<RunArgs>),
+    /// Run programs headlessly in sequence, each reading the previous one's output
+    Pipe(PipeArgs),
+    /// Run every program in a directory headlessly and summarize the results
+    RunAll(RunAllArgs),
+    /// Run two programs on the same input and report where their outputs differ
+    Compare(CompareArgs),
+    /// Run the golden tests in a directory: each `NAME.b` must print
+    /// `NAME.out` when given `NAME.in`
+    Test(TestArgs),
+    /// Run snippets interactively against a persistent tape
+    Repl(ReplArgs),
+    /// Serve the Debug Adapter Protocol on stdin and stdout, for debugging
+    /// from an editor
+    Dap(DapArgs),
+    /// Check a program for errors without running it
+    Check(CheckArgs),
+    /// Print statistics about a program
+    Stats(StatsArgs),
+    /// Time a program run headlessly, with and without fusing common
+    /// instruction sequences into superoperators
+    Bench(BenchArgs),
+    /// Run a program headlessly and save an animation of its source and the
+    /// tape around the pointer: a GIF, or an APNG if FILE ends in `.png` or
+    /// `.apng`
+    #[cfg(feature = "render")]
+    Render(RenderArgs),
+    /// Save a machine's state as JSON, or carry on running a program from one
+    #[command(subcommand, after_help = STATE_SCHEMA)]
+    State(StateCmd),
+    /// Generate a shell completion script
+    Completions {
+        #[arg(value_name = "SHELL")]
+        shell: clap_complete::Shell,
+    },
+}
+
+#[derive(Args)]
+// Debugging at the console, asked for or implied
+#[command(group(ArgGroup::new("at_console").args(["console", "replay_session"]).multiple(true)))]
+pub struct RunArgs {
+    #[command(flatten)]
+    pub source: SourceArgs,
+
+    /// Run to completion without the visual debugger
+    #[arg(long)]
+    pub headless: bool,
+
+    /// Debug at a command prompt instead of in the full-screen debugger.
+    /// Commands are read from stdin, so program input must come from
+    /// --input, --input-bytes or --args; otherwise `,` sees end of input.
+    /// Breakpoints and watchpoints are kept in FILE.breakpoints.toml and
+    /// restored the next time FILE is debugged. Implied by
+    /// --replay-session.
+    #[arg(long, conflicts_with_all = ["headless", "watch", "record", "replay",
+                                      "dump_state_on_exit", "exit_cell", "perf"],
+          default_value_if("replay_session", ArgPredicate::IsPresent, "true"))]
+    pub console: bool,
+
+    /// Run the console commands in FILE before reading any from stdin. With
+    /// `on` handlers, a script can investigate a long run unattended.
+    #[arg(long, value_name = "FILE", requires = "console")]
+    pub script: Option<PathBuf>,
+
+    /// Save the console session to FILE as it goes: the program's input and
+    /// semantics, and every command with the step it was given at, for
+    /// `brainrust debug PROGRAM --replay-session FILE`
+    #[arg(long, value_name = "FILE", requires = "console")]
+    pub record_session: Option<PathBuf>,
+
+    /// Rerun the commands of a recorded console session, with its input and
+    /// semantics, then carry on from the state it reached at the console:
+    /// `brainrust debug PROGRAM --replay-session FILE`
+    #[arg(long, value_name = "FILE",
+          conflicts_with_all = ["record_session", "script", "input", "input_bytes", "args", "headless",
+                                "watch", "record", "replay", "dump_state_on_exit", "exit_cell", "perf"])]
+    pub replay_session: Option<PathBuf>,
+
+    /// Autorun speed of the debugger, in instructions per second [default: 1]
+    #[arg(long, visible_alias = "ips", value_name = "IPS", value_parser = parse_speed,
+          env = "BRAINRUST_SPEED")]
+    pub speed: Option<f32>,
+
+    /// Memory kept for stepping backwards in the debugger, in MiB [default: 64]
+    #[arg(long, value_name = "MIB", env = "BRAINRUST_HISTORY")]
+    pub history: Option<usize>,
+
+    /// Save the machine every STEPS steps in the console, so `goto` can
+    /// reach steps older than the history; 0 for never [default: 65536]
+    #[arg(long, value_name = "STEPS", requires = "at_console")]
+    pub checkpoint_every: Option<u64>,
+
+    /// Memory kept for console checkpoints, in MiB. Past it, they are taken
+    /// half as often [default: 64]
+    #[arg(long, value_name = "MIB", requires = "at_console")]
+    pub checkpoint_budget: Option<usize>,
+
+    /// Record every executed instruction to a file, one
+    /// `step op ptr before after` line each, followed by `@NAME` for an
+    /// instruction labelled with an `@label:NAME` comment
+    #[arg(long, value_name = "FILE", requires = "headless")]
+    pub trace: Option<PathBuf>,
+
+    /// Follow a fixed tape with CELLS canary cells, and fail if one is
+    /// overwritten: a check on the interpreter for writes off the end of
+    /// the tape. They are checked every 4096 steps and on halt, or every
+    /// step with --trace, to name the step that wrote one
+    #[arg(long, value_name = "CELLS")]
+    pub canaries: Option<usize>,
+
+    /// Count executions per instruction and loop, and print the hottest to
+    /// stderr when the program halts
+    #[arg(long, requires = "headless")]
+    pub profile: bool,
+
+    /// Print performance counters to stderr when the program ends:
+    /// instructions per second, loop branches taken, tape pages allocated,
+    /// and in the debugger, the time spent drawing each frame
+    #[arg(long)]
+    pub perf: bool,
+
+    /// Print the source to stderr when the program halts, annotated with how
+    /// often each line ran and how much of the program was covered
+    #[arg(long, requires = "headless")]
+    pub coverage: bool,
+
+    /// When the program halts, write how often each line ran to a file in
+    /// lcov's tracefile format, for genhtml or an editor's coverage view
+    #[arg(long, value_name = "FILE", requires = "headless")]
+    pub lcov: Option<PathBuf>,
+
+    /// When the program halts, write its steps grouped by the loops around
+    /// them to a file as folded stacks, for inferno-flamegraph or
+    /// flamegraph.pl
+    #[arg(long, value_name = "FILE", requires = "headless")]
+    pub flamegraph: Option<PathBuf>,
+
+    /// Write each run of a loop to a file as a duration event in Chrome's
+    /// trace event format, nested in the loops around it, for
+    /// chrome://tracing or Perfetto. Each step takes a microsecond.
+    #[arg(long, value_name = "FILE", requires = "headless")]
+    pub chrome_trace: Option<PathBuf>,
+
+    /// Print the pbrain procedures called to stderr when the program ends,
+    /// each nested under its callers, with how often it was called and the
+    /// steps spent in it. The debugger shows the same tree in a pane of
+    /// its own
+    #[arg(long)]
+    pub call_tree: bool,
+
+    /// Remember the step and instruction that last wrote each cell, shown
+    /// in the debugger for the cell under the pointer
+    #[arg(long, conflicts_with = "headless")]
+    pub provenance: bool,
+
+    /// When the program halts, fails or is quit, write the machine state to
+    /// a file as JSON
+    #[arg(long, value_name = "FILE")]
+    pub dump_state_on_exit: Option<PathBuf>,
+
+    /// Save the input the program consumed, with the semantics it ran
+    /// under, to a file for --replay
+    #[arg(long, value_name = "FILE")]
+    pub record: Option<PathBuf>,
+
+    /// Rerun a recorded execution, with its input and semantics
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["record", "input", "input_bytes", "args"])]
+    pub replay: Option<PathBuf>,
+
+    /// On a clean halt, exit with the final value of cell 0
+    #[arg(long)]
+    pub exit_cell: bool,
+
+    /// Print the result as JSON instead of raw output
+    #[arg(long, requires = "headless")]
+    pub json: bool,
+
+    /// Rerun the program whenever the source file changes. A debugger still
+    /// running the program reloads the edit and carries on where it was
+    #[arg(long, requires = "file")]
+    pub watch: bool,
+
+    #[command(flatten)]
+    pub io: IoArgs,
+
+    #[command(flatten)]
+    pub semantics: SemanticsArgs,
+
+    #[command(flatten)]
+    pub limits: LimitArgs,
+}
+
+#[derive(Args)]
+pub struct PipeArgs {
+    /// Program source files, in pipeline order
+    #[arg(value_name = "FILE", required = true)]
+    pub files: Vec<PathBuf>,
+
+    #[command(flatten)]
+    pub io: IoArgs,
+
+    #[command(flatten)]
+    pub semantics: SemanticsArgs,
+
+    #[command(flatten)]
+    pub limits: LimitArgs,
+}
+
+#[derive(Args)]
+pub struct RunAllArgs {
+    /// Directory of `.b` and `.bf` programs
+    #[arg(value_name = "DIR")]
+    pub dir: PathBuf,
+
+    /// Wall-clock limit per program, in seconds
+    #[arg(long, value_name = "SECS", default_value_t = 10.0, value_parser = parse_speed)]
+    pub timeout: f32,
+
+    /// Number of programs to run at once; 0 for one per core
+    #[arg(short, long, value_name = "N", default_value_t = 0)]
+    pub jobs: u32,
+
+    #[command(flatten)]
+    pub semantics: SemanticsArgs,
+
+    #[command(flatten)]
+    pub limits: LimitArgs,
+}
+
+#[derive(Args)]
+pub struct TestArgs {
+    /// Directory of test programs and their expected outputs
+    #[arg(value_name = "DIR", default_value = "examples")]
+    pub dir: PathBuf,
+
+    #[command(flatten)]
+    pub limits: LimitArgs,
+}
+
+#[derive(Args)]
+pub struct CompareArgs {
+    /// The first program
+    #[arg(value_name = "FIRST")]
+    pub first: PathBuf,
+
+    /// The program to compare against it
+    #[arg(value_name = "SECOND", required_unless_present = "oracle")]
+    pub second: Option<PathBuf>,
+
+    /// Instead of a second program, run FIRST through another Brainfuck
+    /// interpreter and compare against its output. It is called with the
+    /// program's path as its only argument and the input on stdin.
+    #[arg(long, value_name = "INTERPRETER", conflicts_with = "second")]
+    pub oracle: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub input: InputArgs,
+
+    #[command(flatten)]
+    pub semantics: SemanticsArgs,
+
+    #[command(flatten)]
+    pub limits: LimitArgs,
+}
+
+#[derive(Args)]
+pub struct CheckArgs {
+    #[command(flatten)]
+    pub source: SourceArgs,
+
+    /// Language the program is written in [default: brainfuck]
+    #[arg(long, value_name = "DIALECT", env = "BRAINRUST_DIALECT")]
+    pub dialect: Option<Dialect>,
+
+    /// Print the result as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Args)]
+pub struct StatsArgs {
+    #[command(flatten)]
+    pub source: SourceArgs,
+
+    /// Language the program is written in [default: brainfuck]
+    #[arg(long, value_name = "DIALECT", env = "BRAINRUST_DIALECT")]
+    pub dialect: Option<Dialect>,
+
+    /// Print the statistics as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Args)]
+pub struct BenchArgs {
+    #[command(flatten)]
+    pub source: SourceArgs,
+
+    /// Times to run the program each way; the fastest run counts
+    #[arg(long, value_name = "N", default_value_t = 5,
+          value_parser = clap::value_parser!(u32).range(1..))]
+    pub runs: u32,
+
+    /// How the interpreter finds the code for each instruction
+    #[arg(long, value_name = "STRATEGY", default_value = "match")]
+    pub dispatch: Dispatch,
+
+    /// Instead, time single steps under each dispatch strategy
+    #[arg(long, conflicts_with = "dispatch")]
+    pub dispatch_compare: bool,
+
+    #[command(flatten)]
+    pub input: InputArgs,
+
+    #[command(flatten)]
+    pub semantics: SemanticsArgs,
+
+    #[command(flatten)]
+    pub limits: LimitArgs,
+}
+
+#[derive(Args)]
+pub struct ReplArgs {
+    /// Autorun speed of the debugger, in instructions per second [default: 1]
+    #[arg(long, visible_alias = "ips", value_name = "IPS", value_parser = parse_speed,
+          env = "BRAINRUST_SPEED")]
+    pub speed: Option<f32>,
+
+    #[command(flatten)]
+    pub semantics: SemanticsArgs,
+}
+
+#[cfg(feature = "render")]
+#[derive(Args)]
+pub struct RenderArgs {
+    #[command(flatten)]
+    pub source: SourceArgs,
+
+    /// Animation file to write
+    #[arg(short, long, value_name = "FILE")]
+    pub output: PathBuf,
+
+    /// Frames shown per second
+    #[arg(long, value_name = "FPS", default_value_t = 10.0, value_parser = parse_speed)]
+    pub fps: f32,
+
+    /// Steps run between frames
+    #[arg(long, value_name = "N", default_value_t = 1,
+          value_parser = clap::value_parser!(u64).range(1..))]
+    pub stride: u64,
+
+    /// Stop the run after this many frames
+    #[arg(long, value_name = "N", default_value_t = 1000,
+          value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_frames: u32,
+
+    #[command(flatten)]
+    pub input: InputArgs,
+
+    #[command(flatten)]
+    pub semantics: SemanticsArgs,
+
+    #[command(flatten)]
+    pub limits: LimitArgs,
+}
+
+// The JSON written by `state export` and --dump-state-on-exit, and read by
+// `state import`
+const STATE_SCHEMA: &str = "A state is a JSON object with these fields:\n  \
+    version    1, the version of this schema\n  \
+    semantics  {cell_size, tape_size, overflow, eof, ptr_bounds, dialect}, as \
+               their command-line values, such as \"8\" or \"wrap\"\n  \
+    tape_len   Cells in the tape\n  \
+    tape       The nonzero cells, as an object from index to value, such as {\"0\": 72}\n  \
+    data_ptr   Index of the cell under the pointer\n  \
+    prog_ctr   Index of the next instruction, counting only instructions\n  \
+    steps      Instructions executed so far\n  \
+    halted     Whether the program has finished\n  \
+    output     Every byte written so far, as an array of numbers\n  \
+    procedures For pbrain, the instruction index of the `(` defining each \
+               procedure, as an object from number to index\n  \
+    calls      For pbrain, the calls not yet returned from, innermost last, \
+               as objects {at, procedure, step}: the `:`'s index, the \
+               procedure and the steps taken before it\n\n\
+    Exports also give the run's status, line and column, and any error, which \
+    imports ignore. Input read before the export is not kept.";
+
+#[derive(Subcommand)]
+pub enum StateCmd {
+    /// Run a program headlessly and write the state it reaches. Its output
+    /// is kept in the state rather than printed.
+    Export(StateExportArgs),
+    /// Load a state and run the program on from it, under the semantics it
+    /// was saved with
+    Import(StateImportArgs),
+}
+
+#[derive(Args)]
+pub struct StateExportArgs {
+    #[command(flatten)]
+    pub source: SourceArgs,
+
+    /// Stop after this many steps rather than running until the program halts
+    #[arg(long, value_name = "N")]
+    pub steps: Option<u64>,
+
+    /// Write the state to a file instead of stdout
+    #[arg(long, value_name = "FILE")]
+    pub to: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub input: InputArgs,
+
+    #[command(flatten)]
+    pub semantics: SemanticsArgs,
+
+    #[command(flatten)]
+    pub limits: LimitArgs,
+}
+
+#[derive(Args)]
+pub struct StateImportArgs {
+    /// State file, as written by `state export` or --dump-state-on-exit
+    #[arg(value_name = "STATE")]
+    pub state: PathBuf,
+
+    #[command(flatten)]
+    pub source: SourceArgs,
+
+    /// Stop after this many more steps rather than running until the program
+    /// halts
+    #[arg(long, value_name = "N")]
+    pub steps: Option<u64>,
+
+    /// Write the state the run reaches to a file, as `state export` would.
+    /// Without it the state goes to stdout, and program output only to
+    /// --output if given.
+    #[arg(long, value_name = "FILE")]
+    pub export: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub io: IoArgs,
+
+    #[command(flatten)]
+    pub limits: LimitArgs,
+}
+
+#[derive(Args)]
+pub struct DapArgs {
+    #[command(flatten)]
+    pub semantics: SemanticsArgs,
+}
+
+// Where the program comes from
+#[derive(Args)]
+pub struct SourceArgs {
+    /// Program source file, or `-` to read it from stdin
+    #[arg(value_name = "FILE", conflicts_with = "eval")]
+    pub file: Option<PathBuf>,
+
+    /// Use the given program text instead of reading it from a file
+    #[arg(short, long, value_name = "PROGRAM", allow_hyphen_values = true)]
+    pub eval: Option<String>,
+}
+
+// Where program input comes from and output goes to
+#[derive(Args)]
+pub struct IoArgs {
+    #[command(flatten)]
+    pub input: InputArgs,
+
+    /// Write program output to a file as raw bytes
+    #[arg(short, long, value_name = "FILE", env = "BRAINRUST_OUTPUT")]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct InputArgs {
+    /// Read program input from a file instead of stdin
+    #[arg(short, long, value_name = "FILE", env = "BRAINRUST_INPUT")]
+    pub input: Option<PathBuf>,
+
+    /// Use the given bytes as program input; accepts `\xHH`, `\n`, `\r`,
+    /// `\t`, `\0` and `\\` escapes. Takes precedence over --input.
+    #[arg(long, value_name = "BYTES", value_parser = parse_bytes)]
+    pub input_bytes: Option<std::vec::Vec<u8>>,
+
+    /// Whitespace-separated arguments to feed the program ahead of its
+    /// input, each followed by a separator
+    #[arg(long, value_name = "ARGS", allow_hyphen_values = true)]
+    pub args: Option<String>,
+
+    /// Byte written after each of --args
+    #[arg(long, value_name = "SEP", default_value = "newline")]
+    pub args_separator: ArgsSeparator,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ArgsSeparator {
+    Nul,
+    Newline,
+}
+
+impl ArgsSeparator {
+    pub fn byte(self) -> u8 {
+        match self {
+            ArgsSeparator::Nul => 0,
+            ArgsSeparator::Newline => b'\n',
+        }
+    }
+}
+
+// Bounds on how much work a program may do
+#[derive(Args)]
+pub struct LimitArgs {
+    /// Stop with an error after executing this many instructions
+    #[arg(long, value_name = "N", help_heading = "Limits", env = "BRAINRUST_MAX_STEPS")]
+    pub max_steps: Option<u64>,
+
+    /// Stop with an error once the program has written this many bytes
+    #[arg(long, value_name = "BYTES", help_heading = "Limits",
+          env = "BRAINRUST_MAX_OUTPUT_BYTES")]
+    pub max_output_bytes: Option<usize>,
+
+    /// Stop with an error if the data pointer moves to this cell or beyond
+    #[arg(long, value_name = "CELLS", help_heading = "Limits",
+          env = "BRAINRUST_MAX_TAPE_CELLS")]
+    pub max_tape_cells: Option<usize>,
+
+    /// Stop with an error once the tape, output and tracking data take more
+    /// than this much memory, in MiB. The debugger forgets steps to stay
+    /// under it before the program is stopped.
+    #[arg(long, value_name = "MIB", help_heading = "Limits", env = "BRAINRUST_MEM_LIMIT")]
+    pub mem_limit: Option<usize>,
+}
+
+// Runtime semantics, and where their defaults come from
+#[derive(Args)]
+pub struct SemanticsArgs {
+    /// Width of a tape cell in bits [default: 8]
+    #[arg(long, value_name = "BITS", help_heading = "Semantics", env = "BRAINRUST_CELL_SIZE")]
+    pub cell_size: Option<CellSize>,
+
+    /// Number of cells on the tape [default: 30000]
+    #[arg(long, value_name = "CELLS", help_heading = "Semantics", env = "BRAINRUST_TAPE_SIZE")]
+    pub tape_size: Option<usize>,
+
+    /// Behavior when a cell goes out of range [default: wrap]
+    #[arg(long, value_name = "POLICY", help_heading = "Semantics", env = "BRAINRUST_OVERFLOW")]
+    pub overflow: Option<Overflow>,
+
+    /// Report every cell overflow and underflow as an error at the
+    /// instruction that caused it; the same as --overflow error. Wrapping
+    /// runs no slower for the check being available.
+    #[arg(long, help_heading = "Semantics", conflicts_with = "overflow")]
+    pub checked: bool,
+
+    /// Value stored by `,` at end of input [default: zero]
+    #[arg(long, value_name = "POLICY", help_heading = "Semantics", env = "BRAINRUST_EOF")]
+    pub eof: Option<Eof>,
+
+    /// Behavior when the data pointer leaves the tape [default: error]
+    #[arg(long, value_name = "POLICY", help_heading = "Semantics",
+          env = "BRAINRUST_PTR_BOUNDS")]
+    pub ptr_bounds: Option<PtrBounds>,
+
+    /// Language the program is written in: pbrain adds procedures, `(` to
+    /// `)` defining the one the current cell numbers and `:` calling it
+    /// [default: brainfuck]
+    #[arg(long, value_name = "DIALECT", help_heading = "Semantics", env = "BRAINRUST_DIALECT")]
+    pub dialect: Option<Dialect>,
+
+    /// Print the effective configuration at startup
+    #[arg(short, long)]
+    pub verbose: bool,
+
+    /// Read defaults from this file instead of ~/.config/brainrust/config.toml
+    #[arg(long, value_name = "FILE", env = "BRAINRUST_CONFIG")]
+    pub config: Option<PathBuf>,
+}
+
+// Parse a positive, finite number
+fn parse_speed(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err("expected a positive number".to_owned()),
+    }
+}
+
+// Decode an escaped byte string from the command line
+fn parse_bytes(s: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    let mut chars = s.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('x') => {
+                // Exactly two hex digits; `from_str_radix` alone would take a sign
+                let hex: String = chars.by_ref().take(2).collect();
+                if hex.len() != 2 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Err(format!("invalid hex escape `\\x{}`", hex));
+                }
+                bytes.push(u8::from_str_radix(&hex, 16).unwrap());
+            },
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('t') => bytes.push(b'\t'),
+            Some('0') => bytes.push(0),
+            Some('\\') => bytes.push(b'\\'),
+            Some(other) => return Err(format!("unknown escape `\\{}`", other)),
+            None => return Err("trailing `\\`".to_owned()),
+        }
+    }
+    Ok(bytes)
+}
+
+impl LimitArgs {
+    pub fn to_limits(&self) -> Limits {
+        Limits {
+            max_steps: self.max_steps,
+            max_output: self.max_output_bytes,
+            max_tape: self.max_tape_cells,
+            max_memory: self.mem_limit.map(|mib| mib.saturating_mul(1 << 20)),
+        }
+    }
+}
+
+impl Cli {
+    // The subcommand to run, falling back to `run` if none was given.
+    pub fn into_command(self) -> Cmd {
+        self.command.unwrap_or(Cmd::Run(Box::new(self.run)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use clap::Parser;
+
+    use super::{Cli, Cmd, parse_bytes};
+
+    #[test]
+    fn debugs_at_the_console() {
+        let cli = Cli::try_parse_from(["brainrust", "debug", "f.b", "--console"]).unwrap();
+        match cli.into_command() {
+            Cmd::Debug(args) => {
+                assert!(args.console);
+                assert_eq!(args.source.file.as_deref(), Some("f.b".as_ref()));
+            },
+            _ => panic!("expected the debug subcommand"),
+        }
+    }
+
+    #[test]
+    fn replays_sessions_at_the_console() {
+        let cli = Cli::try_parse_from(["brainrust", "debug", "f.b", "--replay-session", "s.json"]).unwrap();
+        match cli.into_command() {
+            Cmd::Debug(args) => assert!(args.console),  // Implied
+            _ => panic!("expected the debug subcommand"),
+        }
+        assert!(Cli::try_parse_from(["brainrust", "debug", "f.b", "--replay-session", "s.json", "--headless"]).is_err());
+    }
+
+    #[test]
+    fn completes_flag_values() {
+        let mut script = Vec::new();
+        clap_complete::generate(clap_complete::Shell::Bash, &mut Cli::command(), "brainrust", &mut script);
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.contains(r#"compgen -W "brainfuck pbrain""#));
+        assert!(script.contains(r#"compgen -W "8 16 32""#));
+    }
+
+    #[test]
+    fn parses_escaped_bytes() {
+        assert_eq!(parse_bytes(r"a\x41\xfF\n\0\\").unwrap(), b"aA\xff\n\0\\");
+        assert_eq!(parse_bytes("é").unwrap(), "é".as_bytes());
+        assert_eq!(parse_bytes(r"\x+f").unwrap_err(), r"invalid hex escape `\x+f`");
+        assert_eq!(parse_bytes(r"\x-1").unwrap_err(), r"invalid hex escape `\x-1`");
+        assert_eq!(parse_bytes(r"ab\xf").unwrap_err(), r"invalid hex escape `\xf`");
+        assert_eq!(parse_bytes(r"\x").unwrap_err(), r"invalid hex escape `\x`");
+        assert_eq!(parse_bytes(r"\q").unwrap_err(), r"unknown escape `\q`");
+        assert_eq!(parse_bytes("\\").unwrap_err(), "trailing `\\`");
+    }
+}
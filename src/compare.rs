@@ -0,0 +1,111 @@
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{self, Command, Stdio};
+use std::thread;
+
+use brainrust::{Machine, Semantics};
+
+use crate::cli::{CompareArgs, LimitArgs};
+use crate::{configure, exit, open_input, read_program};
+
+// Bytes of context shown on either side of a divergence
+const CONTEXT: usize = 16;
+
+// Run both programs on the same input and report the first byte at which
+// their outputs differ. Exits nonzero if they do.
+pub fn compare(args: CompareArgs) {
+    let (_, semantics) = configure(&args.semantics);
+    let mut input = Vec::new();
+    open_input(&args.input).read_to_end(&mut input).unwrap_or_else(|_| {
+        eprintln!("Input read failed!");
+        process::exit(exit::FAILURE);
+    });
+
+    let first = run(&args.first, &input, &semantics, &args.limits);
+    let (second, b) = match (&args.second, &args.oracle) {
+        (Some(second), _) => (run(second, &input, &semantics, &args.limits), second.display()),
+        (None, Some(oracle)) => (consult(oracle, &args.first, &input), oracle.display()),
+        (None, None) => unreachable!("clap requires one of them"),
+    };
+    let a = args.first.display();
+
+    let divergence = first.iter()
+        .zip(&second)
+        .position(|(x, y)| x != y)
+        .or_else(|| if first.len() != second.len() {
+            Some(first.len().min(second.len()))
+        } else {
+            None
+        });
+    match divergence {
+        None => println!("Outputs match ({} bytes)", first.len()),
+        Some(offset) => {
+            println!("Outputs diverge at byte {}:", offset);
+            let width = a.to_string().len().max(b.to_string().len());
+            println!("  {:w$}  {}", a, context(&first, offset), w = width);
+            println!("  {:w$}  {}", b, context(&second, offset), w = width);
+            process::exit(exit::FAILURE);
+        },
+    }
+}
+
+// Run a program headlessly, returning its output. Errors are reported but
+// the output produced up to that point is still compared.
+fn run(path: &Path, input: &[u8], semantics: &Semantics, limits: &LimitArgs) -> Vec<u8> {
+    let mut machine = Machine::builder()
+        .semantics(semantics.clone())
+        .input(io::Cursor::new(input.to_vec()))
+        .limits(limits.to_limits())
+        .build(read_program(path))
+        .unwrap_or_else(|err| {
+            eprintln!("{}: Failed to parse program: {}", path.display(), err);
+            process::exit(exit::PARSE);
+        });
+    if let Err(err) = machine.run() {
+        eprintln!("{}: Runtime error at {}", path.display(), machine.fault(err));
+    }
+    machine.output
+}
+
+// Run a program through an external interpreter, returning its output. A
+// nonzero exit is reported, like a runtime error, but still compared.
+fn consult(oracle: &Path, path: &Path, input: &[u8]) -> Vec<u8> {
+    let mut child = Command::new(oracle)
+        .arg(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|err| {
+            eprintln!("{}: Failed to start: {}", oracle.display(), err);
+            process::exit(exit::FAILURE);
+        });
+    // Feed input from another thread so a full stdout pipe can't deadlock us
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_vec();
+    let writer = thread::spawn(move || stdin.write_all(&input));
+    let result = child.wait_with_output();
+    // The interpreter may exit without reading all its input
+    let _ = writer.join();
+    let output = result.unwrap_or_else(|err| {
+        eprintln!("{}: Failed to run: {}", oracle.display(), err);
+        process::exit(exit::FAILURE);
+    });
+    if !output.status.success() {
+        eprintln!("{}: Exited with {}", oracle.display(), output.status);
+    }
+    output.stdout
+}
+
+// The output around `offset`, escaped, with the diverging byte bracketed
+fn context(output: &[u8], offset: usize) -> String {
+    let start = offset.saturating_sub(CONTEXT);
+    let end = output.len().min(offset + CONTEXT);
+    let escape = |bytes: &[u8]| bytes.escape_ascii().to_string();
+    match output.get(offset) {
+        Some(&byte) => format!("{}[{}]{}",
+                               escape(&output[start..offset]),
+                               escape(&[byte]),
+                               escape(&output[offset + 1..end])),
+        None => format!("{}<end of output>", escape(&output[start..])),
+    }
+}
@@ -0,0 +1,266 @@
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
+
+use thiserror::Error;
+
+use crate::vm::Machine;
+
+// A test on the machine's state, like `cell[3] == 65 && ptr > 10`.
+//
+// Values are integers: `ptr`, `pc` and `steps`, `cell` for the cell under the
+// pointer, `cell[EXPR]` for any other, and decimal literals. They combine with
+// `+ -`, compare with `== != < <= > >=`, and join with `&& || !` and
+// parentheses. Any nonzero value is true, and comparisons are 1 or 0. Cells
+// off the tape read as 0.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Error, PartialEq)]
+#[error("column {}: {message}", offset + 1)]
+pub struct ConditionError {
+    pub offset: usize,  // Bytes into the condition
+    pub message: &'static str,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    Number(i64),
+    Ptr,
+    Pc,
+    Steps,
+    Cell(Option<Box<Expr>>),  // The current cell if there is no index
+    Not(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op { Or, And, Eq, Ne, Lt, Le, Gt, Ge, Add, Sub }
+
+// Operators by precedence, loosest first
+const LEVELS: &[&[(&str, Op)]] = &[
+    &[("||", Op::Or)],
+    &[("&&", Op::And)],
+    &[("==", Op::Eq), ("!=", Op::Ne), ("<=", Op::Le), (">=", Op::Ge), ("<", Op::Lt), (">", Op::Gt)],
+    &[("+", Op::Add), ("-", Op::Sub)],
+];
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Condition, ConditionError> {
+        let mut parser = Parser { src: source, pos: 0 };
+        let expr = parser.expr(0)?;
+        parser.skip_space();
+        if parser.pos < source.len() {
+            return Err(parser.error("unexpected input"));
+        }
+        Ok(Condition { source: source.trim().to_string(), expr })
+    }
+
+    pub fn holds(&self, machine: &Machine) -> bool {
+        self.value(machine) != 0
+    }
+
+    pub fn value(&self, machine: &Machine) -> i64 {
+        eval(&self.expr, machine)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+// Text with values filled in, like `i = {cell[2]} at {ptr}`. Each `{EXPR}`
+// is replaced by the value of a condition's expression; `{{` and `}}` are
+// literal braces.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogMessage {
+    source: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Value(Condition),
+}
+
+impl LogMessage {
+    pub fn parse(source: &str) -> Result<LogMessage, ConditionError> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut rest = source;
+        while let Some(i) = rest.find(['{', '}']) {
+            text.push_str(&rest[..i]);
+            let offset = source.len() - rest.len() + i;
+            let tail = &rest[i..];
+            if tail.starts_with("{{") || tail.starts_with("}}") {
+                text.push_str(&tail[..1]);
+                rest = &tail[2..];
+                continue;
+            }
+            if tail.starts_with('}') {
+                return Err(ConditionError { offset, message: "unmatched `}`" });
+            }
+            let end = tail.find('}').ok_or(ConditionError { offset, message: "expected `}`" })?;
+            let value = Condition::parse(&tail[1..end]).map_err(|err| {
+                ConditionError { offset: offset + 1 + err.offset, message: err.message }
+            })?;
+            if !text.is_empty() {
+                parts.push(Part::Text(core::mem::take(&mut text)));
+            }
+            parts.push(Part::Value(value));
+            rest = &tail[end + 1..];
+        }
+        text.push_str(rest);
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(LogMessage { source: source.to_string(), parts })
+    }
+
+    pub fn format(&self, machine: &Machine) -> String {
+        let mut message = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => message.push_str(text),
+                Part::Value(value) => message.push_str(&value.value(machine).to_string()),
+            }
+        }
+        message
+    }
+}
+
+impl fmt::Display for LogMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+fn eval(expr: &Expr, machine: &Machine) -> i64 {
+    match expr {
+        Expr::Number(n) => *n,
+        Expr::Ptr => machine.data_ptr as i64,
+        Expr::Pc => machine.prog_ctr as i64,
+        Expr::Steps => machine.steps as i64,
+        Expr::Cell(index) => {
+            let index = index.as_ref().map_or(machine.data_ptr as i64, |i| eval(i, machine));
+            usize::try_from(index).ok().and_then(|i| machine.cell(i)).map_or(0, i64::from)
+        },
+        Expr::Not(expr) => (eval(expr, machine) == 0) as i64,
+        Expr::Binary(Op::Or, a, b) => (eval(a, machine) != 0 || eval(b, machine) != 0) as i64,
+        Expr::Binary(Op::And, a, b) => (eval(a, machine) != 0 && eval(b, machine) != 0) as i64,
+        Expr::Binary(op, a, b) => {
+            let (a, b) = (eval(a, machine), eval(b, machine));
+            match op {
+                Op::Eq => (a == b) as i64,
+                Op::Ne => (a != b) as i64,
+                Op::Lt => (a < b) as i64,
+                Op::Le => (a <= b) as i64,
+                Op::Gt => (a > b) as i64,
+                Op::Ge => (a >= b) as i64,
+                Op::Add => a.saturating_add(b),
+                Op::Sub => a.saturating_sub(b),
+                Op::Or | Op::And => unreachable!(),
+            }
+        },
+    }
+}
+
+// Recursive descent over `LEVELS`, then unary expressions
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn expr(&mut self, level: usize) -> Result<Expr, ConditionError> {
+        let Some(ops) = LEVELS.get(level) else {
+            return self.unary();
+        };
+        let mut lhs = self.expr(level + 1)?;
+        'operands: loop {
+            for &(token, op) in *ops {
+                if self.eat(token) {
+                    let rhs = self.expr(level + 1)?;
+                    lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+                    continue 'operands;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, ConditionError> {
+        self.skip_space();
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let expr = self.expr(0)?;
+            return self.expect(")").map(|_| expr);
+        }
+        let rest = &self.src[self.pos..];
+        let len = rest.find(|ch: char| !ch.is_ascii_alphanumeric() && ch != '_').unwrap_or(rest.len());
+        let word = &rest[..len];
+        if word.is_empty() {
+            return Err(self.error("expected a value"));
+        }
+        let start = self.pos;
+        self.pos += len;
+        Ok(match word {
+            "ptr" => Expr::Ptr,
+            "pc" => Expr::Pc,
+            "steps" => Expr::Steps,
+            "cell" if self.eat("[") => {
+                let index = self.expr(0)?;
+                self.expect("]")?;
+                Expr::Cell(Some(Box::new(index)))
+            },
+            "cell" => Expr::Cell(None),
+            _ => match word.parse() {
+                Ok(n) => Expr::Number(n),
+                Err(_) => {
+                    self.pos = start;
+                    return Err(self.error("unknown name"));
+                },
+            },
+        })
+    }
+
+    // Consume `token`, after any whitespace, if it comes next
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_space();
+        let found = self.src[self.pos..].starts_with(token);
+        if found {
+            self.pos += token.len();
+        }
+        found
+    }
+
+    fn expect(&mut self, token: &'static str) -> Result<(), ConditionError> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(match token {
+                ")" => "expected `)`",
+                _ => "expected `]`",
+            }))
+        }
+    }
+
+    fn skip_space(&mut self) {
+        let rest = &self.src[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn error(&self, message: &'static str) -> ConditionError {
+        ConditionError { offset: self.pos, message }
+    }
+}
@@ -0,0 +1,156 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Deserializer};
+
+use brainrust::{CellSize, Dialect, Eof, Overflow, PtrBounds};
+
+// Defaults loaded from the user's config file. Every field is optional, and
+// environment variables and command-line flags take precedence over anything
+// set here.
+#[derive(Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    pub speed: Option<f32>,
+    pub history: Option<usize>,  // MiB
+    #[serde(deserialize_with = "value_enum")]
+    pub cell_size: Option<CellSize>,
+    pub tape_size: Option<usize>,
+    #[serde(deserialize_with = "value_enum")]
+    pub overflow: Option<Overflow>,
+    #[serde(deserialize_with = "value_enum")]
+    pub eof: Option<Eof>,
+    #[serde(deserialize_with = "value_enum")]
+    pub ptr_bounds: Option<PtrBounds>,
+    #[serde(deserialize_with = "value_enum")]
+    pub dialect: Option<Dialect>,
+    pub theme: ThemeConfig,
+    pub keys: KeysConfig,
+}
+
+// Colors are ANSI palette indices
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThemeConfig {
+    #[serde(deserialize_with = "color")]
+    pub highlight: Option<u8>,
+    #[serde(deserialize_with = "color")]
+    pub output: Option<u8>,
+    #[serde(deserialize_with = "color")]
+    pub changed: Option<u8>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeysConfig {
+    pub quit: Option<char>,
+    pub advance: Option<char>,
+    pub back: Option<char>,
+    pub over: Option<char>,
+    pub out: Option<char>,
+    pub output: Option<char>,
+    pub writer: Option<char>,
+    pub run: Option<char>,
+    pub calls: Option<char>,
+}
+
+impl Config {
+    // Load the config file at `path`, or at the default location if none is
+    // given, then apply overrides from the environment.
+    pub fn load(path: Option<&Path>) -> Result<Config, String> {
+        let mut config = Config::read(path)?;
+        config.theme.apply_env()?;
+        Ok(config)
+    }
+
+    // A missing default config is not an error.
+    fn read(path: Option<&Path>) -> Result<Config, String> {
+        let (path, explicit) = match path {
+            Some(path) => (path.to_owned(), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Config::default()),
+            },
+        };
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(_) if !explicit && !path.exists() => return Ok(Config::default()),
+            Err(err) => return Err(format!("Failed to read {}: {}", path.display(), err)),
+        };
+        let config: Config = toml::from_str(&text)
+            .map_err(|err| format!("Invalid config file {}: {}", path.display(), err))?;
+        if let Some(speed) = config.speed {
+            if !(speed > 0.0 && speed.is_finite()) {
+                return Err(format!("Invalid config file {}: speed must be positive",
+                                   path.display()));
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl ThemeConfig {
+    // Override colors from `BRAINRUST_THEME_*`, which have no flags of their own
+    fn apply_env(&mut self) -> Result<(), String> {
+        for (var, slot) in [("BRAINRUST_THEME_HIGHLIGHT", &mut self.highlight),
+                            ("BRAINRUST_THEME_OUTPUT", &mut self.output),
+                            ("BRAINRUST_THEME_CHANGED", &mut self.changed)] {
+            if let Some(value) = env::var_os(var) {
+                let value = value.to_string_lossy();
+                let value = value.parse::<u8>()
+                    .map(|n| toml::Value::Integer(n.into()))
+                    .unwrap_or_else(|_| toml::Value::String(value.into_owned()));
+                *slot = color(value).map_err(|err| format!("Invalid {}: {}", var, err))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// `$XDG_CONFIG_HOME/brainrust/config.toml`, falling back to `~/.config`
+fn default_path() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_dir.join("brainrust").join("config.toml"))
+}
+
+// Accept the same values as the corresponding command-line flag, written
+// either as a string or (for cell sizes) a bare integer
+fn value_enum<'de, D, T>(de: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: ValueEnum,
+{
+    let name = match toml::Value::deserialize(de)? {
+        toml::Value::String(name) => name,
+        toml::Value::Integer(n) => n.to_string(),
+        other => return Err(serde::de::Error::custom(format!("unexpected value {}", other))),
+    };
+    T::from_str(&name, false).map(Some).map_err(serde::de::Error::custom)
+}
+
+// A terminal color, given by name or as an ANSI palette index
+fn color<'de, D: Deserializer<'de>>(de: D) -> Result<Option<u8>, D::Error> {
+    const NAMES: [&str; 8] = [
+        "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+    ];
+    let index = match toml::Value::deserialize(de)? {
+        toml::Value::Integer(n) if (0..=255).contains(&n) => n as u8,
+        toml::Value::String(name) => {
+            let (base, offset) = match name.strip_prefix("light-") {
+                Some(base) => (base, 8),
+                None => (name.as_str(), 0),
+            };
+            match NAMES.iter().position(|&n| n == base) {
+                Some(i) => i as u8 + offset,
+                None => return Err(serde::de::Error::custom(format!("unknown color `{}`", name))),
+            }
+        },
+        other => return Err(serde::de::Error::custom(format!("unexpected color {}", other))),
+    };
+    Ok(Some(index))
+}
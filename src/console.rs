@@ -0,0 +1,994 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write, stdout};
+use std::path::Path;
+use std::process;
+
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+
+use brainrust::debugger::{CHECKPOINT_BUDGET, CHECKPOINT_EVERY};
+use brainrust::{
+    Condition, Debugger, HistoryEntry, HitCount, LogMessage, Machine, OutputBreak, RuntimeError, Stop, Trigger, Watch,
+};
+
+use crate::cli::RunArgs;
+use crate::profile;
+use crate::replay::{self, Session, SessionCommand};
+use crate::sidecar::Sidecar;
+use crate::{configure, exit, history_budget, open_input_or};
+
+const CONSOLE_HELP: &str = r#"Commands:
+  break LOC [hit N] [ignore N] [if COND]
+                            stop before the instruction at LOC, when COND
+                            holds, e.g. `cell[3] == 65 && ptr > 10` (b). A
+                            LOC is LINE:COL, an instruction index, or @NAME
+                            for the instruction after an `@label:NAME` comment.
+                            Given `hit N`, only the Nth time stops; given
+                            `ignore N`, not the first N
+  delete [LOC]              remove a breakpoint, or all of them (d)
+  break-output "TEXT" | N   stop once the output contains TEXT, or after its
+                            Nth byte (bo)
+  delete output [N]         remove output breakpoint N, or all of them
+  trace LOC MESSAGE         log MESSAGE whenever the instruction at LOC runs,
+                            without stopping; `{EXPR}` in it shows the value
+                            of EXPR, e.g. `i = {cell[2]}` (tp)
+  delete trace [LOC]        remove a tracepoint, or all of them
+  trace-log [FILE]          append tracepoint messages to FILE, or show them
+                            here again
+  watch CELL [read|write|change] [= VALUE]
+                            stop after any step that accesses the cell so,
+                            changes it by default, leaving it at VALUE
+  unwatch CELL              stop watching the cell
+  run                       start the program over and run it (r)
+  continue                  run until a breakpoint or watchpoint (c)
+  step [N]                  execute N instructions (s)
+  next                      step, running a loop that starts here as one step (n)
+  finish                    run until the innermost loop around here exits (f)
+  until-output [BYTE]       run until the program writes a byte, or writes
+                            BYTE, a number or a quoted character like 'A' (uo)
+  reverse-step [N]          undo N instructions (rs)
+  reverse-continue          undo until a breakpoint or watchpoint (rc)
+  goto STEP | @NAME         go back or forward to just after step STEP, from
+                            the checkpoint before it if it's older than the
+                            history, or run on to the label NAME (g, goto-step)
+  last-write CELL           undo back to just after the last write to CELL (lw)
+  written-by CELL           show the step and instruction that last wrote CELL,
+                            given --provenance (wb)
+  print EXPR                show $ptr, $pc, $steps, $cell or [CELL] (p)
+  set cell[CELL] = VALUE    patch the state and carry on: a cell, the cell
+  set ptr = CELL            under the pointer (`set cell = VALUE`), the data
+  set pc = LOC | NAME       pointer, or the next instruction, given as a LOC
+                            or a label's NAME. A VALUE is a number, 0x hex
+                            or a quoted character like 'A'. Forgets the steps
+                            that could be undone
+  x/N START                 show N cells from START, a cell or $ptr
+  list                      show the source around the next instruction (l)
+  info breakpoints|watchpoints|labels|loops|checkpoints|memory
+                            `info loops` shows the loops around the next
+                            instruction and the iteration each is on
+  output                    show everything the program has written
+  dump-history FILE         write the steps that can be undone to FILE, as CSV
+                            if it ends in .csv and as JSON otherwise (dh)
+  on EVENT COMMANDS         run COMMANDS, separated by `;`, whenever the
+                            program stops for EVENT: `breakpoint [LOC]`,
+                            `watchpoint [CELL]`, `output` or `halt`; e.g.
+                            `on breakpoint 3 x/8 0; continue`
+  help                      show this message (h)
+  quit                      leave the console (q)"#;
+
+// Cells shown on each line by `x`
+const ROW_LEN: usize = 8;
+// Source lines shown on either side of the next instruction by `list`
+const LIST_CONTEXT: usize = 3;
+
+// A line-oriented debugger console. Commands are read from stdin, so they
+// can be scripted; program input comes only from the input options.
+pub fn console(args: &RunArgs, program: String) {
+    let (config, mut semantics) = configure(&args.semantics);
+    let replayed: Option<Session> = args.replay_session.as_deref().map(replay::load);
+    // A recorded or replayed session holds all the input, so every restart
+    // reads the same
+    let input: Option<Vec<u8>> = match &replayed {
+        Some(session) => {
+            semantics = session.semantics.clone();
+            Some(session.input.clone())
+        },
+        None if args.record_session.is_some() => {
+            let mut input = Vec::new();
+            open_input_or(&args.io.input, Box::new(io::empty())).read_to_end(&mut input).unwrap_or_else(|err| {
+                eprintln!("Failed to read input: {}", err);
+                process::exit(exit::FAILURE);
+            });
+            Some(input)
+        },
+        None => None,
+    };
+    let fresh = || {
+        let mut machine = Machine::builder()
+            .semantics(semantics.clone())
+            .input(match &input {
+                Some(input) => Box::new(io::Cursor::new(input.clone())),
+                None => open_input_or(&args.io.input, Box::new(io::empty())),
+            })
+            .limits(args.limits.to_limits())
+            .build(program.clone())
+            .unwrap_or_else(|err| {
+                eprintln!("Failed to parse program: {}", err);
+                process::exit(exit::PARSE);
+            });
+        if args.provenance {
+            machine.provenance = Some(Vec::new());
+        }
+        machine.iterations = Some(Vec::new());
+        let mut debugger = Debugger::new(machine);
+        debugger.set_history_budget(history_budget(args, &config));
+        debugger.set_checkpoints(
+            args.checkpoint_every.unwrap_or(CHECKPOINT_EVERY),
+            args.checkpoint_budget.map_or(CHECKPOINT_BUDGET, |mib| mib.saturating_mul(1 << 20)),
+        );
+        debugger
+    };
+
+    let mut editor = DefaultEditor::new().unwrap_or_else(|err| {
+        eprintln!("Failed to start line editor: {}", err);
+        process::exit(exit::FAILURE);
+    });
+    let mut debugger = fresh();
+    let mut shown = 0;  // Bytes of output already printed
+    // Commands to run before prompting, from the script or a replayed
+    // session, with the step each is expected at when replayed
+    let mut script: VecDeque<(Option<u64>, String)> = VecDeque::new();
+    if let Some(path) = &args.script {
+        let text = fs::read_to_string(path).unwrap_or_else(|err| {
+            eprintln!("Failed to read {}: {}", path.display(), err);
+            process::exit(exit::FAILURE);
+        });
+        script.extend(text.lines().filter(|line| !line.trim_start().starts_with('#')).map(|line| (None, line.to_owned())));
+    }
+    // Commands of handlers, run before anything else
+    let mut queue: VecDeque<String> = VecDeque::new();
+    let mut handlers: Vec<(Event, String)> = Vec::new();
+    let mut log_file: Option<File> = None;  // Where tracepoint messages go, if not here
+
+    // Breakpoints and watchpoints persist beside a program file. A replayed
+    // session starts with those it was recorded with.
+    let file = args.source.file.as_deref().filter(|path| path.as_os_str() != "-");
+    let sidecar = match (replayed, file) {
+        (Some(session), _) => {
+            script.extend(session.commands.into_iter().map(|command| (Some(command.step), command.line)));
+            session.sidecar
+        },
+        (None, Some(path)) => Sidecar::load(path).unwrap_or_else(|msg| {
+            println!("{}", msg);
+            None
+        }),
+        (None, None) => None,
+    };
+    if let Some(sidecar) = &sidecar {
+        sidecar.restore(&mut debugger).iter().for_each(|complaint| println!("{}", complaint));
+    }
+    let mut recording = args.record_session.as_deref().map(|path| {
+        let session = Session {
+            semantics: semantics.clone(),
+            input: input.clone().unwrap_or_default(),
+            sidecar,
+            commands: Vec::new(),
+        };
+        replay::save(path, &session);
+        (path, session)
+    });
+    let mut saved = Sidecar::capture(&debugger);
+    loop {
+        let line = if let Some(line) = queue.pop_front() {
+            line
+        } else {
+            let line = match script.pop_front() {
+                Some((Some(step), _)) if step != debugger.machine().steps => {
+                    println!("Session diverged: a command given at step {} came at step {}; stopped replaying",
+                             step, debugger.machine().steps);
+                    script.clear();
+                    continue;
+                },
+                Some((_, line)) => line,
+                None => match editor.readline("(bf) ") {
+                    Ok(line) => {
+                        let _ = editor.add_history_entry(line.as_str());
+                        line
+                    },
+                    Err(ReadlineError::Interrupted) => continue,
+                    Err(ReadlineError::Eof) => break,
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        break;
+                    },
+                },
+            };
+            // Leaving isn't recorded, so a replay stops where the session did
+            let quitting = matches!(line.split_whitespace().next(), Some("q" | "quit"));
+            if let (Some((path, session)), false) = (&mut recording, quitting) {
+                session.commands.push(SessionCommand { step: debugger.machine().steps, line: line.clone() });
+                replay::save(path, session);
+            }
+            line
+        };
+        let mut was_halted = debugger.machine().halted;
+
+        let mut words = line.split_whitespace();
+        let Some(cmd) = words.next() else { continue };
+        let arg = words.next();
+        // Commands that move through the program return where they stopped
+        let result: Result<Option<Stop>, String> = match cmd {
+            "q" | "quit" => break,
+            "h" | "help" => {
+                println!("{}", CONSOLE_HELP);
+                Ok(None)
+            },
+            "on" => handler(&debugger, &line).map(|handler| {
+                handlers.push(handler);
+                None
+            }),
+            "r" | "run" => {
+                let mut restarted = fresh();
+                carry_breakpoints(&debugger, &mut restarted);
+                debugger = restarted;
+                shown = 0;
+                was_halted = false;
+                run(&mut debugger, Debugger::resume)
+            },
+            "c" | "continue" => run(&mut debugger, Debugger::resume),
+            "s" | "step" => count(arg).and_then(|n| {
+                run(&mut debugger, |debugger| {
+                    let mut stop = Stop::Step;
+                    for _ in 0..n {
+                        stop = debugger.step()?;
+                        if stop != Stop::Step {
+                            break;
+                        }
+                    }
+                    Ok(stop)
+                })
+            }),
+            "n" | "next" => run(&mut debugger, Debugger::step_over),
+            "f" | "finish" => run(&mut debugger, Debugger::step_out),
+            "uo" | "until-output" => byte(arg).and_then(|byte| {
+                run(&mut debugger, |debugger| debugger.run_to_output(byte))
+            }),
+            "rs" | "reverse-step" => count(arg).map(|n| {
+                let mut stop = Stop::Step;
+                for _ in 0..n {
+                    stop = debugger.step_back();
+                    if stop == Stop::StartOfHistory {
+                        break;
+                    }
+                }
+                Some(stop)
+            }),
+            "rc" | "reverse-continue" => Ok(Some(debugger.reverse_resume())),
+            "g" | "goto" | "goto-step" => match arg {
+                Some(label) if label.starts_with('@') => location(&debugger, arg)
+                    .and_then(|i| run(&mut debugger, |debugger| run_to(debugger, i))),
+                _ => arg.ok_or_else(|| "Expected a step number or a label".to_string())
+                    .and_then(number)
+                    .and_then(|step| run(&mut debugger, |debugger| debugger.seek(step as u64))),
+            },
+            "lw" | "last-write" => cell(&debugger, arg).and_then(|cell| {
+                let writer = debugger.reverse_to_write(cell)
+                    .ok_or_else(|| format!("No recorded step wrote [{}]", cell))?;
+                println!("[{}] was last written by", cell);
+                println!("{}", pointed(debugger.machine(), writer));
+                Ok(Some(Stop::Step))
+            }),
+            "dh" | "dump-history" => {
+                let path = arg.ok_or_else(|| "Expected a file name".to_string());
+                path.and_then(|path| dump_history(&debugger, Path::new(path)).map(|n| {
+                    println!("Wrote {} steps to {}", n, path);
+                    None
+                }))
+            },
+            "wb" | "written-by" => cell(&debugger, arg).and_then(|cell| {
+                written_by(debugger.machine(), cell).map(|_| None)
+            }),
+            "b" | "break" => condition(&line).and_then(|condition| {
+                let i = location(&debugger, arg)?;
+                let count = hit_count(&line)?;
+                match condition {
+                    Some(condition) => debugger.set_conditional_breakpoint(i, condition),
+                    None => debugger.set_breakpoint(i),
+                }
+                debugger.set_hit_count(i, count);
+                println!("Breakpoint at {}", describe(debugger.machine(), i));
+                Ok(None)
+            }),
+            "bo" | "break-output" => output_break(&line).map(|brk| {
+                let n = debugger.break_on_output(brk);
+                println!("Output breakpoint {}", n + 1);
+                None
+            }),
+            "tp" | "trace" => location(&debugger, arg).and_then(|i| {
+                let message = line.trim().splitn(3, char::is_whitespace).nth(2).map(str::trim)
+                    .ok_or("Expected a message to log")?;
+                let message = message.strip_prefix('"').and_then(|m| m.strip_suffix('"')).unwrap_or(message);
+                let message = LogMessage::parse(message).map_err(|err| format!("Bad message at {}", err))?;
+                debugger.set_tracepoint(i, message);
+                println!("Tracepoint at {}", describe(debugger.machine(), i));
+                Ok(None)
+            }),
+            "trace-log" => match arg {
+                Some(path) => OpenOptions::new().create(true).append(true).open(path)
+                    .map(|file| {
+                        log_file = Some(file);
+                        None
+                    })
+                    .map_err(|err| format!("Failed to open {}: {}", path, err)),
+                None => {
+                    log_file = None;
+                    Ok(None)
+                },
+            },
+            "d" | "delete" => match arg {
+                Some("trace") => match words.next() {
+                    None => {
+                        let all: Vec<usize> = debugger.tracepoints().collect();
+                        all.into_iter().for_each(|i| debugger.clear_tracepoint(i));
+                        Ok(None)
+                    },
+                    loc => location(&debugger, loc).map(|i| {
+                        debugger.clear_tracepoint(i);
+                        None
+                    }),
+                },
+                Some("output") => match words.next() {
+                    None => {
+                        while debugger.clear_output_break(0).is_some() { }
+                        Ok(None)
+                    },
+                    Some(n) => number(n).and_then(|n| {
+                        let brk = n.checked_sub(1).and_then(|i| debugger.clear_output_break(i));
+                        brk.map(|_| None).ok_or_else(|| format!("No output breakpoint {}", n))
+                    }),
+                },
+                None => {
+                    let all: Vec<usize> = debugger.breakpoints().collect();
+                    all.into_iter().for_each(|i| debugger.clear_breakpoint(i));
+                    Ok(None)
+                },
+                Some(_) => location(&debugger, arg).map(|i| {
+                    debugger.clear_breakpoint(i);
+                    None
+                }),
+            },
+            "watch" => cell(&debugger, arg).and_then(|cell| {
+                debugger.watch_for(cell, watch(words)?);
+                Ok(None)
+            }),
+            "unwatch" => cell(&debugger, arg).map(|cell| {
+                debugger.unwatch(cell);
+                None
+            }),
+            "p" | "print" => print(&debugger, arg).map(|_| None),
+            "set" => set(&mut debugger, &line).map(|_| None),
+            "l" | "list" => {
+                list(debugger.machine());
+                Ok(None)
+            },
+            "info" => info(&debugger, arg).map(|_| None),
+            "output" => {
+                let mut out = stdout();
+                out.write_all(&debugger.machine().output).unwrap();
+                writeln!(out).unwrap();
+                Ok(None)
+            },
+            _ => match cmd.strip_prefix("x/") {
+                Some(n) => examine(&debugger, n, arg).map(|_| None),
+                None => Err(format!("Unknown command {}; try `help`", cmd)),
+            },
+        };
+
+        // Log what tracepoints saw, then show whatever the program wrote
+        // since the last command, then why it stopped
+        for message in debugger.take_log() {
+            match &mut log_file {
+                Some(file) => {
+                    if let Err(err) = writeln!(file, "{}", message) {
+                        println!("Failed to write trace log: {}", err);
+                        log_file = None;
+                    }
+                },
+                None => println!("trace: {}", message),
+            }
+        }
+        let output = &debugger.machine().output;
+        if output.len() < shown {
+            shown = output.len();  // Stepped back over output
+        }
+        if output.len() > shown {
+            let mut out = stdout();
+            out.write_all(&output[shown..]).unwrap();
+            if !output.ends_with(b"\n") {
+                writeln!(out).unwrap();
+            }
+        }
+        shown = output.len();
+        if let Some(path) = file {
+            let now = Sidecar::capture(&debugger);
+            if now != saved {
+                if let Err(msg) = now.save(path) {
+                    println!("{}", msg);
+                }
+                saved = now;
+            }
+        }
+        match result {
+            Ok(Some(stop)) => {
+                report(&debugger, stop);
+                // Run the handlers for the stop next, in the order given. A
+                // program that was already halted doesn't halt again.
+                if stop == Stop::Halted && was_halted {
+                    continue;
+                }
+                let commands: Vec<&str> = handlers.iter()
+                    .filter(|(event, _)| event.matches(stop))
+                    .flat_map(|(_, commands)| commands.split(';'))
+                    .collect();
+                for command in commands.into_iter().rev() {
+                    queue.push_front(command.trim().to_owned());
+                }
+            },
+            Ok(None) => {},
+            Err(msg) => println!("{}", msg),
+        }
+    }
+}
+
+// What an `on` handler waits for
+// Breakpoints and watchpoints outlive a restart: copy them all from the old
+// debugger to the new
+fn carry_breakpoints(from: &Debugger, to: &mut Debugger) {
+    for i in from.breakpoints() {
+        match from.condition(i) {
+            Some(condition) => to.set_conditional_breakpoint(i, condition.clone()),
+            None => to.set_breakpoint(i),
+        }
+        to.set_hit_count(i, from.hit_count(i).unwrap());
+    }
+    for cell in from.watchpoints() {
+        to.watch_for(cell, from.watchpoint(cell).unwrap());
+    }
+    for i in from.tracepoints() {
+        to.set_tracepoint(i, from.tracepoint(i).unwrap().clone());
+    }
+    for brk in from.output_breaks() {
+        to.break_on_output(brk.clone());
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Event {
+    Breakpoint(Option<usize>),  // At this instruction, or any
+    Watchpoint(Option<usize>),  // On this cell, or any
+    Output,                     // An output breakpoint
+    Halt,
+}
+
+impl Event {
+    fn matches(self, stop: Stop) -> bool {
+        match (self, stop) {
+            (Event::Breakpoint(at), Stop::Breakpoint(i)) => at.is_none_or(|at| at == i),
+            (Event::Watchpoint(on), Stop::Watchpoint(cell)) => on.is_none_or(|on| on == cell),
+            (Event::Output, Stop::OutputBreak(_)) => true,
+            (Event::Halt, Stop::Halted) => true,
+            _ => false,
+        }
+    }
+}
+
+// An `on EVENT COMMANDS` line. The commands may be wrapped in braces.
+fn handler(debugger: &Debugger, line: &str) -> Result<(Event, String), String> {
+    let mut words = line.split_whitespace().skip(1).peekable();
+    let event = words.next().ok_or("Expected breakpoint, watchpoint, output or halt")?;
+    // An event's argument starts with a digit, `[` or `@`, unlike any command
+    let arg = words.next_if(|word| word.starts_with(|ch: char| ch.is_ascii_digit() || ch == '[' || ch == '@'));
+    let event = match event {
+        "breakpoint" | "b" => Event::Breakpoint(arg.map(|arg| location(debugger, Some(arg))).transpose()?),
+        "watchpoint" | "w" => Event::Watchpoint(arg.map(|arg| cell(debugger, Some(arg))).transpose()?),
+        "output" => Event::Output,
+        "halt" => Event::Halt,
+        event => return Err(format!("Unknown event {}", event)),
+    };
+    let commands = words.collect::<Vec<_>>().join(" ");
+    let commands = commands.trim().trim_start_matches('{').trim_end_matches('}').trim();
+    if commands.is_empty() {
+        return Err("Expected commands to run".to_owned());
+    }
+    Ok((event, commands.to_owned()))
+}
+
+// Resume with a breakpoint at `index` that lasts only for this run
+fn run_to(debugger: &mut Debugger, index: usize) -> Result<Stop, RuntimeError> {
+    let set = debugger.breakpoints().any(|i| i == index);
+    if !set {
+        debugger.set_breakpoint(index);
+    }
+    let stop = debugger.resume();
+    if !set {
+        debugger.clear_breakpoint(index);
+    }
+    stop
+}
+
+// Drive the debugger forward
+fn run(debugger: &mut Debugger,
+       go: impl FnOnce(&mut Debugger) -> Result<Stop, RuntimeError>)
+       -> Result<Option<Stop>, String> {
+    go(debugger)
+        .map(Some)
+        .map_err(|err| format!("Runtime error at {}", debugger.machine().fault(err)))
+}
+
+fn report(debugger: &Debugger, stop: Stop) {
+    let machine = debugger.machine();
+    match stop {
+        Stop::Halted => println!("Program halted after {} steps", machine.steps),
+        Stop::StartOfHistory => {
+            println!("No earlier steps recorded; the earliest is step {}", debugger.earliest_step());
+        },
+        Stop::Watchpoint(cell) => {
+            println!("Watchpoint: [{}] = {}", cell, machine.data[cell]);
+        },
+        Stop::Breakpoint(_) => println!("Breakpoint"),
+        Stop::Output(byte) => println!("Wrote {} '{}'", byte, [byte].escape_ascii()),
+        Stop::OutputBreak(i) => {
+            println!("Output breakpoint {}: {}", i + 1, show_output_break(&debugger.output_breaks()[i]));
+        },
+        Stop::Step => {},
+    }
+    if !machine.halted {
+        println!("{}", describe(machine, machine.prog_ctr));
+    }
+}
+
+// Write the debugger's history to `path`, returning how many steps it held
+fn dump_history(debugger: &Debugger, path: &Path) -> Result<usize, String> {
+    let failed = |err: io::Error| format!("Failed to write {}: {}", path.display(), err);
+    let mut file = BufWriter::new(File::create(path).map_err(failed)?);
+    let entries: Vec<HistoryEntry> = debugger.history().collect();
+    if path.extension().is_some_and(|ext| ext == "csv") {
+        writeln!(file, "step,micros,op,prog_ctr,data_ptr,cell_delta,output").map_err(failed)?;
+        for entry in &entries {
+            let op = match entry.op {
+                ',' => "\",\"".to_string(),
+                op => op.to_string(),
+            };
+            writeln!(file, "{},{},{},{},{},{},{}",
+                     entry.step,
+                     entry.micros.map_or(String::new(), |micros| micros.to_string()),
+                     op,
+                     entry.prog_ctr,
+                     entry.data_ptr,
+                     entry.cell_delta,
+                     entry.output.map_or(String::new(), |byte| byte.to_string()))
+                .map_err(failed)?;
+        }
+    } else {
+        serde_json::to_writer_pretty(&mut file, &entries).map_err(|err| failed(err.into()))?;
+        writeln!(file).map_err(failed)?;
+    }
+    file.flush().map_err(failed)?;
+    Ok(entries.len())
+}
+
+// `line:col  source line` for an instruction
+fn describe(machine: &Machine, index: usize) -> String {
+    let (x, y) = machine.prog.positions[index];
+    format!("{}:{}  {}", y + 1, x + 1, machine.src_line(y).trim_end())
+}
+
+// `describe`, with a caret under the instruction on the following line
+fn pointed(machine: &Machine, index: usize) -> String {
+    let (x, y) = machine.prog.positions[index];
+    let indent = format!("{}:{}  ", y + 1, x + 1).len() + x;
+    format!("{}\n{:indent$}^", describe(machine, index), "", indent = indent)
+}
+
+// An instruction by index, or by the 1-based position of it or of the
+// comment just before it
+fn location(debugger: &Debugger, arg: Option<&str>) -> Result<usize, String> {
+    let arg = arg.ok_or("Expected LINE:COL, an instruction index or @LABEL")?;
+    if let Some(name) = arg.strip_prefix('@') {
+        return debugger.machine().label(name).ok_or_else(|| format!("No label {}", name));
+    }
+    let prog = &debugger.machine().prog;
+    let index = match arg.split_once(':') {
+        Some((line, col)) => {
+            let line = number(line)?.checked_sub(1).ok_or("Lines count from 1")?;
+            let col = number(col)?.checked_sub(1).ok_or("Columns count from 1")?;
+            prog.positions.iter()
+                .position(|&(x, y)| (y, x) >= (line, col))
+                .ok_or_else(|| format!("No instructions at or after {}", arg))?
+        },
+        None => number(arg)?,
+    };
+    if index >= prog.len() {
+        return Err(format!("The program has {} instructions", prog.len()));
+    }
+    Ok(index)
+}
+
+// The condition after `if` in a `break` command, if there is one
+fn condition(line: &str) -> Result<Option<Condition>, String> {
+    match line.split_once(" if ") {
+        Some((_, condition)) => Condition::parse(condition)
+            .map(Some)
+            .map_err(|err| format!("Bad condition at {}", err)),
+        None => Ok(None),
+    }
+}
+
+// The `hit N` and `ignore N` between the location and any condition in a
+// `break` command
+fn hit_count(line: &str) -> Result<HitCount, String> {
+    let before = line.split_once(" if ").map_or(line, |(before, _)| before);
+    let mut words = before.split_whitespace().skip(2);
+    let mut count = HitCount::default();
+    while let Some(word) = words.next() {
+        let n = words.next().ok_or_else(|| format!("Expected a number after {}", word))?;
+        match word {
+            "hit" => count.hit = Some(number(n)? as u64).filter(|&hit| hit > 0),
+            "ignore" => count.ignore = number(n)? as u64,
+            _ => return Err(format!("Expected `hit`, `ignore` or `if`, not {}", word)),
+        }
+    }
+    Ok(count)
+}
+
+// The rest of a `break-output` command: quoted text, with `\n`, `\t`, `\\`
+// and `\"` escapes, or a byte count
+fn output_break(line: &str) -> Result<OutputBreak, String> {
+    let arg = line.trim().split_once(char::is_whitespace).map(|(_, arg)| arg.trim())
+        .ok_or("Expected quoted text or a byte count")?;
+    let Some(text) = arg.strip_prefix('"').and_then(|arg| arg.strip_suffix('"')) else {
+        return number(arg).map(OutputBreak::Byte);
+    };
+    let mut pattern = Vec::new();
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        let ch = match ch {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some(ch @ ('\\' | '"')) => ch,
+                _ => return Err(format!("Bad escape in {}", arg)),
+            },
+            ch => ch,
+        };
+        pattern.extend(ch.encode_utf8(&mut [0; 4]).as_bytes());
+    }
+    if pattern.is_empty() {
+        return Err("Expected some text to look for".to_string());
+    }
+    Ok(OutputBreak::Contains(pattern))
+}
+
+fn show_output_break(brk: &OutputBreak) -> String {
+    match brk {
+        OutputBreak::Contains(pattern) => format!("contains \"{}\"", pattern.escape_ascii()),
+        OutputBreak::Byte(n) => format!("byte {}", n),
+    }
+}
+
+// The rest of a `watch` command: a trigger, then a value after `=`
+fn watch<'a>(words: impl Iterator<Item = &'a str>) -> Result<Watch, String> {
+    let rest: String = words.collect::<Vec<_>>().join(" ");
+    let (trigger, value) = match rest.split_once('=') {
+        Some((trigger, value)) => (trigger.trim(), Some(value.trim_start_matches('=').trim())),
+        None => (rest.trim(), None),
+    };
+    let trigger = match trigger {
+        "read" => Trigger::Read,
+        "write" => Trigger::Write,
+        "change" | "" => Trigger::Change,
+        other => return Err(format!("Expected read, write or change, not {}", other)),
+    };
+    let value = value.map(|value| {
+        let n = number(value)?;
+        u32::try_from(n).map_err(|_| format!("{} is too big for a cell", n))
+    }).transpose()?;
+    Ok(Watch { trigger, value })
+}
+
+// A cell index, or `$ptr`
+fn cell(debugger: &Debugger, arg: Option<&str>) -> Result<usize, String> {
+    let cell = match arg.ok_or("Expected a cell")? {
+        "$ptr" => debugger.machine().data_ptr,
+        arg => number(arg.trim_start_matches('[').trim_end_matches(']'))?,
+    };
+    match debugger.cell(cell) {
+        Some(_) => Ok(cell),
+        None => Err(format!("Cell {} is off the tape", cell)),
+    }
+}
+
+fn print(debugger: &Debugger, arg: Option<&str>) -> Result<(), String> {
+    let machine = debugger.machine();
+    match arg.ok_or("Expected $ptr, $pc, $steps, $cell or [CELL]")? {
+        "$ptr" => println!("{}", machine.data_ptr),
+        "$pc" => println!("{}", machine.prog_ctr),
+        "$steps" => println!("{}", machine.steps),
+        "$cell" => println!("{}", machine.data[machine.data_ptr]),
+        arg if arg.starts_with('[') => {
+            let cell = cell(debugger, Some(arg))?;
+            println!("{}", machine.data[cell]);
+        },
+        arg => return Err(format!("Can't print {}", arg)),
+    }
+    Ok(())
+}
+
+// `set TARGET = VALUE`
+fn set(debugger: &mut Debugger, line: &str) -> Result<(), String> {
+    let rest = line.trim().strip_prefix("set").unwrap_or_default();
+    let (target, value) = rest.split_once('=').ok_or("Expected `set TARGET = VALUE`")?;
+    let (target, value) = (target.trim(), value.trim());
+    match target {
+        "ptr" => {
+            let ptr = number(value)?;
+            if !debugger.set_data_ptr(ptr) {
+                return Err(format!("Cell {} is off the tape", ptr));
+            }
+            println!("ptr = {}", ptr);
+        },
+        "pc" => {
+            // A bare name is a label
+            let loc = if value.starts_with(|ch: char| ch.is_ascii_digit() || ch == '@') {
+                value.to_owned()
+            } else {
+                format!("@{}", value)
+            };
+            let index = location(debugger, Some(&loc))?;
+            debugger.set_prog_ctr(index);
+            println!("{}", describe(debugger.machine(), index));
+        },
+        _ => {
+            let index = match target.strip_prefix("cell") {
+                Some("") => debugger.machine().data_ptr,
+                Some(index) => cell(debugger, Some(index))?,
+                None if target.starts_with('[') => cell(debugger, Some(target))?,
+                None => return Err(format!("Can't set {}; try cell[CELL], ptr or pc", target)),
+            };
+            let value = cell_value(value)?;
+            if !debugger.set_cell(index, value) {
+                let max = debugger.machine().semantics.cell_size.max();
+                return Err(format!("{} doesn't fit in a cell; the most is {}", value, max));
+            }
+            println!("[{}] = {}", index, value);
+        },
+    }
+    Ok(())
+}
+
+// A number, 0x hex, or a quoted character
+fn cell_value(text: &str) -> Result<u32, String> {
+    if let Some(ch) = text.strip_prefix('\'').and_then(|text| text.strip_suffix('\'')) {
+        let mut chars = ch.chars();
+        return match (chars.next(), chars.next()) {
+            (Some(ch), None) => Ok(ch as u32),
+            _ => Err(format!("Expected a single character, not {}", text)),
+        };
+    }
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).map_err(|_| format!("Expected a hex number, not {}", text)),
+        None => text.parse().map_err(|_| format!("Expected a number, not {}", text)),
+    }
+}
+
+fn written_by(machine: &Machine, cell: usize) -> Result<(), String> {
+    if machine.provenance.is_none() {
+        return Err("Writes aren't tracked; start the console with --provenance".to_string());
+    }
+    match machine.written_by(cell) {
+        Some(write) => {
+            println!("[{}] = {}, last written at step {} by", cell, machine.data[cell], write.step);
+            println!("{}", pointed(machine, write.prog_ctr));
+        },
+        None => println!("[{}] = {}, never written", cell, machine.data[cell]),
+    }
+    Ok(())
+}
+
+// `x/N START`: N cells from START, `ROW_LEN` to a line
+fn examine(debugger: &Debugger, n: &str, start: Option<&str>) -> Result<(), String> {
+    let n = number(n)?;
+    let start = cell(debugger, start)?;
+    let cells = debugger.machine().cells(start..start.saturating_add(n));
+    for (row, chunk) in cells.chunks(ROW_LEN).enumerate() {
+        let values: Vec<String> = chunk.iter().map(u32::to_string).collect();
+        println!("{:>6}: {}", start + row * ROW_LEN, values.join(" "));
+    }
+    Ok(())
+}
+
+fn list(machine: &Machine) {
+    let current = machine.prog.positions.get(machine.prog_ctr).map_or(0, |&(_, y)| y);
+    let first = current.saturating_sub(LIST_CONTEXT);
+    let lines = machine.src_lines().enumerate().skip(first).take(current + LIST_CONTEXT + 1 - first);
+    for (linum, line) in lines {
+        let marker = if linum == current && !machine.halted { '>' } else { ' ' };
+        println!("{} {:>5}  {}", marker, linum + 1, line);
+    }
+}
+
+fn info(debugger: &Debugger, arg: Option<&str>) -> Result<(), String> {
+    match arg {
+        Some("breakpoints") | Some("b") => {
+            let machine = debugger.machine();
+            for i in debugger.breakpoints() {
+                let mut shown = format!("{:>6}  {}", i, describe(machine, i));
+                if let Some(condition) = debugger.condition(i) {
+                    shown.push_str(&format!("  if {}", condition));
+                }
+                let count = debugger.hit_count(i).unwrap();
+                if let Some(hit) = count.hit {
+                    shown.push_str(&format!("  hit {}", hit));
+                }
+                if count.ignore > 0 {
+                    shown.push_str(&format!("  ignore {}", count.ignore));
+                }
+                println!("{}  ({} hits)", shown, debugger.hits(i).unwrap());
+            }
+            for i in debugger.tracepoints() {
+                println!("{:>6}  {}  trace {}", i, describe(machine, i), debugger.tracepoint(i).unwrap());
+            }
+            for (i, brk) in debugger.output_breaks().iter().enumerate() {
+                println!("output {}  {}", i + 1, show_output_break(brk));
+            }
+        },
+        Some("watchpoints") | Some("w") => {
+            for cell in debugger.watchpoints() {
+                let watch = debugger.watchpoint(cell).unwrap();
+                let trigger = match watch.trigger {
+                    Trigger::Read => "read",
+                    Trigger::Write => "write",
+                    Trigger::Change => "change",
+                };
+                let value = watch.value.map_or_else(String::new, |value| format!(" = {}", value));
+                println!("[{}] = {}  on {}{}", cell, debugger.machine().data[cell], trigger, value);
+            }
+        },
+        Some("labels") | Some("l") => {
+            let machine = debugger.machine();
+            for (name, i) in &machine.labels {
+                println!("@{}  {}", name, describe(machine, *i));
+            }
+        },
+        Some("checkpoints") | Some("c") => {
+            let steps: Vec<String> = debugger.checkpoints().map(|step| step.to_string()).collect();
+            if steps.is_empty() {
+                println!("No checkpoints");
+            } else {
+                println!("Checkpoints at steps {}", steps.join(" "));
+            }
+        },
+        Some("memory") | Some("m") => println!("{}", profile::memory(&debugger.memory())),
+        Some("loops") => {
+            let machine = debugger.machine();
+            for start in machine.loop_stack() {
+                println!("#{:<6} {}", machine.iteration(start).unwrap_or_default(), describe(machine, start));
+            }
+        },
+        _ => {
+            return Err("Expected `info breakpoints`, `info watchpoints`, `info labels`, `info loops`, \
+                        `info checkpoints` or `info memory`".to_owned());
+        },
+    }
+    Ok(())
+}
+
+// A byte to wait for, as a number or a quoted character
+fn byte(arg: Option<&str>) -> Result<Option<u8>, String> {
+    let Some(arg) = arg else { return Ok(None) };
+    let quoted = arg.strip_prefix('\'').and_then(|arg| arg.strip_suffix('\''));
+    match quoted {
+        Some(ch) if ch.len() == 1 => Ok(Some(ch.as_bytes()[0])),
+        Some(_) => Err(format!("Expected a single ASCII character, not {}", arg)),
+        None => arg.parse().map(Some).map_err(|_| format!("Expected a byte, not {}", arg)),
+    }
+}
+
+// How many times to repeat a command, 1 by default
+fn count(arg: Option<&str>) -> Result<u64, String> {
+    arg.map_or(Ok(1), |arg| number(arg).map(|n| n as u64))
+}
+
+fn number(text: &str) -> Result<usize, String> {
+    text.parse().map_err(|_| format!("Expected a number, not {}", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use brainrust::{Debugger, HitCount, Machine, OutputBreak, Stop, Trigger, Watch};
+
+    use super::{byte, carry_breakpoints, cell_value, hit_count, location, output_break, watch};
+
+    #[test]
+    fn restarts_keep_output_breaks() {
+        let program = "++++++++[>++++++++<-]>+.+.+.";
+        let fresh = || Debugger::new(Machine::builder().build(program.to_owned()).unwrap());
+        let mut debugger = fresh();
+        debugger.break_on_output(OutputBreak::Byte(2));
+        assert_eq!(debugger.resume().unwrap(), Stop::OutputBreak(0));
+
+        let mut restarted = fresh();
+        carry_breakpoints(&debugger, &mut restarted);
+        assert_eq!(restarted.output_breaks(), [OutputBreak::Byte(2)]);
+        assert_eq!(restarted.resume().unwrap(), Stop::OutputBreak(0));
+        assert_eq!(restarted.machine().output, b"AB");
+    }
+
+    #[test]
+    fn finds_locations() {
+        let machine = Machine::builder().build("+ @label:top\n [-]\n".to_owned()).unwrap();
+        let debugger = Debugger::new(machine);
+        assert_eq!(location(&debugger, Some("2")), Ok(2));
+        assert_eq!(location(&debugger, Some("2:1")), Ok(1));  // The `[` after the indent
+        assert_eq!(location(&debugger, Some("1:3")), Ok(1));  // In the comment before it
+        assert_eq!(location(&debugger, Some("@top")), Ok(1));
+        assert_eq!(location(&debugger, Some("@end")), Err("No label end".to_owned()));
+        assert_eq!(location(&debugger, Some("4")), Err("The program has 4 instructions".to_owned()));
+        assert_eq!(location(&debugger, Some("3:1")), Err("No instructions at or after 3:1".to_owned()));
+        assert_eq!(location(&debugger, Some("0:1")), Err("Lines count from 1".to_owned()));
+        assert!(location(&debugger, None).is_err());
+    }
+
+    #[test]
+    fn parses_hit_counts() {
+        assert_eq!(hit_count("break 3"), Ok(HitCount::default()));
+        assert_eq!(hit_count("break 3 hit 2 ignore 1 if cell == 0"), Ok(HitCount { hit: Some(2), ignore: 1 }));
+        assert_eq!(hit_count("break 3 hit 0"), Ok(HitCount { hit: None, ignore: 0 }));
+        assert_eq!(hit_count("break 3 hit"), Err("Expected a number after hit".to_owned()));
+        assert_eq!(hit_count("break 3 miss 2"), Err("Expected `hit`, `ignore` or `if`, not miss".to_owned()));
+    }
+
+    #[test]
+    fn parses_output_breaks() {
+        assert_eq!(output_break("break-output 10"), Ok(OutputBreak::Byte(10)));
+        assert_eq!(output_break(r#"break-output "a\"b\n""#), Ok(OutputBreak::Contains(b"a\"b\n".to_vec())));
+        assert_eq!(output_break(r#"break-output "é""#), Ok(OutputBreak::Contains("é".as_bytes().to_vec())));
+        assert_eq!(output_break(r#"break-output "\q""#), Err(r#"Bad escape in "\q""#.to_owned()));
+        assert_eq!(output_break(r#"break-output """#), Err("Expected some text to look for".to_owned()));
+        assert!(output_break("break-output").is_err());
+    }
+
+    #[test]
+    fn parses_watches() {
+        assert_eq!(watch("".split_whitespace()), Ok(Watch { trigger: Trigger::Change, value: None }));
+        assert_eq!(watch("write == 65".split_whitespace()), Ok(Watch { trigger: Trigger::Write, value: Some(65) }));
+        assert_eq!(watch("= 0".split_whitespace()), Ok(Watch { trigger: Trigger::Change, value: Some(0) }));
+        assert_eq!(watch("read".split_whitespace()), Ok(Watch { trigger: Trigger::Read, value: None }));
+        assert_eq!(watch("poke".split_whitespace()), Err("Expected read, write or change, not poke".to_owned()));
+        assert_eq!(watch("write = 4294967296".split_whitespace()),
+                   Err("4294967296 is too big for a cell".to_owned()));
+    }
+
+    #[test]
+    fn parses_cell_values() {
+        assert_eq!(cell_value("65"), Ok(65));
+        assert_eq!(cell_value("0x41"), Ok(65));
+        assert_eq!(cell_value("0XfF"), Ok(255));
+        assert_eq!(cell_value("'A'"), Ok(65));
+        assert_eq!(cell_value("'é'"), Ok(0xe9));
+        assert_eq!(cell_value("'ab'"), Err("Expected a single character, not 'ab'".to_owned()));
+        assert_eq!(cell_value("0xg"), Err("Expected a hex number, not 0xg".to_owned()));
+        assert_eq!(cell_value("-1"), Err("Expected a number, not -1".to_owned()));
+    }
+
+    #[test]
+    fn parses_bytes() {
+        assert_eq!(byte(None), Ok(None));
+        assert_eq!(byte(Some("10")), Ok(Some(10)));
+        assert_eq!(byte(Some("'A'")), Ok(Some(b'A')));
+        assert_eq!(byte(Some("'é'")), Err("Expected a single ASCII character, not 'é'".to_owned()));
+        assert_eq!(byte(Some("256")), Err("Expected a byte, not 256".to_owned()));
+    }
+}
@@ -0,0 +1,84 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::vm::Machine;
+
+// Which instructions of a program have run, from the machine's execution
+// counts. Comments and whitespace aren't instructions and never count.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Coverage {
+    pub lines: Vec<LineCoverage>,  // Only lines holding instructions, in order
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct LineCoverage {
+    pub line: usize,          // 1-based
+    pub instructions: usize,
+    pub covered: usize,       // Instructions run at least once
+    pub hits: u64,            // Runs of the line's most executed instruction
+}
+
+impl Coverage {
+    pub fn instructions(&self) -> usize {
+        self.lines.iter().map(|line| line.instructions).sum()
+    }
+
+    pub fn covered(&self) -> usize {
+        self.lines.iter().map(|line| line.covered).sum()
+    }
+
+    // Share of instructions run, out of 100. An empty program is fully
+    // covered.
+    pub fn percent(&self) -> f64 {
+        match self.instructions() {
+            0 => 100.0,
+            total => 100.0 * self.covered() as f64 / total as f64,
+        }
+    }
+
+    pub fn line(&self, line: usize) -> Option<&LineCoverage> {
+        self.lines.iter().find(|entry| entry.line == line)
+    }
+
+    // An lcov tracefile with one record for the program at `source`, for
+    // genhtml and editors' coverage views. Each line counts as often as its
+    // most executed instruction ran.
+    pub fn lcov(&self, source: &str) -> String {
+        let mut out = String::new();
+        writeln!(out, "TN:").unwrap();
+        writeln!(out, "SF:{}", source).unwrap();
+        for line in &self.lines {
+            writeln!(out, "DA:{},{}", line.line, line.hits).unwrap();
+        }
+        writeln!(out, "LF:{}", self.lines.len()).unwrap();
+        writeln!(out, "LH:{}", self.lines.iter().filter(|line| line.covered > 0).count()).unwrap();
+        writeln!(out, "end_of_record").unwrap();
+        out
+    }
+}
+
+impl Machine {
+    // Coverage so far. Needs execution counts, so `None` unless `profile`
+    // was set before running.
+    pub fn coverage(&self) -> Option<Coverage> {
+        let counts = self.profile.as_ref()?;
+        let mut lines: BTreeMap<usize, LineCoverage> = BTreeMap::new();
+        for (&(_, y), &count) in self.prog.positions.iter().zip(counts) {
+            let line = y + 1;
+            let entry = lines.entry(line).or_insert(LineCoverage {
+                line, instructions: 0, covered: 0, hits: 0,
+            });
+            entry.instructions += 1;
+            entry.covered += (count > 0) as usize;
+            entry.hits = entry.hits.max(count);
+        }
+        Some(Coverage { lines: lines.into_values().collect() })
+    }
+}
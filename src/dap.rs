@@ -0,0 +1,541 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write, stdin, stdout};
+use std::process;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use serde_json::{Value, json};
+
+use brainrust::{Condition, Debugger, HitCount, LogMessage, Machine, RunOutcome, Semantics, Stop};
+
+use crate::cli::DapArgs;
+use crate::{configure, exit};
+
+// Steps run between checks for a pause request
+const RUN_CHUNK: u64 = 1 << 16;
+
+// Variable references for the two scopes
+const TAPE: u64 = 1;
+const REGISTERS: u64 = 2;
+
+// The program runs as the only thread
+const THREAD: u64 = 1;
+
+// Largest message body read, so a bad header can't make it allocate without
+// bound
+const MAX_MESSAGE: usize = 16 << 20;
+
+// Serve the Debug Adapter Protocol on stdin and stdout, so editors can drive
+// the debugger. The program and its input come from the `launch` request;
+// its output is sent to the editor as output events.
+pub fn dap(args: DapArgs) {
+    let (_, semantics) = configure(&args.semantics);
+    let mut session = Session {
+        semantics,
+        requests: spawn_reader(),
+        pending: VecDeque::new(),
+        seq: 0,
+        debugger: None,
+        path: String::new(),
+        breakpoints: Vec::new(),
+        stop_on_entry: false,
+        configured: false,
+        shown: 0,
+    };
+    while let Some(request) = session.next_request() {
+        if !session.handle(&request) {
+            break;
+        }
+    }
+}
+
+// A breakpoint as the editor set it, kept until the program is loaded
+struct SourceBreakpoint {
+    line: usize,            // 1-based
+    column: Option<usize>,  // 1-based
+    condition: Option<Condition>,
+    hit: Option<u64>,  // Stop only on this hit
+    log_message: Option<LogMessage>,  // Log this instead of stopping
+}
+
+struct Session {
+    semantics: Semantics,
+    requests: Receiver<Value>,
+    pending: VecDeque<Value>,  // Requests that arrived while the program ran
+    seq: u64,
+    debugger: Option<Debugger>,
+    path: String,
+    breakpoints: Vec<SourceBreakpoint>,
+    stop_on_entry: bool,
+    configured: bool,
+    shown: usize,  // Bytes of output already sent to the editor
+}
+
+impl Session {
+    fn next_request(&mut self) -> Option<Value> {
+        self.pending.pop_front().or_else(|| self.requests.recv().ok())
+    }
+
+    // Answer one request, returning whether to keep serving
+    fn handle(&mut self, request: &Value) -> bool {
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+        let result = match command {
+            "initialize" => {
+                self.respond(request, Ok(json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsConditionalBreakpoints": true,
+                    "supportsHitConditionalBreakpoints": true,
+                    "supportsLogPoints": true,
+                    "supportsStepBack": true,
+                })));
+                self.event("initialized", json!({}));
+                return true;
+            },
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "configurationDone" => {
+                self.configured = true;
+                Ok(json!({}))
+            },
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD, "name": "main" }] })),
+            "stackTrace" => self.debugger().map(|debugger| stack_trace(debugger.machine(), &self.path)),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Tape", "variablesReference": TAPE, "expensive": false },
+                { "name": "Registers", "variablesReference": REGISTERS, "expensive": false },
+            ]})),
+            "variables" => self.debugger().map(|debugger| variables(debugger.machine(), args)),
+            "continue" | "next" | "stepIn" | "stepOut" | "stepBack" | "reverseContinue" => {
+                match self.debugger() {
+                    Ok(_) => {
+                        self.respond(request, Ok(json!({ "allThreadsContinued": true })));
+                        self.go(command);
+                        return true;
+                    },
+                    Err(err) => Err(err),
+                }
+            },
+            "pause" => Ok(json!({})),  // Only meaningful while running
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(json!({})));
+                return false;
+            },
+            _ => Err(format!("Unsupported request {}", command)),
+        };
+        self.respond(request, result);
+        if matches!(command, "launch" | "configurationDone") {
+            self.start();
+        }
+        true
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["program"].as_str().ok_or("Expected a program to launch")?;
+        let program = fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read {}: {}", path, err))?;
+        let input = args["input"].as_str().unwrap_or_default().as_bytes().to_vec();
+        let mut machine = Machine::builder()
+            .semantics(self.semantics.clone())
+            .input(io::Cursor::new(input))
+            .build(program)
+            .map_err(|err| format!("Failed to parse program: {}", err))?;
+        machine.iterations = Some(Vec::new());
+        self.debugger = Some(Debugger::new(machine));
+        self.path = path.to_owned();
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.apply_breakpoints();
+        Ok(json!({}))
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let mut breakpoints = Vec::new();
+        for bp in args["breakpoints"].as_array().into_iter().flatten() {
+            let condition = match bp["condition"].as_str().filter(|c| !c.trim().is_empty()) {
+                Some(condition) => Some(Condition::parse(condition)
+                    .map_err(|err| format!("Bad condition at {}", err))?),
+                None => None,
+            };
+            let hit = match bp["hitCondition"].as_str().map(str::trim).filter(|hit| !hit.is_empty()) {
+                Some(hit) => Some(hit.parse().ok().filter(|&hit| hit > 0)
+                    .ok_or_else(|| format!("Expected a hit count, not {}", hit))?),
+                None => None,
+            };
+            let log_message = bp["logMessage"].as_str()
+                .map(|message| LogMessage::parse(message).map_err(|err| format!("Bad log message at {}", err)))
+                .transpose()?;
+            breakpoints.push(SourceBreakpoint {
+                line: bp["line"].as_u64().ok_or("Expected a line")? as usize,
+                column: bp["column"].as_u64().map(|column| column as usize),
+                condition,
+                hit,
+                log_message,
+            });
+        }
+        self.breakpoints = breakpoints;
+        let verified = self.apply_breakpoints();
+        let breakpoints: Vec<Value> = self.breakpoints.iter().zip(verified).map(|(bp, at)| {
+            match at {
+                Some((line, column)) => json!({ "verified": true, "line": line, "column": column }),
+                None => json!({ "verified": false, "line": bp.line }),
+            }
+        }).collect();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    // Replace the debugger's breakpoints with the editor's, returning where
+    // each landed, if the program is loaded and has an instruction there
+    fn apply_breakpoints(&mut self) -> Vec<Option<(usize, usize)>> {
+        let Some(debugger) = &mut self.debugger else {
+            return self.breakpoints.iter().map(|_| None).collect();
+        };
+        let old: Vec<usize> = debugger.breakpoints().collect();
+        old.into_iter().for_each(|i| debugger.clear_breakpoint(i));
+        let old: Vec<usize> = debugger.tracepoints().collect();
+        old.into_iter().for_each(|i| debugger.clear_tracepoint(i));
+        self.breakpoints.iter().map(|bp| {
+            let machine = debugger.machine();
+            let index = machine.prog.positions.iter().position(|&(x, y)| {
+                y + 1 == bp.line && bp.column.is_none_or(|column| x + 1 >= column)
+            })?;
+            let (x, y) = machine.prog.positions[index];
+            match (&bp.log_message, &bp.condition) {
+                (Some(message), _) => debugger.set_tracepoint(index, message.clone()),
+                (None, Some(condition)) => debugger.set_conditional_breakpoint(index, condition.clone()),
+                (None, None) => debugger.set_breakpoint(index),
+            }
+            debugger.set_hit_count(index, HitCount { hit: bp.hit, ignore: 0 });
+            Some((y + 1, x + 1))
+        }).collect()
+    }
+
+    // Once launched and configured, stop at the entry or run
+    fn start(&mut self) {
+        if self.debugger.is_none() || !self.configured {
+            return;
+        }
+        if self.stop_on_entry {
+            self.stopped("entry", None);
+        } else {
+            self.go("continue");
+        }
+    }
+
+    // Move the program as the request says, then report where it stopped
+    fn go(&mut self, command: &str) {
+        let debugger = self.debugger.as_mut().expect("checked by the caller");
+        let result = match command {
+            "continue" => return self.run(),
+            "next" => debugger.step_over(),
+            "stepIn" => debugger.step(),
+            "stepOut" => debugger.step_out(),
+            "stepBack" => Ok(debugger.step_back()),
+            "reverseContinue" => Ok(debugger.reverse_resume()),
+            _ => unreachable!("only movement requests get here"),
+        };
+        match result {
+            Ok(stop) => self.report(stop, "step"),
+            Err(err) => {
+                let text = format!("Runtime error at {}", debugger.machine().fault(err));
+                self.stopped("exception", Some(&text));
+            },
+        }
+    }
+
+    // Run in chunks, until the program stops or the editor asks to pause
+    fn run(&mut self) {
+        loop {
+            let debugger = self.debugger.as_mut().expect("checked by the caller");
+            match debugger.run_for(RUN_CHUNK) {
+                Ok(RunOutcome::BudgetExhausted) => {},
+                Ok(RunOutcome::Halted) => return self.report(Stop::Halted, "step"),
+                Ok(RunOutcome::Breakpoint(i)) => return self.report(Stop::Breakpoint(i), "step"),
+                Ok(RunOutcome::Watchpoint(cell)) => return self.report(Stop::Watchpoint(cell), "step"),
+                Ok(RunOutcome::OutputBreak(i)) => return self.report(Stop::OutputBreak(i), "step"),
+                Ok(RunOutcome::AwaitingInput) => return self.report(Stop::Step, "step"),
+                Err(err) => {
+                    let text = format!("Runtime error at {}", debugger.machine().fault(err));
+                    return self.stopped("exception", Some(&text));
+                },
+            }
+            self.flush_output();
+            loop {
+                match self.requests.try_recv() {
+                    Ok(request) if request["command"] == "pause" => {
+                        self.respond(&request, Ok(json!({})));
+                        return self.stopped("pause", None);
+                    },
+                    Ok(request) => self.pending.push_back(request),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => process::exit(exit::FAILURE),
+                }
+            }
+        }
+    }
+
+    fn report(&mut self, stop: Stop, step_reason: &str) {
+        match stop {
+            Stop::Halted => {
+                self.flush_output();
+                self.event("exited", json!({ "exitCode": 0 }));
+                self.event("terminated", json!({}));
+            },
+            Stop::Breakpoint(_) => self.stopped("breakpoint", None),
+            Stop::Watchpoint(cell) => self.stopped("data breakpoint", Some(&format!("Cell {}", cell))),
+            Stop::OutputBreak(_) => self.stopped("breakpoint", Some("Output breakpoint")),
+            Stop::StartOfHistory => self.stopped(step_reason, Some("No earlier steps recorded")),
+            Stop::Step | Stop::Output(_) => self.stopped(step_reason, None),
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<&str>) {
+        self.flush_output();
+        let mut body = json!({ "reason": reason, "threadId": THREAD, "allThreadsStopped": true });
+        if let Some(text) = text {
+            body["text"] = json!(text);
+        }
+        self.event("stopped", body);
+    }
+
+    // Send the editor what logpoints logged and whatever the program has
+    // written since last time. Stepping back can leave less output than was
+    // sent; it isn't unsent.
+    fn flush_output(&mut self) {
+        let Some(debugger) = &mut self.debugger else { return };
+        for message in debugger.take_log() {
+            self.event("output", json!({ "category": "console", "output": format!("{}\n", message) }));
+        }
+        let Some(debugger) = &self.debugger else { return };
+        let output = &debugger.machine().output;
+        if output.len() > self.shown {
+            let text = String::from_utf8_lossy(&output[self.shown..]).into_owned();
+            self.shown = output.len();
+            self.event("output", json!({ "category": "stdout", "output": text }));
+        } else {
+            self.shown = output.len();
+        }
+    }
+
+    fn debugger(&self) -> Result<&Debugger, String> {
+        self.debugger.as_ref().ok_or_else(|| "No program launched".to_owned())
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) {
+        let mut message = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => message["body"] = body,
+            Err(text) => message["message"] = json!(text),
+        }
+        self.send(message);
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        let mut out = stdout().lock();
+        let sent = write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body).and_then(|_| out.flush());
+        if sent.is_err() {
+            process::exit(exit::FAILURE);
+        }
+    }
+}
+
+// Read requests on another thread, so a running program can be paused
+fn spawn_reader() -> Receiver<Value> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stdin().lock());
+        while let Some(message) = read_message(&mut reader) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+// One message: headers, a blank line, then a JSON body of `Content-Length`
+// bytes. Ends at end of input, on anything malformed, or on a body longer
+// than `MAX_MESSAGE`.
+fn read_message(reader: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse().ok().filter(|&length| length <= MAX_MESSAGE);
+            }
+        }
+    }
+    let mut body = vec![0; length?];
+    reader.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+// The next instruction, then the `[` of each loop around it, innermost first
+fn stack_trace(machine: &Machine, path: &str) -> Value {
+    let frame = |id: usize, index: usize, name: String| {
+        let (x, y) = machine.prog.positions.get(index).copied().unwrap_or_default();
+        json!({
+            "id": id,
+            "name": name,
+            "source": { "path": path },
+            "line": y + 1,
+            "column": x + 1,
+        })
+    };
+    let mut frames = vec![frame(0, machine.prog_ctr, format!("step {}", machine.steps))];
+    for (depth, &start) in machine.loop_stack().iter().rev().enumerate() {
+        let iteration = machine.iteration(start).unwrap_or_default();
+        frames.push(frame(depth + 1, start, format!("loop at instruction {}, iteration {}", start, iteration)));
+    }
+    json!({ "stackFrames": frames, "totalFrames": frames.len() })
+}
+
+fn variables(machine: &Machine, args: &Value) -> Value {
+    let variable = |name: String, value: String| json!({
+        "name": name, "value": value, "variablesReference": 0,
+    });
+    let variables: Vec<Value> = match args["variablesReference"].as_u64() {
+        Some(TAPE) => {
+            let end = machine.used_cells().len().max(machine.data_ptr + 1);
+            machine.cells(..end).iter().enumerate()
+                .map(|(i, value)| variable(format!("[{}]", i), value.to_string()))
+                .collect()
+        },
+        Some(REGISTERS) => vec![
+            variable("ptr".to_owned(), machine.data_ptr.to_string()),
+            variable("pc".to_owned(), machine.prog_ctr.to_string()),
+            variable("steps".to_owned(), machine.steps.to_string()),
+            variable("output".to_owned(), format!("{:?}", String::from_utf8_lossy(&machine.output))),
+        ],
+        _ => Vec::new(),
+    };
+    json!({ "variables": variables })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::mpsc;
+
+    use serde_json::json;
+
+    use brainrust::{Debugger, Machine, Semantics};
+
+    use super::{MAX_MESSAGE, REGISTERS, Session, TAPE, read_message, stack_trace, variables};
+
+    // A session with a program loaded, as after `launch`
+    fn session(program: &str) -> Session {
+        let mut machine = Machine::builder().build(program.to_owned()).unwrap();
+        machine.iterations = Some(Vec::new());
+        Session {
+            semantics: Semantics::default(),
+            requests: mpsc::channel().1,
+            pending: Default::default(),
+            seq: 0,
+            debugger: Some(Debugger::new(machine)),
+            path: "test.b".to_owned(),
+            breakpoints: Vec::new(),
+            stop_on_entry: false,
+            configured: false,
+            shown: 0,
+        }
+    }
+
+    #[test]
+    fn reads_messages() {
+        let mut input = Cursor::new(b"Content-Length: 9\r\n\r\n{\"seq\":1}content-length:9\r\nX: y\r\n\r\n{\"seq\":2}".to_vec());
+        assert_eq!(read_message(&mut input), Some(json!({ "seq": 1 })));
+        assert_eq!(read_message(&mut input), Some(json!({ "seq": 2 })));
+        assert_eq!(read_message(&mut input), None);
+
+        for bad in ["X: y\r\n\r\n{}", "Content-Length: two\r\n\r\n{}", "Content-Length: 9\r\n\r\n{\"seq\"",
+                    "Content-Length: 2\r\n\r\n[", "Content-Length: 3\r\n"] {
+            assert_eq!(read_message(&mut Cursor::new(bad)), None, "{:?}", bad);
+        }
+        // Too long to take, without trying to allocate it
+        let huge = format!("Content-Length: {}\r\n\r\n{{}}", MAX_MESSAGE + 1);
+        assert_eq!(read_message(&mut Cursor::new(huge)), None);
+        let huge = format!("Content-Length: {}\r\n\r\n{{}}", usize::MAX);
+        assert_eq!(read_message(&mut Cursor::new(huge)), None);
+    }
+
+    #[test]
+    fn sets_breakpoints() {
+        let mut session = session("+\n +[-]");
+        let set = session.set_breakpoints(&json!({ "breakpoints": [
+            { "line": 2 },
+            { "line": 2, "column": 3 },
+            { "line": 2, "column": 6 },
+            { "line": 3 },
+        ]})).unwrap();
+        assert_eq!(set, json!({ "breakpoints": [
+            { "verified": true, "line": 2, "column": 2 },
+            { "verified": true, "line": 2, "column": 3 },
+            { "verified": false, "line": 2 },
+            { "verified": false, "line": 3 },
+        ]}));
+        assert_eq!(session.debugger.as_ref().unwrap().breakpoints().collect::<Vec<_>>(), [1, 2]);
+
+        // A log message makes a tracepoint, which replaces the breakpoints
+        session.set_breakpoints(&json!({ "breakpoints": [
+            { "line": 1, "logMessage": "cell is {cell}" },
+        ]})).unwrap();
+        let debugger = session.debugger.as_ref().unwrap();
+        assert_eq!(debugger.breakpoints().count(), 0);
+        assert_eq!(debugger.tracepoints().collect::<Vec<_>>(), [0]);
+
+        for hit in ["0", "-1", "often"] {
+            let set = session.set_breakpoints(&json!({ "breakpoints": [{ "line": 1, "hitCondition": hit }]}));
+            assert_eq!(set, Err(format!("Expected a hit count, not {}", hit)));
+        }
+        let set = session.set_breakpoints(&json!({ "breakpoints": [{ "line": 1, "hitCondition": " 2 " }]}));
+        assert!(set.is_ok());
+        assert!(session.set_breakpoints(&json!({ "breakpoints": [{ "line": 1, "condition": "cell ==" }]})).is_err());
+        assert!(session.set_breakpoints(&json!({ "breakpoints": [{ "column": 1 }]})).is_err());
+    }
+
+    #[test]
+    fn describes_the_machine() {
+        let mut machine = Machine::builder().build("+\n[>+\n[-]<-]".to_owned()).unwrap();
+        machine.iterations = Some(Vec::new());
+        machine.run_for(5).unwrap();
+        let frames = stack_trace(&machine, "test.b");
+        let frames: Vec<_> = frames["stackFrames"].as_array().unwrap().iter()
+            .map(|frame| (frame["name"].as_str().unwrap(), frame["line"].as_u64().unwrap(), frame["column"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(frames, [
+            ("step 5", 3, 2),
+            ("loop at instruction 4, iteration 1", 3, 1),
+            ("loop at instruction 1, iteration 1", 2, 1),
+        ]);
+
+        let tape = variables(&machine, &json!({ "variablesReference": TAPE }));
+        assert_eq!(tape["variables"][1], json!({ "name": "[1]", "value": "1", "variablesReference": 0 }));
+        assert_eq!(tape["variables"].as_array().unwrap().len(), 2);
+        let registers = variables(&machine, &json!({ "variablesReference": REGISTERS }));
+        let registers: Vec<_> = registers["variables"].as_array().unwrap().iter()
+            .map(|variable| (variable["name"].as_str().unwrap(), variable["value"].as_str().unwrap()))
+            .collect();
+        assert_eq!(registers, [("ptr", "1"), ("pc", "5"), ("steps", "5"), ("output", "\"\"")]);
+        assert_eq!(variables(&machine, &json!({})), json!({ "variables": [] }));
+    }
+}
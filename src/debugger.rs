@@ -0,0 +1,1007 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem;
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::calls::Call;
+use crate::condition::{Condition, LogMessage};
+use crate::parse::{Command, ParseError, Program, Remap};
+use crate::snapshot::Snapshot;
+use crate::vm::{
+    CHECK_INTERVAL, InputLog, Iteration, Machine, MemoryUsage, OutputBreak, Provenance, RuntimeError, StepEvent,
+    VmEvent,
+};
+
+// Memory kept for stepping backwards, in bytes: about a million records, each
+// a step or a run of them
+pub const HISTORY_BUDGET: usize = 64 << 20;
+// Longest a run of steps in the history can take, in microseconds, as its
+// steps are all exported as starting when it did
+#[cfg(feature = "std")]
+const RUN_MICROS: u64 = 1000;
+// Memory kept for checkpoints, in bytes, and the steps between them before
+// they are thinned out to fit
+pub const CHECKPOINT_BUDGET: usize = 64 << 20;
+pub const CHECKPOINT_EVERY: u64 = 1 << 16;
+
+// Why the debugger stopped running the machine
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stop {
+    Step,               // The requested step is done
+    Breakpoint(usize),  // About to execute the instruction at this index
+    Watchpoint(usize),  // A watched cell was just accessed
+    Output(u8),         // This byte was just written, for `run_to_output`
+    OutputBreak(usize), // The output just set off the machine's output break at this index
+    Halted,
+    StartOfHistory,     // Nothing further back was recorded
+}
+
+// What a watchpoint watches for
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Trigger {
+    Read,      // `.`, or a `[` or `]` testing the cell
+    Write,     // `+`, `-` or `,`, even if the value stays the same
+    #[default]
+    Change,    // Any step that leaves the cell with a different value
+}
+
+// A watchpoint: stop after a step that accesses the cell as `trigger` says
+// and, given a `value`, leaves it holding that value
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Watch {
+    pub trigger: Trigger,
+    pub value: Option<u32>,
+}
+
+impl Watch {
+    // Whether a step that left the cell holding `after` sets this off
+    fn triggered(&self, event: &StepEvent, after: u32) -> bool {
+        let accessed = match self.trigger {
+            Trigger::Read => matches!(event.op, '.' | '[' | ']'),
+            Trigger::Write => writes(event.op),
+            Trigger::Change => event.cell_delta != 0,
+        };
+        accessed && self.value.is_none_or(|value| value == after)
+    }
+}
+
+// Which hits of a breakpoint stop the program. A hit is a run reaching the
+// breakpoint while its condition holds. Only the `hit`th hit stops it, if
+// given, and none of the first `ignore`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HitCount {
+    pub hit: Option<u64>,
+    pub ignore: u64,
+}
+
+impl HitCount {
+    fn stops(&self, hits: u64) -> bool {
+        hits > self.ignore && self.hit.is_none_or(|hit| hits == hit)
+    }
+}
+
+struct Breakpoint {
+    condition: Option<Condition>,
+    count: HitCount,
+    hits: u64,
+}
+
+impl Breakpoint {
+    fn new(condition: Option<Condition>) -> Breakpoint {
+        Breakpoint { condition, count: HitCount::default(), hits: 0 }
+    }
+}
+
+// Executed steps, with what they left behind so they can be undone and
+// redone. A run of `+`, `-`, `<` or `>` written out one after another and
+// stepped straight through, each step changing the same by the same, is one
+// record. Pointers are kept as how far a step moved them, so undoing works
+// back from where they are now; a run's program counter only ever moves on.
+// A pbrain `(` or `)` writes no cell, so it keeps what it replaced in the
+// fields for the cell's last write instead: the procedure's definition
+// before it, or the call it returned from.
+#[derive(Clone, Copy)]
+struct Record {
+    prog_ctr: u32,       // Of the first step's instruction
+    after: u32,          // Program counter after the last step
+    count: u32,          // Steps in the run
+    written_pc: u32,     // Instruction of the cell's last write before the first step
+    written_at: u64,     // ... and its step, or 0 if there was none
+    moved: isize,        // By each step, to the data pointer
+    cell_delta: i64,     // Each step's change to the cell under the pointer
+    output: Option<u8>,
+    halted: bool,        // After the last step
+    #[cfg(feature = "std")]
+    micros: u64,         // When the first step ran, since the debugger started
+}
+
+impl Record {
+    fn written_by(&self) -> Option<Provenance> {
+        (self.written_at > 0).then_some(Provenance { step: self.written_at, prog_ctr: self.written_pc as usize })
+    }
+
+    // For a `(`, the `(` that defined its procedure before it, if any
+    fn replaced(&self) -> Option<usize> {
+        (self.written_at > 0).then_some(self.written_pc as usize)
+    }
+
+    // For a `)`, the call it returned from, if it did. The `:` is the
+    // instruction before the one it went back to, or that one, if the
+    // program then halted on it.
+    fn returned(&self) -> Option<Call> {
+        (self.written_at > 0).then(|| Call {
+            at: self.after as usize - usize::from(!self.halted),
+            procedure: self.written_pc,
+            step: self.written_at - 1,
+        })
+    }
+
+    // The step the record holds, which has to be only one, as an event
+    fn event(&self, prog: &Program, data_ptr: usize) -> StepEvent {
+        let prog_ctr = self.prog_ctr as usize;
+        StepEvent { prog_ctr, op: prog.chars[prog_ctr], data_ptr, cell_delta: self.cell_delta, output: self.output }
+    }
+}
+
+// The machine as it was at a step, for going back further than the history
+// reaches
+struct Checkpoint {
+    snapshot: Snapshot,
+    reads: usize,  // Input read by then
+    provenance: Option<Vec<Option<Provenance>>>,
+    iterations: Option<Vec<u64>>,
+}
+
+impl Checkpoint {
+    // Roughly the memory it takes, in bytes
+    fn footprint(&self) -> usize {
+        mem::size_of::<Checkpoint>()
+            + self.snapshot.tape.len() * mem::size_of::<(usize, u32)>()
+            + self.snapshot.output.len()
+            + self.provenance.as_ref().map_or(0, |writes| writes.len() * mem::size_of::<Option<Provenance>>())
+            + self.iterations.as_ref().map_or(0, |counts| counts.len() * mem::size_of::<u64>())
+    }
+}
+
+// A step in the debugger's history, for exporting
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct HistoryEntry {
+    pub step: u64,             // Counting from 1
+    pub micros: Option<u64>,   // When it first ran, since the debugger started
+    pub op: char,
+    pub prog_ctr: usize,       // Of the instruction
+    pub data_ptr: usize,       // Before the step
+    pub cell_delta: i64,
+    pub output: Option<u8>,
+}
+
+// Whether a step set its cell, whatever value it left
+fn writes(op: char) -> bool {
+    matches!(op, '+' | '-' | ',')
+}
+
+// Drives a machine on behalf of a user interface: the terminal debugger, or
+// a remote protocol server. Steps can be undone as far back as the history
+// budget allows; stepping forward again replays them rather than re-running
+// them, so input is not read twice. Output already sent to the machine's sink
+// stays sent.
+pub struct Debugger {
+    machine: Machine,
+    breakpoints: BTreeMap<usize, Breakpoint>,  // By instruction index
+    watchpoints: BTreeMap<usize, Watch>,  // By cell index
+    tracepoints: BTreeMap<usize, LogMessage>,  // By instruction index
+    log: Vec<String>,              // From tracepoints, until taken
+    history: VecDeque<Record>,
+    history_steps: usize,          // In the history's runs
+    undone: Vec<Record>,           // One step each, most recently undone last
+    max_records: usize,            // Most records kept, undone or not
+    checkpoints: Vec<Checkpoint>,  // In step order
+    checkpoint_every: u64,         // Steps between checkpoints, or 0 for none
+    checkpoint_budget: usize,
+    #[cfg(feature = "std")]
+    started: Instant,
+}
+
+impl Debugger {
+    pub fn new(machine: Machine) -> Debugger {
+        Debugger {
+            machine,
+            breakpoints: BTreeMap::new(),
+            watchpoints: BTreeMap::new(),
+            tracepoints: BTreeMap::new(),
+            log: Vec::new(),
+            history: VecDeque::new(),
+            history_steps: 0,
+            undone: Vec::new(),
+            max_records: HISTORY_BUDGET / mem::size_of::<Record>(),
+            checkpoints: Vec::new(),
+            checkpoint_every: 0,
+            checkpoint_budget: CHECKPOINT_BUDGET,
+            #[cfg(feature = "std")]
+            started: Instant::now(),
+        }
+    }
+
+    // Bound the memory used by the step history, forgetting the oldest steps
+    // if it is already over. Undone steps are kept until they are replayed.
+    pub fn set_history_budget(&mut self, bytes: usize) {
+        self.max_records = (bytes / mem::size_of::<Record>()).max(1);
+        let excess = (self.history.len() + self.undone.len()).saturating_sub(self.max_records);
+        self.forget_oldest(excess);
+    }
+
+    // Save the machine every `every` steps from now on, so `seek` can reach
+    // steps the history has forgotten by going to the checkpoint before and
+    // running forward. Input is logged so it can be read again. When the
+    // checkpoints outgrow `budget` bytes, every other one is dropped and they
+    // are taken half as often. An `every` of 0 stops taking them.
+    pub fn set_checkpoints(&mut self, every: u64, budget: usize) {
+        self.checkpoint_every = every;
+        self.checkpoint_budget = budget;
+        self.checkpoints.clear();
+        if every > 0 {
+            self.machine.input_log.get_or_insert_with(InputLog::default);
+            self.checkpoint();
+        }
+    }
+
+    // The steps that checkpoints were taken at
+    pub fn checkpoints(&self) -> impl Iterator<Item = u64> + '_ {
+        self.checkpoints.iter().map(|checkpoint| checkpoint.snapshot.steps)
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    pub fn into_machine(self) -> Machine {
+        self.machine
+    }
+
+    // Roughly the memory the machine and the debugger hold
+    pub fn memory(&self) -> MemoryUsage {
+        MemoryUsage {
+            history: (self.history.len() + self.undone.len()) * mem::size_of::<Record>()
+                + self.checkpoints.iter().map(Checkpoint::footprint).sum::<usize>(),
+            traces: self.log.iter().map(String::capacity).sum(),
+            ..self.machine.memory()
+        }
+    }
+
+    // Swap in an edit of the program, as `Machine::reload` does. Breakpoints
+    // and tracepoints move along with their instructions, and are dropped
+    // with edited ones. Steps taken can't be replayed through the new
+    // program, so the history and checkpoints start afresh.
+    pub fn reload(&mut self, program: String) -> Result<Remap, ParseError> {
+        let remap = self.machine.reload(program)?;
+        self.breakpoints = mem::take(&mut self.breakpoints).into_iter()
+            .filter_map(|(index, breakpoint)| Some((remap.index(index)?, breakpoint)))
+            .collect();
+        self.tracepoints = mem::take(&mut self.tracepoints).into_iter()
+            .filter_map(|(index, message)| Some((remap.index(index)?, message)))
+            .collect();
+        self.clear_history();
+        self.checkpoints.clear();
+        if self.checkpoint_every > 0 {
+            self.checkpoint();
+        }
+        Ok(remap)
+    }
+
+    // Breakpoints stop a run before the instruction at `index` executes
+    pub fn set_breakpoint(&mut self, index: usize) {
+        self.breakpoints.insert(index, Breakpoint::new(None));
+    }
+
+    // A breakpoint that only stops a run when `condition` holds as the
+    // instruction is reached
+    pub fn set_conditional_breakpoint(&mut self, index: usize, condition: Condition) {
+        self.breakpoints.insert(index, Breakpoint::new(Some(condition)));
+    }
+
+    pub fn condition(&self, index: usize) -> Option<&Condition> {
+        self.breakpoints.get(&index)?.condition.as_ref()
+    }
+
+    // Choose which hits of the breakpoint at `index` stop a run, and count
+    // them afresh. Returns false if there is no breakpoint there.
+    pub fn set_hit_count(&mut self, index: usize, count: HitCount) -> bool {
+        let Some(breakpoint) = self.breakpoints.get_mut(&index) else {
+            return false;
+        };
+        breakpoint.count = count;
+        breakpoint.hits = 0;
+        true
+    }
+
+    pub fn hit_count(&self, index: usize) -> Option<HitCount> {
+        self.breakpoints.get(&index).map(|breakpoint| breakpoint.count)
+    }
+
+    // How many times runs have reached the breakpoint at `index` with its
+    // condition holding. Stepping back doesn't take hits away.
+    pub fn hits(&self, index: usize) -> Option<u64> {
+        self.breakpoints.get(&index).map(|breakpoint| breakpoint.hits)
+    }
+
+    pub fn clear_breakpoint(&mut self, index: usize) {
+        self.breakpoints.remove(&index);
+    }
+
+    // Set a breakpoint if there is none, or clear it. Returns whether one is
+    // now set.
+    pub fn toggle_breakpoint(&mut self, index: usize) -> bool {
+        if self.breakpoints.remove(&index).is_none() {
+            self.breakpoints.insert(index, Breakpoint::new(None));
+        }
+        self.breakpoints.contains_key(&index)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.keys().copied()
+    }
+
+    // Watchpoints stop a run after any step that changes the cell
+    pub fn watch(&mut self, cell: usize) {
+        self.watchpoints.insert(cell, Watch::default());
+    }
+
+    // Watch a cell for a particular kind of access, replacing any watchpoint
+    // already on it
+    pub fn watch_for(&mut self, cell: usize, watch: Watch) {
+        self.watchpoints.insert(cell, watch);
+    }
+
+    pub fn watchpoint(&self, cell: usize) -> Option<Watch> {
+        self.watchpoints.get(&cell).copied()
+    }
+
+    pub fn unwatch(&mut self, cell: usize) {
+        self.watchpoints.remove(&cell);
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.watchpoints.keys().copied()
+    }
+
+    // Output breaks live on the machine, so that budgeted runs see them too
+    pub fn break_on_output(&mut self, brk: OutputBreak) -> usize {
+        self.machine.break_on_output(brk)
+    }
+
+    pub fn clear_output_break(&mut self, index: usize) -> Option<OutputBreak> {
+        self.machine.clear_output_break(index)
+    }
+
+    pub fn output_breaks(&self) -> &[OutputBreak] {
+        self.machine.output_breaks()
+    }
+
+    // Log a message, made from the machine as it reaches the instruction at
+    // `index`, each time the instruction runs. Replayed steps aren't logged
+    // again.
+    pub fn set_tracepoint(&mut self, index: usize, message: LogMessage) {
+        self.tracepoints.insert(index, message);
+    }
+
+    pub fn clear_tracepoint(&mut self, index: usize) {
+        self.tracepoints.remove(&index);
+    }
+
+    pub fn tracepoint(&self, index: usize) -> Option<&LogMessage> {
+        self.tracepoints.get(&index)
+    }
+
+    pub fn tracepoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.tracepoints.keys().copied()
+    }
+
+    // The messages logged since the last call, oldest first
+    pub fn take_log(&mut self) -> Vec<String> {
+        mem::take(&mut self.log)
+    }
+
+    // Patch the machine by hand, to carry on a run instead of restarting it.
+    // Recorded steps can't be undone or replayed across a patch, so the
+    // history is forgotten. Each returns false, changing nothing, if the
+    // target is off the tape or the program, or the value doesn't fit a cell.
+    pub fn set_cell(&mut self, index: usize, value: u32) -> bool {
+        let machine = &mut self.machine;
+        if index >= machine.data.len() || value > machine.semantics.cell_size.max() {
+            return false;
+        }
+        machine.data[index] = value;
+        machine.touch(index);
+        machine.set_written_by(index, None);
+        self.forget_history();
+        true
+    }
+
+    pub fn set_data_ptr(&mut self, ptr: usize) -> bool {
+        if ptr >= self.machine.data.len() {
+            return false;
+        }
+        self.machine.data_ptr = ptr;
+        self.forget_history();
+        true
+    }
+
+    // Continue from the instruction at `index`, even if the program halted
+    pub fn set_prog_ctr(&mut self, index: usize) -> bool {
+        if index >= self.machine.prog.len() {
+            return false;
+        }
+        self.machine.prog_ctr = index;
+        self.machine.halted = false;
+        self.forget_history();
+        true
+    }
+
+    // Execute one instruction
+    pub fn step(&mut self) -> Result<Stop, RuntimeError> {
+        let event = if let Some(record) = self.undone.pop() {
+            self.redo(record)
+        } else {
+            if let Some(message) = self.tracepoints.get(&self.machine.prog_ctr).filter(|_| !self.machine.halted) {
+                self.log.push(message.format(&self.machine));
+            }
+            match self.execute()? {
+                Some(event) => event,
+                None => return Ok(Stop::Halted),
+            }
+        };
+        if self.watched(&event, self.machine.data[event.data_ptr]) {
+            Ok(Stop::Watchpoint(event.data_ptr))
+        } else if let Some(index) = self.machine.output_hit() {
+            Ok(Stop::OutputBreak(index))
+        } else if self.machine.halted {
+            Ok(Stop::Halted)
+        } else {
+            Ok(Stop::Step)
+        }
+    }
+
+    // Run until a breakpoint or watchpoint, or the program halts. A
+    // breakpoint on the current instruction doesn't stop it.
+    pub fn resume(&mut self) -> Result<Stop, RuntimeError> {
+        loop {
+            match self.step()? {
+                Stop::Step => {},
+                stop => return Ok(stop),
+            }
+            if let Some(prog_ctr) = self.at_breakpoint() {
+                return Ok(Stop::Breakpoint(prog_ctr));
+            }
+        }
+    }
+
+    // Run the loop starting at the next instruction as if it were one step.
+    // Any other instruction is just stepped.
+    pub fn step_over(&mut self) -> Result<Stop, RuntimeError> {
+        match self.machine.prog.commands.get(self.machine.prog_ctr) {
+            Some(&Command::JumpForward(end)) => self.run_past(end as usize),
+            _ => self.step(),
+        }
+    }
+
+    // Run until the innermost loop around the next instruction exits, or
+    // like `resume` outside any loop
+    pub fn step_out(&mut self) -> Result<Stop, RuntimeError> {
+        match self.enclosing_loop() {
+            Some(end) => self.run_past(end),
+            None => self.resume(),
+        }
+    }
+
+    // Run until a `.` writes a byte, or writes `byte` if one is given,
+    // stopping early as `resume` would
+    pub fn run_to_output(&mut self, byte: Option<u8>) -> Result<Stop, RuntimeError> {
+        loop {
+            let output_len = self.machine.output_len();
+            let stop = self.step()?;
+            let written = self.machine.output.last().filter(|_| self.machine.output_len() > output_len);
+            if let Some(&written) = written {
+                if byte.is_none_or(|byte| byte == written) {
+                    return Ok(Stop::Output(written));
+                }
+            }
+            match stop {
+                Stop::Step => {},
+                stop => return Ok(stop),
+            }
+            if let Some(prog_ctr) = self.at_breakpoint() {
+                return Ok(Stop::Breakpoint(prog_ctr));
+            }
+        }
+    }
+
+    // Undo the last step
+    pub fn step_back(&mut self) -> Stop {
+        let Some(record) = self.pop_step() else {
+            return Stop::StartOfHistory;
+        };
+        let machine = &mut self.machine;
+        let event = record.event(&machine.prog, machine.data_ptr.wrapping_sub(record.moved as usize));
+        let event = &event;
+        machine.prog_ctr = event.prog_ctr;
+        machine.data_ptr = event.data_ptr;
+        let cell = &mut machine.data[event.data_ptr];
+        let after = *cell;
+        *cell = (*cell as i64 - event.cell_delta) as u32;
+        machine.touch(event.data_ptr);
+        if event.output.is_some() {
+            machine.output.pop();
+        }
+        machine.steps -= 1;
+        machine.halted = false;
+        if writes(event.op) {
+            machine.set_written_by(event.data_ptr, record.written_by());
+        }
+        match machine.prog.commands[event.prog_ctr] {
+            // A loop left on an earlier run's last iteration isn't running,
+            // so only looping back needs undoing
+            Command::JumpBackward(start) if machine.data[event.data_ptr] != 0 => {
+                machine.count_iteration(start as usize, Iteration::Previous);
+            },
+            // Taking the read back, so that executing it afresh reads the
+            // same byte. The log only has the reads since it was started.
+            Command::Input => {
+                if let Some(log) = &mut machine.input_log {
+                    log.pos = log.pos.saturating_sub(1);
+                }
+            },
+            Command::ProcStart(_) => {
+                let procedure = machine.data[event.data_ptr];
+                match record.replaced() {
+                    Some(start) => machine.procedures.insert(procedure, start),
+                    None => machine.procedures.remove(&procedure),
+                };
+            },
+            Command::Call => { machine.pop_call(false); },
+            Command::ProcEnd(_) => {
+                if let Some(call) = record.returned() {
+                    machine.push_call(call, false);
+                }
+            },
+            _ => {},
+        }
+        let watched = self.watched(event, after);
+        let cell = event.data_ptr;
+        self.undone.push(record);
+        if watched { Stop::Watchpoint(cell) } else { Stop::Step }
+    }
+
+    // Undo steps until a breakpoint or watchpoint, or the start of history.
+    // Hit counts only apply going forwards.
+    pub fn reverse_resume(&mut self) -> Stop {
+        loop {
+            match self.step_back() {
+                Stop::Step => {},
+                stop => return stop,
+            }
+            if self.breaks_here() {
+                return Stop::Breakpoint(self.machine.prog_ctr);
+            }
+        }
+    }
+
+    // Undo steps back to just after the most recent one that wrote `cell`,
+    // and return the index of its instruction. Without such a step in the
+    // history, nothing is undone.
+    pub fn reverse_to_write(&mut self, cell: usize) -> Option<usize> {
+        let after = self.history().collect::<Vec<_>>().iter().rev()
+            .position(|entry| entry.data_ptr == cell && writes(entry.op))?;
+        for _ in 0..after {
+            self.step_back();
+        }
+        self.history.back().map(|record| (record.prog_ctr + record.count - 1) as usize)
+    }
+
+    // Steps that can be undone
+    pub fn history_len(&self) -> usize {
+        self.history_steps
+    }
+
+    // The steps that can be undone, oldest first. Where the data pointer was
+    // is worked out back from where it is now, then the steps are listed
+    // going forwards from there.
+    pub fn history(&self) -> impl Iterator<Item = HistoryEntry> + '_ {
+        let moved = |record: &Record, steps: u32| (record.moved as usize).wrapping_mul(steps as usize);
+        let total = self.history.iter().fold(0usize, |total, record| total.wrapping_add(moved(record, record.count)));
+        let start = (self.earliest_step(), self.machine.data_ptr.wrapping_sub(total));
+        let runs = self.history.iter().scan(start, move |(step, data_ptr), record| {
+            let run = (*step, *data_ptr, record);
+            *step += record.count as u64;
+            *data_ptr = data_ptr.wrapping_add(moved(record, record.count));
+            Some(run)
+        });
+        runs.flat_map(move |(step, data_ptr, record)| (0..record.count).map(move |i| HistoryEntry {
+            step: step + 1 + i as u64,
+            #[cfg(feature = "std")]
+            micros: Some(record.micros),
+            #[cfg(not(feature = "std"))]
+            micros: None,
+            op: self.machine.prog.chars[(record.prog_ctr + i) as usize],
+            prog_ctr: (record.prog_ctr + i) as usize,
+            data_ptr: data_ptr.wrapping_add(moved(record, i)),
+            cell_delta: record.cell_delta,
+            output: record.output,
+        }))
+    }
+
+    // The step number furthest back that `seek` can reach
+    pub fn earliest_step(&self) -> u64 {
+        self.machine.steps - self.history_steps as u64
+    }
+
+    // Travel to the point after `step` steps, undoing or running steps as
+    // needed, or from the last checkpoint before it if the history doesn't
+    // go back that far. Breakpoints and watchpoints are passed over. Stops
+    // short at the start of history, or when the program halts.
+    pub fn seek(&mut self, step: u64) -> Result<Stop, RuntimeError> {
+        let checkpoint = self.checkpoints.iter().rposition(|checkpoint| checkpoint.snapshot.steps <= step);
+        if let (true, Some(i)) = (step < self.earliest_step(), checkpoint) {
+            return self.run_from_checkpoint(i, step);
+        }
+        while self.machine.steps > step {
+            if self.step_back() == Stop::StartOfHistory {
+                return Ok(Stop::StartOfHistory);
+            }
+        }
+        while self.machine.steps < step {
+            if self.step()? == Stop::Halted {
+                return Ok(Stop::Halted);
+            }
+        }
+        Ok(Stop::Step)
+    }
+
+    pub fn cell(&self, index: usize) -> Option<u32> {
+        self.machine.cell(index)
+    }
+
+    // 0-based (column, line) of the next instruction
+    pub fn position(&self) -> (usize, usize) {
+        self.machine.prog.positions.get(self.machine.prog_ctr).copied().unwrap_or_default()
+    }
+
+    // The breakpoint on the next instruction, if any, reported to
+    // subscribers. Counts a hit, which only stops the run as its hit count
+    // says.
+    pub(crate) fn at_breakpoint(&mut self) -> Option<usize> {
+        let prog_ctr = self.machine.prog_ctr;
+        if !self.breaks_here() {
+            return None;
+        }
+        let breakpoint = self.breakpoints.get_mut(&prog_ctr).expect("checked by breaks_here");
+        breakpoint.hits += 1;
+        breakpoint.count.stops(breakpoint.hits).then(|| {
+            self.machine.emit(VmEvent::Breakpoint(prog_ctr));
+            prog_ctr
+        })
+    }
+
+    // Run until the program counter passes `end`, stopping early as `resume`
+    // would. A pbrain procedure called from before `end` may lie past it, so
+    // only passing it outside any call made since counts.
+    fn run_past(&mut self, end: usize) -> Result<Stop, RuntimeError> {
+        let depth = self.machine.calls.len();
+        loop {
+            match self.step()? {
+                Stop::Step => {},
+                stop => return Ok(stop),
+            }
+            if self.machine.prog_ctr > end && self.machine.calls.len() <= depth {
+                return Ok(Stop::Step);
+            }
+            if let Some(prog_ctr) = self.at_breakpoint() {
+                return Ok(Stop::Breakpoint(prog_ctr));
+            }
+        }
+    }
+
+    // The index of the `]` closing the innermost loop around the next
+    // instruction. Brackets nest, so that loop opens at the nearest `[`
+    // before the instruction whose `]` isn't.
+    fn enclosing_loop(&self) -> Option<usize> {
+        let prog_ctr = self.machine.prog_ctr;
+        self.machine.prog.commands[..prog_ctr.min(self.machine.prog.len())].iter().rev()
+            .find_map(|command| match *command {
+                Command::JumpForward(end) if end as usize >= prog_ctr => Some(end as usize),
+                _ => None,
+            })
+    }
+
+    // Whether a step that left its cell holding `after` sets off a watchpoint
+    fn watched(&self, event: &StepEvent, after: u32) -> bool {
+        self.watchpoints.get(&event.data_ptr).is_some_and(|watch| watch.triggered(event, after))
+    }
+
+    // Whether a breakpoint on the next instruction applies now, its hit
+    // count aside
+    fn breaks_here(&self) -> bool {
+        match self.breakpoints.get(&self.machine.prog_ctr) {
+            Some(breakpoint) => breakpoint.condition.as_ref().is_none_or(|c| c.holds(&self.machine)),
+            None => false,
+        }
+    }
+
+    // Whether the next step replays an undone one
+    pub(crate) fn replaying(&self) -> bool {
+        !self.undone.is_empty()
+    }
+
+    pub(crate) fn machine_mut(&mut self) -> &mut Machine {
+        &mut self.machine
+    }
+
+    // Patching the machine changes where the checkpoints lead, so they
+    // start over from here
+    fn forget_history(&mut self) {
+        self.clear_history();
+        if self.checkpoint_every > 0 {
+            self.checkpoints.clear();
+            self.checkpoint();
+        }
+    }
+
+    // Execute the next instruction afresh, recording it
+    fn execute(&mut self) -> Result<Option<StepEvent>, RuntimeError> {
+        let kept = self.kept();
+        let Some(event) = self.machine.steps().next() else {
+            return Ok(None);
+        };
+        let event = event?;
+        self.record(event.clone(), kept);
+        if self.checkpoint_every > 0 && self.machine.steps.is_multiple_of(self.checkpoint_every) {
+            self.checkpoint();
+        }
+        if self.machine.steps.is_multiple_of(CHECK_INTERVAL) {
+            self.fit_memory();
+        }
+        Ok(Some(event))
+    }
+
+    // Keep within the machine's memory limit by giving up the oldest half of
+    // the history, and then every checkpoint but the first, for as long as
+    // that helps. The machine stops itself if it is over on its own.
+    fn fit_memory(&mut self) {
+        let Some(max_memory) = self.machine.limits.max_memory else { return };
+        while self.memory().total() > max_memory && !self.history.is_empty() {
+            self.forget_oldest(self.history.len().div_ceil(2));
+            self.max_records = self.history.len().max(1);
+        }
+        if self.memory().total() > max_memory {
+            self.checkpoints.truncate(1);
+        }
+    }
+
+    // Save the machine as it is now, unless it was saved at this step
+    // already, then thin the checkpoints out until they fit the budget
+    fn checkpoint(&mut self) {
+        let steps = self.machine.steps;
+        let Err(at) = self.checkpoints.binary_search_by_key(&steps, |checkpoint| checkpoint.snapshot.steps) else {
+            return;
+        };
+        let machine = &self.machine;
+        self.checkpoints.insert(at, Checkpoint {
+            snapshot: machine.snapshot(),
+            reads: machine.input_log.as_ref().map_or(0, |log| log.pos),
+            provenance: machine.provenance.clone(),
+            iterations: machine.iterations.clone(),
+        });
+        while self.checkpoints.len() > 1
+            && self.checkpoints.iter().map(Checkpoint::footprint).sum::<usize>() > self.checkpoint_budget {
+            // The first is kept, as the furthest back that can be reached
+            self.checkpoint_every *= 2;
+            let every = self.checkpoint_every;
+            let mut first = true;
+            self.checkpoints.retain(|checkpoint| mem::take(&mut first) || checkpoint.snapshot.steps.is_multiple_of(every));
+            if self.checkpoints.len() == 1 {
+                break;
+            }
+        }
+    }
+
+    // Go back to checkpoint `i` and run forward to `step`, as quietly as
+    // possible: output isn't sent again, and tracepoints don't log
+    fn run_from_checkpoint(&mut self, i: usize, step: u64) -> Result<Stop, RuntimeError> {
+        let checkpoint = &self.checkpoints[i];
+        let machine = &mut self.machine;
+        machine.restore(&checkpoint.snapshot).expect("checkpoints fit the machine they came from");
+        machine.provenance = checkpoint.provenance.clone();
+        machine.iterations = checkpoint.iterations.clone();
+        if let Some(log) = &mut machine.input_log {
+            log.pos = checkpoint.reads;
+        }
+        machine.output_hit = None;
+        self.clear_history();
+        let sink = self.machine.sink.take();
+        let mut result = Ok(Stop::Step);
+        while self.machine.steps < step {
+            match self.execute() {
+                Ok(Some(_)) => {},
+                Ok(None) => break,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                },
+            }
+        }
+        self.machine.sink = sink;
+        if self.machine.halted && result.is_ok() {
+            result = Ok(Stop::Halted);
+        }
+        result
+    }
+
+    // What the record of the next step keeps of what the step overwrites:
+    // where the cell under the pointer was last written, or for a `(` or
+    // `)`, as the record says
+    fn kept(&self) -> (u32, u64) {
+        let machine = &self.machine;
+        match machine.prog.commands.get(machine.prog_ctr) {
+            Some(Command::ProcStart(_)) => match machine.procedures.get(&machine.data[machine.data_ptr]) {
+                Some(&start) => (start as u32, 1),
+                None => (0, 0),
+            },
+            Some(Command::ProcEnd(_)) => match machine.calls.last() {
+                Some(call) => (call.procedure, call.step + 1),
+                None => (0, 0),
+            },
+            _ => match machine.written_by(machine.data_ptr) {
+                Some(write) => (write.prog_ctr as u32, write.step),
+                None => (0, 0),
+            },
+        }
+    }
+
+    fn record(&mut self, event: StepEvent, (written_pc, written_at): (u32, u64)) {
+        let machine = &self.machine;
+        let record = Record {
+            prog_ctr: event.prog_ctr as u32,
+            after: machine.prog_ctr as u32,
+            count: 1,
+            written_pc,
+            written_at,
+            moved: machine.data_ptr.wrapping_sub(event.data_ptr) as isize,
+            cell_delta: event.cell_delta,
+            output: event.output,
+            halted: machine.halted,
+            #[cfg(feature = "std")]
+            micros: self.started.elapsed().as_micros() as u64,
+        };
+        self.push(record);
+    }
+
+    // Add a step to the history, as part of the last run if it carries on
+    // from it
+    fn push(&mut self, record: Record) {
+        self.history_steps += 1;
+        if let Some(last) = self.history.back() {
+            if self.continues(last, &record) {
+                let last = self.history.back_mut().expect("checked above");
+                last.count += 1;
+                last.after = record.after;
+                return;
+            }
+        }
+        while self.history.len() >= self.max_records {
+            self.forget_oldest(1);
+        }
+        self.history.push_back(record);
+    }
+
+    // Whether the step just taken, the machine's last, carries on the run
+    // `last`: it is the next instruction, the same one again, and did the
+    // same thing soon after. A `+` or `-` must also have found its cell
+    // last written by the step before it, if writes are being tracked.
+    fn continues(&self, last: &Record, record: &Record) -> bool {
+        let commands = &self.machine.prog.commands;
+        let command = commands[last.prog_ctr as usize];
+        let adds = matches!(command, Command::IncData | Command::DecData);
+        #[cfg(feature = "std")]
+        let soon = record.micros - last.micros < RUN_MICROS;
+        #[cfg(not(feature = "std"))]
+        let soon = true;
+        (adds || matches!(command, Command::IncPtr | Command::DecPtr))
+            && last.after == last.prog_ctr + last.count
+            && record.prog_ctr == last.after
+            && record.after == record.prog_ctr + 1
+            && commands[record.prog_ctr as usize] == command
+            && (record.moved, record.cell_delta) == (last.moved, last.cell_delta)
+            && !last.halted && !record.halted
+            && soon
+            && (!adds || record.written_by() == self.machine.provenance.is_some().then(|| Provenance {
+                step: self.machine.steps - 1,
+                prog_ctr: record.prog_ctr as usize - 1,
+            }))
+    }
+
+    // Take the last step off the history, splitting it from its run if it
+    // ends one
+    fn pop_step(&mut self) -> Option<Record> {
+        let last = self.history.back_mut()?;
+        self.history_steps -= 1;
+        if last.count == 1 {
+            return self.history.pop_back();
+        }
+        last.count -= 1;
+        let prog_ctr = last.prog_ctr + last.count;
+        let mut record = Record { prog_ctr, count: 1, ..*last };
+        last.after = prog_ctr;
+        // The step before it in the run wrote the cell it found
+        let (written_pc, written_at) = match self.machine.provenance {
+            Some(_) => (prog_ctr - 1, self.machine.steps - 1),
+            None => (0, 0),
+        };
+        record.written_pc = written_pc;
+        record.written_at = written_at;
+        Some(record)
+    }
+
+    // Drop up to `records` of the oldest records from the history
+    fn forget_oldest(&mut self, records: usize) {
+        let records = records.min(self.history.len());
+        self.history_steps -= self.history.drain(..records).map(|record| record.count as usize).sum::<usize>();
+    }
+
+    fn clear_history(&mut self) {
+        self.history.clear();
+        self.history_steps = 0;
+        self.undone.clear();
+    }
+
+    // Apply an undone step again
+    fn redo(&mut self, record: Record) -> StepEvent {
+        let machine = &mut self.machine;
+        let event = record.event(&machine.prog, machine.data_ptr);
+        let cell = &mut machine.data[event.data_ptr];
+        *cell = (*cell as i64 + event.cell_delta) as u32;
+        machine.touch(event.data_ptr);
+        machine.output.extend(event.output);
+        machine.output_hit = None;
+        if event.output.is_some() {
+            machine.check_output_breaks();
+        }
+        machine.prog_ctr = record.after as usize;
+        machine.data_ptr = event.data_ptr.wrapping_add(record.moved as usize);
+        machine.halted = record.halted;
+        machine.steps += 1;
+        if writes(event.op) {
+            let write = Provenance { step: machine.steps, prog_ctr: event.prog_ctr };
+            machine.set_written_by(event.data_ptr, Some(write));
+        }
+        if machine.data[event.data_ptr] != 0 {
+            match machine.prog.commands[event.prog_ctr] {
+                Command::JumpForward(_) => machine.count_iteration(event.prog_ctr, Iteration::First),
+                Command::JumpBackward(start) => machine.count_iteration(start as usize, Iteration::Next),
+                _ => {},
+            }
+        }
+        match machine.prog.commands[event.prog_ctr] {
+            Command::Input => {
+                if let Some(log) = machine.input_log.as_mut().filter(|log| log.pos < log.reads.len()) {
+                    log.pos += 1;
+                }
+            },
+            Command::ProcStart(_) => {
+                machine.procedures.insert(machine.data[event.data_ptr], event.prog_ctr);
+            },
+            Command::Call => {
+                let call = Call { at: event.prog_ctr, procedure: machine.data[event.data_ptr], step: machine.steps - 1 };
+                machine.push_call(call, false);
+            },
+            Command::ProcEnd(_) if record.returned().is_some() => { machine.pop_call(false); },
+            _ => {},
+        }
+        self.push(record);
+        event
+    }
+}
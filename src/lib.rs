@@ -0,0 +1,48 @@
+// The BrainRust interpreter: a parser, a virtual machine, and the terminal
+// debugger that drives it. Without the `std` feature the parser and machine
+// need only `alloc`; the terminal debugger is behind the `ui` feature.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod calls;
+pub mod condition;
+pub mod coverage;
+pub mod debugger;
+pub mod driver;
+#[cfg(feature = "std")]
+pub mod farm;
+pub mod fuse;
+#[cfg(feature = "std")]
+pub mod golden;
+pub mod parse;
+pub mod snapshot;
+pub mod tape;
+#[cfg(feature = "ui")]
+pub mod ui;
+pub mod vm;
+
+pub use calls::{Call, CallNode, CallTree};
+pub use condition::{Condition, ConditionError, LogMessage};
+pub use coverage::{Coverage, LineCoverage};
+pub use debugger::{Debugger, HistoryEntry, HitCount, Stop, Trigger, Watch};
+pub use driver::{CancelToken, RunOutcome};
+#[cfg(feature = "std")]
+pub use driver::InputFeed;
+#[cfg(feature = "std")]
+pub use farm::{FarmRun, FarmSummary, RunFarm};
+pub use fuse::{Fused, Op};
+pub use parse::{
+    Command, Dialect, ParseError, Program, Remap, labels, parse, parse_dialect, parse_with_extensions,
+    reparse_with_extensions,
+};
+pub use snapshot::{CellChange, RestoreError, Snapshot, StateDiff};
+pub use tape::Tape;
+#[cfg(feature = "ui")]
+pub use ui::{DisplaySpec, Keys, Theme};
+pub use vm::{
+    ByteSink, ByteSource, CellSize, Dispatch, Eof, Fault, IoError, Limits, Machine, MachineBuilder,
+    MemoryUsage, Overflow, OutputBreak, Perf, Provenance, PtrBounds, RuntimeError, Semantics, StepEvent,
+    Steps, VmEvent,
+};
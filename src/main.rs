@@ -1,368 +1,561 @@
-use std::env;
 use std::fs;
-use std::io;
-use std::io::{Write, stdin, stdout};
-use std::fmt;
+use std::io::{self, IsTerminal, Read, Write, stdin, stdout};
+use std::mem;
 use std::path;
-use std::thread;
-use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::process;
+use std::time::Instant;
+
+use clap::{CommandFactory, Parser};
+use serde_json::json;
+
+use brainrust::{
+    CallTree, Dialect, Fault, Machine, Overflow, ParseError, Perf, Program, PtrBounds, RuntimeError, Semantics, parse_dialect,
+};
+#[cfg(feature = "ui")]
+use brainrust::DisplaySpec;
+use brainrust::debugger::HISTORY_BUDGET;
+#[cfg(feature = "ui")]
+use termion::color::AnsiValue;
+
+mod batch;
+mod bench;
+mod cli;
+mod compare;
+mod config;
+mod console;
+mod dap;
+mod profile;
+#[cfg(feature = "render")]
+mod render;
+mod repl;
+mod replay;
+mod sidecar;
+mod state;
+mod stats;
+mod suite;
+mod watch;
+use cli::{CheckArgs, Cli, Cmd, InputArgs, IoArgs, PipeArgs, RunArgs, SemanticsArgs, SourceArgs, StatsArgs};
+use config::Config;
+use stats::Stats;
+
+// Process exit codes
+mod exit {
+    pub const FAILURE: i32 = 1;     // I/O failure or bad configuration
+    pub const USAGE: i32 = 2;       // Bad command line, as reported by clap
+    pub const PARSE: i32 = 3;
+    pub const RUNTIME: i32 = 4;
+    pub const LIMIT: i32 = 5;       // Step, output or tape limit exceeded
+}
 
-use termion::color;
-use termion::event::Key;
-use termion::input::TermRead;
-use termion::raw::IntoRawMode;
-use itertools::{Itertools, EitherOrBoth};
-
-const MEM_SIZE: usize = 30_000;
-const WELCOME_MESSAGE: &'static str = r#"Welcome to BrainRust!
-[q] quit, [a] advance
-"#;
-
-// Commands known to the VM
-enum Command {
-    JumpForward(usize),
-    JumpBackward(usize),
-    DecPtr,
-    IncPtr,
-    DecData,
-    IncData,
-    Input,
-    Output,
-    NoOp,
+fn exit_code(err: &RuntimeError) -> i32 {
+    match err {
+        RuntimeError::StepLimit(_)
+        | RuntimeError::OutputLimit(_)
+        | RuntimeError::TapeLimit(_)
+        | RuntimeError::MemoryLimit(_) => exit::LIMIT,
+        _ => exit::RUNTIME,
+    }
 }
 
-// Parsed instruction with satellite data
-struct Instruction {
-    command: Command,
-    ch: char,
-    pos: (usize, usize),  // Screen position
+// Fetch the program text: inline, from a file, or from stdin if the file is
+// `-` or omitted while stdin is piped.
+fn load_program(args: &SourceArgs) -> String {
+    if let Some(src) = &args.eval {
+        return src.clone();
+    }
+    let from_stdin = match &args.file {
+        Some(script) => script.as_os_str() == "-",
+        None => !stdin().is_terminal(),
+    };
+    if from_stdin {
+        let mut src = String::new();
+        stdin().read_to_string(&mut src).unwrap_or_else(|_| {
+            eprintln!("File read failed!");
+            process::exit(exit::FAILURE);
+        });
+        src
+    } else if let Some(script) = &args.file {
+        read_program(script)
+    } else {
+        eprintln!("No program given!");
+        process::exit(exit::FAILURE);
+    }
 }
 
-// Transform a sequence of characters into a sequence of instructions
-fn parse(chs: &Vec<char>) -> Result<Vec<Instruction>, ()> {
-    let mut instructions: Vec<Instruction> = Vec::new();
-    let mut brack_stack: Vec<usize> = Vec::new();
+fn read_program(path: &path::Path) -> String {
+    fs::read_to_string(path).unwrap_or_else(|_| {
+        eprintln!("File read failed!");
+        process::exit(exit::FAILURE);
+    })
+}
 
-    let (mut pos_x, mut pos_y): (usize, usize) = (0, 0);
-    for (i, ch) in chs.iter().enumerate() {
-        // Is this bad form?
-        let command = match ch {
-            '[' => {
-                brack_stack.push(i);
-                // To be replaced. This is probably confusing/bad form. There's
-                // probably a better data structure to use that doesn't lead to
-                // this confusion.
-                Command::JumpForward(0)
-            },
-            ']' => {
-                let match_pos = brack_stack.pop().ok_or(())?;
-                instructions[match_pos].command = Command::JumpForward(i);
-                Command::JumpBackward(match_pos)
-            },
-            '<' => { Command::DecPtr },
-            '>' => { Command::IncPtr },
-            '-' => { Command::DecData },
-            '+' => { Command::IncData },
-            '.' => { Command::Output },
-            ',' => { Command::Input },
-            '\n' => { pos_y += 1;
-                      Command:: NoOp
-            }
-            _ => { Command::NoOp },
-        };
+// Open the stream read by `,`: any program arguments, then inline bytes, a
+// file, or stdin
+fn open_input(args: &InputArgs) -> Box<dyn Read + Send> {
+    open_input_or(args, Box::new(stdin()))
+}
 
-        instructions.push(
-            Instruction {
-                command: command,
-                ch: *ch,
-                pos: (pos_x, pos_y),
+// Open the stream read by `,`, falling back to `default` rather than stdin
+fn open_input_or(args: &InputArgs, default: Box<dyn Read + Send>) -> Box<dyn Read + Send> {
+    let input = open_stream(args, default);
+    match &args.args {
+        Some(words) => {
+            let mut prefix = Vec::new();
+            for word in words.split_whitespace() {
+                prefix.extend_from_slice(word.as_bytes());
+                prefix.push(args.args_separator.byte());
             }
-        );
-
-        if *ch == '\n' {
-            pos_x = 0;
-        } else {
-            pos_x += 1;
-        }
+            Box::new(io::Cursor::new(prefix).chain(input))
+        },
+        None => input,
     }
+}
 
-    Ok(instructions)
+fn open_stream(args: &InputArgs, default: Box<dyn Read + Send>) -> Box<dyn Read + Send> {
+    if let Some(bytes) = &args.input_bytes {
+        Box::new(io::Cursor::new(bytes.clone()))
+    } else if let Some(path) = &args.input {
+        Box::new(fs::File::open(path).unwrap_or_else(|_| {
+            eprintln!("Input file read failed!");
+            process::exit(exit::FAILURE);
+        }))
+    } else {
+        default
+    }
 }
 
-struct DisplaySpec {
-    visible: bool,
-    decimal: bool,
-    hex: bool,
-    ascii: bool,
-    frame_dur: Duration,
+// Load the config file and settle the runtime semantics, or exit
+fn configure(args: &SemanticsArgs) -> (Config, Semantics) {
+    let config = Config::load(args.config.as_deref()).unwrap_or_else(|msg| {
+        eprintln!("{}", msg);
+        process::exit(exit::FAILURE);
+    });
+    let semantics = semantics(args, &config).unwrap_or_else(|msg| {
+        eprintln!("{}", msg);
+        process::exit(exit::FAILURE);
+    });
+    if args.verbose {
+        eprintln!("{}", semantics);
+    }
+    (config, semantics)
 }
 
-impl DisplaySpec {
-    fn new(rate: f32) -> DisplaySpec {
-        DisplaySpec {
-            visible: true,
-            decimal: true,
-            hex: true,
-            ascii: true,
-            frame_dur: Duration::from_millis((1000.0 / rate) as u64),
+// Collect the runtime semantics from the command line and config file,
+// rejecting combinations that make no sense
+fn semantics(args: &SemanticsArgs, config: &Config) -> Result<Semantics, String> {
+    let mut semantics = Semantics::default();
+    if let Some(cell_size) = args.cell_size.or(config.cell_size) {
+        semantics.cell_size = cell_size;
+    }
+    if args.checked {
+        semantics.overflow = Overflow::Error;
+    } else if let Some(overflow) = args.overflow.or(config.overflow) {
+        semantics.overflow = overflow;
+    }
+    if let Some(eof) = args.eof.or(config.eof) {
+        semantics.eof = eof;
+    }
+    if let Some(ptr_bounds) = args.ptr_bounds.or(config.ptr_bounds) {
+        semantics.ptr_bounds = ptr_bounds;
+    }
+    if let Some(dialect) = args.dialect.or(config.dialect) {
+        semantics.dialect = dialect;
+    }
+    // A configured tape size is only a default, so it quietly gives way to an
+    // extending tape; an explicit one does not.
+    if let (Some(_), PtrBounds::Extend) = (args.tape_size, semantics.ptr_bounds) {
+        return Err("--tape-size cannot be used with --ptr-bounds extend, \
+                    which grows the tape as needed".to_owned());
+    }
+    if let Some(tape_size) = args.tape_size.or(config.tape_size) {
+        if tape_size == 0 {
+            return Err("--tape-size must be at least 1".to_owned());
         }
+        semantics.tape_size = tape_size;
     }
+    Ok(semantics)
 }
 
-// Language virtual machine
-struct Machine {
-    prog: Vec<Instruction>,
-    data: [u8; MEM_SIZE],
-    prog_ctr: usize,
-    data_ptr: usize,
-
-    last_data_cell: usize,
-    prog_src: Vec<String>,
-    display_spec: DisplaySpec,
-    output: String,
+// Set up the debugger from the command line and config file
+#[cfg(feature = "ui")]
+fn display_spec(speed: Option<f32>, config: &Config, visible: bool) -> DisplaySpec {
+    let mut display_spec = DisplaySpec::new(speed.or(config.speed).unwrap_or(1.0));
+    display_spec.visible = visible;
+    let theme = &mut display_spec.theme;
+    theme.highlight = config.theme.highlight.map_or(theme.highlight, AnsiValue);
+    theme.output = config.theme.output.map_or(theme.output, AnsiValue);
+    theme.changed = config.theme.changed.map_or(theme.changed, AnsiValue);
+    let keys = &mut display_spec.keys;
+    keys.quit = config.keys.quit.unwrap_or(keys.quit);
+    keys.advance = config.keys.advance.unwrap_or(keys.advance);
+    keys.back = config.keys.back.unwrap_or(keys.back);
+    keys.over = config.keys.over.unwrap_or(keys.over);
+    keys.out = config.keys.out.unwrap_or(keys.out);
+    keys.output = config.keys.output.unwrap_or(keys.output);
+    keys.writer = config.keys.writer.unwrap_or(keys.writer);
+    keys.run = config.keys.run.unwrap_or(keys.run);
+    keys.calls = config.keys.calls.unwrap_or(keys.calls);
+    display_spec
 }
 
+// Bytes of step history the debugger may keep
+fn history_budget(args: &RunArgs, config: &Config) -> usize {
+    args.history.or(config.history).map_or(HISTORY_BUDGET, |mib| mib.saturating_mul(1 << 20))
+}
 
-impl Machine {
-    fn new(program: String) -> Result<Machine, ()> {
-        let machine = Machine {
-            prog: parse(&program.chars().collect())?,
-
-            data: [0; MEM_SIZE],
-            prog_ctr: 0,
-            data_ptr: 0,
-
-            prog_src: program.split('\n')
-                .map(|s| s.to_owned())
-                .collect(),
-            last_data_cell: 0,
-            display_spec : DisplaySpec::new(1.0),
-            output: String::new(),
-        };
-
-        Ok(machine)
+// Bytes of output a headless run keeps once it has gone to the sink
+const OUTPUT_TAIL: usize = 1 << 16;
+
+// Open the stream that receives the program's output as it is produced: the
+// requested file, or stdout for headless runs. The debugger already shows it
+// on screen. Either is buffered; the machine flushes it before reading input.
+fn open_output(args: &IoArgs, headless: bool) -> Option<Box<dyn Write + Send>> {
+    if let Some(path) = &args.output {
+        let file = fs::File::create(path).unwrap_or_else(|_| {
+            eprintln!("Output file creation failed!");
+            process::exit(exit::FAILURE);
+        });
+        Some(Box::new(io::BufWriter::new(file)))
+    } else if headless {
+        Some(Box::new(io::BufWriter::new(stdout())))
+    } else {
+        None
     }
+}
 
+// Flush whatever output the machine's sink is still holding, or exit
+fn flush_output(machine: &mut Machine) {
+    if let Some(sink) = &mut machine.sink {
+        sink.flush().unwrap_or_else(|_| {
+            eprintln!("Output write failed!");
+            process::exit(exit::FAILURE);
+        });
+    }
+}
 
-    // Run the machine to termination.
-    fn run(&mut self) {
-        println!("{}{}{}{}",
-                 termion::cursor::Goto(1,1),
-                 termion::clear::AfterCursor,
-                 WELCOME_MESSAGE,
-                 termion::cursor::Hide);
+fn run(args: RunArgs) {
+    #[cfg(not(feature = "ui"))]
+    if !args.headless && !args.console {
+        eprintln!("This build has no visual debugger; run with --headless or --console");
+        process::exit(exit::USAGE);
+    }
+    if args.console {
+        console::console(&args, load_program(&args.source));
+        return;
+    }
+    if args.watch {
+        watch::watch(args);
+    }
+    let (code, _) = run_once(&args, load_program(&args.source), None, &mut None);
+    process::exit(code);
+}
 
-        if self.display_spec.visible {
-            let input_stream = stdin(); // should this go here?
-            let mut output_stream = stdout().into_raw_mode().unwrap();
-            self.redraw(&mut output_stream);
-            for c in input_stream.keys() {
-                match c.unwrap() {
-                    Key::Char('q') => {
-                        write!(output_stream, "{}", termion::cursor::Show).unwrap();
-                        break
-                    },
-                    Key::Char('a') => { self.advance(); },
-                    _ => { },
+// Run a program once and report on it. Returns the exit code, and whether
+// the debugger was stopped before the program halted. A debugger stopped by
+// a change to the source leaves its machine in `session`, and the next run
+// reloads the edited program into it instead of starting over.
+fn run_once(args: &RunArgs, program: String, source_changed: Option<Arc<AtomicBool>>,
+            session: &mut Option<Machine>) -> (i32, bool) {
+    #[cfg_attr(not(feature = "ui"), allow(unused_variables))]
+    let (config, mut semantics) = configure(&args.semantics);
+    let replay: Option<replay::Replay> = args.replay.as_deref().map(replay::load);
+    let mut recording = None;
+    let mut machine = match session.take() {
+        Some(mut machine) => match machine.reload(program) {
+            Ok(_) => machine,
+            Err(err) => {
+                eprintln!("Failed to parse program: {}", err);
+                *session = Some(machine);  // Kept for when the error is fixed
+                return (exit::PARSE, false);
+            },
+        },
+        None => {
+            let mut input = match &replay {
+                Some(replay) => {
+                    semantics = replay.semantics.clone();
+                    Box::new(io::Cursor::new(replay.input.clone()))
+                },
+                None => open_input(&args.io.input),
+            };
+            if args.record.is_some() {
+                let (recorder, log) = replay::record(input);
+                input = Box::new(recorder);
+                recording = Some(log);
+            }
+            let mut builder = Machine::builder()
+                .semantics(semantics)
+                .input(input)
+                .limits(args.limits.to_limits())
+                .canaries(args.canaries.unwrap_or(0));
+            #[cfg(feature = "ui")]
+            {
+                builder = builder.display_spec(DisplaySpec {
+                    history: history_budget(args, &config),
+                    ..display_spec(args.speed, &config, !args.headless)
+                });
+            }
+            if let Some(sink) = open_output(&args.io, args.headless && !args.json) {
+                builder = builder.output(sink);
+                // Nothing reads the output back but a JSON report or state dump
+                if args.headless && !args.json && args.dump_state_on_exit.is_none() {
+                    builder = builder.output_tail(OUTPUT_TAIL);
                 }
-                self.redraw(&mut output_stream);
             }
-        } else {
-            loop {
-                self.advance();
+            let mut machine = match builder.build(program) {
+                Ok(machine) => machine,
+                Err(err) => {
+                    if args.json {
+                        println!("{}", json!({ "status": "parse_error", "error": parse_error_json(&err) }));
+                    } else {
+                        eprintln!("Failed to parse program: {}", err);
+                    }
+                    return (exit::PARSE, false);
+                },
+            };
+            #[cfg(feature = "ui")]
+            {
+                machine.source_changed = source_changed.clone();
             }
-        }
-
+            if args.profile || args.coverage || args.lcov.is_some() || args.flamegraph.is_some() {
+                machine.profile = Some(vec![0; machine.prog.len()]);
+            }
+            if args.provenance {
+                machine.provenance = Some(Vec::new());
+            }
+            if args.perf {
+                machine.perf = Some(Perf::default());
+            }
+            if !args.headless {
+                machine.iterations = Some(Vec::new());
+            }
+            if args.call_tree || (!args.headless && machine.semantics.dialect == Dialect::Pbrain) {
+                machine.call_tree = Some(CallTree::default());
+            }
+            machine
+        },
+    };
+    if let Some(path) = &args.trace {
+        let file = fs::File::create(path).unwrap_or_else(|_| {
+            eprintln!("Trace file creation failed!");
+            process::exit(exit::FAILURE);
+        });
+        let mut trace = io::BufWriter::new(file);
+        writeln!(trace, "# step op ptr before after [@label]").unwrap();
+        machine.trace = Some(Box::new(trace));
     }
-
-    // Draw the machine state
-    fn redraw(&self, output_stream: &mut std::io::Stdout) {
-        writeln!(output_stream, "{}{}{}",
-               termion::cursor::Goto(1,3),
-               termion::clear::AfterCursor,
-               self);
-        output_stream.flush().unwrap();
+    let chrome_trace = args.chrome_trace.as_ref().map(|path| {
+        fs::File::create(path)
+            .and_then(|file| profile::ChromeTrace::attach(&mut machine, file))
+            .unwrap_or_else(|_| {
+                eprintln!("Trace file creation failed!");
+                process::exit(exit::FAILURE);
+            })
+    });
+    let started = Instant::now();
+    let result = machine.run().map_err(|err| machine.fault(err));
+    let elapsed = started.elapsed();
+    if let Some(mut trace) = machine.trace.take() {
+        trace.flush().unwrap_or_else(|_| {
+            eprintln!("Trace write failed!");
+            process::exit(exit::FAILURE);
+        });
     }
-
-    // Advance to next non-noop command
-    fn advance(&mut self) {
-        self.execute();
-        self.inc_prog_ctr();
-        while let Command::NoOp = &self.prog[self.prog_ctr].command {
-            self.inc_prog_ctr();
-        }
+    if let Some(trace) = chrome_trace {
+        trace.finish(&machine).unwrap_or_else(|_| {
+            eprintln!("Trace write failed!");
+            process::exit(exit::FAILURE);
+        });
     }
-
-    // Step forward or terminate
-    fn inc_prog_ctr(&mut self) {
-        if self.prog_ctr == self.prog.len() - 1 {
-            println!("{}", termion::cursor::Show);
-            process::exit(0);
-        }
-        self.prog_ctr += 1;
+    flush_output(&mut machine);
+    if args.profile {
+        eprint!("{}", profile::report(&machine));
     }
-
-    // Execute the command under the read head
-    fn execute(&mut self) {
-        match self.prog[self.prog_ctr].command {
-            Command::JumpForward(i) => { self.jmp_eq(i); },
-            Command::JumpBackward(i) => { self.jmp_ne(i); },
-            Command::DecPtr => { self.data_ptr -= 1; },
-            Command::IncPtr => { self.data_ptr += 1; },
-            Command::DecData => { self.dec_data(); },
-            Command::IncData => { self.inc_data(); },
-            Command::Output => { self.output.push(self.data[self.data_ptr] as char); },
-            Command::Input => { todo!(); },
-            Command::NoOp => { },
-        }
+    if args.coverage {
+        eprint!("{}", profile::coverage(&machine));
     }
-
-    // Jump to point if zero under read head
-    fn jmp_eq(&mut self, i: usize) {
-        if self.data[self.data_ptr] == 0 {
-            self.prog_ctr = i;
+    if args.call_tree {
+        eprint!("{}", profile::call_tree(&machine));
+    }
+    if let Some(perf) = machine.perf {
+        eprint!("{}", profile::perf(&machine, if args.headless { elapsed } else { perf.executing }));
+    }
+    if let Some(path) = &args.flamegraph {
+        fs::write(path, profile::folded(&machine)).unwrap_or_else(|err| {
+            eprintln!("Failed to write {}: {}", path.display(), err);
+            process::exit(exit::FAILURE);
+        });
+    }
+    if let (Some(path), Some(coverage)) = (&args.lcov, machine.coverage()) {
+        let source = match &args.source.file {
+            Some(file) if file.as_os_str() != "-" => file.display().to_string(),
+            _ => "-".to_owned(),
+        };
+        fs::write(path, coverage.lcov(&source)).unwrap_or_else(|err| {
+            eprintln!("Failed to write {}: {}", path.display(), err);
+            process::exit(exit::FAILURE);
+        });
+    }
+    if let (Some(path), Some(log)) = (&args.record, recording) {
+        let input = mem::take(&mut *log.lock().unwrap());
+        replay::save(path, &replay::Replay {
+            semantics: machine.semantics.clone(), input, steps: machine.steps,
+        });
+    }
+    if let Some(replay) = &replay {
+        if args.headless && replay.steps != machine.steps {
+            eprintln!("Replay diverged: recorded {} steps, ran {}", replay.steps, machine.steps);
         }
     }
-
-    // Jump to point if nonzero under read head
-    fn jmp_ne(&mut self, i: usize) {
-        if self.data[self.data_ptr] != 0 {
-            self.prog_ctr = i;
+    let code = match &result {
+        Err(fault) => exit_code(&fault.error),
+        Ok(()) if args.exit_cell => machine.data[0] as u8 as i32,
+        Ok(()) => 0,
+    };
+    if args.json {
+        let mut report = json!({
+            "status": if result.is_ok() { "halted" } else { "runtime_error" },
+            "exit_code": code,
+            "steps": machine.steps,
+            "output": String::from_utf8_lossy(&machine.output),
+        });
+        if let Err(fault) = &result {
+            let (x, y) = fault.pos;
+            report["error"] = json!({
+                "message": fault.error.to_string(),
+                "line": y + 1,
+                "column": x + 1,
+                "data_ptr": fault.data_ptr,
+            });
         }
+        println!("{}", report);
+    } else if let Err(fault) = &result {
+        eprintln!("Runtime error at {}", fault);
     }
-
-    // Decrement the data cell; track last nonzero cell.
-    // TODO consider using a (slightly) more sophisticated data structure here.
-    fn dec_data(&mut self) {
-        self.data[self.data_ptr] -= 1;
-        if self.data[self.data_ptr] == 0 &&
-            self.data_ptr == self.last_data_cell {
-                let mut p = self.data_ptr;
-                while self.data[p] == 0 || p > 0 {
-                    p -= 1;
-                }
-                self.last_data_cell = p;
-            }
+    if let Some(path) = &args.dump_state_on_exit {
+        let state = serde_json::to_string_pretty(&state_json(&machine, &result)).unwrap();
+        fs::write(path, state + "\n").unwrap_or_else(|_| {
+            eprintln!("State dump failed!");
+            process::exit(exit::FAILURE);
+        });
     }
-
-    // Increment the data cell.
-    fn inc_data(&mut self) {
-        if self.data[self.data_ptr] == 0 &&
-            self.data_ptr > self.last_data_cell {
-                self.last_data_cell = self.data_ptr;
-            }
-        self.data[self.data_ptr] += 1;
+    let stopped = result.is_ok() && !machine.halted;
+    if stopped && source_changed.is_some_and(|changed| changed.load(Ordering::SeqCst)) {
+        *session = Some(machine);
     }
+    (code, stopped)
+}
 
-    // Returns a formatted data cell in decimal, hex, and ascii
-    // TODO This is pretty janky. I feel like I'm missing an abstraction here.
-    // Should I be using a custom formatter?
-    // TODO I'm not sure what the "right" place to put it is.
-    fn fmt_data_cell(&self, cell: usize) -> String {
-        let data = &self.data[cell];
-        let text = format!("{}{}{}",
-                           if self.display_spec.decimal {
-                               format!("{:03}", data)       // Decimal column
-                           } else {
-                               String::new()
-                           },
+// Machine state for post-mortem inspection or `state import`: a snapshot,
+// with how the run ended and where
+fn state_json(machine: &Machine, result: &Result<(), Fault>) -> serde_json::Value {
+    let mut state = serde_json::to_value(machine.snapshot()).unwrap();
+    state["version"] = json!(state::VERSION);
+    let (x, y) = machine.prog.positions.get(machine.prog_ctr).copied().unwrap_or_default();
+    state["status"] = json!(match result {
+        Err(_) => "runtime_error",
+        Ok(()) if machine.halted => "halted",
+        Ok(()) => "stopped",
+    });
+    state["line"] = json!(y + 1);
+    state["column"] = json!(x + 1);
+    if let Err(fault) = result {
+        state["error"] = json!(fault.error.to_string());
+    }
+    state
+}
 
-                           if self.display_spec.hex {      // Hex column
-                               format!(" 0x{:02x}", data)
-                           } else {
-                               String::new()
-                           },
+fn parse_error_json(err: &ParseError) -> serde_json::Value {
+    let (x, y) = err.pos();
+    json!({ "message": err.message(), "line": y + 1, "column": x + 1, "offset": err.offset() })
+}
 
-                           if self.display_spec.ascii {    // Ascii  column
-                               // Printable ascii: is there a better way to do this?
-                               // Also, this gets DEL wrong
-                               format!(" {:}", if *data >= 0x20 as u8 { (*data as char) }
-                                       else { ' ' })
-                           } else {
-                               String::new()
-                           },
-        );
-        if cell == self.data_ptr {
-            format!("{}{}{}",
-                    color::Bg(color::Blue),
-                    text,
-                    color::Bg(color::Reset)
-            )
+// Parse a program, or report the error and exit
+fn parse_or_exit(program: &str, dialect: Option<Dialect>, json: bool) -> Program {
+    parse_dialect(program, dialect.unwrap_or_default(), |_| false).unwrap_or_else(|err| {
+        if json {
+            println!("{}", json!({ "ok": false, "errors": [parse_error_json(&err)] }));
         } else {
-            text
+            eprintln!("{}", err);
         }
-    }
+        process::exit(exit::PARSE);
+    })
+}
 
-    // Returns a formatted line of source code with read-head highlighting
-    fn fmt_src_line(&self, linum: usize) -> String {
-        let (pos_x, pos_y) = self.prog[self.prog_ctr].pos;
-        if linum == pos_y {
-            let (head, tail) = &self.prog_src[linum].split_at(pos_x);
-            let (ch, tail) = if tail.len() > 0 {
-                tail.split_at(1)
-            } else {
-                ("", "")
-            };
-            format!("{}{}{}{}{}",
-                    head,
-                    color::Bg(color::Blue),
-                    ch,
-                    color::Bg(color::Reset),
-                    tail)
-        } else {
-            self.prog_src[linum].clone()
-        }
+// Check that a program parses, without running it
+fn check(args: CheckArgs) {
+    parse_or_exit(&load_program(&args.source), args.dialect, args.json);
+    if args.json {
+        println!("{}", json!({ "ok": true, "errors": [] }));
+    } else {
+        println!("ok");
     }
 }
 
-impl fmt::Display for Machine {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let data_col_width = self.fmt_data_cell(0).len();
-        let repr = (0..=std::cmp::max(self.last_data_cell, self.data_ptr))  // Data column
-            // TODO should I put the `fmt_data_cell` here, or in the `match cols` below?
-            // .map(|x| self.fmt_data_cell(x))  // Format the left-hand column
-            .zip_longest(0..self.prog_src.len())     // Zip with source column
-            .map(|cols| {                            // Join the columns
-                match cols {
-                    EitherOrBoth::Both(cell, src) => {
-                        format!("{} {}\r\n", self.fmt_data_cell(cell),
-                                self.fmt_src_line(src))
-                    }
-                    EitherOrBoth::Left(cell) => {
-                        format!("{}\r\n", self.fmt_data_cell(cell))
-                    },
-                    EitherOrBoth::Right(src) => {
-                        format!("           {}\r\n",  // TODO this is a bug
-                                self.fmt_src_line(src))
-                                //width = data_col_width + 1)
-                    },
-                }
-            })
-            .collect::<String>();
+// Summarize a program's structure
+fn stats(args: StatsArgs) {
+    let program = load_program(&args.source);
+    let stats = Stats::new(&program, &parse_or_exit(&program, args.dialect, args.json));
+    if args.json {
+        println!("{}", serde_json::to_string(&stats).unwrap());
+    } else {
+        print!("{}", stats);
+    }
+}
 
-        write!(f, "{}\r\n{}\r\n{}",      // The output line
-               color::Fg(color::Green),
-               self.output,
-               color::Fg(color::Reset),
-        );
-        write!(f, "{}", repr)            // The memory and source
+// Run each program headlessly, feeding its output to the next
+fn pipe(args: PipeArgs) {
+    let (_, semantics) = configure(&args.semantics);
+    let mut input = open_input(&args.io.input);
+    for (i, path) in args.files.iter().enumerate() {
+        let program = read_program(path);
+        let mut builder = Machine::builder()
+            .semantics(semantics.clone())
+            .input(input)
+            .limits(args.limits.to_limits());
+        if i + 1 == args.files.len() {
+            if let Some(sink) = open_output(&args.io, true) {
+                builder = builder.output(sink);
+            }
+        }
+        let mut machine = builder.build(program)
+            .unwrap_or_else(|err| {
+                eprintln!("{}: Failed to parse program: {}", path.display(), err);
+                process::exit(exit::PARSE);
+            });
+        if let Err(err) = machine.run() {
+            let fault = machine.fault(err);
+            eprintln!("{}: Runtime error at {}", path.display(), fault);
+            process::exit(exit_code(&fault.error));
+        }
+        flush_output(&mut machine);
+        input = Box::new(io::Cursor::new(mem::take(&mut machine.output)));
     }
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let script: &str = &args[1];
-
-    let program = fs::read_to_string(path::Path::new(script))
-        .unwrap_or_else(|_| {
-            eprintln!("File read failed!");
-            process::exit(1);
-        });
-    let mut machine = Machine::new(program)
-        .unwrap_or_else(|_| {
-            eprintln!("Failed to parse program!");
-            process::exit(2);
-        });
-    machine.run();
+    let cli = Cli::try_parse().unwrap_or_else(|err| {
+        let _ = err.print();
+        process::exit(if err.use_stderr() { exit::USAGE } else { 0 });
+    });
+    match cli.into_command() {
+        Cmd::Run(args) | Cmd::Debug(args) => run(*args),
+        Cmd::Pipe(args) => pipe(args),
+        Cmd::RunAll(args) => batch::run_all(args),
+        Cmd::Compare(args) => compare::compare(args),
+        Cmd::Test(args) => suite::test(args),
+        Cmd::Repl(args) => repl::repl(args),
+        Cmd::Dap(args) => dap::dap(args),
+        Cmd::Check(args) => check(args),
+        Cmd::Stats(args) => stats(args),
+        Cmd::Bench(args) => bench::bench(args),
+        #[cfg(feature = "render")]
+        Cmd::Render(args) => render::render(args),
+        Cmd::State(command) => state::state(command),
+        Cmd::Completions { shell } => {
+            clap_complete::generate(shell, &mut Cli::command(), "brainrust", &mut stdout());
+        },
+    }
 }
+
@@ -0,0 +1,446 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::condition::Condition;
+
+// Commands known to the VM. Jumps hold the index of the matching bracket,
+// and the ends of a procedure that of the other end.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    JumpForward(u32),
+    JumpBackward(u32),
+    DecPtr,
+    IncPtr,
+    DecData,
+    IncData,
+    Input,
+    Output,
+    Extension(char),  // A character the embedder gave a handler
+    Assert(u32),  // From an `{assert COND}` comment; indexes the conditions
+    ProcStart(u32),  // pbrain's `(`
+    ProcEnd(u32),    // pbrain's `)`
+    Call,            // pbrain's `:`
+}
+
+// The language a program is written in. pbrain adds procedures to
+// brainfuck: `(` defines the one numbered by the cell under the pointer as
+// everything up to its `)`, and `:` calls the one the cell numbers. Plain
+// brainfuck takes all three for comments.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(rename_all = "kebab-case"))]
+pub enum Dialect {
+    #[default]
+    Brainfuck,
+    Pbrain,
+}
+
+// A parsed program. The commands the machine executes are packed on their
+// own, and what the debuggers show of each is kept beside them, by index.
+// Comments aren't instructions, so an instruction's position is all that
+// places it in the source.
+#[derive(Default)]
+pub struct Program {
+    pub commands: Vec<Command>,
+    pub chars: Vec<char>,
+    pub positions: Vec<(usize, usize)>,  // Screen positions
+    pub conditions: Vec<Condition>,      // Of the assertions
+    pub dialect: Dialect,                // It was parsed as
+}
+
+impl Program {
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    fn push(&mut self, command: Command, ch: char, pos: (usize, usize)) {
+        self.commands.push(command);
+        self.chars.push(ch);
+        self.positions.push(pos);
+    }
+
+    // Whether each bracket, and each end of a procedure, holds the index of
+    // its partner, which holds the index of it back. The machine jumps
+    // straight to the partner without checking, so a parsed program always
+    // passes.
+    pub fn jumps_match(&self) -> bool {
+        let partner = |i: usize| match self.commands[i] {
+            Command::JumpForward(j) => Some((j as usize, true, false)),
+            Command::JumpBackward(j) => Some((j as usize, false, false)),
+            Command::ProcStart(j) => Some((j as usize, true, true)),
+            Command::ProcEnd(j) => Some((j as usize, false, true)),
+            _ => None,
+        };
+        (0..self.len()).all(|i| match partner(i) {
+            Some((j, forward, proc)) => forward == (i < j)
+                && j < self.len()
+                && partner(j).is_some_and(|(back, _, other)| back == i && other == proc),
+            None => true,
+        })
+    }
+
+    // The index the next instruction will have
+    fn next_index(&self) -> u32 {
+        u32::try_from(self.len()).expect("programs have fewer than 2^32 instructions")
+    }
+}
+
+// Errors found while parsing. Positions are 0-based (column, line) pairs.
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error("{}:{}: unmatched `[`", pos.1 + 1, pos.0 + 1)]
+    UnmatchedOpen { offset: usize, pos: (usize, usize) },
+    #[error("{}:{}: unmatched `]`", pos.1 + 1, pos.0 + 1)]
+    UnmatchedClose { offset: usize, pos: (usize, usize) },
+    #[error("{}:{}: unmatched `(`", pos.1 + 1, pos.0 + 1)]
+    UnmatchedProcStart { offset: usize, pos: (usize, usize) },
+    #[error("{}:{}: unmatched `)`", pos.1 + 1, pos.0 + 1)]
+    UnmatchedProcEnd { offset: usize, pos: (usize, usize) },
+    #[error("{}:{}: bad assertion: {message}", pos.1 + 1, pos.0 + 1)]
+    BadAssertion { offset: usize, pos: (usize, usize), message: &'static str },
+}
+
+impl ParseError {
+    // Character offset of the offending bracket
+    pub fn offset(&self) -> usize {
+        match self {
+            ParseError::UnmatchedOpen { offset, .. } => *offset,
+            ParseError::UnmatchedClose { offset, .. } => *offset,
+            ParseError::UnmatchedProcStart { offset, .. } => *offset,
+            ParseError::UnmatchedProcEnd { offset, .. } => *offset,
+            ParseError::BadAssertion { offset, .. } => *offset,
+        }
+    }
+
+    pub fn pos(&self) -> (usize, usize) {
+        match self {
+            ParseError::UnmatchedOpen { pos, .. } => *pos,
+            ParseError::UnmatchedClose { pos, .. } => *pos,
+            ParseError::UnmatchedProcStart { pos, .. } => *pos,
+            ParseError::UnmatchedProcEnd { pos, .. } => *pos,
+            ParseError::BadAssertion { pos, .. } => *pos,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            ParseError::UnmatchedOpen { .. } => "unmatched `[`",
+            ParseError::UnmatchedClose { .. } => "unmatched `]`",
+            ParseError::UnmatchedProcStart { .. } => "unmatched `(`",
+            ParseError::UnmatchedProcEnd { .. } => "unmatched `)`",
+            ParseError::BadAssertion { message, .. } => message,
+        }
+    }
+}
+
+// Transform source text into a sequence of instructions
+pub fn parse(src: &str) -> Result<Program, ParseError> {
+    parse_with_extensions(src, |_| false)
+}
+
+// Parse, making the comment characters for which `extension` holds into
+// extension instructions, except in a `#!` line or an assertion. Other
+// comment characters are left out, so jumps and every other index into the
+// program count only instructions.
+pub fn parse_with_extensions(src: &str, extension: impl Fn(char) -> bool)
+                             -> Result<Program, ParseError> {
+    parse_dialect(src, Dialect::Brainfuck, extension)
+}
+
+// Parse as `parse_with_extensions` does, in the given dialect. Brackets and
+// procedures have to nest inside one another. The `:` of an `@label:`
+// comment stays a comment.
+pub fn parse_dialect(src: &str, dialect: Dialect, extension: impl Fn(char) -> bool)
+                     -> Result<Program, ParseError> {
+    let mut prog = Program { dialect, ..Program::default() };
+    // Instruction index, character offset and position of each open `[`,
+    // and `(`
+    let mut brack_stack: Vec<(u32, usize, (usize, usize))> = Vec::new();
+
+    // A leading `#!` line is an interpreter directive, not code
+    let shebang_len = if src.starts_with("#!") {
+        src.find('\n').unwrap_or(src.len())
+    } else {
+        0
+    };
+
+    // The byte offset of the end of the assertion being read, whose text is
+    // all comment
+    let mut assert_end: Option<usize> = None;
+
+    // Positions and character offsets are counted along the way; `at` is
+    // the byte offset
+    let (mut pos_x, mut pos_y): (usize, usize) = (0, 0);
+    for (i, (at, ch)) in src.char_indices().enumerate() {
+        let pos = (pos_x, pos_y);
+        if ch == '\n' {
+            pos_x = 0;
+            pos_y += 1;
+        } else {
+            pos_x += 1;
+        }
+        let command = match ch {
+            _ if at < shebang_len => continue,
+            _ if assert_end.is_some_and(|end| at <= end) => continue,
+            '{' if is_assertion(&src[at..]) => {
+                let (end, condition) = assertion(src, at, i, pos)?;
+                assert_end = Some(end);
+                prog.conditions.push(condition);
+                Command::Assert((prog.conditions.len() - 1) as u32)
+            },
+            '[' => {
+                brack_stack.push((prog.next_index(), i, pos));
+                // Patched with the index of the matching `]` once it's found
+                Command::JumpForward(0)
+            },
+            ']' => {
+                let (match_index, _, _) = brack_stack.pop()
+                    .filter(|&(open, _, _)| matches!(prog.commands[open as usize], Command::JumpForward(_)))
+                    .ok_or(ParseError::UnmatchedClose { offset: i, pos })?;
+                prog.commands[match_index as usize] = Command::JumpForward(prog.next_index());
+                Command::JumpBackward(match_index)
+            },
+            '(' if dialect == Dialect::Pbrain => {
+                brack_stack.push((prog.next_index(), i, pos));
+                Command::ProcStart(0)  // Patched like a `[`
+            },
+            ')' if dialect == Dialect::Pbrain => {
+                let (match_index, _, _) = brack_stack.pop()
+                    .filter(|&(open, _, _)| matches!(prog.commands[open as usize], Command::ProcStart(_)))
+                    .ok_or(ParseError::UnmatchedProcEnd { offset: i, pos })?;
+                prog.commands[match_index as usize] = Command::ProcStart(prog.next_index());
+                Command::ProcEnd(match_index)
+            },
+            _ if in_label(src, at) => continue,
+            _ => match command(ch, dialect, &extension) {
+                Some(command) => command,
+                None => continue,
+            },
+        };
+
+        prog.push(command, ch, pos);
+    }
+
+    if let Some(&(open, offset, pos)) = brack_stack.last() {
+        return Err(match prog.commands[open as usize] {
+            Command::ProcStart(_) => ParseError::UnmatchedProcStart { offset, pos },
+            _ => ParseError::UnmatchedOpen { offset, pos },
+        });
+    }
+    debug_assert!(prog.jumps_match());
+    Ok(prog)
+}
+
+// The instruction `ch` stands for on its own, if any: anything but a
+// bracket, an end of a procedure or an assertion
+fn command(ch: char, dialect: Dialect, extension: &impl Fn(char) -> bool) -> Option<Command> {
+    match ch {
+        '<' => Some(Command::DecPtr),
+        '>' => Some(Command::IncPtr),
+        '-' => Some(Command::DecData),
+        '+' => Some(Command::IncData),
+        '.' => Some(Command::Output),
+        ',' => Some(Command::Input),
+        ':' if dialect == Dialect::Pbrain => Some(Command::Call),
+        _ if ch != '\n' && extension(ch) => Some(Command::Extension(ch)),
+        _ => None,
+    }
+}
+
+// Whether the character at byte offset `at` ends the tag of an `@label:`
+// comment
+fn in_label(src: &str, at: usize) -> bool {
+    src[at..].starts_with(':') && src[..at + 1].ends_with(LABEL_TAG)
+}
+
+// How the instructions of a program correspond to those of an edit of it:
+// the first `kept` are the same, the next `removed` were replaced by `added`
+// new ones, and the rest are the same but moved along
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Remap {
+    pub kept: usize,
+    pub removed: usize,
+    pub added: usize,
+}
+
+impl Remap {
+    // The new index of the old instruction at `index`, unless it was edited
+    pub fn index(&self, index: usize) -> Option<usize> {
+        if index < self.kept {
+            Some(index)
+        } else if index < self.kept + self.removed {
+            None
+        } else {
+            Some(index - self.removed + self.added)
+        }
+    }
+}
+
+// Parse `new`, an edit of the source `old` that parsed to `prog`, along with
+// how the instructions moved. Only the lines between those the two have in
+// common at the start and the end are parsed; the instructions on the rest
+// are taken from `prog`, and then the brackets are matched again. Where an
+// assertion or a `#!` line could change how the lines around an edit read,
+// `new` is parsed whole. `extension` should be what `prog` was parsed with;
+// `new` is parsed in the same dialect.
+pub fn reparse_with_extensions(prog: &Program, old: &str, new: &str, extension: impl Fn(char) -> bool)
+                               -> Result<(Program, Remap), ParseError> {
+    let old_lines: Vec<&str> = old.split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new.split_inclusive('\n').collect();
+    let head = old_lines.iter().zip(&new_lines).take_while(|(a, b)| a == b).count();
+    let tail = old_lines[head..].iter().rev()
+        .zip(new_lines[head..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (old_tail, new_tail) = (old_lines.len() - tail, new_lines.len() - tail);  // First lines of the tails
+    let lines_before = |prog: &Program, line| prog.positions.partition_point(|&(_, y)| y < line);
+    let kept = lines_before(prog, head);
+    let moved = prog.len() - lines_before(prog, old_tail);
+
+    let shebang = head == 0 && (old.starts_with("#!") || new.starts_with("#!"));
+    if !shebang && !old.contains(ASSERT_TAG) && !new.contains(ASSERT_TAG) {
+        let start: usize = new_lines[..head].iter().map(|line| line.len()).sum();
+        let end: usize = new.len() - new_lines[new_tail..].iter().map(|line| line.len()).sum::<usize>();
+        let mut edited = Program { dialect: prog.dialect, ..Program::default() };
+        edited.commands.extend_from_slice(&prog.commands[..kept]);
+        edited.chars.extend_from_slice(&prog.chars[..kept]);
+        edited.positions.extend_from_slice(&prog.positions[..kept]);
+        let (mut pos_x, mut pos_y) = (0, head);
+        let dialect = prog.dialect;
+        for (at, ch) in new[start..end].char_indices() {
+            let pos = (pos_x, pos_y);
+            if ch == '\n' {
+                pos_x = 0;
+                pos_y += 1;
+            } else {
+                pos_x += 1;
+            }
+            let command = match ch {
+                '[' => Command::JumpForward(0),
+                ']' => Command::JumpBackward(0),
+                '(' if dialect == Dialect::Pbrain => Command::ProcStart(0),
+                ')' if dialect == Dialect::Pbrain => Command::ProcEnd(0),
+                _ if in_label(new, start + at) => continue,
+                _ => match command(ch, dialect, &extension) {
+                    Some(command) => command,
+                    None => continue,
+                },
+            };
+            edited.push(command, ch, pos);
+        }
+        let added = edited.len() - kept;
+        let rest = prog.len() - moved;
+        edited.commands.extend_from_slice(&prog.commands[rest..]);
+        edited.chars.extend_from_slice(&prog.chars[rest..]);
+        edited.positions.extend(prog.positions[rest..].iter().map(|&(x, y)| (x, y - old_tail + new_tail)));
+        // Unmatched brackets are left for the full parse to report
+        if match_brackets(&mut edited.commands) {
+            debug_assert!(edited.jumps_match());
+            return Ok((edited, Remap { kept, removed: prog.len() - kept - moved, added }));
+        }
+    }
+
+    let edited = parse_dialect(new, prog.dialect, extension)?;
+    let remap = if lines_before(&edited, head) == kept && edited.len() - lines_before(&edited, new_tail) == moved {
+        Remap { kept, removed: prog.len() - kept - moved, added: edited.len() - kept - moved }
+    } else {
+        Remap { kept: 0, removed: prog.len(), added: edited.len() }
+    };
+    Ok((edited, remap))
+}
+
+// Point each bracket, and each end of a procedure, at its match, returning
+// whether they all have one
+fn match_brackets(commands: &mut [Command]) -> bool {
+    let mut opens = Vec::new();
+    for i in 0..commands.len() {
+        match commands[i] {
+            Command::JumpForward(_) | Command::ProcStart(_) => opens.push(i),
+            Command::JumpBackward(_) => {
+                let Some(open) = opens.pop().filter(|&open| matches!(commands[open], Command::JumpForward(_))) else {
+                    return false;
+                };
+                commands[open] = Command::JumpForward(i as u32);
+                commands[i] = Command::JumpBackward(open as u32);
+            },
+            Command::ProcEnd(_) => {
+                let Some(open) = opens.pop().filter(|&open| matches!(commands[open], Command::ProcStart(_))) else {
+                    return false;
+                };
+                commands[open] = Command::ProcStart(i as u32);
+                commands[i] = Command::ProcEnd(open as u32);
+            },
+            _ => {},
+        }
+    }
+    opens.is_empty()
+}
+
+const ASSERT_TAG: &str = "{assert";
+const LABEL_TAG: &str = "@label:";
+
+// Does an `{assert COND}` comment start here?
+fn is_assertion(src: &str) -> bool {
+    src.starts_with(ASSERT_TAG) && src[ASSERT_TAG.len()..].starts_with(char::is_whitespace)
+}
+
+// Read the assertion whose `{` is at byte offset `start`, character offset
+// `offset` and `pos`, returning the byte offset of its `}` and its condition
+fn assertion(src: &str, start: usize, offset: usize, pos: (usize, usize))
+             -> Result<(usize, Condition), ParseError> {
+    let body = start + ASSERT_TAG.len();
+    let end = src[body..].find('}').map(|len| body + len).ok_or(
+        ParseError::BadAssertion { offset, pos, message: "no closing `}`" }
+    )?;
+    let condition = Condition::parse(&src[body..end]).map_err(|err| {
+        let before = &src[start..body + err.offset];
+        ParseError::BadAssertion {
+            offset: offset + before.chars().count(), pos: pos_after(pos, before), message: err.message,
+        }
+    })?;
+    Ok((end, condition))
+}
+
+// The screen position reached from `pos` by the text `src`
+fn pos_after((mut x, mut y): (usize, usize), src: &str) -> (usize, usize) {
+    for ch in src.chars() {
+        if ch == '\n' {
+            x = 0;
+            y += 1;
+        } else {
+            x += 1;
+        }
+    }
+    (x, y)
+}
+
+// `@label:NAME` annotations in the comments of `src`, each naming the first
+// instruction of `prog` after it, in source order. Names are letters, digits
+// and `_`. A label with no instruction after it names nothing.
+pub fn labels(src: &str, prog: &Program) -> Vec<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut pos = (0, 0);
+    let mut last = 0;  // Byte offset `pos` was reached at
+    for (start, _) in src.match_indices(LABEL_TAG) {
+        pos = pos_after(pos, &src[last..start]);
+        last = start;
+        let tail = &src[start + LABEL_TAG.len()..];
+        let name_len = tail.find(|ch: char| !(ch.is_alphanumeric() || ch == '_')).unwrap_or(tail.len());
+        let name = &tail[..name_len];
+        let end = pos_after(pos, &src[start..start + LABEL_TAG.len() + name_len]);
+        let target = prog.positions.iter().position(|&(x, y)| (y, x) >= (end.1, end.0));
+        if let (false, Some(target)) = (name.is_empty(), target) {
+            labels.push((String::from(name), target));
+        }
+    }
+    labels
+}
@@ -0,0 +1,318 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs::File;
+use std::io::{self, BufWriter, Write as _};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use serde_json::json;
+
+use brainrust::{Command, Machine, MemoryUsage};
+use brainrust::tape::PAGE_LEN;
+
+// Number of entries in each section of the report
+const REPORT_LEN: usize = 10;
+// Longest source excerpt shown for a loop
+const EXCERPT_LEN: usize = 40;
+
+// Render the procedures a pbrain program called, each under its caller
+pub fn call_tree(machine: &Machine) -> String {
+    let tree = match &machine.call_tree {
+        Some(tree) => tree,
+        None => return String::new(),
+    };
+    let mut out = String::new();
+    writeln!(out, "Call tree:").unwrap();
+    for line in tree.lines(&machine.calls, machine.steps) {
+        writeln!(out, "{}", line).unwrap();
+    }
+    out
+}
+
+// Render a ranked hot-spot report from the machine's execution counts
+pub fn report(machine: &Machine) -> String {
+    let counts = match &machine.profile {
+        Some(counts) => counts,
+        None => return String::new(),
+    };
+    let total: u64 = counts.iter().sum();
+    let mut out = String::new();
+    writeln!(out, "Profile: {} steps", total).unwrap();
+
+    // A loop's cost includes everything nested inside it. Each execution of
+    // its `]` ends one iteration.
+    let mut loops: Vec<(usize, usize, u64)> = machine.prog.commands.iter()
+        .enumerate()
+        .filter_map(|(start, command)| match *command {
+            Command::JumpForward(end) if end as usize > start => {
+                let end = end as usize;
+                Some((start, end, counts[start..=end].iter().sum()))
+            },
+            _ => None,
+        })
+        .collect();
+    loops.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+    writeln!(out, "\nHottest loops:").unwrap();
+    for (rank, &(start, end, steps)) in loops.iter().take(REPORT_LEN).enumerate() {
+        writeln!(out, "{:>3}. {:>8}  {:>12} steps {:>6.2}%  {:>10} iterations  {}",
+                 rank + 1,
+                 position(machine, start),
+                 steps,
+                 percent(steps, total),
+                 counts[end],
+                 excerpt(machine, start, end)).unwrap();
+    }
+
+    // Each time its `[` runs, a loop runs zero or more iterations
+    loops.sort_by(|a, b| counts[b.1].cmp(&counts[a.1]).then(a.0.cmp(&b.0)));
+    writeln!(out, "\nMost iterated loops:").unwrap();
+    for (rank, &(start, end, _)) in loops.iter().take(REPORT_LEN).enumerate() {
+        writeln!(out, "{:>3}. {:>8}  {:>12} iterations  {:>10} runs {:>12.1} per run  {}",
+                 rank + 1,
+                 position(machine, start),
+                 counts[end],
+                 counts[start],
+                 per_run(counts[end], counts[start]),
+                 excerpt(machine, start, end)).unwrap();
+    }
+
+    let mut spots: Vec<usize> = (0..counts.len()).filter(|&i| counts[i] > 0).collect();
+    spots.sort_by(|&a, &b| counts[b].cmp(&counts[a]).then(a.cmp(&b)));
+    writeln!(out, "\nHottest instructions:").unwrap();
+    for (rank, &i) in spots.iter().take(REPORT_LEN).enumerate() {
+        writeln!(out, "{:>3}. {:>8}  {:>12} steps {:>6.2}%  {}",
+                 rank + 1,
+                 position(machine, i),
+                 counts[i],
+                 percent(counts[i], total),
+                 machine.prog.chars[i]).unwrap();
+    }
+    out
+}
+
+// Render the source annotated gcov-style: each line with instructions gets
+// its hit count, `#####` if it never ran, and a `*` if only some of its
+// instructions did
+pub fn coverage(machine: &Machine) -> String {
+    let coverage = match machine.coverage() {
+        Some(coverage) => coverage,
+        None => return String::new(),
+    };
+    let mut out = String::new();
+    writeln!(out, "Coverage: {} of {} instructions ({:.2}%)",
+             coverage.covered(), coverage.instructions(), coverage.percent()).unwrap();
+    for (i, src) in machine.src_lines().enumerate() {
+        let count = match coverage.line(i + 1) {
+            None => "-".to_owned(),
+            Some(line) if line.covered == 0 => "#####".to_owned(),
+            Some(line) if line.covered < line.instructions => format!("{}*", line.hits),
+            Some(line) => line.hits.to_string(),
+        };
+        writeln!(out, "{:>10} {:>5}: {}", count, i + 1, src).unwrap();
+    }
+    out
+}
+
+// Steps grouped by the loops open around them, as folded stacks for inferno
+// or flamegraph.pl: one `program;OUTER;INNER STEPS` line per stack, with
+// each loop named by its label or its position. A loop's brackets count
+// as inside it.
+pub fn folded(machine: &Machine) -> String {
+    let counts = match &machine.profile {
+        Some(counts) => counts,
+        None => return String::new(),
+    };
+    let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+    let mut stack = vec!["program".to_owned()];
+    for (i, &command) in machine.prog.commands.iter().enumerate() {
+        if let Command::JumpForward(end) = command {
+            if end as usize > i {
+                stack.push(loop_name(machine, i));
+            }
+        }
+        if counts[i] > 0 {
+            *stacks.entry(stack.join(";")).or_default() += counts[i];
+        }
+        if let Command::JumpBackward(start) = command {
+            if (start as usize) < i {
+                stack.pop();
+            }
+        }
+    }
+    let mut out = String::new();
+    for (stack, steps) in stacks {
+        writeln!(out, "{} {}", stack, steps).unwrap();
+    }
+    out
+}
+
+// Loops as nested duration events in Chrome's trace event format, for
+// chrome://tracing or Perfetto, inside one event for the whole run. Time is
+// counted in steps, each shown as a microsecond. Events are written as the
+// loops enter and exit, so the run is never held in memory.
+pub struct ChromeTrace {
+    writer: Arc<Mutex<TraceWriter>>,
+}
+
+struct TraceWriter {
+    out: BufWriter<File>,
+    names: BTreeMap<usize, String>,  // By the index of the loop's `[`
+    open: Vec<usize>,                // Loops entered and not yet exited
+    failed: Option<io::Error>,       // The first write to fail
+}
+
+impl TraceWriter {
+    // Write an event, beginning (`B`) or ending (`E`) the loop at `i`, or
+    // the whole run for `None`
+    fn event(&mut self, phase: &str, i: Option<usize>, steps: u64) {
+        let (name, category) = match i {
+            Some(i) => (self.names[&i].as_str(), "loop"),
+            None => ("program", "run"),
+        };
+        let mut event = json!({ "name": name, "cat": category, "ph": phase, "ts": steps, "pid": 1, "tid": 1 });
+        if let (Some(i), "B") = (i, phase) {
+            event["args"] = json!({ "index": i });
+        }
+        let result = writeln!(self.out, ",").and_then(|()| Ok(serde_json::to_writer(&mut self.out, &event)?));
+        if let Err(err) = result {
+            self.failed.get_or_insert(err);
+        }
+    }
+}
+
+impl ChromeTrace {
+    // Start tracing the machine's loops to `file`
+    pub fn attach(machine: &mut Machine, file: File) -> io::Result<ChromeTrace> {
+        let names = machine.prog.commands.iter().enumerate()
+            .filter(|&(_, command)| matches!(command, Command::JumpForward(_)))
+            .map(|(i, _)| (i, loop_name(machine, i)))
+            .collect();
+        let mut out = BufWriter::new(file);
+        // The run's own event comes first, so every later one can follow a comma
+        let start = json!({ "name": "program", "cat": "run", "ph": "B", "ts": machine.steps, "pid": 1, "tid": 1 });
+        write!(out, "[")?;
+        serde_json::to_writer(&mut out, &start)?;
+        let writer = Arc::new(Mutex::new(TraceWriter { out, names, open: Vec::new(), failed: None }));
+
+        // The loop hooks run during a step, before it is counted here
+        let steps = Arc::new(AtomicU64::new(machine.steps));
+        let counter = steps.clone();
+        machine.on_step(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        let (entered, counter) = (writer.clone(), steps.clone());
+        machine.on_loop_enter(move |i| {
+            let mut writer = entered.lock().unwrap();
+            writer.open.push(i);
+            writer.event("B", Some(i), counter.load(Ordering::Relaxed));
+        });
+        let exited = writer.clone();
+        machine.on_loop_exit(move |i| {
+            let mut writer = exited.lock().unwrap();
+            writer.open.pop();
+            writer.event("E", Some(i), steps.load(Ordering::Relaxed) + 1);
+        });
+        Ok(ChromeTrace { writer })
+    }
+
+    // End the loops still running, as they are when a run fails, and the
+    // run itself, then finish the file
+    pub fn finish(self, machine: &Machine) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        while let Some(i) = writer.open.pop() {
+            writer.event("E", Some(i), machine.steps);
+        }
+        writer.event("E", None, machine.steps);
+        if let Some(err) = writer.failed.take() {
+            return Err(err);
+        }
+        writeln!(writer.out, "]")?;
+        writer.out.flush()
+    }
+}
+
+// Render the machine's performance counters, given how long it spent
+// executing. In the debugger, that is only while carrying out actions.
+pub fn perf(machine: &Machine, executing: Duration) -> String {
+    let perf = match &machine.perf {
+        Some(perf) => perf,
+        None => return String::new(),
+    };
+    let secs = executing.as_secs_f64();
+    let mut out = String::new();
+    writeln!(out, "Perf: {} instructions in {:.3} s, {:.1}M per second",
+             machine.steps, secs, if secs > 0.0 { machine.steps as f64 / secs / 1e6 } else { 0.0 }).unwrap();
+    writeln!(out, "Loops: {} entered, {} skipped, {} repeated, {} exited",
+             perf.loops_entered, perf.loops_skipped, perf.loops_repeated, perf.loops_exited).unwrap();
+    writeln!(out, "Tape pages allocated: {} ({} cells each)", machine.data.pages(), PAGE_LEN).unwrap();
+    writeln!(out, "{}", memory(&machine.memory())).unwrap();
+    if perf.frames > 0 {
+        writeln!(out, "Debugger: {} frames drawn in {:.3} s, {:.2} ms each",
+                 perf.frames, perf.drawing.as_secs_f64(),
+                 perf.drawing.as_secs_f64() * 1e3 / perf.frames as f64).unwrap();
+    }
+    out
+}
+
+// A one-line breakdown of memory use
+pub fn memory(usage: &MemoryUsage) -> String {
+    format!("Memory: {} in all; tape {}, output {}, tracking {}, history {}, traces {}",
+            size(usage.total()), size(usage.tape), size(usage.output), size(usage.tracking),
+            size(usage.history), size(usage.traces))
+}
+
+// A byte count in the largest unit that keeps it at 1 or more
+fn size(bytes: usize) -> String {
+    match bytes {
+        0..=1023 => format!("{} B", bytes),
+        1024..=0xf_ffff => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / (1 << 20) as f64),
+    }
+}
+
+// A loop's label, or else where its `[` is
+fn loop_name(machine: &Machine, i: usize) -> String {
+    match machine.label_at(i) {
+        Some(label) => format!("@{}", label),
+        None => format!("loop {}", position(machine, i)),
+    }
+}
+
+// 1-based `line:col` of an instruction
+fn position(machine: &Machine, i: usize) -> String {
+    let (x, y) = machine.prog.positions[i];
+    format!("{}:{}", y + 1, x + 1)
+}
+
+// A loop's source without whitespace, shortened to fit
+fn excerpt(machine: &Machine, start: usize, end: usize) -> String {
+    let excerpt: String = machine.prog.chars[start..=end].iter()
+        .filter(|ch| !ch.is_whitespace())
+        .collect();
+    truncate(&excerpt)
+}
+
+fn per_run(iterations: u64, runs: u64) -> f64 {
+    if runs == 0 {
+        0.0
+    } else {
+        iterations as f64 / runs as f64
+    }
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        100.0 * part as f64 / total as f64
+    }
+}
+
+fn truncate(excerpt: &str) -> String {
+    if excerpt.chars().count() <= EXCERPT_LEN {
+        excerpt.to_owned()
+    } else {
+        excerpt.chars().take(EXCERPT_LEN - 3).chain("...".chars()).collect()
+    }
+}
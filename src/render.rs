@@ -0,0 +1,220 @@
+use std::fs;
+use std::io::BufWriter;
+use std::path::Path;
+use std::process;
+
+use brainrust::{Machine, Program};
+
+use crate::cli::RenderArgs;
+use crate::{configure, exit, exit_code, load_program, open_input};
+
+// Frame layout, in characters of a 3x5 pixel font, each drawn `SCALE` times
+// over with a gap to its right and below
+const SCALE: usize = 2;
+const CHAR_W: usize = 4 * SCALE;
+const CHAR_H: usize = 7 * SCALE;
+const COLUMNS: usize = 70;      // Of source, and of the tape row
+const SOURCE_ROWS: usize = 8;
+const INDEX_DIGITS: usize = 5;  // Of the first cell shown
+const WIDTH: usize = 2 * CHAR_W + COLUMNS * CHAR_W;
+// Source, a blank row, the tape, a blank row and the step count
+const HEIGHT: usize = 2 * CHAR_H + (SOURCE_ROWS + 4) * CHAR_H;
+// How long the last frame stays up before the animation loops, in
+// hundredths of a second
+const HOLD: u16 = 200;
+
+// Colors, by palette index
+const BACKGROUND: u8 = 0;
+const TEXT: u8 = 1;
+const DIM: u8 = 2;
+const NEXT: u8 = 3;     // Behind the next instruction
+const POINTER: u8 = 4;  // Behind the cell under the pointer
+const PALETTE: [u8; 24] = [
+    0x1e, 0x1e, 0x2e,
+    0xcd, 0xd6, 0xf4,
+    0x6c, 0x70, 0x86,
+    0xf9, 0xe2, 0xaf,
+    0x89, 0xb4, 0xfa,
+    0, 0, 0,
+    0, 0, 0,
+    0, 0, 0,
+];
+
+// What a frame shows of the machine
+struct View {
+    prog_ctr: usize,
+    data_ptr: usize,
+    first_cell: usize,
+    cells: Vec<u32>,
+    steps: u64,
+}
+
+// Run a program headlessly, keeping a view of it every `--stride` steps,
+// then draw them as an animation: a GIF, or an APNG for a `.png` or `.apng`
+// file. Exits as a headless run would, after saving what it got to.
+pub fn render(args: RenderArgs) {
+    let (_, semantics) = configure(&args.semantics);
+    let digits = semantics.cell_size.max().to_string().len();
+    let per_row = (COLUMNS - INDEX_DIGITS - 1) / (digits + 1);
+    let mut machine = Machine::builder()
+        .semantics(semantics)
+        .input(open_input(&args.input))
+        .limits(args.limits.to_limits())
+        .build(load_program(&args.source))
+        .unwrap_or_else(|err| {
+            eprintln!("Failed to parse program: {}", err);
+            process::exit(exit::PARSE);
+        });
+
+    let view = |machine: &Machine| {
+        let first_cell = machine.data_ptr - machine.data_ptr % per_row;
+        View {
+            prog_ctr: machine.prog_ctr,
+            data_ptr: machine.data_ptr,
+            first_cell,
+            cells: machine.cells(first_cell..first_cell + per_row),
+            steps: machine.steps,
+        }
+    };
+    let mut views = vec![view(&machine)];
+    let mut result = Ok(());
+    while !machine.halted && views.len() < args.max_frames as usize {
+        result = machine.run_for(args.stride).map(|_| ());
+        views.push(view(&machine));
+        if result.is_err() {
+            break;
+        }
+    }
+    let frames = views.iter().map(|view| draw(&machine.prog, view, digits));
+    let delay = (100.0 / args.fps).round().max(1.0) as u16;
+    let written = match args.output.extension().and_then(|ext| ext.to_str()) {
+        Some("png" | "apng") => write_apng(&args.output, frames, views.len(), delay),
+        _ => write_gif(&args.output, frames, views.len(), delay),
+    };
+    written.unwrap_or_else(|err| {
+        eprintln!("Failed to write {}: {}", args.output.display(), err);
+        process::exit(exit::FAILURE);
+    });
+    if let Err(err) = result {
+        let fault = machine.fault(err);
+        eprintln!("Runtime error at {}", fault);
+        process::exit(exit_code(&fault.error));
+    }
+}
+
+fn write_gif(path: &Path, frames: impl Iterator<Item = Vec<u8>>, count: usize, delay: u16) -> Result<(), String> {
+    let file = BufWriter::new(fs::File::create(path).map_err(|err| err.to_string())?);
+    let mut encoder = gif::Encoder::new(file, WIDTH as u16, HEIGHT as u16, &PALETTE).map_err(|err| err.to_string())?;
+    encoder.set_repeat(gif::Repeat::Infinite).map_err(|err| err.to_string())?;
+    for (i, pixels) in frames.enumerate() {
+        let mut frame = gif::Frame::from_indexed_pixels(WIDTH as u16, HEIGHT as u16, pixels, None);
+        frame.delay = if i + 1 == count { delay.max(HOLD) } else { delay };
+        encoder.write_frame(&frame).map_err(|err| err.to_string())?;
+    }
+    Ok(())
+}
+
+fn write_apng(path: &Path, frames: impl Iterator<Item = Vec<u8>>, count: usize, delay: u16) -> Result<(), String> {
+    let file = BufWriter::new(fs::File::create(path).map_err(|err| err.to_string())?);
+    let mut encoder = png::Encoder::new(file, WIDTH as u32, HEIGHT as u32);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(&PALETTE[..]);
+    encoder.set_animated(count as u32, 0).map_err(|err| err.to_string())?;
+    let mut writer = encoder.write_header().map_err(|err| err.to_string())?;
+    for (i, pixels) in frames.enumerate() {
+        let delay = if i + 1 == count { delay.max(HOLD) } else { delay };
+        writer.set_frame_delay(delay, 100).map_err(|err| err.to_string())?;
+        writer.write_image_data(&pixels).map_err(|err| err.to_string())?;
+    }
+    writer.finish().map_err(|err| err.to_string())
+}
+
+// A frame's pixels, as palette indices: the source around the next
+// instruction, the row of the tape holding the pointer, and the step count
+fn draw(prog: &Program, view: &View, digits: usize) -> Vec<u8> {
+    let mut pixels = vec![BACKGROUND; WIDTH * HEIGHT];
+    let rows = prog.len().div_ceil(COLUMNS);
+    let first_row = (view.prog_ctr / COLUMNS).saturating_sub(SOURCE_ROWS / 2).min(rows.saturating_sub(SOURCE_ROWS));
+    let shown = first_row * COLUMNS..(first_row + SOURCE_ROWS) * COLUMNS;
+    for (i, &ch) in prog.chars.iter().enumerate().take(shown.end).skip(shown.start) {
+        let (col, row) = (i % COLUMNS, i / COLUMNS - first_row);
+        if i == view.prog_ctr {
+            put(&mut pixels, col, row, ch, BACKGROUND, Some(NEXT));
+        } else {
+            put(&mut pixels, col, row, ch, TEXT, None);
+        }
+    }
+
+    let row = SOURCE_ROWS + 1;
+    write(&mut pixels, 0, row, &format!("{:>w$}", view.first_cell, w = INDEX_DIGITS), DIM, None);
+    for (i, cell) in view.cells.iter().enumerate() {
+        let col = INDEX_DIGITS + 1 + i * (digits + 1);
+        let text = format!("{:>w$}", cell, w = digits);
+        if view.first_cell + i == view.data_ptr {
+            write(&mut pixels, col, row, &text, BACKGROUND, Some(POINTER));
+        } else {
+            write(&mut pixels, col, row, &text, if *cell == 0 { DIM } else { TEXT }, None);
+        }
+    }
+    write(&mut pixels, 0, SOURCE_ROWS + 3, &view.steps.to_string(), DIM, None);
+    pixels
+}
+
+fn write(pixels: &mut [u8], col: usize, row: usize, text: &str, color: u8, fill: Option<u8>) {
+    for (i, ch) in text.chars().enumerate() {
+        put(pixels, col + i, row, ch, color, fill);
+    }
+}
+
+// Draw a character at a column and row of the frame, over `fill` if given.
+// Anything past the right edge is left out.
+fn put(pixels: &mut [u8], col: usize, row: usize, ch: char, color: u8, fill: Option<u8>) {
+    if col >= COLUMNS {
+        return;
+    }
+    let (x0, y0) = (CHAR_W + col * CHAR_W, CHAR_H + row * CHAR_H);
+    if let Some(fill) = fill {
+        for y in y0..y0 + CHAR_H {
+            pixels[y * WIDTH + x0..y * WIDTH + x0 + CHAR_W].fill(fill);
+        }
+    }
+    for (dy, bits) in glyph(ch).iter().enumerate() {
+        for dx in (0..3).filter(|dx| bits & (0b100 >> dx) != 0) {
+            for y in 0..SCALE {
+                let at = (y0 + SCALE + dy * SCALE + y) * WIDTH + x0 + SCALE / 2 + dx * SCALE;
+                pixels[at..at + SCALE].fill(color);
+            }
+        }
+    }
+}
+
+// Rows of a 3x5 glyph, top first, as the low three bits. Only digits and
+// the commands have one; anything else is a dot in the middle.
+fn glyph(ch: char) -> [u8; 5] {
+    match ch {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '<' => [0b001, 0b010, 0b100, 0b010, 0b001],
+        '>' => [0b100, 0b010, 0b001, 0b010, 0b100],
+        '[' => [0b110, 0b100, 0b100, 0b100, 0b110],
+        ']' => [0b011, 0b001, 0b001, 0b001, 0b011],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        '(' => [0b010, 0b100, 0b100, 0b100, 0b010],
+        ')' => [0b010, 0b001, 0b001, 0b001, 0b010],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        ' ' => [0; 5],
+        _ => [0b000, 0b000, 0b010, 0b000, 0b000],
+    }
+}
@@ -0,0 +1,93 @@
+use std::io::{Write, stdin, stdout};
+
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+
+use brainrust::Machine;
+
+use crate::cli::ReplArgs;
+use crate::{configure, exit};
+#[cfg(feature = "ui")]
+use crate::display_spec;
+
+const REPL_HELP: &str = r#"Type Brainfuck to run it against the tape, or:
+  :debug [CODE]  step through CODE in the visual debugger
+  :reset         clear the tape, pointer and output
+  :quit          leave the REPL"#;
+
+// Read-eval-print loop over a tape that persists between snippets
+pub fn repl(args: ReplArgs) {
+    #[cfg_attr(not(feature = "ui"), allow(unused_variables))]
+    let (config, semantics) = configure(&args.semantics);
+    let fresh_machine = || {
+        let builder = Machine::builder()
+            .semantics(semantics.clone())
+            .input(stdin());
+        #[cfg(feature = "ui")]
+        let builder = builder.display_spec(display_spec(args.speed, &config, false));
+        builder.build(String::new()).expect("the empty program always parses")
+    };
+
+    let mut editor = DefaultEditor::new().unwrap_or_else(|err| {
+        eprintln!("Failed to start line editor: {}", err);
+        std::process::exit(exit::FAILURE);
+    });
+    let mut machine = fresh_machine();
+    println!("{}", REPL_HELP);
+    loop {
+        let line = match editor.readline("bf> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                eprintln!("{}", err);
+                break;
+            },
+        };
+        let _ = editor.add_history_entry(line.as_str());
+
+        let line = line.trim();
+        #[cfg_attr(not(feature = "ui"), allow(unused_variables))]
+        let (code, visible) = match line.split_once(' ').unwrap_or((line, "")) {
+            (":quit", _) | (":q", _) => break,
+            (":reset", _) => {
+                machine = fresh_machine();
+                continue;
+            },
+            #[cfg(feature = "ui")]
+            (":debug", code) => (code, true),
+            #[cfg(not(feature = "ui"))]
+            (":debug", _) => {
+                println!("This build has no visual debugger");
+                continue;
+            },
+            (cmd, _) if cmd.starts_with(':') => {
+                println!("Unknown command {}\n{}", cmd, REPL_HELP);
+                continue;
+            },
+            _ => (line, false),
+        };
+
+        if let Err(err) = machine.load(code.to_owned()) {
+            println!("Failed to parse program: {}", err);
+            continue;
+        }
+        let output_start = machine.output.len();
+        #[cfg(feature = "ui")]
+        {
+            machine.display_spec.visible = visible;
+        }
+        let result = machine.run();
+
+        let mut out = stdout();
+        let new_output = &machine.output[output_start..];
+        out.write_all(new_output).unwrap();
+        if !new_output.is_empty() && !new_output.ends_with(b"\n") {
+            writeln!(out).unwrap();
+        }
+        if let Err(err) = result {
+            println!("Runtime error at {}", machine.fault(err));
+        }
+        println!("[{}] = {}", machine.data_ptr, machine.data[machine.data_ptr]);
+    }
+}
@@ -0,0 +1,83 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process;
+use std::sync::{Arc, Mutex};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use brainrust::Semantics;
+
+use crate::exit;
+use crate::sidecar::Sidecar;
+
+// A recorded run: the semantics it ran under and every byte `,` consumed.
+// Input is the interpreter's only source of nondeterminism, so these are
+// enough to reproduce the run exactly.
+#[derive(Deserialize, Serialize)]
+pub struct Replay {
+    pub semantics: Semantics,
+    pub input: Vec<u8>,
+    pub steps: u64,  // For noticing when a replay diverges
+}
+
+// A recorded console session: what the program ran with, the breakpoints
+// restored at the start, and every command given, with the step the machine
+// was on at the time. Replaying the commands, with `brainrust debug PROGRAM
+// --replay-session FILE`, reaches the same state, and the steps show where a
+// replay diverges.
+#[derive(Deserialize, Serialize)]
+pub struct Session {
+    pub semantics: Semantics,
+    pub input: Vec<u8>,  // All of it, since a restart reads it again
+    pub sidecar: Option<Sidecar>,
+    pub commands: Vec<SessionCommand>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct SessionCommand {
+    pub step: u64,
+    pub line: String,
+}
+
+// Input stream that keeps a copy of every byte read from it
+pub struct Recorder {
+    inner: Box<dyn Read + Send>,
+    log: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Read for Recorder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.log.lock().unwrap().extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+// Wrap an input stream to record it. The machine reads one byte at a time,
+// so the log holds exactly what the program consumed.
+pub fn record(input: Box<dyn Read + Send>) -> (Recorder, Arc<Mutex<Vec<u8>>>) {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    (Recorder { inner: input, log: log.clone() }, log)
+}
+
+// Read a replay or session file
+pub fn load<T: DeserializeOwned>(path: &Path) -> T {
+    let text = fs::read_to_string(path).unwrap_or_else(|_| {
+        eprintln!("Replay file read failed!");
+        process::exit(exit::FAILURE);
+    });
+    serde_json::from_str(&text).unwrap_or_else(|err| {
+        eprintln!("{}: invalid replay: {}", path.display(), err);
+        process::exit(exit::FAILURE);
+    })
+}
+
+pub fn save(path: &Path, replay: &impl Serialize) {
+    let text = serde_json::to_string_pretty(replay).unwrap();
+    fs::write(path, text + "\n").unwrap_or_else(|_| {
+        eprintln!("Replay file write failed!");
+        process::exit(exit::FAILURE);
+    });
+}
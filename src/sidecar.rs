@@ -0,0 +1,157 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use brainrust::{Condition, Debugger, HitCount, Trigger, Watch};
+
+// Breakpoints and watchpoints saved beside a program, so the console can
+// restore them when the same program is opened again. Breakpoints are kept
+// by label where there is one, and otherwise by source position, so they
+// stay put while the program is edited.
+#[derive(Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sidecar {
+    #[serde(rename = "breakpoint", skip_serializing_if = "Vec::is_empty")]
+    pub breakpoints: Vec<SavedBreakpoint>,
+    #[serde(rename = "watchpoint", skip_serializing_if = "Vec::is_empty")]
+    pub watchpoints: Vec<SavedWatchpoint>,
+}
+
+#[derive(Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SavedBreakpoint {
+    pub line: usize,    // 1-based
+    pub column: usize,  // 1-based
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,  // Preferred to the position while it exists
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub condition: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hit: Option<u64>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub ignore: u64,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+#[derive(Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SavedWatchpoint {
+    pub cell: usize,
+    pub trigger: String,  // read, write or change
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<u32>,
+}
+
+// `PROGRAM.breakpoints.toml`, beside the program
+pub fn path(program: &Path) -> PathBuf {
+    let mut name = program.file_name().unwrap_or_default().to_owned();
+    name.push(".breakpoints.toml");
+    program.with_file_name(name)
+}
+
+impl Sidecar {
+    // The sidecar of `program`, or `None` if it has none
+    pub fn load(program: &Path) -> Result<Option<Sidecar>, String> {
+        let path = path(program);
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(format!("Failed to read {}: {}", path.display(), err)),
+        };
+        toml::from_str(&text)
+            .map(Some)
+            .map_err(|err| format!("Invalid breakpoint file {}: {}", path.display(), err))
+    }
+
+    // Write the sidecar of `program`, or remove it if there is nothing to keep
+    pub fn save(&self, program: &Path) -> Result<(), String> {
+        let path = path(program);
+        let result = if self.breakpoints.is_empty() && self.watchpoints.is_empty() {
+            match fs::remove_file(&path) {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+                result => result,
+            }
+        } else {
+            let text = toml::to_string(self).expect("sidecars always serialize");
+            fs::write(&path, text)
+        };
+        result.map_err(|err| format!("Failed to write {}: {}", path.display(), err))
+    }
+
+    pub fn capture(debugger: &Debugger) -> Sidecar {
+        let machine = debugger.machine();
+        let breakpoints = debugger.breakpoints().map(|i| {
+            let (x, y) = machine.prog.positions[i];
+            SavedBreakpoint {
+                line: y + 1,
+                column: x + 1,
+                label: machine.label_at(i).map(str::to_owned),
+                condition: debugger.condition(i).map(|condition| condition.to_string()),
+                hit: debugger.hit_count(i).unwrap().hit,
+                ignore: debugger.hit_count(i).unwrap().ignore,
+            }
+        }).collect();
+        let watchpoints = debugger.watchpoints().map(|cell| {
+            let watch = debugger.watchpoint(cell).unwrap();
+            SavedWatchpoint {
+                cell,
+                trigger: match watch.trigger {
+                    Trigger::Read => "read",
+                    Trigger::Write => "write",
+                    Trigger::Change => "change",
+                }.to_owned(),
+                value: watch.value,
+            }
+        }).collect();
+        Sidecar { breakpoints, watchpoints }
+    }
+
+    // Set the saved breakpoints and watchpoints on `debugger`. Each breakpoint
+    // goes on its label, or the first instruction at or after its position. Returns
+    // complaints about any that couldn't be restored.
+    pub fn restore(&self, debugger: &mut Debugger) -> Vec<String> {
+        let mut complaints = Vec::new();
+        for bp in &self.breakpoints {
+            let machine = debugger.machine();
+            let at = (bp.line.saturating_sub(1), bp.column.saturating_sub(1));
+            let index = bp.label.as_deref().and_then(|label| machine.label(label)).or_else(|| {
+                machine.prog.positions.iter().position(|&(x, y)| (y, x) >= at)
+            });
+            let Some(index) = index else {
+                complaints.push(format!("No instructions at or after {}:{}", bp.line, bp.column));
+                continue;
+            };
+            match bp.condition.as_deref().map(Condition::parse) {
+                None => debugger.set_breakpoint(index),
+                Some(Ok(condition)) => debugger.set_conditional_breakpoint(index, condition),
+                Some(Err(err)) => {
+                    complaints.push(format!("Bad condition for {}:{} at {}", bp.line, bp.column, err));
+                    continue;
+                },
+            }
+            debugger.set_hit_count(index, HitCount { hit: bp.hit, ignore: bp.ignore });
+        }
+        for wp in &self.watchpoints {
+            let trigger = match wp.trigger.as_str() {
+                "read" => Trigger::Read,
+                "write" => Trigger::Write,
+                "change" => Trigger::Change,
+                trigger => {
+                    complaints.push(format!("Unknown trigger {} for cell {}", trigger, wp.cell));
+                    continue;
+                },
+            };
+            if debugger.cell(wp.cell).is_none() {
+                complaints.push(format!("Cell {} is off the tape", wp.cell));
+                continue;
+            }
+            debugger.watch_for(wp.cell, Watch { trigger, value: wp.value });
+        }
+        complaints
+    }
+}
@@ -0,0 +1,173 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use core::sync::atomic::AtomicUsize;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::calls::Call;
+use crate::parse::Command;
+use crate::tape::Tape;
+use crate::vm::{Machine, PtrBounds, Semantics};
+
+// The state of a machine, apart from its program and I/O streams. The tape
+// is kept sparsely, as its nonzero cells.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Snapshot {
+    pub semantics: Semantics,
+    pub tape_len: usize,
+    pub tape: BTreeMap<usize, u32>,
+    pub data_ptr: usize,
+    pub prog_ctr: usize,
+    pub steps: u64,
+    pub halted: bool,
+    pub output: Vec<u8>,
+    // pbrain's procedures and calls, left out when there are none
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "BTreeMap::is_empty"))]
+    pub procedures: BTreeMap<u32, usize>,
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub calls: Vec<Call>,
+}
+
+// What changed from one snapshot to a later one
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct StateDiff {
+    pub cells: Vec<CellChange>,            // In tape order
+    pub data_ptr: Option<(usize, usize)>,  // From, to
+    pub prog_ctr: Option<(usize, usize)>,
+    pub output_removed: usize,             // Bytes dropped from the end, by stepping back
+    pub output_added: Vec<u8>,             // Bytes then appended
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CellChange {
+    pub index: usize,
+    pub before: u32,
+    pub after: u32,
+}
+
+impl StateDiff {
+    pub fn is_empty(&self) -> bool {
+        *self == StateDiff::default()
+    }
+
+    pub fn changed(&self, cell: usize) -> bool {
+        self.cells.binary_search_by_key(&cell, |change| change.index).is_ok()
+    }
+}
+
+impl Snapshot {
+    // The changes that turn this state into `later`. Cells past the end of
+    // the shorter tape count as zero.
+    pub fn diff(&self, later: &Snapshot) -> StateDiff {
+        let indices: BTreeSet<usize> = self.tape.keys().chain(later.tape.keys()).copied().collect();
+        let cell = |snapshot: &Snapshot, i| snapshot.tape.get(&i).copied().unwrap_or(0);
+        let cells = indices.into_iter()
+            .map(|index| CellChange { index, before: cell(self, index), after: cell(later, index) })
+            .filter(|change| change.before != change.after)
+            .collect();
+        let moved = |from, to| (from != to).then_some((from, to));
+        let kept = self.output.iter()
+            .zip(&later.output)
+            .take_while(|(a, b)| a == b)
+            .count();
+        StateDiff {
+            cells,
+            data_ptr: moved(self.data_ptr, later.data_ptr),
+            prog_ctr: moved(self.prog_ctr, later.prog_ctr),
+            output_removed: self.output.len() - kept,
+            output_added: later.output[kept..].to_vec(),
+        }
+    }
+}
+
+// Reasons a snapshot doesn't fit the machine it is restored into
+#[derive(Debug, Error)]
+pub enum RestoreError {
+    #[error("cell {0} is outside the tape")]
+    CellOutOfRange(usize),
+    #[error("cell {0} holds {1}, more than a cell can")]
+    CellTooLarge(usize, u32),
+    #[error("data pointer is outside the tape")]
+    DataPtrOutOfRange,
+    #[error("program counter is outside the program")]
+    ProgCtrOutOfRange,
+    #[error("state is for another dialect")]
+    DialectMismatch,
+    #[error("procedure {0} isn't defined by a `(` of the program")]
+    BadProcedure(u32),
+    #[error("call isn't from a `:` of the program, or was made after the state was saved")]
+    BadCall,
+}
+
+impl Machine {
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            semantics: self.semantics.clone(),
+            tape_len: self.data.len(),
+            tape: self.nonzero_cells().collect(),
+            data_ptr: self.data_ptr,
+            prog_ctr: self.prog_ctr,
+            steps: self.steps,
+            halted: self.halted,
+            output: self.output.clone(),
+            procedures: self.procedures.clone(),
+            calls: self.calls.clone(),
+        }
+    }
+
+    // Put the machine back in a saved state. The program is left alone, so
+    // the snapshot should come from a machine running the same one.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), RestoreError> {
+        if let Some((&i, _)) = snapshot.tape.range(snapshot.tape_len..).next() {
+            return Err(RestoreError::CellOutOfRange(i));
+        }
+        let max = snapshot.semantics.cell_size.max();
+        if let Some((&i, &cell)) = snapshot.tape.iter().find(|(_, &cell)| cell > max) {
+            return Err(RestoreError::CellTooLarge(i, cell));
+        }
+        if snapshot.data_ptr >= snapshot.tape_len {
+            return Err(RestoreError::DataPtrOutOfRange);
+        }
+        if snapshot.prog_ctr >= self.prog.len().max(1) {
+            return Err(RestoreError::ProgCtrOutOfRange);
+        }
+        if snapshot.semantics.dialect != self.prog.dialect {
+            return Err(RestoreError::DialectMismatch);
+        }
+        let command = |i: usize| self.prog.commands.get(i).copied();
+        if let Some((&procedure, _)) = snapshot.procedures.iter()
+            .find(|&(_, &start)| !matches!(command(start), Some(Command::ProcStart(_)))) {
+            return Err(RestoreError::BadProcedure(procedure));
+        }
+        if snapshot.calls.iter().any(|call| command(call.at) != Some(Command::Call) || call.step >= snapshot.steps) {
+            return Err(RestoreError::BadCall);
+        }
+        self.semantics = snapshot.semantics.clone();
+        let guard = self.data.guard();
+        self.data = Tape::new(snapshot.tape_len);
+        if self.semantics.ptr_bounds != PtrBounds::Extend {
+            self.data.set_guard(guard);
+        }
+        for (&i, &cell) in &snapshot.tape {
+            self.data[i] = cell;
+        }
+        self.used = AtomicUsize::new(snapshot.tape.keys().next_back().map_or(0, |&last| last + 1));
+        self.data_ptr = snapshot.data_ptr;
+        self.prog_ctr = snapshot.prog_ctr;
+        self.steps = snapshot.steps;
+        self.halted = snapshot.halted;
+        self.output = snapshot.output.clone();
+        self.output_dropped = 0;
+        self.procedures = snapshot.procedures.clone();
+        self.calls = snapshot.calls.clone();
+        if let Some(tree) = &mut self.call_tree {
+            tree.follow(&self.calls);
+        }
+        Ok(())
+    }
+}
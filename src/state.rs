@@ -0,0 +1,102 @@
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use brainrust::{Machine, RunOutcome, Snapshot};
+
+use crate::cli::{StateCmd, StateExportArgs, StateImportArgs};
+use crate::{configure, exit, exit_code, flush_output, load_program, open_input, open_output, state_json};
+
+// Version of the state schema described by `brainrust state --help`. Adding
+// fields keeps it; changing or removing them bumps it.
+pub const VERSION: u64 = 1;
+
+pub fn state(command: StateCmd) {
+    match command {
+        StateCmd::Export(args) => export(args),
+        StateCmd::Import(args) => import(args),
+    }
+}
+
+fn export(args: StateExportArgs) {
+    let (_, semantics) = configure(&args.semantics);
+    let program = load_program(&args.source);
+    let machine = Machine::builder()
+        .semantics(semantics)
+        .input(open_input(&args.input))
+        .limits(args.limits.to_limits())
+        .build(program)
+        .unwrap_or_else(|err| {
+            eprintln!("Failed to parse program: {}", err);
+            process::exit(exit::PARSE);
+        });
+    let code = run_and_save(machine, args.steps, args.to.as_deref());
+    process::exit(code);
+}
+
+fn import(args: StateImportArgs) {
+    let snapshot = load(&args.state).unwrap_or_else(|why| {
+        eprintln!("{}: {}", args.state.display(), why);
+        process::exit(exit::FAILURE);
+    });
+    let program = load_program(&args.source);
+    let mut builder = Machine::builder()
+        .semantics(snapshot.semantics.clone())
+        .input(open_input(&args.io.input))
+        .limits(args.limits.to_limits());
+    // Program output goes to stdout only if the state doesn't; either way
+    // the state keeps it
+    if let Some(sink) = open_output(&args.io, args.export.is_some()) {
+        builder = builder.output(sink);
+    }
+    let mut machine = builder.build(program).unwrap_or_else(|err| {
+        eprintln!("Failed to parse program: {}", err);
+        process::exit(exit::PARSE);
+    });
+    machine.restore(&snapshot).unwrap_or_else(|err| {
+        eprintln!("{}: State doesn't fit the program: {}", args.state.display(), err);
+        process::exit(exit::FAILURE);
+    });
+    let code = run_and_save(machine, args.steps, args.export.as_deref());
+    process::exit(code);
+}
+
+// Run the machine for at most `steps` steps, or until it halts, and write
+// its state to `to`, or stdout. A machine that has already halted is saved
+// as it is. Returns the exit code for how the run ended.
+fn run_and_save(mut machine: Machine, steps: Option<u64>, to: Option<&Path>) -> i32 {
+    let result = match steps {
+        _ if machine.halted => Ok(()),
+        Some(steps) => machine.run_for(steps).map(|_: RunOutcome| ()),
+        None => machine.run(),
+    };
+    let result = result.map_err(|err| machine.fault(err));
+    flush_output(&mut machine);
+    let state = serde_json::to_string_pretty(&state_json(&machine, &result)).unwrap() + "\n";
+    match to {
+        Some(path) => fs::write(path, state).unwrap_or_else(|_| {
+            eprintln!("State write failed!");
+            process::exit(exit::FAILURE);
+        }),
+        None => print!("{}", state),
+    }
+    match result {
+        Ok(()) => 0,
+        Err(fault) => {
+            eprintln!("Runtime error at {}", fault);
+            exit_code(&fault.error)
+        },
+    }
+}
+
+// Read a state file, checking it is a version this build understands. Dumps
+// from before states were versioned have the fields of version 1.
+fn load(path: &Path) -> Result<Snapshot, String> {
+    let text = fs::read_to_string(path).map_err(|err| format!("read failed: {}", err))?;
+    let state: serde_json::Value = serde_json::from_str(&text).map_err(|err| format!("not JSON: {}", err))?;
+    match state.get("version").and_then(serde_json::Value::as_u64) {
+        Some(VERSION) | None => {},
+        Some(version) => return Err(format!("state version {} isn't supported", version)),
+    }
+    serde_json::from_value(state).map_err(|err| format!("invalid state: {}", err))
+}
@@ -0,0 +1,58 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::Serialize;
+
+use brainrust::{Command, Program};
+
+// Static summary of a program's structure
+#[derive(Serialize)]
+pub struct Stats {
+    pub chars: usize,
+    pub lines: usize,
+    pub commands: usize,               // Instructions other than comments
+    pub counts: BTreeMap<char, usize>, // Occurrences of each command
+    pub loops: usize,
+    pub max_depth: usize,              // Deepest loop nesting
+}
+
+impl Stats {
+    pub fn new(program: &str, prog: &Program) -> Stats {
+        let mut counts = BTreeMap::new();
+        let (mut depth, mut max_depth, mut loops) = (0, 0, 0);
+        for (command, &ch) in prog.commands.iter().zip(&prog.chars) {
+            match command {
+                Command::Assert(_) => continue,
+                Command::JumpForward(_) => {
+                    loops += 1;
+                    depth += 1;
+                    max_depth = max_depth.max(depth);
+                },
+                Command::JumpBackward(_) => { depth -= 1; },
+                _ => { },
+            }
+            *counts.entry(ch).or_insert(0) += 1;
+        }
+        Stats {
+            chars: program.chars().count(),
+            lines: program.lines().count(),
+            commands: counts.values().sum(),
+            counts,
+            loops,
+            max_depth,
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "characters: {}", self.chars)?;
+        writeln!(f, "lines:      {}", self.lines)?;
+        writeln!(f, "commands:   {}", self.commands)?;
+        for (ch, count) in &self.counts {
+            writeln!(f, "  {}  {}", ch, count)?;
+        }
+        writeln!(f, "loops:      {}", self.loops)?;
+        writeln!(f, "max depth:  {}", self.max_depth)
+    }
+}
@@ -0,0 +1,29 @@
+use std::process;
+
+use brainrust::golden;
+
+use crate::cli::TestArgs;
+use crate::exit;
+
+// Run every golden test in a directory and report each result. Exits
+// nonzero unless all of them pass.
+pub fn test(args: TestArgs) {
+    let cases = golden::cases(&args.dir).unwrap_or_else(|err| {
+        eprintln!("Failed to read {}: {}", args.dir.display(), err);
+        process::exit(exit::FAILURE);
+    });
+    let mut failed = 0;
+    for case in &cases {
+        match golden::check(case, args.limits.to_limits()) {
+            Ok(()) => println!("ok    {}", case.name),
+            Err(failure) => {
+                failed += 1;
+                println!("FAIL  {}: {}", case.name, failure);
+            },
+        }
+    }
+    println!("{} tests: {} passed, {} failed", cases.len(), cases.len() - failed, failed);
+    if failed > 0 {
+        process::exit(exit::FAILURE);
+    }
+}
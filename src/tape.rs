@@ -0,0 +1,180 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::{Index, IndexMut};
+
+// Cells in each page of the tape
+pub const PAGE_LEN: usize = 4096;
+
+// What a canary cell holds while it is intact: a value no 8- or 16-bit cell
+// can take
+pub const CANARY: u32 = 0xCAFE_F00D;
+
+// The machine's tape, kept as fixed-size pages that are only allocated once
+// a cell in them is written. Until then a page reads as zeros, so a program
+// that touches widely spaced cells, or a huge tape that is mostly unused,
+// costs only the pages it writes. The page last written is remembered, so
+// most accesses don't look it up.
+//
+// A fixed tape may be followed by a band of canary cells. They are out of
+// bounds for the machine, but indexing reaches them, so a write that
+// strays off the end lands on one rather than failing.
+#[derive(Clone)]
+pub struct Tape {
+    len: usize,
+    guard: usize,  // Canary cells past the end
+    slots: BTreeMap<usize, usize>,  // Index into `pages` by page number
+    pages: Vec<Box<[u32]>>,
+    current: (usize, usize),  // Page number and slot of the page last written
+}
+
+impl Tape {
+    // A tape of `len` cells, all zero
+    pub fn new(len: usize) -> Tape {
+        Tape { len, guard: 0, slots: BTreeMap::new(), pages: Vec::new(), current: (usize::MAX, 0) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Lengthen the tape to `len` cells, the new ones zero. A tape with
+    // canaries has a fixed length.
+    pub fn grow(&mut self, len: usize) {
+        debug_assert_eq!(self.guard, 0, "a tape with canaries doesn't grow");
+        self.len = self.len.max(len);
+    }
+
+    // Place `cells` canary cells past the end of the tape, in place of any
+    // already there
+    pub fn set_guard(&mut self, cells: usize) {
+        for index in self.len..self.len + self.guard {
+            self[index] = 0;
+        }
+        self.guard = cells;
+        for index in self.len..self.len + self.guard {
+            self[index] = CANARY;
+        }
+    }
+
+    // Canary cells past the end
+    pub fn guard(&self) -> usize {
+        self.guard
+    }
+
+    // The index of the first canary cell that no longer holds `CANARY`
+    pub fn overwritten_canary(&self) -> Option<usize> {
+        (self.len..self.len + self.guard).find(|&index| self[index] != CANARY)
+    }
+
+    pub fn get(&self, index: usize) -> Option<u32> {
+        (index < self.len).then(|| self[index])
+    }
+
+    // Pages allocated so far
+    pub fn pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    // Indices and values of the cells that aren't zero, in tape order
+    pub fn nonzero(&self) -> impl Iterator<Item = (usize, u32)> + '_ {
+        self.slots.iter().flat_map(move |(&page, &slot)| {
+            self.pages[slot].iter().enumerate()
+                .map(move |(offset, &cell)| (page * PAGE_LEN + offset, cell))
+                .filter(move |&(index, cell)| cell != 0 && index < self.len)
+        })
+    }
+
+    // The index of the first cell holding `value`
+    pub fn position(&self, value: u32) -> Option<usize> {
+        if value != 0 {
+            return self.nonzero().find(|&(_, cell)| cell == value).map(|(index, _)| index);
+        }
+        // The first zero is in the first page that isn't all nonzero
+        (0..self.len.div_ceil(PAGE_LEN))
+            .find_map(|page| match self.slots.get(&page) {
+                Some(&slot) => {
+                    self.pages[slot].iter().position(|&cell| cell == 0).map(|offset| page * PAGE_LEN + offset)
+                },
+                None => Some(page * PAGE_LEN),
+            })
+            .filter(|&index| index < self.len)
+    }
+
+    // One past the last nonzero cell before `end`, or 0 if there is none
+    pub fn nonzero_end(&self, end: usize) -> usize {
+        let end = end.min(self.len);
+        if end == 0 {
+            return 0;
+        }
+        for (&page, &slot) in self.slots.range(..=(end - 1) / PAGE_LEN).rev() {
+            let cells = &self.pages[slot][..(end - page * PAGE_LEN).min(PAGE_LEN)];
+            if let Some(offset) = cells.iter().rposition(|&cell| cell != 0) {
+                return page * PAGE_LEN + offset + 1;
+            }
+        }
+        0
+    }
+
+    fn slot(&self, page: usize) -> Option<usize> {
+        if page == self.current.0 {
+            Some(self.current.1)
+        } else {
+            self.slots.get(&page).copied()
+        }
+    }
+}
+
+impl Index<usize> for Tape {
+    type Output = u32;
+
+    fn index(&self, index: usize) -> &u32 {
+        assert!(index < self.len + self.guard, "cell {} is off a tape of {} cells", index, self.len);
+        match self.slot(index / PAGE_LEN) {
+            Some(slot) => &self.pages[slot][index % PAGE_LEN],
+            None => &0,
+        }
+    }
+}
+
+impl IndexMut<usize> for Tape {
+    fn index_mut(&mut self, index: usize) -> &mut u32 {
+        assert!(index < self.len + self.guard, "cell {} is off a tape of {} cells", index, self.len);
+        let page = index / PAGE_LEN;
+        if page != self.current.0 {
+            let slot = match self.slots.get(&page) {
+                Some(&slot) => slot,
+                None => {
+                    self.pages.push(vec![0; PAGE_LEN].into_boxed_slice());
+                    self.slots.insert(page, self.pages.len() - 1);
+                    self.pages.len() - 1
+                },
+            };
+            self.current = (page, slot);
+        }
+        &mut self.pages[self.current.1][index % PAGE_LEN]
+    }
+}
+
+// Tapes are equal when their cells are, however they are paged and whatever
+// canaries follow them
+impl PartialEq for Tape {
+    fn eq(&self, other: &Tape) -> bool {
+        self.len == other.len && self.nonzero().eq(other.nonzero())
+    }
+}
+
+impl fmt::Debug for Tape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tape")
+            .field("len", &self.len)
+            .field("nonzero", &self.nonzero().collect::<BTreeMap<_, _>>())
+            .finish()
+    }
+}
@@ -0,0 +1,629 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops::Range;
+use std::io::{Write, stdout};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver, RecvError, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use itertools::{EitherOrBoth, Itertools};
+use termion::color;
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+
+use crate::debugger::{Debugger, HISTORY_BUDGET, Stop};
+use crate::snapshot::StateDiff;
+use crate::tape::Tape;
+use crate::vm::{CellSize, Machine, Perf, RuntimeError};
+
+const WELCOME_MESSAGE: &str = "Welcome to BrainRust!";
+
+// How often the debugger draws the latest state, if it changed, and checks
+// whether it should reload
+const FRAME_POLL: Duration = Duration::from_millis(16);
+
+// Bytes of output the screen keeps, the most recent, so drawing doesn't slow
+// as the output grows
+const OUTPUT_SHOWN: usize = 4096;
+
+pub struct DisplaySpec {
+    pub visible: bool,
+    pub decimal: bool,
+    pub hex: bool,
+    pub ascii: bool,
+    pub frame_dur: Duration,  // Time between autorun steps
+    pub history: usize,       // Bytes of step history kept for stepping back
+    pub theme: Theme,
+    pub keys: Keys,
+}
+
+impl DisplaySpec {
+    pub fn new(rate: f32) -> DisplaySpec {
+        DisplaySpec {
+            visible: true,
+            decimal: true,
+            hex: true,
+            ascii: true,
+            frame_dur: Duration::try_from_secs_f32(1.0 / rate).unwrap_or(Duration::MAX),
+            history: HISTORY_BUDGET,
+            theme: Theme::default(),
+            keys: Keys::default(),
+        }
+    }
+}
+
+// Colors used by the debugger
+#[derive(Clone, Copy)]
+pub struct Theme {
+    pub highlight: color::AnsiValue,  // Background of the data pointer and read head
+    pub output: color::AnsiValue,     // Foreground of the output line
+    pub changed: color::AnsiValue,    // Foreground of cells the last step changed
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme {
+            highlight: color::AnsiValue(4),  // Blue
+            output: color::AnsiValue(2),     // Green
+            changed: color::AnsiValue(3),    // Yellow
+        }
+    }
+}
+
+// Debugger keybindings
+#[derive(Clone, Copy)]
+pub struct Keys {
+    pub quit: char,
+    pub advance: char,
+    pub back: char,
+    pub over: char,    // Step over a loop
+    pub out: char,     // Step out of a loop
+    pub output: char,  // Run until the next output
+    pub writer: char,  // Rewind to the last write of the current cell
+    pub run: char,
+    pub calls: char,   // Show or hide the call tree
+}
+
+impl Default for Keys {
+    fn default() -> Keys {
+        Keys {
+            quit: 'q',
+            advance: 'a',
+            back: 'b',
+            over: 'n',
+            out: 'f',
+            output: 'o',
+            writer: 'w',
+            run: 'r',
+            calls: 'c',
+        }
+    }
+}
+
+impl fmt::Display for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] quit, [{}] advance, [{}] back, [{}] over loop, [{}] out of loop, \
+                   [{}] to output, [{}] last write, [{}] run/pause, [{}] call tree",
+               self.quit, self.advance, self.back, self.over, self.out, self.output, self.writer,
+               self.run, self.calls)
+    }
+}
+
+
+// What the keys ask of the execution thread
+#[derive(Clone, Copy)]
+enum Action {
+    Advance,
+    Over,
+    Out,
+    ToOutput,
+    Back,
+    Writer,
+    Run,
+}
+
+// What an action did to the machine, sent from the execution thread to be
+// drawn. Only the changes are sent; the screen keeps its own copy of the
+// state to apply them to.
+struct Frame {
+    diff: StateDiff,
+    status: String,
+    mark: Option<usize>,  // Instruction to mark after rewinding to a write
+    calls: Vec<String>,   // The call tree, if tracked
+}
+
+impl Debugger {
+    // Run the machine in the visual debugger. The machine runs on a thread of
+    // its own, taking actions from the keys and sending back what each one
+    // changed. Drawing happens on this thread, at most once a frame and only
+    // of the latest state, so a slow terminal never holds up execution.
+    pub(crate) fn run_visual(&mut self) -> Result<(), RuntimeError> {
+        println!("{}{}{}\n{}\n{}",
+                 termion::cursor::Goto(1,1),
+                 termion::clear::AfterCursor,
+                 WELCOME_MESSAGE,
+                 self.machine().display_spec.keys,
+                 termion::cursor::Hide);
+
+        // Keys come from the terminal itself, leaving stdin free to
+        // carry the program.
+        let input_stream = termion::get_tty()?;
+        let tty_fd = input_stream.as_raw_fd();
+        let mut keys = input_stream.keys();
+
+        let mut output_stream = stdout().into_raw_mode()?;
+        let mut screen = Screen::new(self.machine());
+        let bindings = self.machine().display_spec.keys;
+        let source_changed = self.machine().source_changed.clone();
+        let mut drawn = Perf::default();  // Frames and drawing time, for the machine's counters
+        redraw(&mut output_stream, &mut screen, &mut drawn)?;
+
+        let (actions, action_rx) = mpsc::channel();
+        let (frame_tx, frames) = mpsc::channel();
+        let result = thread::scope(|scope| {
+            let execution = scope.spawn(|| self.serve(action_rx, frame_tx));
+            let drawn = &mut drawn;
+            let mut show = move || -> Result<(), RuntimeError> {
+                loop {
+                    if source_changed.as_ref().is_some_and(|changed| changed.load(Ordering::SeqCst)) {
+                        return Ok(());
+                    }
+                    let mut fresh = false;
+                    loop {
+                        match frames.try_recv() {
+                            Ok(frame) => {
+                                screen.apply(frame);
+                                fresh = true;
+                            },
+                            Err(TryRecvError::Empty) => break,
+                            // The machine halted or failed. Show where it got to.
+                            Err(TryRecvError::Disconnected) => {
+                                if fresh {
+                                    redraw(&mut output_stream, &mut screen, drawn)?;
+                                }
+                                return Ok(());
+                            },
+                        }
+                    }
+                    if fresh {
+                        redraw(&mut output_stream, &mut screen, drawn)?;
+                    }
+                    if !wait_readable(tty_fd, FRAME_POLL) {
+                        continue;
+                    }
+                    let action = match keys.next() {
+                        Some(key) => match key? {
+                            Key::Char(c) if c == bindings.quit => return Ok(()),
+                            Key::Char(c) if c == bindings.advance => Action::Advance,
+                            Key::Char(c) if c == bindings.over => Action::Over,
+                            Key::Char(c) if c == bindings.out => Action::Out,
+                            Key::Char(c) if c == bindings.output => Action::ToOutput,
+                            Key::Char(c) if c == bindings.back => Action::Back,
+                            Key::Char(c) if c == bindings.writer => Action::Writer,
+                            Key::Char(c) if c == bindings.run => Action::Run,
+                            // Only the screen changes, so the machine needn't hear of it
+                            Key::Char(c) if c == bindings.calls => {
+                                screen.show_calls = !screen.show_calls;
+                                screen.stale = true;
+                                redraw(&mut output_stream, &mut screen, drawn)?;
+                                continue;
+                            },
+                            _ => continue,
+                        },
+                        None => return Ok(()),
+                    };
+                    if actions.send(action).is_err() {
+                        return Ok(());
+                    }
+                }
+            };
+            let shown = show();
+            drop(show);  // And with it the channels, which stops the execution thread
+            let executed = execution.join().expect("the execution thread doesn't panic");
+            shown.and(executed)
+        });
+        self.machine_mut().count_perf(|perf| {
+            perf.frames += drawn.frames;
+            perf.drawing += drawn.drawing;
+        });
+        write!(stdout(), "{}", termion::cursor::Show)?;
+        result
+    }
+
+    // The execution thread: take actions until told to stop, or until the
+    // machine halts or fails, sending a frame after each. While autorunning,
+    // step whenever no action arrives before the next tick. Autorun faster
+    // than frames are drawn takes a batch of steps a tick instead, so that
+    // it keeps its speed without a frame for every step.
+    fn serve(&mut self, actions: Receiver<Action>, frames: Sender<Frame>) -> Result<(), RuntimeError> {
+        let frame_dur = self.machine().display_spec.frame_dur;
+        let batch = (FRAME_POLL.as_nanos() / frame_dur.as_nanos().max(1)).clamp(1, u32::MAX as u128) as u32;
+        let tick = frame_dur * batch;
+        let mut autorun = false;
+        let mut next_tick = Instant::now();
+        let mut sent = self.machine().snapshot();
+        loop {
+            let action = if autorun {
+                match actions.recv_timeout(next_tick.saturating_duration_since(Instant::now())) {
+                    Ok(action) => Some(action),
+                    Err(RecvTimeoutError::Timeout) => {
+                        next_tick = Instant::now() + tick;
+                        None
+                    },
+                    Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                }
+            } else {
+                match actions.recv() {
+                    Ok(action) => Some(action),
+                    Err(RecvError) => return Ok(()),
+                }
+            };
+            let mut mark = None;
+            let mut batched = None;
+            let started = Instant::now();
+            let running = match action {
+                None if batch > 1 => self.run_batch(batch, started + tick).map(|(stop, steps)| {
+                    batched = Some(steps);
+                    stop != Stop::Halted
+                }),
+                None | Some(Action::Advance) => self.step().map(|stop| stop != Stop::Halted),
+                Some(Action::Over) => self.step_over().map(|stop| stop != Stop::Halted),
+                Some(Action::Out) => self.step_out().map(|stop| stop != Stop::Halted),
+                Some(Action::ToOutput) => self.run_to_output(None).map(|stop| stop != Stop::Halted),
+                Some(Action::Back) => {
+                    autorun = false;
+                    self.step_back();
+                    Ok(true)
+                },
+                Some(Action::Writer) => {
+                    autorun = false;
+                    mark = self.reverse_to_write(self.machine().data_ptr);
+                    Ok(true)
+                },
+                Some(Action::Run) => {
+                    autorun = !autorun;
+                    next_tick = Instant::now() + tick;
+                    Ok(true)
+                },
+            };
+            let executing = started.elapsed();
+            self.machine_mut().count_perf(|perf| perf.executing += executing);
+            if !matches!(running, Ok(true)) {
+                return running.map(|_| ());
+            }
+            let now = self.machine().snapshot();
+            let mut status = self.machine().status_line();
+            if let Some(steps) = batched {
+                status += &format!("autorun: {} steps per frame\r\n", steps);
+            }
+            let frame = Frame { diff: sent.diff(&now), status, mark, calls: self.machine().call_lines() };
+            sent = now;
+            if frames.send(frame).is_err() {
+                return Ok(());
+            }
+        }
+    }
+
+    // Take `steps` steps for one frame of autorun, or as many as fit before
+    // `deadline`, so a machine slower than the autorun speed still answers
+    // keys. Returns why it stopped and the steps taken.
+    fn run_batch(&mut self, steps: u32, deadline: Instant) -> Result<(Stop, u32), RuntimeError> {
+        let mut taken = 0;
+        loop {
+            let stop = self.step()?;
+            taken += 1;
+            if stop != Stop::Step
+                || taken == steps
+                || taken % BATCH_CHECK == 0 && Instant::now() >= deadline {
+                return Ok((stop, taken));
+            }
+        }
+    }
+}
+
+// Steps between looks at the clock in a batch
+const BATCH_CHECK: u32 = 1024;
+
+// Draw the screen, then start marking changes afresh. Nothing is written if
+// the terminal already shows the same. The frame is built in the screen's
+// buffer, which is reused so drawing doesn't allocate once it has grown to
+// fit. The frame and the time it took are counted in `drawn`.
+fn redraw(output_stream: &mut std::io::Stdout, screen: &mut Screen, drawn: &mut Perf)
+          -> std::io::Result<()> {
+    if !screen.stale {
+        return Ok(());
+    }
+    let started = Instant::now();
+    let mut frame = std::mem::take(&mut screen.frame);
+    frame.clear();
+    {
+        use std::fmt::Write;
+        write!(frame, "{}{}", termion::cursor::Goto(1,3), termion::clear::AfterCursor)
+            .and_then(|()| screen.render(&mut frame))
+            .and_then(|()| frame.write_str("\n"))
+            .expect("writing to a string doesn't fail");
+    }
+    output_stream.write_all(frame.as_bytes())?;
+    screen.frame = frame;
+    // Changes are marked only until the next draw, which has to unmark them
+    screen.stale = !screen.changed.is_empty() || screen.fresh_output > 0;
+    screen.changed.clear();
+    screen.fresh_output = 0;
+    output_stream.flush()?;
+    drawn.frames += 1;
+    drawn.drawing += started.elapsed();
+    Ok(())
+}
+
+// Wait until the file descriptor has data to read, or the timeout elapses.
+// Returns whether it became readable.
+fn wait_readable(fd: RawFd, timeout: Duration) -> bool {
+    let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    let millis = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    // Safety: `pollfd` is a single valid entry that outlives the call.
+    unsafe { libc::poll(&mut pollfd, 1, millis) > 0 }
+}
+
+// What the debugger shows of a machine: a copy of its state, kept up to date
+// from frames, and what changed since it was last drawn
+struct Screen {
+    src: Arc<str>,
+    lines: Vec<Range<usize>>,  // Byte ranges of the source's lines
+    positions: Vec<(usize, usize)>,
+    cell_size: CellSize,
+    decimal: bool,
+    hex: bool,
+    ascii: bool,
+    theme: Theme,
+    tape: Tape,
+    data_ptr: usize,
+    prog_ctr: usize,
+    output: Vec<u8>,           // The tail of it
+    status: String,
+    mark: Option<usize>,
+    calls: Vec<String>,        // The call tree, if tracked
+    show_calls: bool,          // Whether it's expanded
+    changed: BTreeSet<usize>,  // Cells
+    fresh_output: usize,       // Bytes at the end of the output
+    stale: bool,               // Whether the terminal shows something else
+    frame: String,             // The last frame drawn, its space kept for the next
+}
+
+impl Screen {
+    fn new(machine: &Machine) -> Screen {
+        let spec = &machine.display_spec;
+        Screen {
+            src: machine.prog_src.clone(),
+            lines: machine.src_lines()
+                .scan(0, |start, line| {
+                    let range = *start..*start + line.len();
+                    *start = range.end + 1;
+                    Some(range)
+                })
+                .collect(),
+            positions: machine.prog.positions.clone(),
+            cell_size: machine.semantics.cell_size,
+            decimal: spec.decimal,
+            hex: spec.hex,
+            ascii: spec.ascii,
+            theme: spec.theme,
+            tape: machine.data.clone(),
+            data_ptr: machine.data_ptr,
+            prog_ctr: machine.prog_ctr,
+            output: machine.output[machine.output.len().saturating_sub(OUTPUT_SHOWN)..].to_vec(),
+            status: machine.status_line(),
+            mark: None,
+            calls: machine.call_lines(),
+            show_calls: false,
+            changed: BTreeSet::new(),
+            fresh_output: 0,
+            stale: true,
+            frame: String::new(),
+        }
+    }
+
+    // Bring the state up to date with a frame. The changes of frames not yet
+    // drawn add up.
+    fn apply(&mut self, frame: Frame) {
+        let diff = frame.diff;
+        self.stale |= !diff.is_empty() || frame.status != self.status || frame.mark != self.mark
+            || frame.calls != self.calls;
+        for change in &diff.cells {
+            self.tape.grow(change.index + 1);
+            self.tape[change.index] = change.after;
+            self.changed.insert(change.index);
+        }
+        self.output.truncate(self.output.len().saturating_sub(diff.output_removed));
+        self.output.extend_from_slice(&diff.output_added);
+        if self.output.len() > 2 * OUTPUT_SHOWN {
+            self.output.drain(..self.output.len() - OUTPUT_SHOWN);
+        }
+        self.fresh_output = (self.fresh_output.saturating_sub(diff.output_removed) + diff.output_added.len())
+            .min(self.output.len());
+        if let Some((_, to)) = diff.data_ptr {
+            self.data_ptr = to;
+            self.tape.grow(to + 1);
+        }
+        if let Some((_, to)) = diff.prog_ctr {
+            self.prog_ctr = to;
+        }
+        self.status = frame.status;
+        self.mark = frame.mark;
+        self.calls = frame.calls;
+    }
+
+    // Characters `write_data_cell` takes, which all cells of a size share
+    fn cell_width(&self) -> usize {
+        let mut width = 0;
+        if self.decimal {
+            width += self.cell_size.dec_width();
+        }
+        if self.hex {
+            width += 3 + self.cell_size.hex_width();  // " 0x" and the digits
+        }
+        if self.ascii {
+            width += 2;
+        }
+        width
+    }
+
+    // Write a data cell in decimal, hex, and ascii
+    fn write_data_cell(&self, out: &mut impl fmt::Write, cell: usize) -> fmt::Result {
+        let data = self.tape[cell];
+        if cell == self.data_ptr {
+            write!(out, "{}", color::Bg(self.theme.highlight))?;
+        }
+        if self.changed.contains(&cell) {
+            write!(out, "{}", color::Fg(self.theme.changed))?;
+        }
+        if self.decimal {
+            write!(out, "{:0w$}", data, w = self.cell_size.dec_width())?;
+        }
+        if self.hex {
+            write!(out, " 0x{:0w$x}", data, w = self.cell_size.hex_width())?;
+        }
+        if self.ascii {
+            // Printable ascii, or a blank
+            let ch = if (0x20..0x7f).contains(&data) { data as u8 as char } else { ' ' };
+            write!(out, " {}", ch)?;
+        }
+        if self.changed.contains(&cell) {
+            write!(out, "{}", color::Fg(color::Reset))?;
+        }
+        if cell == self.data_ptr {
+            write!(out, "{}", color::Bg(color::Reset))?;
+        }
+        Ok(())
+    }
+
+    // Write a line of source code with read-head highlighting, and the
+    // marked instruction in the changed color
+    fn write_src_line(&self, out: &mut impl fmt::Write, linum: usize) -> fmt::Result {
+        let line = &self.src[self.lines[linum].clone()];
+        // The bytes of the instruction at `pos`, if it's on this line.
+        // Positions count characters, which may be wider than a byte.
+        let span = |(x, y): (usize, usize)| {
+            (y == linum).then(|| line.char_indices().nth(x)).flatten()
+                .map(|(at, ch)| at..at + ch.len_utf8())
+        };
+        let head = span(self.positions[self.prog_ctr]);
+        let mark = self.mark.and_then(|i| span(self.positions[i]));
+        let mut spans: Vec<Range<usize>> = head.iter().chain(&mark).cloned().collect();
+        spans.sort_by_key(|span| span.start);
+        spans.dedup();
+        let theme = &self.theme;
+        let mut at = 0;
+        for span in spans {
+            let (is_head, is_mark) = (head.as_ref() == Some(&span), mark.as_ref() == Some(&span));
+            out.write_str(&line[at..span.start])?;
+            if is_head {
+                write!(out, "{}", color::Bg(theme.highlight))?;
+            }
+            if is_mark {
+                write!(out, "{}", color::Fg(theme.changed))?;
+            }
+            out.write_str(&line[span.clone()])?;
+            if is_mark {
+                write!(out, "{}", color::Fg(color::Reset))?;
+            }
+            if is_head {
+                write!(out, "{}", color::Bg(color::Reset))?;
+            }
+            at = span.end;
+        }
+        out.write_str(&line[at..])
+    }
+
+    // The output line, the call tree if tracked, collapsed to its heading
+    // unless shown, then the memory and source side by side, with what
+    // changed since the last draw marked
+    fn render(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        let (old, new) = self.output.split_at(self.output.len() - self.fresh_output);
+        write!(out, "{}\r\n", color::Fg(self.theme.output))?;
+        write_lossy(out, old)?;
+        write!(out, "{}", color::Fg(self.theme.changed))?;
+        write_lossy(out, new)?;
+        write!(out, "\r\n{}{}", color::Fg(color::Reset), self.status)?;
+        if !self.calls.is_empty() {
+            write!(out, "[{}] call tree\r\n", if self.show_calls { '-' } else { '+' })?;
+            if self.show_calls {
+                for line in &self.calls {
+                    write!(out, "{}\r\n", line)?;
+                }
+            }
+        }
+
+        let used = self.tape.nonzero_end(self.tape.len());
+        let rows = (0..std::cmp::max(used, self.data_ptr + 1))  // Data column
+            .zip_longest(0..self.lines.len());                  // Zip with source column
+        for cols in rows {
+            match cols {
+                EitherOrBoth::Both(cell, src) => {
+                    self.write_data_cell(out, cell)?;
+                    out.write_char(' ')?;
+                    self.write_src_line(out, src)?;
+                },
+                EitherOrBoth::Left(cell) => self.write_data_cell(out, cell)?,
+                EitherOrBoth::Right(src) => {
+                    write!(out, "{:w$} ", "", w = self.cell_width())?;
+                    self.write_src_line(out, src)?;
+                },
+            }
+            out.write_str("\r\n")?;
+        }
+        Ok(())
+    }
+}
+
+// Write bytes as text, with any that aren't UTF-8 replaced, as
+// `String::from_utf8_lossy` would but without allocating
+fn write_lossy(out: &mut impl fmt::Write, bytes: &[u8]) -> fmt::Result {
+    for chunk in bytes.utf8_chunks() {
+        out.write_str(chunk.valid())?;
+        if !chunk.invalid().is_empty() {
+            out.write_char(char::REPLACEMENT_CHARACTER)?;
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Machine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Screen::new(self).render(f)
+    }
+}
+
+impl Machine {
+    // The lines of the call tree, or none if it isn't tracked
+    fn call_lines(&self) -> Vec<String> {
+        self.call_tree.as_ref().map_or_else(Vec::new, |tree| tree.lines(&self.calls, self.steps))
+    }
+
+    // The loops around the next instruction with the iteration each is on,
+    // and where the cell under the pointer was last written, when tracked
+    fn status_line(&self) -> String {
+        let mut status = String::new();
+        let loops = self.loop_stack();
+        if self.iterations.is_some() && !loops.is_empty() {
+            let loops: Vec<String> = loops.iter().map(|&start| {
+                let (x, y) = self.prog.positions[start];
+                format!("{}:{} #{}", y + 1, x + 1, self.iteration(start).unwrap_or_default())
+            }).collect();
+            status += &format!("loops {}\r\n", loops.join(" > "));
+        }
+        if self.provenance.is_some() {
+            status += &match self.written_by(self.data_ptr) {
+                Some(write) => {
+                    let (x, y) = self.prog.positions[write.prog_ctr];
+                    format!("[{}] written at step {} by {}:{}\r\n", self.data_ptr, write.step, y + 1, x + 1)
+                },
+                None => format!("[{}] never written\r\n", self.data_ptr),
+            };
+        }
+        status
+    }
+}
@@ -0,0 +1,1506 @@
+#[cfg(feature = "cli")]
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+#[cfg(feature = "cli")]
+use core::fmt;
+use core::mem;
+use core::ops::{Bound, RangeBounds};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use alloc::sync::Arc;
+#[cfg(feature = "ui")]
+use core::sync::atomic::AtomicBool;
+#[cfg(feature = "std")]
+use std::sync::mpsc::{self, Receiver, Sender};
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::calls::{Call, CallTree};
+use crate::parse::{Command, Dialect, ParseError, Program, Remap, labels, parse_dialect, reparse_with_extensions};
+use crate::tape::{PAGE_LEN, Tape};
+#[cfg(feature = "ui")]
+use crate::debugger::Debugger;
+#[cfg(feature = "ui")]
+use crate::ui::DisplaySpec;
+
+pub const MEM_SIZE: usize = 30_000;
+
+// Steps between checks of the deadline and the memory limit. Reading the
+// clock, or adding up memory, every step would dominate the run time.
+pub(crate) const CHECK_INTERVAL: u64 = 4096;
+
+#[cfg(feature = "std")]
+pub use std::io::Error as IoError;
+
+// Failure to write output, where there is no `std::io`
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Error)]
+#[error("write failed")]
+pub struct IoError;
+
+// Where `,` reads from
+pub trait ByteSource {
+    // The next byte, or `None` at end of input
+    fn read_byte(&mut self) -> Option<u8>;
+
+    // Whether `read_byte` would answer now, with a byte or end of input,
+    // rather than wait for more. Only sources fed while the machine runs
+    // need say otherwise.
+    fn available(&mut self) -> bool {
+        true
+    }
+}
+
+// Where output and traces are written
+pub trait ByteSink {
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), IoError>;
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
+    }
+}
+
+// With `std`, any reader or writer will do. Read errors count as end of input.
+#[cfg(feature = "std")]
+impl<R: std::io::Read + ?Sized> ByteSource for R {
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut byte = [0];
+        (self.read(&mut byte).unwrap_or(0) == 1).then_some(byte[0])
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write + ?Sized> ByteSink for W {
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), IoError> {
+        self.write_all(bytes)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        std::io::Write::flush(self)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl ByteSource for &[u8] {
+    fn read_byte(&mut self) -> Option<u8> {
+        let (&byte, rest) = self.split_first()?;
+        *self = rest;
+        Some(byte)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl ByteSink for Vec<u8> {
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), IoError> {
+        self.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+// Input that is always at its end
+struct NoInput;
+
+impl ByteSource for NoInput {
+    fn read_byte(&mut self) -> Option<u8> {
+        None
+    }
+}
+
+// Width of a tape cell
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(rename_all = "kebab-case"))]
+pub enum CellSize {
+    #[cfg_attr(feature = "cli", value(name = "8"))]
+    #[cfg_attr(feature = "serde", serde(rename = "8"))]
+    U8,
+    #[cfg_attr(feature = "cli", value(name = "16"))]
+    #[cfg_attr(feature = "serde", serde(rename = "16"))]
+    U16,
+    #[cfg_attr(feature = "cli", value(name = "32"))]
+    #[cfg_attr(feature = "serde", serde(rename = "32"))]
+    U32,
+}
+
+impl CellSize {
+    pub fn max(self) -> u32 {
+        match self {
+            CellSize::U8 => 0xff,
+            CellSize::U16 => 0xffff,
+            CellSize::U32 => 0xffff_ffff,
+        }
+    }
+
+    // Number of hex digits needed to show a cell
+    pub fn hex_width(self) -> usize {
+        match self {
+            CellSize::U8 => 2,
+            CellSize::U16 => 4,
+            CellSize::U32 => 8,
+        }
+    }
+
+    // Number of decimal digits needed to show a cell
+    pub fn dec_width(self) -> usize {
+        match self {
+            CellSize::U8 => 3,
+            CellSize::U16 => 5,
+            CellSize::U32 => 10,
+        }
+    }
+}
+
+// What happens when a cell is incremented past its maximum or decremented
+// below zero
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(rename_all = "kebab-case"))]
+pub enum Overflow {
+    Wrap,
+    Saturate,
+    Error,
+}
+
+// What `,` stores once the input is exhausted
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(rename_all = "kebab-case"))]
+pub enum Eof {
+    Zero,
+    Max,
+    Unchanged,
+}
+
+// What happens when the data pointer leaves the tape
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(rename_all = "kebab-case"))]
+pub enum PtrBounds {
+    Error,
+    Wrap,
+    // Grow the tape to the right as needed; moving left of cell 0 is an error.
+    Extend,
+}
+
+// How the machine finds the code for an instruction. The strategies behave
+// the same; they are kept so their speed can be compared.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Dispatch {
+    #[default]
+    Match,  // Match on the command
+    Table,  // Call through a table holding each instruction's function
+}
+
+// Runtime semantics of the machine
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Semantics {
+    pub cell_size: CellSize,
+    pub tape_size: usize,
+    pub overflow: Overflow,
+    pub eof: Eof,
+    pub ptr_bounds: PtrBounds,
+    #[cfg_attr(feature = "serde", serde(default))]  // States saved before there were dialects are brainfuck
+    pub dialect: Dialect,
+}
+
+impl Default for Semantics {
+    fn default() -> Semantics {
+        Semantics {
+            cell_size: CellSize::U8,
+            tape_size: MEM_SIZE,
+            overflow: Overflow::Wrap,
+            eof: Eof::Zero,
+            ptr_bounds: PtrBounds::Error,
+            dialect: Dialect::Brainfuck,
+        }
+    }
+}
+
+#[cfg(feature = "cli")]
+impl fmt::Display for Semantics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tape_size = match self.ptr_bounds {
+            PtrBounds::Extend => "unbounded".to_owned(),
+            _ => self.tape_size.to_string(),
+        };
+        write!(f, "cell size: {} bits, tape size: {}, overflow: {}, eof: {}, pointer bounds: {}, dialect: {}",
+               value_name(&self.cell_size),
+               tape_size,
+               value_name(&self.overflow),
+               value_name(&self.eof),
+               value_name(&self.ptr_bounds),
+               value_name(&self.dialect))
+    }
+}
+
+// Name of an option value as written on the command line
+#[cfg(feature = "cli")]
+fn value_name<T: clap::ValueEnum>(value: &T) -> String {
+    value.to_possible_value()
+        .map(|v| v.get_name().to_owned())
+        .unwrap_or_default()
+}
+
+// Errors that halt a running program
+#[derive(Debug, Error)]
+pub enum RuntimeError {
+    #[error("data pointer moved left of the tape")]
+    PtrUnderflow,
+    #[error("data pointer moved right of the tape")]
+    PtrOverflow,
+    #[error("cell decremented below zero")]
+    CellUnderflow,
+    #[error("cell incremented past its maximum")]
+    CellOverflow,
+    #[error("step limit of {0} exceeded")]
+    StepLimit(u64),
+    #[error("output limit of {0} bytes exceeded")]
+    OutputLimit(usize),
+    #[error("tape limit of {0} cells exceeded")]
+    TapeLimit(usize),
+    #[error("memory limit of {0} bytes exceeded")]
+    MemoryLimit(usize),
+    #[error("canary cell {0} past the end of the tape was overwritten")]
+    CanaryOverwritten(usize),
+    #[error("time limit exceeded")]
+    Timeout,
+    #[error("run cancelled")]
+    Cancelled,
+    #[error("I/O error: {0}")]
+    Io(#[from] IoError),
+    #[error("{0}")]
+    Extension(String),  // Raised by an extension's handler
+    #[error("assertion failed: {0}")]
+    AssertionFailed(String),
+    #[error("procedure {0} isn't defined")]
+    UndefinedProcedure(u32),
+}
+
+// A runtime error, with where in the program and the run it happened
+#[derive(Debug, Error)]
+#[error("{}:{}: {error} (data pointer {data_ptr}, step {steps})", pos.1 + 1, pos.0 + 1)]
+pub struct Fault {
+    #[source]
+    pub error: RuntimeError,
+    pub prog_ctr: usize,
+    pub pos: (usize, usize),    // 0-based (column, line) of the instruction
+    pub data_ptr: usize,
+    pub steps: u64,
+}
+
+// Every read from the input, and how many of them the machine has made.
+// Going back to an earlier state moves `pos` back, and the reads after it
+// are made again from the log.
+#[derive(Default)]
+pub(crate) struct InputLog {
+    pub(crate) reads: Vec<Option<u8>>,
+    pub(crate) pos: usize,
+}
+
+// How a step changes a loop's iteration count
+#[derive(Clone, Copy)]
+pub(crate) enum Iteration {
+    First,
+    Next,
+    Previous,
+}
+
+// A test on everything written so far, made as each byte goes out
+#[derive(Clone, Debug, PartialEq)]
+pub enum OutputBreak {
+    Contains(Vec<u8>),  // The new byte completes this
+    Byte(usize),        // The new byte is the output's nth, counting from 1
+}
+
+impl OutputBreak {
+    // Whether the last byte of `output`, the `written`th byte written in
+    // all, sets this off
+    pub fn hit(&self, output: &[u8], written: usize) -> bool {
+        match self {
+            OutputBreak::Contains(pattern) => !pattern.is_empty() && output.ends_with(pattern),
+            OutputBreak::Byte(n) => written == *n,
+        }
+    }
+}
+
+// The most recent write to a cell: a `+`, `-` or `,`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Provenance {
+    pub step: u64,        // Counting from 1
+    pub prog_ctr: usize,  // Of the instruction
+}
+
+// Counts of what the interpreter did, for telling whether the machine or the
+// debugger's drawing is the bottleneck. The debugger adds its time.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Perf {
+    pub loops_entered: u64,   // `[` on a nonzero cell
+    pub loops_skipped: u64,   // `[` on zero, jumping past its `]`
+    pub loops_repeated: u64,  // `]` on a nonzero cell, jumping back
+    pub loops_exited: u64,    // `]` on zero
+    pub executing: Duration,  // In the debugger, carrying out actions
+    pub frames: u64,          // Drawn by the debugger
+    pub drawing: Duration,
+}
+
+// Roughly the heap memory a machine holds, and a debugger driving it, in
+// bytes
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MemoryUsage {
+    pub tape: usize,      // Pages allocated
+    pub output: usize,    // Output kept
+    pub tracking: usize,  // Profile, provenance, loop iterations and input log
+    pub history: usize,   // The debugger's steps and checkpoints
+    pub traces: usize,    // Tracepoint messages not yet taken
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.tape + self.output + self.tracking + self.history + self.traces
+    }
+}
+
+// What one executed instruction did
+#[derive(Clone, Debug, PartialEq)]
+pub struct StepEvent {
+    pub prog_ctr: usize,
+    pub op: char,
+    pub data_ptr: usize,       // Before the step
+    pub cell_delta: i64,       // Change to the cell at `data_ptr`
+    pub output: Option<u8>,    // Byte written, if any
+}
+
+// Iterator over the steps of a running machine. It ends when the program
+// halts, or after yielding the error that stopped it.
+pub struct Steps<'a> {
+    machine: &'a mut Machine,
+    failed: bool,
+}
+
+impl Iterator for Steps<'_> {
+    type Item = Result<StepEvent, RuntimeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let machine = &mut *self.machine;
+        if self.failed || machine.halted {
+            return None;
+        }
+        if machine.prog.is_empty() {
+            machine.halt();
+            return None;
+        }
+        let start = machine.step_start();
+        if let Err(err) = machine.advance() {
+            self.failed = true;
+            return Some(Err(err));
+        }
+        Some(Ok(machine.step_event(&start)))
+    }
+}
+
+// What a running machine reports to its subscribers
+#[derive(Clone, Debug, PartialEq)]
+pub enum VmEvent {
+    Step(StepEvent),
+    Output(u8),
+    InputRequest,
+    Breakpoint(usize),  // Reached the instruction at this index
+    Halt,
+}
+
+// Where a step began, for describing it once it has run
+struct StepStart {
+    prog_ctr: usize,
+    data_ptr: usize,
+    cell: u32,
+    output_len: usize,
+}
+
+type Hook<F> = Option<Box<F>>;
+
+type Handler = Box<dyn FnMut(&mut Machine) -> Result<(), RuntimeError> + Send>;
+
+// Executes an instruction, returning the index of the next one
+type StepFn = fn(&mut Machine) -> Result<usize, RuntimeError>;
+
+// Callbacks run as the machine executes
+#[derive(Default)]
+struct Hooks {
+    on_step: Hook<dyn FnMut(&StepEvent) + Send>,
+    on_output: Hook<dyn FnMut(u8) + Send>,
+    on_input_request: Hook<dyn FnMut() + Send>,
+    on_loop_enter: Hook<dyn FnMut(usize) + Send>,
+    on_loop_exit: Hook<dyn FnMut(usize) + Send>,
+    on_halt: Hook<dyn FnMut() + Send>,
+    extensions: BTreeMap<char, Handler>,
+    #[cfg(feature = "std")]
+    subscribers: Vec<Sender<VmEvent>>,
+}
+
+// Bounds on how much work a program may do
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    pub max_steps: Option<u64>,
+    pub max_output: Option<usize>,  // Bytes
+    pub max_tape: Option<usize>,    // Cells
+    pub max_memory: Option<usize>,  // Bytes, as `Machine::memory` counts them
+}
+
+// Language virtual machine
+pub struct Machine {
+    pub prog: Program,
+    pub data: Tape,
+    pub prog_ctr: usize,
+    pub data_ptr: usize,
+
+    // Cells from here on are all zero. Writes raise it; `used_cells` lowers
+    // it again when asked, so zeroing a cell costs nothing.
+    pub(crate) used: AtomicUsize,
+    pub prog_src: Arc<str>,  // Shared with the debugger's screen, not copied
+    pub labels: Vec<(String, usize)>,  // From `@label:` comments, by instruction index
+    #[cfg(feature = "ui")]
+    pub display_spec: DisplaySpec,
+    pub semantics: Semantics,
+    pub input: Box<dyn ByteSource + Send>,
+    pub output: Vec<u8>,  // All output written, or its tail when `output_tail` is set
+    pub output_tail: Option<usize>,  // Bytes of `output` kept, at least, when only its tail is wanted
+    pub(crate) output_dropped: usize,  // Bytes of output no longer kept
+    pub sink: Option<Box<dyn ByteSink + Send>>,  // Receives output as it is produced
+    unflushed: bool,  // Whether the sink has been written since it was last flushed
+    pub halted: bool,  // Whether the program ran off its end
+    pub steps: u64,
+    pub limits: Limits,
+    #[cfg(feature = "std")]
+    pub deadline: Option<Instant>,
+    pub trace: Option<Box<dyn ByteSink + Send>>,
+    pub profile: Option<Vec<u64>>,  // Execution count of each instruction
+    pub perf: Option<Perf>,
+    pub fusion: bool,  // Whether `run` may execute superoperators, when nothing watches its steps
+    pub dispatch: Dispatch,
+    table: Vec<StepFn>,  // By instruction, for `Dispatch::Table`
+    pub provenance: Option<Vec<Option<Provenance>>>,  // Last write to each cell, if tracked
+    pub iterations: Option<Vec<u64>>,  // By `[`, the iteration each loop is on or last ended on, if tracked
+    pub(crate) input_log: Option<InputLog>,  // For reading input again after going back
+    pub procedures: BTreeMap<u32, usize>,  // pbrain: the `(` defining each procedure, by number
+    pub calls: Vec<Call>,  // pbrain: calls not yet returned from, innermost last
+    pub call_tree: Option<CallTree>,  // pbrain: the calls made, if tracked
+    #[cfg(feature = "ui")]
+    pub source_changed: Option<Arc<AtomicBool>>,  // Set when the debugger should reload
+    hooks: Hooks,
+    output_breaks: Vec<OutputBreak>,
+    pub(crate) output_hit: Option<usize>,  // The output break set off by the last step
+}
+
+
+// Configures and builds a `Machine`. Unless given a display spec, the
+// machine runs headless; unless given an input, `,` sees end of input.
+pub struct MachineBuilder {
+    semantics: Semantics,
+    input: Box<dyn ByteSource + Send>,
+    sink: Option<Box<dyn ByteSink + Send>>,
+    output_tail: Option<usize>,
+    limits: Limits,
+    canaries: usize,
+    #[cfg(feature = "ui")]
+    display_spec: DisplaySpec,
+}
+
+impl Default for MachineBuilder {
+    fn default() -> MachineBuilder {
+        MachineBuilder {
+            semantics: Semantics::default(),
+            input: Box::new(NoInput),
+            sink: None,
+            output_tail: None,
+            limits: Limits::default(),
+            canaries: 0,
+            #[cfg(feature = "ui")]
+            display_spec: DisplaySpec { visible: false, ..DisplaySpec::new(1.0) },
+        }
+    }
+}
+
+impl MachineBuilder {
+    pub fn new() -> MachineBuilder {
+        MachineBuilder::default()
+    }
+
+    pub fn semantics(mut self, semantics: Semantics) -> MachineBuilder {
+        self.semantics = semantics;
+        self
+    }
+
+    pub fn cell_size(mut self, cell_size: CellSize) -> MachineBuilder {
+        self.semantics.cell_size = cell_size;
+        self
+    }
+
+    pub fn tape_size(mut self, tape_size: usize) -> MachineBuilder {
+        self.semantics.tape_size = tape_size;
+        self
+    }
+
+    pub fn overflow(mut self, overflow: Overflow) -> MachineBuilder {
+        self.semantics.overflow = overflow;
+        self
+    }
+
+    pub fn eof(mut self, eof: Eof) -> MachineBuilder {
+        self.semantics.eof = eof;
+        self
+    }
+
+    pub fn ptr_bounds(mut self, ptr_bounds: PtrBounds) -> MachineBuilder {
+        self.semantics.ptr_bounds = ptr_bounds;
+        self
+    }
+
+    pub fn input(mut self, input: impl ByteSource + Send + 'static) -> MachineBuilder {
+        self.input = Box::new(input);
+        self
+    }
+
+    // Also write each output byte here as it is produced
+    pub fn output(mut self, sink: impl ByteSink + Send + 'static) -> MachineBuilder {
+        self.sink = Some(Box::new(sink));
+        self
+    }
+
+    // Keep only the last `bytes` or so of output in `output`, for output
+    // that goes to a sink and needn't all stay in memory. Output breaks on
+    // a pattern longer than that may be missed.
+    pub fn output_tail(mut self, bytes: usize) -> MachineBuilder {
+        self.output_tail = Some(bytes);
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> MachineBuilder {
+        self.limits = limits;
+        self
+    }
+
+    // Follow a fixed tape with `cells` canary cells, for catching
+    // interpreter bugs that write off its end. They are checked every so
+    // often and when the program halts, or after every step while tracing,
+    // so that the fault names the step that wrote one.
+    pub fn canaries(mut self, cells: usize) -> MachineBuilder {
+        self.canaries = cells;
+        self
+    }
+
+    #[cfg(feature = "ui")]
+    pub fn display_spec(mut self, display_spec: DisplaySpec) -> MachineBuilder {
+        self.display_spec = display_spec;
+        self
+    }
+
+    pub fn build(self, program: String) -> Result<Machine, ParseError> {
+        // An extending tape starts with a single cell
+        let tape_size = match self.semantics.ptr_bounds {
+            PtrBounds::Extend => 1,
+            _ => self.semantics.tape_size,
+        };
+        let mut machine = Machine {
+            prog: Program::default(),
+
+            data: Tape::new(tape_size),
+            prog_ctr: 0,
+            data_ptr: 0,
+
+            prog_src: Arc::from(""),
+            labels: Vec::new(),
+            used: AtomicUsize::new(0),
+            #[cfg(feature = "ui")]
+            display_spec: self.display_spec,
+            semantics: self.semantics,
+            input: self.input,
+            output: Vec::new(),
+            output_tail: self.output_tail,
+            output_dropped: 0,
+            sink: self.sink,
+            unflushed: false,
+            halted: false,
+            steps: 0,
+            limits: self.limits,
+            #[cfg(feature = "std")]
+            deadline: None,
+            trace: None,
+            profile: None,
+            perf: None,
+            fusion: true,
+            dispatch: Dispatch::default(),
+            table: Vec::new(),
+            provenance: None,
+            iterations: None,
+            input_log: None,
+            procedures: BTreeMap::new(),
+            calls: Vec::new(),
+            call_tree: None,
+            #[cfg(feature = "ui")]
+            source_changed: None,
+            hooks: Hooks::default(),
+            output_breaks: Vec::new(),
+            output_hit: None,
+        };
+        if machine.semantics.ptr_bounds != PtrBounds::Extend {
+            machine.data.set_guard(self.canaries);
+        }
+        machine.load(program)?;
+
+        Ok(machine)
+    }
+}
+
+impl Machine {
+    pub fn builder() -> MachineBuilder {
+        MachineBuilder::new()
+    }
+
+    // Replace the program and rewind to its start, keeping the tape, data
+    // pointer and output. Procedures the old program defined are gone.
+    pub fn load(&mut self, program: String) -> Result<(), ParseError> {
+        let extensions = &self.hooks.extensions;
+        self.prog = parse_dialect(&program, self.semantics.dialect, |ch| extensions.contains_key(&ch))?;
+        self.table = self.prog.commands.iter().map(|&command| step_fn(command)).collect();
+        self.labels = labels(&program, &self.prog);
+        self.prog_src = program.into();
+        self.prog_ctr = 0;
+        self.halted = false;
+        if let Some(profile) = &mut self.profile {
+            *profile = vec![0; self.prog.len()];  // Counts were by the old program's instructions
+        }
+        if let Some(iterations) = &mut self.iterations {
+            iterations.clear();  // They were counted by the old program's brackets
+        }
+        self.procedures.clear();
+        self.calls.clear();
+        if let Some(tree) = &mut self.call_tree {
+            *tree = CallTree::default();
+        }
+        Ok(())
+    }
+
+    // Replace the program with an edit of it, keeping the tape, data pointer,
+    // output and step count, and the machine's place: the program counter
+    // moves along with its instruction, or to the start of the edit if the
+    // edit replaced it. Counts kept by instruction move along too, and are
+    // lost for edited instructions, as are procedures defined by an edited
+    // `(`. Calls are kept unless the edit took out a `:` one returns to.
+    pub fn reload(&mut self, program: String) -> Result<Remap, ParseError> {
+        let extensions = &self.hooks.extensions;
+        let (prog, remap) = reparse_with_extensions(&self.prog, &self.prog_src, &program,
+                                                    |ch| extensions.contains_key(&ch))?;
+        self.prog = prog;
+        self.table = self.prog.commands.iter().map(|&command| step_fn(command)).collect();
+        self.labels = labels(&program, &self.prog);
+        self.prog_src = program.into();
+        let len = self.prog.len();
+        if let Some(profile) = &mut self.profile {
+            *profile = remapped(profile, &remap, len);
+        }
+        if let Some(iterations) = &mut self.iterations {
+            *iterations = remapped(iterations, &remap, len);
+        }
+        if let Some(writes) = &mut self.provenance {
+            for write in writes.iter_mut() {
+                *write = write.and_then(|write| {
+                    Some(Provenance { prog_ctr: remap.index(write.prog_ctr)?, ..write })
+                });
+            }
+        }
+        self.procedures = mem::take(&mut self.procedures).into_iter()
+            .filter_map(|(procedure, start)| Some((procedure, remap.index(start)?)))
+            .collect();
+        let calls: Option<Vec<Call>> = self.calls.iter()
+            .map(|&call| Some(Call { at: remap.index(call.at)?, ..call }))
+            .collect();
+        self.calls = calls.unwrap_or_default();
+        if let Some(tree) = &mut self.call_tree {
+            tree.follow(&self.calls);
+        }
+        self.prog_ctr = remap.index(self.prog_ctr).unwrap_or(remap.kept);
+        if self.prog_ctr >= len {
+            // The edit took out everything from here on
+            self.prog_ctr = len.saturating_sub(1);
+            self.halted = true;
+        }
+        Ok(remap)
+    }
+
+    // Roughly the memory the machine holds. A debugger adds its own to this.
+    pub fn memory(&self) -> MemoryUsage {
+        let counts = |counts: &Option<Vec<u64>>| {
+            counts.as_ref().map_or(0, |counts| counts.capacity() * mem::size_of::<u64>())
+        };
+        MemoryUsage {
+            tape: self.data.pages() * PAGE_LEN * mem::size_of::<u32>(),
+            output: self.output.capacity(),
+            tracking: counts(&self.profile)
+                + counts(&self.iterations)
+                + self.provenance.as_ref().map_or(0, |writes| writes.capacity() * mem::size_of::<Option<Provenance>>())
+                + self.input_log.as_ref().map_or(0, |log| log.reads.capacity() * mem::size_of::<Option<u8>>())
+                + self.procedures.len() * mem::size_of::<(u32, usize)>()
+                + self.calls.capacity() * mem::size_of::<Call>()
+                + self.call_tree.as_ref().map_or(0, CallTree::footprint),
+            ..MemoryUsage::default()
+        }
+    }
+
+    // The lines of the program's source
+    pub fn src_lines(&self) -> impl Iterator<Item = &str> + '_ {
+        self.prog_src.split('\n')
+    }
+
+    // Line `line` of the program's source, counting from 0, or `""` past the end
+    pub fn src_line(&self, line: usize) -> &str {
+        self.src_lines().nth(line).unwrap_or("")
+    }
+
+    // Run the machine to termination.
+    pub fn run(&mut self) -> Result<(), RuntimeError> {
+        if self.prog.is_empty() {
+            self.halt();
+            return Ok(());
+        }
+        #[cfg(feature = "ui")]
+        if self.display_spec.visible {
+            // The debugger holds the machine for the session, then hands it back
+            let placeholder = Machine::builder().tape_size(1).build(String::new())
+                .expect("the empty program always parses");
+            let history = self.display_spec.history;
+            let mut debugger = Debugger::new(core::mem::replace(self, placeholder));
+            debugger.set_history_budget(history);
+            let result = debugger.run_visual();
+            *self = debugger.into_machine();
+            return result;
+        }
+        // A halted machine rests on its last instruction, which has run
+        if self.halted {
+            return Ok(());
+        }
+        if self.fusible() {
+            return self.run_fused();
+        }
+        while self.advance()? { }
+        Ok(())
+    }
+
+    // Whether `run` may execute superoperators, which skip the steps in
+    // them: only if allowed, nothing is watching the steps, and no extension
+    // handler could change the program under them
+    fn fusible(&self) -> bool {
+        self.fusion
+            && self.hooks.extensions.is_empty()
+            && self.trace.is_none()
+            && self.profile.is_none()
+            && self.provenance.is_none()
+            && self.iterations.is_none()
+            && self.hooks.on_step.is_none()
+            && self.hooks.on_loop_enter.is_none()
+            && self.hooks.on_loop_exit.is_none()
+            && !self.subscribed()
+    }
+
+    // Whether the next instruction is a `,` that would wait for input
+    pub(crate) fn awaiting_input(&mut self) -> bool {
+        !self.halted
+            && self.prog.commands.get(self.prog_ctr) == Some(&Command::Input)
+            && self.input_log.as_ref().is_none_or(|log| log.pos == log.reads.len())
+            && !self.input.available()
+    }
+
+    // Place an error from this machine's last run in the program
+    pub fn fault(&self, error: RuntimeError) -> Fault {
+        let pos = self.prog.positions.get(self.prog_ctr).copied().unwrap_or_default();
+        Fault { error, prog_ctr: self.prog_ctr, pos, data_ptr: self.data_ptr, steps: self.steps }
+    }
+
+    // The last write to the cell at `index`, if provenance is tracked and the
+    // cell has been written
+    pub fn written_by(&self, index: usize) -> Option<Provenance> {
+        self.provenance.as_ref()?.get(index).copied().flatten()
+    }
+
+    // Update the performance counters, if kept
+    pub(crate) fn count_perf(&mut self, count: impl FnOnce(&mut Perf)) {
+        if let Some(perf) = &mut self.perf {
+            count(perf);
+        }
+    }
+
+    // Note that the current step wrote the cell under the pointer
+    fn note_write(&mut self) {
+        let write = Provenance { step: self.steps, prog_ctr: self.prog_ctr };
+        self.set_written_by(self.data_ptr, Some(write));
+    }
+
+    pub(crate) fn set_written_by(&mut self, index: usize, write: Option<Provenance>) {
+        if let Some(writes) = &mut self.provenance {
+            if writes.len() <= index {
+                writes.resize(index + 1, None);
+            }
+            writes[index] = write;
+        }
+    }
+
+    // The iteration, counting from 1, that the loop opened by the `[` at
+    // `start` is on, or ended on if it isn't running. `None` if iterations
+    // aren't tracked or the loop never ran.
+    pub fn iteration(&self, start: usize) -> Option<u64> {
+        self.iterations.as_ref()?.get(start).copied().filter(|&n| n > 0)
+    }
+
+    // The `[` of each loop around the next instruction, outermost first.
+    // None are running once the machine halts.
+    pub fn loop_stack(&self) -> Vec<usize> {
+        let mut loops = Vec::new();
+        if self.halted {
+            return loops;
+        }
+        for (i, command) in self.prog.commands[..self.prog_ctr.min(self.prog.len())].iter().enumerate() {
+            match command {
+                Command::JumpForward(_) => loops.push(i),
+                Command::JumpBackward(_) => { loops.pop(); },
+                _ => {},
+            }
+        }
+        loops
+    }
+
+    // Start the loop at `start` on its first iteration, or move it on to its
+    // next one, or back to its last
+    pub(crate) fn count_iteration(&mut self, start: usize, change: Iteration) {
+        if let Some(iterations) = &mut self.iterations {
+            if iterations.len() <= start {
+                iterations.resize(start + 1, 0);
+            }
+            iterations[start] = match change {
+                Iteration::First => 1,
+                Iteration::Next => iterations[start] + 1,
+                Iteration::Previous => iterations[start].saturating_sub(1),
+            };
+        }
+    }
+
+    // The instruction a label names
+    pub fn label(&self, name: &str) -> Option<usize> {
+        self.labels.iter().find(|(label, _)| label == name).map(|&(_, index)| index)
+    }
+
+    // The first label naming the instruction at `index`
+    pub fn label_at(&self, index: usize) -> Option<&str> {
+        self.labels.iter().find(|&&(_, i)| i == index).map(|(label, _)| label.as_str())
+    }
+
+    pub fn cell(&self, index: usize) -> Option<u32> {
+        self.data.get(index)
+    }
+
+    // The cells up to the last nonzero one
+    pub fn used_cells(&self) -> Vec<u32> {
+        let used = self.data.nonzero_end(self.used.load(Ordering::Relaxed));
+        self.used.store(used, Ordering::Relaxed);
+        self.cells(..used)
+    }
+
+    // Note that the cell at `index` was written, and may no longer be zero
+    pub(crate) fn touch(&mut self, index: usize) {
+        if self.data[index] != 0 && index >= *self.used.get_mut() {
+            *self.used.get_mut() = index + 1;
+        }
+    }
+
+    // The cells in `range`, clipped to the tape
+    pub fn cells(&self, range: impl RangeBounds<usize>) -> Vec<u32> {
+        let len = self.data.len();
+        let start = match range.start_bound() {
+            Bound::Included(&i) => i,
+            Bound::Excluded(&i) => i.saturating_add(1),
+            Bound::Unbounded => 0,
+        }.min(len);
+        let end = match range.end_bound() {
+            Bound::Included(&i) => i.saturating_add(1),
+            Bound::Excluded(&i) => i,
+            Bound::Unbounded => len,
+        }.clamp(start, len);
+        (start..end).map(|i| self.data[i]).collect()
+    }
+
+    // Indices and values of the cells that aren't zero, in tape order
+    pub fn nonzero_cells(&self) -> impl Iterator<Item = (usize, u32)> + '_ {
+        self.data.nonzero()
+    }
+
+    // The index of the first cell holding `value`
+    pub fn find(&self, value: u32) -> Option<usize> {
+        self.data.position(value)
+    }
+
+    // Step through the program one instruction at a time, observing each
+    pub fn steps(&mut self) -> Steps<'_> {
+        Steps { machine: self, failed: false }
+    }
+
+    // Call `hook` after each instruction executes
+    pub fn on_step(&mut self, hook: impl FnMut(&StepEvent) + Send + 'static) {
+        self.hooks.on_step = Some(Box::new(hook));
+    }
+
+    // Call `hook` with each byte the program writes
+    pub fn on_output(&mut self, hook: impl FnMut(u8) + Send + 'static) {
+        self.hooks.on_output = Some(Box::new(hook));
+    }
+
+    // Call `hook` just before the program reads a byte
+    pub fn on_input_request(&mut self, hook: impl FnMut() + Send + 'static) {
+        self.hooks.on_input_request = Some(Box::new(hook));
+    }
+
+    // Call `hook` with the index of a loop's `[` when the loop is entered,
+    // rather than skipped
+    pub fn on_loop_enter(&mut self, hook: impl FnMut(usize) + Send + 'static) {
+        self.hooks.on_loop_enter = Some(Box::new(hook));
+    }
+
+    // Call `hook` with the index of a loop's `[` when the loop finishes
+    pub fn on_loop_exit(&mut self, hook: impl FnMut(usize) + Send + 'static) {
+        self.hooks.on_loop_exit = Some(Box::new(hook));
+    }
+
+    // Call `hook` when the program runs off its end
+    pub fn on_halt(&mut self, hook: impl FnMut() + Send + 'static) {
+        self.hooks.on_halt = Some(Box::new(hook));
+    }
+
+    // Watch the output for `brk`, returning its index. A budgeted run stops
+    // after the step that sets one off, as does the debugger.
+    pub fn break_on_output(&mut self, brk: OutputBreak) -> usize {
+        self.output_breaks.push(brk);
+        self.output_breaks.len() - 1
+    }
+
+    // Stop watching for the output break at `index`; later ones move down
+    pub fn clear_output_break(&mut self, index: usize) -> Option<OutputBreak> {
+        (index < self.output_breaks.len()).then(|| self.output_breaks.remove(index))
+    }
+
+    pub fn output_breaks(&self) -> &[OutputBreak] {
+        &self.output_breaks
+    }
+
+    // The index of the output break the last step set off, if any
+    pub fn output_hit(&self) -> Option<usize> {
+        self.output_hit
+    }
+
+    // Note which output break, if any, the byte just written sets off
+    pub(crate) fn check_output_breaks(&mut self) {
+        self.output_hit = self.output_breaks.iter().position(|brk| brk.hit(&self.output, self.output_len()));
+    }
+
+    // Make `ch` an instruction that calls `handler`, where it would otherwise
+    // be a comment. The handler may change anything about the machine, but
+    // the debugger can only undo changes to the pointer and the cell it
+    // pointed at. A new instruction moves those after it along, so bind
+    // extensions before running or setting breakpoints.
+    pub fn extension(&mut self, ch: char,
+                     handler: impl FnMut(&mut Machine) -> Result<(), RuntimeError> + Send + 'static) {
+        self.hooks.extensions.insert(ch, Box::new(handler));
+        if self.prog_src.contains(ch) {
+            let extensions = &self.hooks.extensions;
+            self.prog = parse_dialect(&self.prog_src, self.prog.dialect, |ch| extensions.contains_key(&ch))
+                .expect("binding an extension doesn't unbalance brackets");
+            self.table = self.prog.commands.iter().map(|&command| step_fn(command)).collect();
+            self.labels = labels(&self.prog_src, &self.prog);
+        }
+    }
+
+    // Check the assertion under the read head
+    fn check_assertion(&self) -> Result<(), RuntimeError> {
+        let Command::Assert(i) = self.prog.commands[self.prog_ctr] else {
+            return Ok(());
+        };
+        let condition = &self.prog.conditions[i as usize];
+        if condition.holds(self) {
+            Ok(())
+        } else {
+            Err(RuntimeError::AssertionFailed(condition.to_string()))
+        }
+    }
+
+    // Run the handler for an extension instruction. It is lent the whole
+    // machine, so it's taken out of the table for the call.
+    fn call_extension(&mut self, ch: char) -> Result<(), RuntimeError> {
+        let Some(mut handler) = self.hooks.extensions.remove(&ch) else {
+            return Ok(());
+        };
+        let result = handler(self);
+        self.hooks.extensions.insert(ch, handler);
+        // It could have written any cell
+        *self.used.get_mut() = self.data.len();
+        result?;
+        if self.data_ptr >= self.data.len() {
+            return Err(RuntimeError::PtrOverflow);
+        }
+        Ok(())
+    }
+
+    // Receive an event for each step, output byte, input request,
+    // debugger breakpoint and halt. Events stop once the receiver is dropped.
+    #[cfg(feature = "std")]
+    pub fn subscribe(&mut self) -> Receiver<VmEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.hooks.subscribers.push(sender);
+        receiver
+    }
+
+    pub(crate) fn subscribed(&self) -> bool {
+        #[cfg(feature = "std")]
+        return !self.hooks.subscribers.is_empty();
+        #[cfg(not(feature = "std"))]
+        false
+    }
+
+    // Send an event to every subscriber still listening
+    pub(crate) fn emit(&mut self, event: VmEvent) {
+        #[cfg(feature = "std")]
+        self.hooks.subscribers.retain(|sender| sender.send(event.clone()).is_ok());
+        #[cfg(not(feature = "std"))]
+        let _ = event;
+    }
+
+    pub(crate) fn halt(&mut self) {
+        self.halted = true;
+        if let Some(hook) = &mut self.hooks.on_halt {
+            hook();
+        }
+        if self.subscribed() {
+            self.emit(VmEvent::Halt);
+        }
+    }
+
+    fn step_start(&self) -> StepStart {
+        StepStart {
+            prog_ctr: self.prog_ctr,
+            data_ptr: self.data_ptr,
+            cell: self.data[self.data_ptr],
+            output_len: self.output_len(),
+        }
+    }
+
+    fn step_event(&self, start: &StepStart) -> StepEvent {
+        StepEvent {
+            prog_ctr: start.prog_ctr,
+            op: self.prog.chars[start.prog_ctr],
+            data_ptr: start.data_ptr,
+            cell_delta: self.data[start.data_ptr] as i64 - start.cell as i64,
+            output: self.output.last().copied().filter(|_| self.output_len() > start.output_len),
+        }
+    }
+
+    // Execute the next instruction and move on to the one after it, or the
+    // one a jump lands on; returns false once the program halts
+    pub fn advance(&mut self) -> Result<bool, RuntimeError> {
+        if let Some(max_steps) = self.limits.max_steps {
+            if self.steps >= max_steps {
+                return Err(RuntimeError::StepLimit(max_steps));
+            }
+        }
+        if self.steps.is_multiple_of(CHECK_INTERVAL) {
+            self.check_periodically()?;
+        }
+        self.steps += 1;
+        self.output_hit = None;
+        if let Some(counts) = &mut self.profile {
+            counts[self.prog_ctr] += 1;
+        }
+        let observed = self.hooks.on_step.is_some() || self.subscribed();
+        let start = observed.then(|| self.step_start());
+        let next = if self.trace.is_some() {
+            self.execute_traced()?
+        } else {
+            self.execute()?
+        };
+        if let Some(start) = start {
+            let event = self.step_event(&start);
+            if let Some(hook) = &mut self.hooks.on_step {
+                hook(&event);
+            }
+            if self.subscribed() {
+                self.emit(VmEvent::Step(event));
+            }
+        }
+        // A halted machine rests on its last instruction
+        if next == self.prog.len() {
+            self.check_canaries()?;
+            self.prog_ctr = next - 1;
+            self.halt();
+            return Ok(false);
+        }
+        self.prog_ctr = next;
+        Ok(true)
+    }
+
+    // The checks too slow to make every step: the deadline, the memory limit
+    // and the canaries
+    fn check_periodically(&self) -> Result<(), RuntimeError> {
+        #[cfg(feature = "std")]
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(RuntimeError::Timeout);
+        }
+        if let Some(max_memory) = self.limits.max_memory {
+            if self.memory().total() > max_memory {
+                return Err(RuntimeError::MemoryLimit(max_memory));
+            }
+        }
+        self.check_canaries()
+    }
+
+    // Fail if a write has strayed off the end of the tape onto a canary
+    pub(crate) fn check_canaries(&self) -> Result<(), RuntimeError> {
+        match self.data.overwritten_canary() {
+            Some(index) => Err(RuntimeError::CanaryOverwritten(index)),
+            None => Ok(()),
+        }
+    }
+
+    // Execute the command under the read head, returning the index of the
+    // next one
+    fn execute(&mut self) -> Result<usize, RuntimeError> {
+        match self.dispatch {
+            Dispatch::Match => self.execute_match(),
+            Dispatch::Table => (self.table[self.prog_ctr])(self),
+        }
+    }
+
+    fn execute_match(&mut self) -> Result<usize, RuntimeError> {
+        match self.prog.commands[self.prog_ctr] {
+            Command::JumpForward(i) => { return Ok(self.jmp_eq(i as usize)); },
+            Command::JumpBackward(i) => { return Ok(self.jmp_ne(i as usize)); },
+            Command::DecPtr => { self.dec_ptr()?; },
+            Command::IncPtr => { self.inc_ptr()?; },
+            Command::DecData => { self.dec_data()?; self.note_write(); },
+            Command::IncData => { self.inc_data()?; self.note_write(); },
+            Command::Output => { self.write_data()?; },
+            Command::Input => { self.read_data()?; self.note_write(); },
+            Command::Extension(ch) => { self.call_extension(ch)?; },
+            Command::Assert(_) => { self.check_assertion()?; },
+            Command::ProcStart(end) => { return Ok(self.define(end as usize)); },
+            Command::ProcEnd(_) => { return Ok(self.ret()); },
+            Command::Call => { return self.call(); },
+        }
+        Ok(self.prog_ctr + 1)
+    }
+
+    // Execute the command under the read head, recording it in the trace as
+    // `step op ptr before after`, where `before` and `after` are the cells
+    // under the data pointer on either side of the step
+    fn execute_traced(&mut self) -> Result<usize, RuntimeError> {
+        let (op, ptr, before) = (self.prog.chars[self.prog_ctr], self.data_ptr, self.data[self.data_ptr]);
+        let label = self.label_at(self.prog_ctr).map(|label| format!(" @{}", label)).unwrap_or_default();
+        let result = self.execute();
+        let after = self.data[self.data_ptr];
+        if let Some(trace) = &mut self.trace {
+            let line = format!("{} {} {} {} {}{}\n", self.steps, op, ptr, before, after, label);
+            trace.write_bytes(line.as_bytes())?;
+        }
+        // Checked every step, so that a fault is the step that wrote one
+        let next = result?;
+        self.check_canaries()?;
+        Ok(next)
+    }
+
+    // Move the data pointer left, subject to the bounds policy
+    fn dec_ptr(&mut self) -> Result<(), RuntimeError> {
+        if self.data_ptr > 0 {
+            self.data_ptr -= 1;
+            return Ok(());
+        }
+        match self.semantics.ptr_bounds {
+            PtrBounds::Wrap => { self.data_ptr = self.data.len() - 1; },
+            PtrBounds::Error | PtrBounds::Extend => {
+                return Err(RuntimeError::PtrUnderflow);
+            },
+        }
+        Ok(())
+    }
+
+    // Move the data pointer right, subject to the bounds policy
+    fn inc_ptr(&mut self) -> Result<(), RuntimeError> {
+        if let Some(max_tape) = self.limits.max_tape {
+            if self.data_ptr + 1 >= max_tape {
+                return Err(RuntimeError::TapeLimit(max_tape));
+            }
+        }
+        if self.data_ptr + 1 < self.data.len() {
+            self.data_ptr += 1;
+            return Ok(());
+        }
+        match self.semantics.ptr_bounds {
+            PtrBounds::Wrap => { self.data_ptr = 0; },
+            PtrBounds::Extend => {
+                self.data.grow(self.data.len() + 1);
+                self.data_ptr += 1;
+            },
+            PtrBounds::Error => { return Err(RuntimeError::PtrOverflow); },
+        }
+        Ok(())
+    }
+
+    // Emit the data cell. Only the low byte of a wide cell is emitted.
+    fn write_data(&mut self) -> Result<(), RuntimeError> {
+        if let Some(max_output) = self.limits.max_output {
+            if self.output_len() >= max_output {
+                return Err(RuntimeError::OutputLimit(max_output));
+            }
+        }
+        let byte = self.data[self.data_ptr] as u8;
+        self.output.push(byte);
+        if let Some(tail) = self.output_tail {
+            // Dropping a tail's worth at a time keeps the cost per byte constant
+            if self.output.len() >= 2 * tail.max(1) {
+                let dropped = self.output.len() - tail;
+                self.output.drain(..dropped);
+                self.output_dropped += dropped;
+            }
+        }
+        if !self.output_breaks.is_empty() {
+            self.check_output_breaks();
+        }
+        if let Some(hook) = &mut self.hooks.on_output {
+            hook(byte);
+        }
+        if self.subscribed() {
+            self.emit(VmEvent::Output(byte));
+        }
+        if let Some(sink) = &mut self.sink {
+            sink.write_bytes(&[byte])?;
+            self.unflushed = true;
+        }
+        Ok(())
+    }
+
+    // Bytes of output written in all, including those no longer kept
+    pub fn output_len(&self) -> usize {
+        self.output_dropped + self.output.len()
+    }
+
+    // Jump past the matching `]` at `i` if zero under read head
+    fn jmp_eq(&mut self, i: usize) -> usize {
+        if self.data[self.data_ptr] == 0 {
+            self.count_perf(|perf| perf.loops_skipped += 1);
+            return i + 1;
+        }
+        self.count_perf(|perf| perf.loops_entered += 1);
+        self.count_iteration(self.prog_ctr, Iteration::First);
+        if let Some(hook) = &mut self.hooks.on_loop_enter {
+            hook(self.prog_ctr);
+        }
+        self.prog_ctr + 1
+    }
+
+    // Jump past the matching `[` at `i` if nonzero under read head
+    fn jmp_ne(&mut self, i: usize) -> usize {
+        if self.data[self.data_ptr] != 0 {
+            self.count_perf(|perf| perf.loops_repeated += 1);
+            self.count_iteration(i, Iteration::Next);
+            return i + 1;
+        }
+        self.count_perf(|perf| perf.loops_exited += 1);
+        if let Some(hook) = &mut self.hooks.on_loop_exit {
+            hook(i);
+        }
+        self.prog_ctr + 1
+    }
+
+    // Define the procedure the cell under the pointer numbers as the body of
+    // the `(` here, replacing any it had, and skip to past its `)` at `end`
+    fn define(&mut self, end: usize) -> usize {
+        self.procedures.insert(self.data[self.data_ptr], self.prog_ctr);
+        end + 1
+    }
+
+    // Call the procedure the cell under the pointer numbers, returning the
+    // index of its first instruction
+    fn call(&mut self) -> Result<usize, RuntimeError> {
+        let procedure = self.data[self.data_ptr];
+        let start = *self.procedures.get(&procedure).ok_or(RuntimeError::UndefinedProcedure(procedure))?;
+        // The step counter already counts this one
+        self.push_call(Call { at: self.prog_ctr, procedure, step: self.steps - 1 }, true);
+        Ok(start + 1)
+    }
+
+    // Return from the innermost call to after its `:`. Outside any call, a
+    // `)` does nothing.
+    fn ret(&mut self) -> usize {
+        match self.pop_call(true) {
+            Some(call) => call.at + 1,
+            None => self.prog_ctr + 1,
+        }
+    }
+
+    // Start a call, counting it in the call tree if `count`: a step the
+    // debugger redoes was counted the first time
+    pub(crate) fn push_call(&mut self, call: Call, count: bool) {
+        if let Some(tree) = &mut self.call_tree {
+            tree.enter(call.procedure, count);
+        }
+        self.calls.push(call);
+    }
+
+    // End the innermost call, adding its steps to the call tree if `count`
+    pub(crate) fn pop_call(&mut self, count: bool) -> Option<Call> {
+        let call = self.calls.pop()?;
+        let steps = self.steps - call.step;
+        if let Some(tree) = &mut self.call_tree {
+            tree.leave(count.then_some(steps));
+        }
+        Some(call)
+    }
+
+    // Decrement the data cell
+    fn dec_data(&mut self) -> Result<(), RuntimeError> {
+        self.data[self.data_ptr] = match self.data[self.data_ptr] {
+            0 => match self.semantics.overflow {
+                Overflow::Wrap => self.semantics.cell_size.max(),
+                Overflow::Saturate => 0,
+                Overflow::Error => return Err(RuntimeError::CellUnderflow),
+            },
+            cell => cell - 1,
+        };
+        self.touch(self.data_ptr);
+        Ok(())
+    }
+
+    // Read a byte into the data cell, subject to the EOF policy. Output still
+    // buffered goes out first, in case it prompts for this input.
+    fn read_data(&mut self) -> Result<(), RuntimeError> {
+        if self.unflushed {
+            if let Some(sink) = &mut self.sink {
+                sink.flush()?;
+            }
+            self.unflushed = false;
+        }
+        if let Some(hook) = &mut self.hooks.on_input_request {
+            hook();
+        }
+        if self.subscribed() {
+            self.emit(VmEvent::InputRequest);
+        }
+        let byte = match &mut self.input_log {
+            Some(log) => {
+                if log.pos == log.reads.len() {
+                    log.reads.push(self.input.read_byte());
+                }
+                log.pos += 1;
+                log.reads[log.pos - 1]
+            },
+            None => self.input.read_byte(),
+        };
+        let value = match byte {
+            Some(byte) => byte as u32,
+            None => match self.semantics.eof {
+                Eof::Zero => 0,
+                Eof::Max => self.semantics.cell_size.max(),
+                Eof::Unchanged => return Ok(()),
+            },
+        };
+        self.data[self.data_ptr] = value;
+        self.touch(self.data_ptr);
+        Ok(())
+    }
+
+    // Increment the data cell.
+    fn inc_data(&mut self) -> Result<(), RuntimeError> {
+        let max = self.semantics.cell_size.max();
+        self.data[self.data_ptr] = match self.data[self.data_ptr] {
+            cell if cell == max => match self.semantics.overflow {
+                Overflow::Wrap => 0,
+                Overflow::Saturate => max,
+                Overflow::Error => return Err(RuntimeError::CellOverflow),
+            },
+            cell => cell + 1,
+        };
+        self.touch(self.data_ptr);
+        Ok(())
+    }
+}
+
+// The function that executes `command`, for table dispatch. Those needing
+// the command's operand read it from the program.
+fn step_fn(command: Command) -> StepFn {
+    match command {
+        Command::JumpForward(_) => |machine| {
+            let Command::JumpForward(i) = machine.prog.commands[machine.prog_ctr] else { unreachable!() };
+            Ok(machine.jmp_eq(i as usize))
+        },
+        Command::JumpBackward(_) => |machine| {
+            let Command::JumpBackward(i) = machine.prog.commands[machine.prog_ctr] else { unreachable!() };
+            Ok(machine.jmp_ne(i as usize))
+        },
+        Command::DecPtr => |machine| {
+            machine.dec_ptr()?;
+            Ok(machine.prog_ctr + 1)
+        },
+        Command::IncPtr => |machine| {
+            machine.inc_ptr()?;
+            Ok(machine.prog_ctr + 1)
+        },
+        Command::DecData => |machine| {
+            machine.dec_data()?;
+            machine.note_write();
+            Ok(machine.prog_ctr + 1)
+        },
+        Command::IncData => |machine| {
+            machine.inc_data()?;
+            machine.note_write();
+            Ok(machine.prog_ctr + 1)
+        },
+        Command::Output => |machine| {
+            machine.write_data()?;
+            Ok(machine.prog_ctr + 1)
+        },
+        Command::Input => |machine| {
+            machine.read_data()?;
+            machine.note_write();
+            Ok(machine.prog_ctr + 1)
+        },
+        Command::Extension(_) => |machine| {
+            let Command::Extension(ch) = machine.prog.commands[machine.prog_ctr] else { unreachable!() };
+            machine.call_extension(ch)?;
+            Ok(machine.prog_ctr + 1)
+        },
+        Command::Assert(_) => |machine| {
+            machine.check_assertion()?;
+            Ok(machine.prog_ctr + 1)
+        },
+        Command::ProcStart(_) => |machine| {
+            let Command::ProcStart(end) = machine.prog.commands[machine.prog_ctr] else { unreachable!() };
+            Ok(machine.define(end as usize))
+        },
+        Command::ProcEnd(_) => |machine| Ok(machine.ret()),
+        Command::Call => |machine| machine.call(),
+    }
+}
+
+// Counts kept by instruction, moved to where `remap` puts the instructions
+fn remapped(counts: &[u64], remap: &Remap, len: usize) -> Vec<u64> {
+    let mut moved = vec![0; len];
+    for (index, &count) in counts.iter().enumerate() {
+        if let Some(to) = remap.index(index).filter(|&to| to < len) {
+            moved[to] = count;
+        }
+    }
+    moved
+}
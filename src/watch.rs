@@ -0,0 +1,83 @@
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use notify::{Event, RecursiveMode, Watcher};
+
+use crate::cli::RunArgs;
+use crate::{exit, run_once};
+
+// How often to look for changes while waiting
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Editors often save in several steps; wait this long for them to finish
+const SETTLE_TIME: Duration = Duration::from_millis(50);
+
+// Run the program, then run it again each time its source file changes. A
+// debugger interrupted by the change picks up where it was in the edited
+// program. Only quitting the debugger ends the loop.
+pub fn watch(args: RunArgs) -> ! {
+    let path = match &args.source.file {
+        Some(path) if path.as_os_str() != "-" => path.clone(),
+        _ => {
+            eprintln!("--watch needs a source file");
+            process::exit(exit::USAGE);
+        },
+    };
+    let changed = Arc::new(AtomicBool::new(false));
+    let _watcher = start_watcher(&path, changed.clone()).unwrap_or_else(|err| {
+        eprintln!("Failed to watch {}: {}", path.display(), err);
+        process::exit(exit::FAILURE);
+    });
+    let mut program = load(&path);
+    let mut session = None;
+    loop {
+        if let Some(program) = program {
+            let (code, stopped) = run_once(&args, program, Some(changed.clone()), &mut session);
+            if stopped && !changed.load(Ordering::SeqCst) {
+                process::exit(code);
+            }
+        }
+        if !changed.load(Ordering::SeqCst) {
+            eprintln!("Watching {} for changes...", path.display());
+        }
+        while !changed.load(Ordering::SeqCst) {
+            thread::sleep(POLL_INTERVAL);
+        }
+        thread::sleep(SETTLE_TIME);
+        changed.store(false, Ordering::SeqCst);
+        program = load(&path);
+    }
+}
+
+// Read the source, or report why it can't be read and wait for the next change
+fn load(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .map_err(|err| eprintln!("Failed to read {}: {}", path.display(), err))
+        .ok()
+}
+
+// Watch the file's directory rather than the file itself, so that editors
+// that save by replacing the file don't end the watch
+fn start_watcher(path: &Path, changed: Arc<AtomicBool>) -> notify::Result<impl Watcher> {
+    let name = path.file_name().map(|name| name.to_owned());
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        let Ok(event) = event else { return };
+        if !(event.kind.is_create() || event.kind.is_modify()) {
+            return;
+        }
+        if event.paths.iter().any(|p| p.file_name() == name.as_deref()) {
+            changed.store(true, Ordering::SeqCst);
+        }
+    })?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
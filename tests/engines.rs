@@ -0,0 +1,186 @@
+// Differential tests: every way of driving the machine must end in the same
+// state as a plain `run`
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+
+use proptest::prelude::*;
+
+use brainrust::{
+    Call, CancelToken, CellSize, Debugger, Dialect, Dispatch, Eof, Limits, Machine, Overflow, PtrBounds,
+    RunOutcome, RuntimeError, Semantics, Stop, Tape,
+};
+
+// What a run leaves behind, for comparing engines
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    error: Option<String>,
+    output: Vec<u8>,
+    data: Tape,
+    data_ptr: usize,
+    prog_ctr: usize,
+    steps: u64,
+    halted: bool,
+    procedures: BTreeMap<u32, usize>,
+    calls: Vec<Call>,
+}
+
+fn outcome(machine: &Machine, result: Result<(), RuntimeError>) -> Outcome {
+    Outcome {
+        error: result.err().map(|err| format!("{:?}", err)),
+        output: machine.output.clone(),
+        data: machine.data.clone(),
+        data_ptr: machine.data_ptr,
+        prog_ctr: machine.prog_ctr,
+        steps: machine.steps,
+        halted: machine.halted,
+        procedures: machine.procedures.clone(),
+        calls: machine.calls.clone(),
+    }
+}
+
+fn machine(program: &str, input: &[u8], semantics: &Semantics) -> Machine {
+    Machine::builder()
+        .semantics(semantics.clone())
+        .input(io::Cursor::new(input.to_vec()))
+        .limits(Limits { max_steps: Some(2000), ..Limits::default() })
+        .build(program.to_owned())
+        .unwrap()
+}
+
+fn run(mut machine: Machine) -> Outcome {
+    let result = machine.run();
+    outcome(&machine, result)
+}
+
+// Single steps, calling through the table of instruction functions
+fn table(mut machine: Machine) -> Outcome {
+    machine.dispatch = Dispatch::Table;
+    machine.fusion = false;
+    let result = machine.run();
+    outcome(&machine, result)
+}
+
+fn steps(mut machine: Machine) -> Outcome {
+    let result = machine.steps().find_map(Result::err).map_or(Ok(()), Err);
+    outcome(&machine, result)
+}
+
+fn run_async(mut machine: Machine) -> Outcome {
+    let mut cx = Context::from_waker(Waker::noop());
+    let cancel = CancelToken::new();
+    let result = {
+        let mut run = pin!(machine.run_async(&cancel));
+        loop {
+            if let Poll::Ready(result) = run.as_mut().poll(&mut cx) {
+                break result;
+            }
+        }
+    };
+    outcome(&machine, result)
+}
+
+// Run in small slices, as a scheduler would
+fn budgeted(mut machine: Machine) -> Outcome {
+    let result = loop {
+        match machine.run_for(7) {
+            Ok(RunOutcome::BudgetExhausted) => {},
+            Ok(outcome) => {
+                assert_eq!(outcome, RunOutcome::Halted);
+                break Ok(());
+            },
+            Err(err) => break Err(err),
+        }
+    };
+    outcome(&machine, result)
+}
+
+fn resume(debugger: &mut Debugger) -> Result<(), RuntimeError> {
+    loop {
+        if debugger.resume()? == Stop::Halted {
+            return Ok(());
+        }
+    }
+}
+
+fn debugger(machine: Machine) -> Outcome {
+    let mut debugger = Debugger::new(machine);
+    let result = resume(&mut debugger);
+    outcome(debugger.machine(), result)
+}
+
+// Run, rewind to the start, and replay to the end. A failed step leaves
+// nothing to rewind, so only clean runs are replayed.
+fn rewound(machine: Machine) -> Outcome {
+    let mut debugger = Debugger::new(machine);
+    let result = resume(&mut debugger);
+    if result.is_ok() {
+        while debugger.reverse_resume() != Stop::StartOfHistory { }
+        assert_eq!(debugger.machine().steps, 0);
+        resume(&mut debugger).unwrap();
+    }
+    outcome(debugger.machine(), result)
+}
+
+// Balanced programs
+fn program() -> impl Strategy<Value = String> {
+    let op = prop::sample::select(vec!["+", "-", ">", "<", ".", ",", " ", "[-]"]).prop_map(str::to_owned);
+    op.prop_recursive(4, 64, 8, |inner| prop_oneof![
+        prop::collection::vec(inner.clone(), 0..8).prop_map(|parts| parts.concat()),
+        prop::collection::vec(inner, 0..8).prop_map(|parts| format!("[{}]", parts.concat())),
+    ])
+}
+
+// Balanced pbrain programs, defining procedures and calling them
+fn pbrain_program() -> impl Strategy<Value = String> {
+    let op = prop::sample::select(vec!["+", "-", ">", "<", ".", ",", ":", "[-]"]).prop_map(str::to_owned);
+    op.prop_recursive(4, 64, 8, |inner| prop_oneof![
+        prop::collection::vec(inner.clone(), 0..8).prop_map(|parts| parts.concat()),
+        prop::collection::vec(inner.clone(), 0..8).prop_map(|parts| format!("[{}]", parts.concat())),
+        prop::collection::vec(inner, 0..8).prop_map(|parts| format!("({})", parts.concat())),
+    ])
+}
+
+fn semantics() -> impl Strategy<Value = Semantics> {
+    (
+        prop::sample::select(vec![CellSize::U8, CellSize::U16, CellSize::U32]),
+        1..16usize,
+        prop::sample::select(vec![Overflow::Wrap, Overflow::Saturate, Overflow::Error]),
+        prop::sample::select(vec![Eof::Zero, Eof::Max, Eof::Unchanged]),
+        prop::sample::select(vec![PtrBounds::Error, PtrBounds::Wrap, PtrBounds::Extend]),
+    ).prop_map(|(cell_size, tape_size, overflow, eof, ptr_bounds)| {
+        Semantics { cell_size, tape_size, overflow, eof, ptr_bounds, dialect: Dialect::Brainfuck }
+    })
+}
+
+proptest! {
+    #[test]
+    fn engines_agree(program in program(),
+                     input in prop::collection::vec(any::<u8>(), 0..8),
+                     semantics in semantics()) {
+        let expected = run(machine(&program, &input, &semantics));
+        prop_assert_eq!(&steps(machine(&program, &input, &semantics)), &expected);
+        prop_assert_eq!(&table(machine(&program, &input, &semantics)), &expected);
+        prop_assert_eq!(&run_async(machine(&program, &input, &semantics)), &expected);
+        prop_assert_eq!(&budgeted(machine(&program, &input, &semantics)), &expected);
+        prop_assert_eq!(&debugger(machine(&program, &input, &semantics)), &expected);
+        prop_assert_eq!(&rewound(machine(&program, &input, &semantics)), &expected);
+    }
+
+    #[test]
+    fn engines_agree_on_pbrain(program in pbrain_program(),
+                               input in prop::collection::vec(any::<u8>(), 0..8),
+                               semantics in semantics()) {
+        let semantics = Semantics { dialect: Dialect::Pbrain, ..semantics };
+        let expected = run(machine(&program, &input, &semantics));
+        prop_assert_eq!(&steps(machine(&program, &input, &semantics)), &expected);
+        prop_assert_eq!(&table(machine(&program, &input, &semantics)), &expected);
+        prop_assert_eq!(&run_async(machine(&program, &input, &semantics)), &expected);
+        prop_assert_eq!(&budgeted(machine(&program, &input, &semantics)), &expected);
+        prop_assert_eq!(&debugger(machine(&program, &input, &semantics)), &expected);
+        prop_assert_eq!(&rewound(machine(&program, &input, &semantics)), &expected);
+    }
+}
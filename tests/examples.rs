@@ -0,0 +1,16 @@
+use std::path::Path;
+
+use brainrust::{Limits, golden};
+
+// Every bundled example must produce its checked-in output
+#[test]
+fn examples_match_expected_output() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+    let cases = golden::cases(&dir).unwrap();
+    assert!(!cases.is_empty());
+    let limits = Limits { max_steps: Some(10_000_000), ..Limits::default() };
+    let failures: Vec<String> = cases.iter()
+        .filter_map(|case| golden::check(case, limits).err().map(|err| format!("{}: {}", case.name, err)))
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
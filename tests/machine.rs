@@ -0,0 +1,1227 @@
+use std::future::Future;
+use std::io;
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+
+use brainrust::{
+    CallTree, CancelToken, CellChange, CellSize, Command, Condition, Debugger, Dialect, FarmSummary, Fused, HistoryEntry, HitCount, InputFeed, LineCoverage,
+    Limits, LogMessage, Machine, Op, OutputBreak, Overflow, ParseError, Perf, Program, Provenance, RunFarm, RunOutcome,
+    RuntimeError, Remap, RestoreError, Semantics, Snapshot, StepEvent, Stop, Tape, Trigger, VmEvent, Watch, parse, parse_dialect, parse_with_extensions,
+    reparse_with_extensions,
+};
+
+// Run a program headlessly on the given input
+fn run(program: &str, input: &[u8]) -> (Machine, Result<(), RuntimeError>) {
+    let mut machine = Machine::builder()
+        .input(io::Cursor::new(input.to_vec()))
+        .build(program.to_owned())
+        .unwrap();
+    let result = machine.run();
+    (machine, result)
+}
+
+#[test]
+fn hello() {
+    let (machine, result) = run("++++++++[>++++++++<-]>+.+.", b"");
+    assert!(result.is_ok());
+    assert!(machine.halted);
+    assert_eq!(machine.output, b"AB");
+}
+
+#[test]
+fn echoes_input() {
+    let (machine, result) = run(",[.,]", b"hi\n");
+    assert!(result.is_ok());
+    assert_eq!(machine.output, b"hi\n");
+}
+
+#[test]
+fn reports_unmatched_brackets() {
+    let src = "+\n[";
+    match parse(src) {
+        Err(err @ ParseError::UnmatchedOpen { .. }) => assert_eq!(err.pos(), (0, 1)),
+        _ => panic!("expected an unmatched `[`"),
+    }
+    let src = "]";
+    assert!(matches!(parse(src), Err(ParseError::UnmatchedClose { offset: 0, .. })));
+    // Offsets and positions count characters, not bytes
+    match parse("é\n ü]") {
+        Err(err @ ParseError::UnmatchedClose { .. }) => assert_eq!((err.offset(), err.pos()), (4, (2, 1))),
+        _ => panic!("expected an unmatched `]`"),
+    }
+}
+
+#[test]
+fn leaves_comments_out_of_the_program() {
+    let src = "a [\n  b -] c";
+    let prog = parse(src).unwrap();
+    assert_eq!(prog.len(), 3);
+    assert_eq!(prog.commands, [Command::JumpForward(2), Command::DecData, Command::JumpBackward(0)]);
+    assert_eq!((prog.chars[1], prog.positions[1]), ('-', (4, 1)));
+    let prog = parse_with_extensions(src, |ch| ch == 'b').unwrap();
+    assert_eq!(prog.commands[..2], [Command::JumpForward(3), Command::Extension('b')]);
+
+    let (machine, _) = run("comment +", b"");
+    assert_eq!(machine.steps, 1);
+}
+
+#[test]
+fn reparses_edits() {
+    let reparse = |old: &str, new: &str| {
+        let (prog, remap) = reparse_with_extensions(&parse(old).unwrap(), old, new, |_| false).unwrap();
+        let whole = parse(new).unwrap();
+        assert_eq!((&prog.commands, &prog.chars, &prog.positions), (&whole.commands, &whole.chars, &whole.positions));
+        remap
+    };
+    // Only the middle line is parsed again, and the brackets around it matched anew
+    let remap = reparse("+[>\n-\n<]\n.", "+[>\n--- x\n<]\n.");
+    assert_eq!(remap, Remap { kept: 3, removed: 1, added: 3 });
+    assert_eq!((remap.index(2), remap.index(3), remap.index(4)), (Some(2), None, Some(6)));
+    assert_eq!(reparse("+\n", "+\n>\n"), Remap { kept: 1, removed: 0, added: 1 });
+    // Edits that assertions or brackets could reach past are parsed whole
+    assert_eq!(reparse("+\n{assert cell == 1}\n-", "+\n{assert cell == 2}\n-"),
+               Remap { kept: 1, removed: 1, added: 1 });
+    assert_eq!(reparse("#!bf\n+", "#!brainrust\n+"), Remap { kept: 0, removed: 0, added: 0 });
+    // Unmatched brackets are reported as a full parse would
+    let unmatched = reparse_with_extensions(&parse("[\n]").unwrap(), "[\n]", "[\n", |_| false);
+    assert!(matches!(unmatched, Err(ParseError::UnmatchedOpen { offset: 0, .. })));
+}
+
+#[test]
+fn reloads_edited_programs() {
+    let mut machine = Machine::builder().build("+>\n++\n<.".to_owned()).unwrap();
+    machine.run_for(3).unwrap();
+    assert_eq!(machine.reload("+>\n++\n>+\n<.".to_owned()).unwrap().added, 2);
+    assert_eq!((machine.prog_ctr, machine.data[1]), (3, 1));  // Still on the second `+` of line 2
+    assert!(machine.run().is_ok());
+    assert_eq!((machine.data[1], machine.data[2]), (2, 1));
+
+    // The debugger's breakpoints move with their instructions
+    let machine = Machine::builder().build("+\n+\n+".to_owned()).unwrap();
+    let mut debugger = Debugger::new(machine);
+    debugger.set_breakpoint(1);
+    debugger.set_breakpoint(2);
+    debugger.reload("+\n-\n>\n+".to_owned()).unwrap();
+    assert_eq!(debugger.breakpoints().collect::<Vec<_>>(), [3]);
+    assert_eq!(debugger.resume().unwrap(), Stop::Breakpoint(3));
+}
+
+#[test]
+fn stops_at_step_limit() {
+    let mut machine = Machine::builder()
+        .limits(Limits { max_steps: Some(10), ..Limits::default() })
+        .build("+[]".to_owned())
+        .unwrap();
+    assert!(matches!(machine.run(), Err(RuntimeError::StepLimit(10))));
+    assert!(!machine.halted);
+}
+
+#[test]
+fn builder_configures_semantics() {
+    let mut machine = Machine::builder()
+        .cell_size(CellSize::U16)
+        .overflow(Overflow::Wrap)
+        .build("-".to_owned())
+        .unwrap();
+    assert!(machine.run().is_ok());
+    assert_eq!(machine.data[0], 0xffff);
+}
+
+#[test]
+fn builder_streams_output_to_sink() {
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+    impl io::Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let sink = Shared::default();
+    let mut machine = Machine::builder()
+        .input(&b"ok"[..])
+        .output(sink.clone())
+        .build(",.,.".to_owned())
+        .unwrap();
+    assert!(machine.run().is_ok());
+    assert_eq!(*sink.0.lock().unwrap(), b"ok");
+}
+
+#[test]
+fn keeps_an_output_tail() {
+    let mut machine = Machine::builder()
+        .input(&b"hello world"[..])
+        .output_tail(4)
+        .build(",[.,]".to_owned())
+        .unwrap();
+    machine.break_on_output(OutputBreak::Byte(10));
+    assert_eq!(machine.run_for(1000).unwrap(), RunOutcome::OutputBreak(0));
+    assert_eq!(machine.output_len(), 10);
+    assert!(machine.output.ends_with(b"worl") && machine.output.len() < 8);
+    assert!(machine.run().is_ok());
+    assert_eq!(machine.output_len(), 11);
+    assert!(machine.output.ends_with(b"world") && machine.output.len() < 8);
+}
+
+#[test]
+fn steps_yields_each_instruction() {
+    let mut machine = Machine::builder().build("+>-.".to_owned()).unwrap();
+    let events: Vec<StepEvent> = machine.steps().map(Result::unwrap).collect();
+    assert_eq!(events, [
+        StepEvent { prog_ctr: 0, op: '+', data_ptr: 0, cell_delta: 1, output: None },
+        StepEvent { prog_ctr: 1, op: '>', data_ptr: 0, cell_delta: 0, output: None },
+        StepEvent { prog_ctr: 2, op: '-', data_ptr: 1, cell_delta: 255, output: None },
+        StepEvent { prog_ctr: 3, op: '.', data_ptr: 1, cell_delta: 0, output: Some(255) },
+    ]);
+    assert!(machine.halted);
+}
+
+#[test]
+fn steps_ends_with_error() {
+    let mut machine = Machine::builder().build("<+".to_owned()).unwrap();
+    let mut steps = machine.steps();
+    assert!(matches!(steps.next(), Some(Err(RuntimeError::PtrUnderflow))));
+    assert!(steps.next().is_none());
+}
+
+#[test]
+fn hooks_observe_execution() {
+    use std::sync::{Arc, Mutex};
+
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let logger = |prefix: &'static str| {
+        let log = log.clone();
+        move |arg: usize| log.lock().unwrap().push(format!("{} {}", prefix, arg))
+    };
+    let note = |event: &'static str| {
+        let log = log.clone();
+        move || log.lock().unwrap().push(event.to_owned())
+    };
+    let mut machine = Machine::builder()
+        .input(&b"x"[..])
+        .build(",[.>+[>]]".to_owned())
+        .unwrap();
+    let output = logger("output");
+    machine.on_input_request(note("input"));
+    machine.on_output(move |byte| output(byte as usize));
+    machine.on_loop_enter(logger("enter"));
+    machine.on_loop_exit(logger("exit"));
+    machine.on_halt(note("halt"));
+    let steps = Arc::new(Mutex::new(0));
+    let step_count = steps.clone();
+    machine.on_step(move |_| *step_count.lock().unwrap() += 1);
+
+    assert!(machine.run().is_ok());
+    assert_eq!(*log.lock().unwrap(), ["input", "enter 1", "output 120", "enter 5", "exit 5",
+                                      "exit 1", "halt"]);
+    assert_eq!(*steps.lock().unwrap(), machine.steps);
+}
+
+#[test]
+fn restores_snapshots() {
+    let mut machine = Machine::builder().build("+>++>+++.".to_owned()).unwrap();
+    let mut steps = machine.steps();
+    steps.nth(3).unwrap().unwrap();
+    let snapshot = machine.snapshot();
+    assert_eq!(snapshot.tape.iter().collect::<Vec<_>>(), [(&0, &1), (&1, &2)]);
+    assert!(machine.run().is_ok());
+    assert_eq!(machine.output, [3]);
+
+    machine.restore(&snapshot).unwrap();
+    assert_eq!((machine.data_ptr, machine.prog_ctr, machine.halted), (1, 4, false));
+    assert!(machine.output.is_empty());
+    assert!(machine.run().is_ok());
+    assert_eq!(machine.snapshot().steps, 9);
+
+    // Cells must fit the cell size the snapshot was taken under
+    let mut wide = snapshot.clone();
+    wide.tape.insert(2, 0x100);
+    assert!(matches!(machine.restore(&wide), Err(RestoreError::CellTooLarge(2, 0x100))));
+}
+
+#[test]
+fn faults_locate_errors() {
+    let (machine, result) = run("+>\n+<<", b"");
+    let fault = machine.fault(result.unwrap_err());
+    assert!(matches!(fault.error, RuntimeError::PtrUnderflow));
+    assert_eq!((fault.pos, fault.data_ptr, fault.steps), ((2, 1), 0, 5));
+    assert_eq!(fault.to_string(), "2:3: data pointer moved left of the tape (data pointer 0, step 5)");
+}
+
+#[test]
+fn run_async_yields_until_cancelled() {
+    let mut machine = Machine::builder().build("+[]".to_owned()).unwrap();
+    let cancel = CancelToken::new();
+    let mut cx = Context::from_waker(Waker::noop());
+    {
+        let mut run = pin!(machine.run_async(&cancel));
+        assert!(run.as_mut().poll(&mut cx).is_pending());
+        assert!(run.as_mut().poll(&mut cx).is_pending());
+        cancel.clone().cancel();
+        assert!(matches!(run.poll(&mut cx), Poll::Ready(Err(RuntimeError::Cancelled))));
+    }
+    assert!(!machine.halted);
+    assert!(machine.steps > 0);
+}
+
+#[test]
+fn run_async_finishes_programs() {
+    let mut machine = Machine::builder().build("++++++++[>++++++++<-]>+.".to_owned()).unwrap();
+    let cancel = CancelToken::new();
+    let mut cx = Context::from_waker(Waker::noop());
+    let result = pin!(machine.run_async(&cancel)).poll(&mut cx);
+    assert!(matches!(result, Poll::Ready(Ok(()))));
+    assert_eq!(machine.output, b"A");
+}
+
+#[test]
+fn run_after_halt_does_nothing() {
+    for fusion in [true, false] {
+        let mut machine = Machine::builder().build("+".to_owned()).unwrap();
+        machine.fusion = fusion;
+        machine.run().unwrap();
+        machine.run().unwrap();
+        assert_eq!((machine.cells(0..1)[0], machine.steps), (1, 1));
+    }
+
+    let mut machine = Machine::builder().build("+.".to_owned()).unwrap();
+    machine.run_for(10).unwrap();
+    machine.run().unwrap();
+    assert_eq!(machine.output, [1]);
+
+    let cancel = CancelToken::new();
+    let mut cx = Context::from_waker(Waker::noop());
+    let result = pin!(machine.run_async(&cancel)).poll(&mut cx);
+    assert!(matches!(result, Poll::Ready(Ok(()))));
+    assert_eq!((machine.output.as_slice(), machine.steps), (&[1][..], 2));
+}
+
+#[test]
+fn farm_runs_machines_in_order() {
+    let mut farm = RunFarm::new(2).limits(Limits { max_steps: Some(100), ..Limits::default() });
+    for program in ["+.", "+[]", "++.", "<"] {
+        farm.add(Machine::builder().build(program.to_owned()).unwrap());
+    }
+    let runs = farm.run();
+    assert_eq!(runs[0].machine.output, [1]);
+    assert!(matches!(runs[1].result, Err(RuntimeError::StepLimit(100))));
+    assert_eq!(runs[2].machine.output, [2]);
+    assert!(matches!(runs[3].result, Err(RuntimeError::PtrUnderflow)));
+
+    // Machines dealt to one thread are taken up by the others, and each
+    // keeps its own budget
+    let mut farm = RunFarm::new(3);
+    for i in 0..40 {
+        let machine = Machine::builder().build("+[+]".to_owned()).unwrap();
+        if i % 4 == 0 {
+            farm.add_with_budget(machine, 10);
+        } else {
+            farm.add(machine);
+        }
+    }
+    let runs = farm.run();
+    assert!(runs.iter().step_by(4).all(|run| matches!(run.result, Err(RuntimeError::StepLimit(10)))));
+    let summary = FarmSummary::of(&runs);
+    assert_eq!((summary.halted, summary.out_of_steps, summary.failed), (30, 10, 0));
+    assert_eq!(summary.steps, 30 * 512 + 10 * 10);
+}
+
+#[test]
+fn debugger_stops_at_breakpoints_and_watchpoints() {
+    let machine = Machine::builder().build("+>+>+>+".to_owned()).unwrap();
+    let mut debugger = Debugger::new(machine);
+    debugger.set_breakpoint(4);
+    debugger.watch(3);
+    assert_eq!(debugger.resume().unwrap(), Stop::Breakpoint(4));
+    assert_eq!(debugger.resume().unwrap(), Stop::Watchpoint(3));
+    assert_eq!(debugger.machine().prog_ctr, 6);
+    assert_eq!(debugger.resume().unwrap(), Stop::Halted);
+}
+
+#[test]
+fn debugger_steps_back_and_replays() {
+    let machine = Machine::builder()
+        .input(&b"ab"[..])
+        .build(",.>,.".to_owned())
+        .unwrap();
+    let mut debugger = Debugger::new(machine);
+    assert_eq!(debugger.resume().unwrap(), Stop::Halted);
+    assert_eq!(debugger.machine().output, b"ab");
+
+    assert_eq!(debugger.step_back(), Stop::Step);
+    assert_eq!(debugger.step_back(), Stop::Step);
+    assert_eq!((debugger.machine().prog_ctr, debugger.cell(1)), (3, Some(0)));
+    assert_eq!(debugger.machine().output, b"a");
+    assert_eq!(debugger.reverse_resume(), Stop::StartOfHistory);
+    assert_eq!(debugger.machine().steps, 0);
+
+    // Replaying doesn't read the input again
+    assert_eq!(debugger.resume().unwrap(), Stop::Halted);
+    assert_eq!(debugger.machine().output, b"ab");
+    assert_eq!((debugger.cell(0), debugger.cell(1)), (Some(b'a' as u32), Some(b'b' as u32)));
+}
+
+#[test]
+fn subscribers_receive_events() {
+    let mut machine = Machine::builder()
+        .input(&b"a"[..])
+        .build(",.".to_owned())
+        .unwrap();
+    let events = machine.subscribe();
+    let runner = std::thread::spawn(move || machine.run().is_ok());
+    let events: Vec<VmEvent> = events.iter().collect();
+    assert!(runner.join().unwrap());
+    assert_eq!(events, [
+        VmEvent::InputRequest,
+        VmEvent::Step(StepEvent { prog_ctr: 0, op: ',', data_ptr: 0, cell_delta: 97, output: None }),
+        VmEvent::Output(b'a'),
+        VmEvent::Step(StepEvent { prog_ctr: 1, op: '.', data_ptr: 0, cell_delta: 0, output: Some(97) }),
+        VmEvent::Halt,
+    ]);
+}
+
+#[test]
+fn reports_coverage() {
+    let mut machine = Machine::builder()
+        .build("+>\n[,]\n.. comment\n".to_owned())
+        .unwrap();
+    assert_eq!(machine.coverage(), None);
+    machine.profile = Some(vec![0; machine.prog.len()]);
+    machine.run().unwrap();
+    let coverage = machine.coverage().unwrap();
+    assert_eq!(coverage.lines, [
+        LineCoverage { line: 1, instructions: 2, covered: 2, hits: 1 },
+        LineCoverage { line: 2, instructions: 3, covered: 1, hits: 1 },
+        LineCoverage { line: 3, instructions: 2, covered: 2, hits: 1 },
+    ]);
+    assert_eq!((coverage.covered(), coverage.instructions()), (5, 7));
+    assert_eq!(coverage.line(4), None);
+    assert_eq!(coverage.lcov("loop.b"),
+               "TN:\nSF:loop.b\nDA:1,1\nDA:2,1\nDA:3,1\nLF:3\nLH:3\nend_of_record\n");
+}
+
+#[test]
+fn loading_resets_the_profile() {
+    let mut machine = Machine::builder().build("+".to_owned()).unwrap();
+    machine.profile = Some(vec![0; 1]);
+    machine.run().unwrap();
+    machine.load("++++".to_owned()).unwrap();
+    assert_eq!(machine.profile.as_deref(), Some(&[0; 4][..]));
+    machine.run().unwrap();
+    assert_eq!(machine.profile.as_deref(), Some(&[1; 4][..]));
+}
+
+#[test]
+fn tracks_used_cells() {
+    let (machine, _) = run("+>+>+<<->->-", b"");
+    assert!(machine.used_cells().is_empty());
+    let (machine, _) = run("->>+<<+", b"");  // Wrapping below zero uses a cell
+    assert_eq!(machine.used_cells(), [0, 0, 1]);
+    let (machine, _) = run(">>,", b"A");
+    assert_eq!(machine.used_cells(), [0, 0, 65]);
+}
+
+#[test]
+fn pages_the_tape() {
+    let mut tape = Tape::new(1 << 40);
+    assert_eq!((tape[123_456_789], tape.pages()), (0, 0));  // Reading allocates nothing
+    tape[5] = 1;
+    tape[1 << 39] = 2;
+    tape[(1 << 39) + 1] = 3;
+    assert_eq!(tape.pages(), 2);
+    assert_eq!(tape.nonzero().collect::<Vec<_>>(), [(5, 1), (1 << 39, 2), ((1 << 39) + 1, 3)]);
+    assert_eq!((tape.position(3), tape.position(0)), (Some((1 << 39) + 1), Some(0)));
+    assert_eq!((tape.nonzero_end(1 << 39), tape.nonzero_end(usize::MAX)), (6, (1 << 39) + 2));
+    assert_eq!(tape.get(1 << 40), None);
+    tape.grow((1 << 40) + 1);
+    assert_eq!(tape.get(1 << 40), Some(0));
+    assert_eq!(tape.clone(), tape);
+}
+
+#[test]
+fn run_for_stops_when_budget_runs_out() {
+    let mut machine = Machine::builder().build("+++.".to_owned()).unwrap();
+    assert_eq!(machine.run_for(3).unwrap(), RunOutcome::BudgetExhausted);
+    assert_eq!((machine.steps, machine.data[0]), (3, 3));
+    assert_eq!(machine.run_for(10).unwrap(), RunOutcome::Halted);
+    assert_eq!(machine.output, [3]);
+    assert_eq!(machine.run_for(10).unwrap(), RunOutcome::Halted);
+    assert_eq!(machine.steps, 4);
+}
+
+#[test]
+fn run_for_waits_for_fed_input() {
+    let feed = InputFeed::new();
+    let mut machine = Machine::builder()
+        .input(feed.clone())
+        .build(",[.,]".to_owned())
+        .unwrap();
+    assert_eq!(machine.run_for(100).unwrap(), RunOutcome::AwaitingInput);
+    assert_eq!(machine.steps, 0);
+    feed.push(b"hi");
+    assert_eq!(machine.run_for(100).unwrap(), RunOutcome::AwaitingInput);
+    assert_eq!(machine.output, b"hi");
+    feed.close();
+    assert_eq!(machine.run_for(100).unwrap(), RunOutcome::Halted);
+}
+
+#[test]
+fn debugger_run_for_stops_at_breakpoints() {
+    let mut debugger = Debugger::new(Machine::builder().build("+>+>+".to_owned()).unwrap());
+    debugger.set_breakpoint(2);
+    assert_eq!(debugger.run_for(1).unwrap(), RunOutcome::BudgetExhausted);
+    assert_eq!(debugger.run_for(10).unwrap(), RunOutcome::Breakpoint(2));
+    debugger.watch(2);
+    assert_eq!(debugger.run_for(10).unwrap(), RunOutcome::Watchpoint(2));
+    assert_eq!(debugger.run_for(10).unwrap(), RunOutcome::Halted);
+}
+
+#[test]
+fn inspects_the_tape() {
+    let (machine, result) = run("+>>+++>>+++", b"");
+    assert!(result.is_ok());
+    assert_eq!(machine.cell(2), Some(3));
+    assert_eq!(machine.cell(machine.data.len()), None);
+    assert_eq!(machine.cells(..5), [1, 0, 3, 0, 3]);
+    assert_eq!(machine.cells(3..=4), [0, 3]);
+    assert_eq!(machine.cells(machine.data.len() - 1..usize::MAX), [0]);
+    assert_eq!(machine.cells(machine.data.len() + 1..), []);
+    assert_eq!(machine.nonzero_cells().collect::<Vec<_>>(), [(0, 1), (2, 3), (4, 3)]);
+    assert_eq!(machine.find(3), Some(2));
+    assert_eq!(machine.find(7), None);
+}
+
+#[test]
+fn diffs_snapshots() {
+    let mut machine = Machine::builder().build("++.>+<[-]".to_owned()).unwrap();
+    machine.steps().take(3).for_each(|step| assert!(step.is_ok()));
+    let before = machine.snapshot();
+    machine.run().unwrap();
+    let diff = before.diff(&machine.snapshot());
+    assert_eq!(diff.cells, [
+        CellChange { index: 0, before: 2, after: 0 },
+        CellChange { index: 1, before: 0, after: 1 },
+    ]);
+    assert!(diff.changed(1) && !diff.changed(2));
+    assert_eq!(diff.data_ptr, None);
+    assert_eq!(diff.prog_ctr, Some((3, 8)));
+    assert_eq!((diff.output_removed, diff.output_added), (0, vec![]));
+    assert!(before.diff(&before).is_empty());
+
+    // Going back in time drops output
+    let diff = machine.snapshot().diff(&Snapshot { output: vec![1], ..before });
+    assert_eq!((diff.output_removed, diff.output_added), (1, vec![1]));
+}
+
+#[test]
+fn runs_extension_instructions() {
+    let mut machine = Machine::builder().build("+++%.# not bound\n%%".to_owned()).unwrap();
+    // Double the current cell
+    machine.extension('%', |machine| {
+        let cell = &mut machine.data[machine.data_ptr];
+        *cell *= 2;
+        Ok(())
+    });
+    machine.extension('!', |_| Err(RuntimeError::Extension("unreachable".to_owned())));
+    machine.run().unwrap();
+    assert_eq!(machine.output, [6]);
+    assert_eq!(machine.data[0], 24);
+
+    machine.extension('#', |_| Err(RuntimeError::Extension("# is reserved".to_owned())));
+    machine.load("+#".to_owned()).unwrap();
+    let err = machine.run().unwrap_err();
+    assert_eq!(machine.fault(err).to_string(), "1:2: # is reserved (data pointer 0, step 9)");
+}
+
+#[test]
+fn evaluates_conditions() {
+    let (machine, _) = run("+++>>+<", b"");
+    let holds = |source: &str| Condition::parse(source).unwrap().holds(&machine);
+    assert!(holds("ptr == 1 && cell == 0 && cell[0] == 3"));
+    assert!(holds("cell[ptr + 1] + 2 == cell[0]"));
+    assert!(holds("!(steps < 7) || pc > 100"));
+    assert!(holds("cell[1000000] == 0 && cell[0 - 1] == 0"));
+    assert!(!holds("cell[0] != 3"));
+    assert_eq!(Condition::parse("ptr >").unwrap_err().to_string(), "column 6: expected a value");
+    assert_eq!(Condition::parse("cell[1").unwrap_err().to_string(), "column 7: expected `]`");
+    assert_eq!(Condition::parse("ptrr").unwrap_err().to_string(), "column 1: unknown name");
+    assert_eq!(Condition::parse("1 2").unwrap_err().to_string(), "column 3: unexpected input");
+}
+
+#[test]
+fn debugger_checks_breakpoint_conditions() {
+    let mut debugger = Debugger::new(Machine::builder().build("+++++[->+<]".to_owned()).unwrap());
+    debugger.set_conditional_breakpoint(6, Condition::parse("cell == 2").unwrap());
+    assert_eq!(debugger.resume().unwrap(), Stop::Breakpoint(6));
+    assert_eq!(debugger.cell(0), Some(2));
+    assert_eq!(debugger.condition(6).unwrap().to_string(), "cell == 2");
+    assert_eq!(debugger.resume().unwrap(), Stop::Halted);
+    assert_eq!(debugger.reverse_resume(), Stop::Breakpoint(6));
+    assert_eq!(debugger.cell(0), Some(2));
+}
+
+#[test]
+fn debugger_counts_breakpoint_hits() {
+    // The `-` runs five times, with cell 0 at 5, 4, 3, 2 and 1
+    let mut debugger = Debugger::new(Machine::builder().build("+++++[->+<]".to_owned()).unwrap());
+    debugger.set_breakpoint(6);
+    assert!(debugger.set_hit_count(6, HitCount { hit: Some(3), ignore: 0 }));
+    assert_eq!(debugger.resume().unwrap(), Stop::Breakpoint(6));
+    assert_eq!((debugger.cell(0), debugger.hits(6)), (Some(3), Some(3)));
+    assert_eq!(debugger.resume().unwrap(), Stop::Halted);
+    assert_eq!(debugger.hits(6), Some(5));
+
+    // Ignored hits still count, and only those the condition allows do
+    let mut debugger = Debugger::new(Machine::builder().build("+++++[->+<]".to_owned()).unwrap());
+    debugger.set_conditional_breakpoint(6, Condition::parse("cell < 5").unwrap());
+    debugger.set_hit_count(6, HitCount { hit: None, ignore: 2 });
+    assert_eq!(debugger.resume().unwrap(), Stop::Breakpoint(6));
+    assert_eq!(debugger.cell(0), Some(2));
+    assert_eq!(debugger.resume().unwrap(), Stop::Breakpoint(6));
+    assert_eq!(debugger.cell(0), Some(1));
+    assert_eq!(debugger.hits(6), Some(4));
+    assert!(!debugger.set_hit_count(0, HitCount::default()));
+}
+
+#[test]
+fn watchpoints_distinguish_triggers() {
+    let machine = || Machine::builder().build("++>+++[-<+>]<.".to_owned()).unwrap();
+
+    // Who set cell 1 to zero?
+    let mut debugger = Debugger::new(machine());
+    debugger.watch_for(1, Watch { trigger: Trigger::Write, value: Some(0) });
+    assert_eq!(debugger.resume().unwrap(), Stop::Watchpoint(1));
+    assert_eq!((debugger.machine().steps, debugger.cell(1)), (18, Some(0)));
+
+    // The loop tests cell 1 on entry and at the end of each iteration
+    let mut debugger = Debugger::new(machine());
+    debugger.watch_for(1, Watch { trigger: Trigger::Read, value: None });
+    let mut reads = 0;
+    while debugger.resume().unwrap() != Stop::Halted {
+        reads += 1;
+    }
+    assert_eq!(reads, 4);
+    assert_eq!(debugger.step_back(), Stop::Step);
+    assert_eq!(debugger.reverse_resume(), Stop::Watchpoint(1));
+    assert_eq!(debugger.watchpoint(1), Some(Watch { trigger: Trigger::Read, value: None }));
+}
+
+#[test]
+fn debugger_steps_over_and_out_of_loops() {
+    let mut debugger = Debugger::new(Machine::builder().build("++[>++[>+<-]<-]>>.".to_owned()).unwrap());
+    assert_eq!(debugger.step_over().unwrap(), Stop::Step);  // Not a loop: one step
+    assert_eq!(debugger.step_over().unwrap(), Stop::Step);
+    assert_eq!(debugger.step_over().unwrap(), Stop::Step);  // The whole outer loop
+    assert_eq!(debugger.machine().prog_ctr, 15);
+    assert_eq!(debugger.machine().cells(0..3), [0, 0, 4]);
+
+    let mut debugger = Debugger::new(Machine::builder().build("++[>++[>+<-]<-]>>.".to_owned()).unwrap());
+    debugger.set_breakpoint(9);
+    assert_eq!(debugger.resume().unwrap(), Stop::Breakpoint(9));
+    assert_eq!(debugger.step_out().unwrap(), Stop::Breakpoint(9));  // Stopped again inside
+    debugger.clear_breakpoint(9);
+    assert_eq!(debugger.step_out().unwrap(), Stop::Step);  // Out of the inner loop
+    assert_eq!(debugger.machine().prog_ctr, 12);
+    assert_eq!(debugger.step_out().unwrap(), Stop::Step);  // Out of the outer one
+    assert_eq!(debugger.machine().prog_ctr, 15);
+    assert_eq!(debugger.step_out().unwrap(), Stop::Halted);  // Outside any loop
+}
+
+#[test]
+fn debugger_runs_to_output() {
+    let mut debugger = Debugger::new(Machine::builder().build("+.+.+.+".to_owned()).unwrap());
+    assert_eq!(debugger.run_to_output(None).unwrap(), Stop::Output(1));
+    assert_eq!(debugger.run_to_output(Some(3)).unwrap(), Stop::Output(3));
+    assert_eq!(debugger.machine().output, [1, 2, 3]);
+    assert_eq!(debugger.run_to_output(None).unwrap(), Stop::Halted);
+}
+
+#[test]
+fn debugger_seeks_through_history() {
+    let mut debugger = Debugger::new(Machine::builder().build("+++++>+++".to_owned()).unwrap());
+    assert_eq!(debugger.seek(7).unwrap(), Stop::Step);
+    assert_eq!(debugger.machine().cells(0..2), [5, 1]);
+    assert_eq!(debugger.seek(3).unwrap(), Stop::Step);
+    assert_eq!(debugger.machine().cells(0..2), [3, 0]);
+    assert_eq!(debugger.seek(20).unwrap(), Stop::Halted);
+    assert_eq!(debugger.machine().cells(0..2), [5, 3]);
+    assert_eq!(debugger.seek(0).unwrap(), Stop::Step);
+    assert_eq!(debugger.machine().steps, 0);
+
+    // A budget too small for the whole run forgets the oldest steps
+    debugger.set_history_budget(64);
+    assert_eq!(debugger.seek(8).unwrap(), Stop::Step);
+    assert!(debugger.earliest_step() > 0);
+    assert_eq!(debugger.seek(0).unwrap(), Stop::StartOfHistory);
+    assert_eq!(debugger.machine().steps, debugger.earliest_step());
+}
+
+#[test]
+fn debugger_rewinds_to_last_write() {
+    let mut debugger = Debugger::new(Machine::builder().build("++>+<.>.".to_owned()).unwrap());
+    assert_eq!(debugger.resume().unwrap(), Stop::Halted);
+    assert_eq!(debugger.reverse_to_write(0), Some(1));
+    assert_eq!(debugger.machine().steps, 2);
+    assert_eq!(debugger.machine().prog_ctr, 2);
+    assert_eq!(debugger.reverse_to_write(1), None);  // Only written later
+    assert_eq!(debugger.machine().steps, 2);
+    assert_eq!(debugger.reverse_to_write(0), Some(1));  // Already just after it
+}
+
+#[test]
+fn breaks_on_output() {
+    let mut machine = Machine::builder().input(&b"hello world"[..]).build(",[.,]".to_owned()).unwrap();
+    machine.break_on_output(OutputBreak::Contains(b"lo".to_vec()));
+    assert_eq!(machine.break_on_output(OutputBreak::Byte(7)), 1);
+    assert_eq!(machine.run_for(1000).unwrap(), RunOutcome::OutputBreak(0));
+    assert_eq!(machine.output, b"hello");
+    assert_eq!(machine.run_for(1000).unwrap(), RunOutcome::OutputBreak(1));
+    assert_eq!(machine.output, b"hello w");
+
+    // Replayed steps set breaks off just as live ones do
+    let machine = Machine::builder().input(&b"hello world"[..]).build(",[.,]".to_owned()).unwrap();
+    let mut debugger = Debugger::new(machine);
+    debugger.break_on_output(OutputBreak::Byte(3));
+    assert_eq!(debugger.resume().unwrap(), Stop::OutputBreak(0));
+    assert_eq!(debugger.resume().unwrap(), Stop::Halted);
+    assert_eq!(debugger.seek(0).unwrap(), Stop::Step);
+    assert_eq!(debugger.resume().unwrap(), Stop::OutputBreak(0));
+    assert_eq!(debugger.machine().output, b"hel");
+    assert_eq!(debugger.clear_output_break(0), Some(OutputBreak::Byte(3)));
+    assert_eq!(debugger.resume().unwrap(), Stop::Halted);
+    assert_eq!(debugger.machine().output, b"hello world");
+}
+
+#[test]
+fn debugger_exports_history() {
+    let machine = Machine::builder().input(&b"a"[..]).build(",.>+".to_owned()).unwrap();
+    let mut debugger = Debugger::new(machine);
+    assert_eq!(debugger.resume().unwrap(), Stop::Halted);
+    debugger.step_back();
+    let history: Vec<HistoryEntry> = debugger.history().collect();
+    assert_eq!(history.iter().map(|entry| entry.step).collect::<Vec<_>>(), [1, 2, 3]);
+    assert_eq!(history.iter().map(|entry| entry.op).collect::<String>(), ",.>");
+    assert_eq!(history[0].cell_delta, 97);
+    assert_eq!(history[1].output, Some(b'a'));
+    assert_eq!(history[2].data_ptr, 0);
+    assert!(history.windows(2).all(|pair| pair[0].micros <= pair[1].micros));
+}
+
+#[test]
+fn debugger_history_keeps_runs_compactly() {
+    let src = format!("{}{}[-]{}", "+".repeat(200), ">".repeat(300), "<".repeat(100));
+    let mut machine = Machine::builder().build(src).unwrap();
+    machine.provenance = Some(Vec::new());
+    let mut debugger = Debugger::new(machine);
+    debugger.set_history_budget(4096);
+    assert_eq!(debugger.resume().unwrap(), Stop::Halted);
+    let steps = debugger.machine().steps;
+    assert_eq!(debugger.earliest_step(), 0);
+    assert_eq!(debugger.history_len() as u64, steps);
+    assert!(debugger.memory().history <= 4096);
+
+    // Runs are listed, undone and replayed a step at a time
+    let history: Vec<HistoryEntry> = debugger.history().collect();
+    assert_eq!(history.len() as u64, steps);
+    assert_eq!(history[250].prog_ctr, 250);
+    assert_eq!(history[250].data_ptr, 50);
+    assert_eq!(history.last().unwrap().data_ptr, 201);
+    assert_eq!(debugger.seek(150).unwrap(), Stop::Step);
+    assert_eq!((debugger.machine().cells(0..1), debugger.machine().data_ptr), (vec![150], 0));
+    assert_eq!(debugger.machine().written_by(0), Some(Provenance { step: 150, prog_ctr: 149 }));
+    assert_eq!(debugger.seek(350).unwrap(), Stop::Step);
+    assert_eq!((debugger.machine().cells(0..1), debugger.machine().data_ptr), (vec![200], 150));
+    assert_eq!(debugger.seek(0).unwrap(), Stop::Step);
+    assert_eq!(debugger.resume().unwrap(), Stop::Halted);
+    assert_eq!(debugger.machine().data_ptr, 200);
+    assert_eq!(debugger.history().collect::<Vec<_>>(), history);
+}
+
+#[test]
+fn tracks_cell_provenance() {
+    let mut machine = Machine::builder().build("++>+<-".to_owned()).unwrap();
+    assert_eq!(machine.run_for(3).unwrap(), RunOutcome::BudgetExhausted);
+    assert_eq!(machine.written_by(0), None);  // Not tracked
+    machine.provenance = Some(Vec::new());
+    let mut debugger = Debugger::new(machine);
+    assert_eq!(debugger.resume().unwrap(), Stop::Halted);
+    assert_eq!(debugger.machine().written_by(0), Some(Provenance { step: 6, prog_ctr: 5 }));
+    assert_eq!(debugger.machine().written_by(1), Some(Provenance { step: 4, prog_ctr: 3 }));
+    assert_eq!(debugger.machine().written_by(2), None);
+
+    // Undoing a write restores the one before it, and redoing it comes back
+    debugger.step_back();
+    assert_eq!(debugger.machine().written_by(0), None);  // Before tracking started
+    assert_eq!(debugger.step().unwrap(), Stop::Halted);
+    assert_eq!(debugger.machine().written_by(0), Some(Provenance { step: 6, prog_ctr: 5 }));
+    assert_eq!(debugger.seek(4).unwrap(), Stop::Step);
+    assert_eq!(debugger.machine().written_by(1), Some(Provenance { step: 4, prog_ctr: 3 }));
+    debugger.step_back();
+    assert_eq!(debugger.machine().written_by(1), None);
+}
+
+#[test]
+fn finds_labels_in_comments() {
+    let program = "+ @label:loop\n[-] @label:exit @label:\n@label:end".to_owned();
+    let mut machine = Machine::builder().build(program).unwrap();
+    assert_eq!(machine.labels, [("loop".to_owned(), 1)]);  // The `[` on the next line
+    assert_eq!(machine.label("loop"), Some(1));
+    assert_eq!(machine.label("exit"), None);  // Nothing after it
+    assert_eq!(machine.label_at(1), Some("loop"));
+
+    machine.load("@label:a @label:b ,".to_owned()).unwrap();
+    assert_eq!(machine.label("a"), Some(0));
+    assert_eq!(machine.label_at(0), Some("a"));
+}
+
+#[test]
+fn checks_inline_assertions() {
+    // The brackets and `+` in the condition aren't code
+    let (machine, result) = run("+++ {assert cell == 3 && cell[1+0] == 0} >++ {assert cell[1]==3}", b"");
+    assert!(matches!(result, Err(RuntimeError::AssertionFailed(ref condition)) if condition == "cell[1]==3"));
+    assert_eq!(machine.data[1], 2);
+    assert_eq!(machine.prog_ctr, 7);  // Stopped on the failed assertion
+
+    let src = "+\n{assert cell[0] ==}";
+    match parse(src) {
+        Err(err @ ParseError::BadAssertion { .. }) => assert_eq!(err.pos(), (18, 1)),
+        _ => panic!("expected a bad assertion"),
+    }
+    let src = "{assert cell == 1";
+    assert!(matches!(parse(src), Err(ParseError::BadAssertion { offset: 0, .. })));
+    let src = "{asserted} {assert}";
+    assert!(parse(src).is_ok());  // Not assertions
+}
+
+#[test]
+fn logs_at_tracepoints() {
+    let machine = Machine::builder().build("+++[>++<-]".to_owned()).unwrap();
+    let mut debugger = Debugger::new(machine);
+    let message = LogMessage::parse("i={cell} j={cell[ptr+1]} {{{steps}}}").unwrap();
+    debugger.set_tracepoint(4, message);  // The `>`
+    assert_eq!(debugger.resume().unwrap(), Stop::Halted);
+    assert_eq!(debugger.take_log(), ["i=3 j=0 {4}", "i=2 j=2 {10}", "i=1 j=4 {16}"]);
+    assert!(debugger.take_log().is_empty());
+
+    // Steps replayed after stepping back aren't logged again
+    for _ in 0..10 {
+        debugger.step_back();
+    }
+    assert_eq!(debugger.resume().unwrap(), Stop::Halted);
+    assert!(debugger.take_log().is_empty());
+
+    assert_eq!(LogMessage::parse("{cell").unwrap_err().offset, 0);
+    assert_eq!(LogMessage::parse("a {cell[}").unwrap_err().offset, 8);
+    assert!(LogMessage::parse("a } b").is_err());
+}
+
+#[test]
+fn counts_loop_iterations() {
+    let mut machine = Machine::builder().build("++[>+++[>+<-]<-]".to_owned()).unwrap();
+    machine.iterations = Some(Vec::new());
+    let mut debugger = Debugger::new(machine);
+    debugger.set_breakpoint(10);  // The inner `<`
+    for _ in 0..5 {
+        assert_eq!(debugger.resume().unwrap(), Stop::Breakpoint(10));
+    }
+    // Second run of the outer loop, second iteration of the inner one
+    let machine = debugger.machine();
+    assert_eq!(machine.loop_stack(), [2, 7]);
+    assert_eq!((machine.iteration(2), machine.iteration(7)), (Some(2), Some(2)));
+
+    // Undoing the inner loop's jump back returns it to its first iteration,
+    // and replaying it moves it on again
+    while debugger.machine().prog_ctr != 12 {
+        debugger.step_back();
+    }
+    assert_eq!(debugger.machine().iteration(7), Some(1));
+    assert_eq!(debugger.resume().unwrap(), Stop::Breakpoint(10));
+    assert_eq!(debugger.machine().iteration(7), Some(2));
+
+    assert_eq!(debugger.resume().unwrap(), Stop::Breakpoint(10));
+    assert_eq!(debugger.resume().unwrap(), Stop::Halted);
+    let machine = debugger.machine();
+    assert!(machine.loop_stack().is_empty());
+    assert_eq!((machine.iteration(2), machine.iteration(7)), (Some(2), Some(3)));  // Where they ended
+    assert_eq!(Machine::builder().build("[]".to_owned()).unwrap().iteration(0), None);
+}
+
+#[test]
+fn debugger_patches_state() {
+    let machine = Machine::builder().tape_size(4).build("++>+.".to_owned()).unwrap();
+    let mut debugger = Debugger::new(machine);
+    debugger.step().unwrap();
+    assert!(debugger.set_cell(3, 0x41));
+    assert!(!debugger.set_cell(4, 1));    // Off the tape
+    assert!(!debugger.set_cell(0, 256));  // Too big for a byte
+    assert!(debugger.set_data_ptr(2));
+    assert!(!debugger.set_data_ptr(4));
+    assert_eq!(debugger.step_back(), Stop::StartOfHistory);  // Patched over
+
+    assert!(debugger.set_prog_ctr(3));  // The second `+`
+    assert_eq!(debugger.resume().unwrap(), Stop::Halted);
+    assert!(!debugger.set_prog_ctr(5));
+    assert!(debugger.set_prog_ctr(3));  // Run it again after halting
+    assert!(!debugger.machine().halted);
+    assert_eq!(debugger.resume().unwrap(), Stop::Halted);
+    let machine = debugger.into_machine();
+    assert_eq!(machine.cells(..), [1, 0, 2, 0x41]);
+    assert_eq!(machine.output, [1, 2]);
+    assert_eq!(machine.used_cells(), [1, 0, 2, 0x41]);
+}
+
+#[test]
+fn debugger_rereads_input_after_stepping_back() {
+    let machine = Machine::builder()
+        .input(&b"xy"[..])
+        .build(",.,.".to_owned())
+        .unwrap();
+    let mut debugger = Debugger::new(machine);
+    debugger.set_checkpoints(1000, usize::MAX);  // Logs the input
+    debugger.step().unwrap();
+    assert_eq!(debugger.step_back(), Stop::Step);
+    // Replaying the `,` takes the same read again
+    debugger.step().unwrap();
+    assert_eq!(debugger.step_back(), Stop::Step);
+
+    // Patching forgets the history, so the `,` runs afresh from the log
+    assert!(debugger.set_cell(1, 5));
+    assert_eq!(debugger.step_back(), Stop::StartOfHistory);
+    assert_eq!(debugger.resume().unwrap(), Stop::Halted);
+    assert_eq!(debugger.machine().output, b"xy");
+}
+
+#[test]
+fn debugger_seeks_through_checkpoints() {
+    let machine = Machine::builder()
+        .input(io::Cursor::new(b"abcdefghij".to_vec()))
+        .build(",[.,]".to_owned())
+        .unwrap();
+    let mut debugger = Debugger::new(machine);
+    debugger.set_history_budget(0);  // Keeps one step
+    debugger.set_checkpoints(4, usize::MAX);
+    assert_eq!(debugger.seek(21).unwrap(), Stop::Step);
+    assert_eq!(debugger.checkpoints().collect::<Vec<_>>(), [0, 4, 8, 12, 16, 20]);
+    assert_eq!(debugger.earliest_step(), 20);
+
+    // Going back reads the same input again, and carries on after it
+    assert_eq!(debugger.seek(10).unwrap(), Stop::Step);
+    assert_eq!((debugger.machine().output.as_slice(), debugger.cell(0)), (&b"abc"[..], Some(b'd' as u32)));
+    assert_eq!(debugger.seek(40).unwrap(), Stop::Halted);
+    assert_eq!(debugger.machine().output, b"abcdefghij");
+
+    // Over budget, every other checkpoint goes
+    debugger.set_checkpoints(1, 1);
+    debugger.seek(1).unwrap();
+    assert_eq!(debugger.checkpoints().count(), 1);
+    let machine = Machine::builder().build("+".repeat(100)).unwrap();
+    let mut debugger = Debugger::new(machine);
+    debugger.set_checkpoints(1, 4096);
+    debugger.seek(100).unwrap();
+    let steps: Vec<u64> = debugger.checkpoints().collect();
+    assert!(steps.len() < 100 && steps[0] == 0);
+    assert!(steps.windows(2).all(|pair| pair[1] - pair[0] == steps[1]));  // Evenly thinned
+    assert_eq!(debugger.seek(3).unwrap(), Stop::Step);
+    assert_eq!(debugger.cell(0), Some(3));
+}
+
+#[test]
+fn fuses_common_sequences() {
+    let machine = Machine::builder().build("++>>[-]<[->+<]>.".to_owned()).unwrap();
+    let fused = Fused::new(&machine.prog);
+    assert_eq!(fused.ops, [
+        Op::AddMove(2, 2), Op::ClearMove(-1), Op::Open(5), Op::AddMove(-1, 1), Op::AddMove(1, -1),
+        Op::CloseMove(3, 1), Op::Step,
+    ]);
+    assert_eq!(fused.starts, [0, 4, 8, 9, 11, 13, 15]);
+    assert_eq!(std::mem::size_of::<Op>(), 12);  // Ops are kept small, apart from their starts
+
+    // A run stopped by the step limit partway through an op ends where
+    // single steps would
+    for max_steps in 0..40 {
+        let limits = Limits { max_steps: Some(max_steps), ..Limits::default() };
+        let runs: Vec<_> = [true, false].iter().map(|&fusion| {
+            let mut machine = Machine::builder().limits(limits).build("+++[->++<]>[-]+".to_owned()).unwrap();
+            machine.fusion = fusion;
+            let result = machine.run().map_err(|err| err.to_string());
+            (result, machine.cells(..4), machine.data_ptr, machine.prog_ctr, machine.steps, machine.halted)
+        }).collect();
+        assert_eq!(runs[0], runs[1]);
+    }
+}
+
+#[test]
+fn counts_loop_branches() {
+    // Fused or not, the same branches are counted
+    for fusion in [true, false] {
+        let mut machine = Machine::builder().build("[+]++[>+++[-]<-]>>".to_owned()).unwrap();
+        machine.perf = Some(Perf::default());
+        machine.fusion = fusion;
+        machine.run().unwrap();
+        let perf = machine.perf.unwrap();
+        assert_eq!((perf.loops_entered, perf.loops_skipped, perf.loops_repeated, perf.loops_exited),
+                   (3, 1, 5, 3), "fusion {}", fusion);
+    }
+}
+
+#[test]
+fn caps_memory() {
+    // Each page of the tape the program writes adds to the total
+    let limits = Limits { max_memory: Some(50_000), ..Limits::default() };
+    let mut machine = Machine::builder().limits(limits).build("+[>+]".to_owned()).unwrap();
+    assert!(matches!(machine.run(), Err(RuntimeError::MemoryLimit(50_000))));
+    assert!(machine.memory().tape > 50_000);
+
+    // The debugger gives up history before the machine goes over
+    let limits = Limits { max_memory: Some(64 << 10), ..Limits::default() };
+    let machine = Machine::builder().limits(limits).build("-[>-[-]<-]".to_owned()).unwrap();
+    let mut debugger = Debugger::new(machine);
+    assert_eq!(debugger.resume().unwrap(), Stop::Halted);
+    assert!(debugger.memory().total() <= 64 << 10);
+    assert!(debugger.history_len() > 0 && (debugger.history_len() as u64) < debugger.machine().steps);
+}
+
+#[test]
+fn catches_writes_onto_canaries() {
+    // A handler that writes off the end stands in for an interpreter bug
+    let build = || {
+        let mut machine = Machine::builder().tape_size(8).canaries(4).build("+!+++".to_owned()).unwrap();
+        machine.extension('!', |machine| {
+            machine.data[9] = 1;
+            Ok(())
+        });
+        machine
+    };
+    let mut machine = build();
+    assert_eq!(machine.cells(..), [0; 8]);
+    let err = machine.run().unwrap_err();
+    assert!(matches!(err, RuntimeError::CanaryOverwritten(9)));
+    assert_eq!(machine.fault(err).steps, 5);  // Found on halt
+
+    // Traced, the fault is the step that wrote it
+    let mut machine = build();
+    machine.trace = Some(Box::new(io::sink()));
+    let err = machine.run().unwrap_err();
+    assert_eq!(machine.fault(err).to_string(),
+               "1:2: canary cell 9 past the end of the tape was overwritten (data pointer 0, step 2)");
+
+    // The canaries survive going back to a snapshot
+    let mut machine = Machine::builder().tape_size(8).canaries(4).build("+>+".to_owned()).unwrap();
+    let snapshot = machine.snapshot();
+    machine.run().unwrap();
+    machine.restore(&snapshot).unwrap();
+    assert_eq!(machine.data.guard(), 4);
+    assert_eq!(machine.data.overwritten_canary(), None);
+}
+
+#[test]
+fn checked_arithmetic_reports_the_instruction() {
+    // Superoperators leave an op that would overflow to single steps, so
+    // the fault is placed the same either way
+    for fusion in [false, true] {
+        let mut machine = Machine::builder().overflow(Overflow::Error).build("+++\n>--".to_owned()).unwrap();
+        machine.fusion = fusion;
+        let err = machine.run().unwrap_err();
+        assert_eq!(machine.fault(err).to_string(),
+                   "2:2: cell decremented below zero (data pointer 1, step 5)", "fusion {}", fusion);
+    }
+}
+
+#[cfg(feature = "ui")]
+#[test]
+fn highlights_the_read_head_after_wide_characters() {
+    let mut machine = Machine::builder().build("é+>\n+".to_owned()).unwrap();
+    machine.run_for(1).unwrap();
+    let screen = machine.to_string();
+    let highlighted = format!("{}>{}", termion::color::Bg(machine.display_spec.theme.highlight),
+                              termion::color::Bg(termion::color::Reset));
+    assert!(screen.contains(&format!("é+{}\r\n", highlighted)), "{:?}", screen);
+}
+
+#[test]
+fn lines_up_source_past_the_tape() {
+    // Source lines beside a cell and below the last one start at the same
+    // column, however wide the cells
+    for cell_size in [CellSize::U8, CellSize::U16, CellSize::U32] {
+        let machine = Machine::builder().cell_size(cell_size).build("+a\n+b\n+c".to_owned()).unwrap();
+        // The screen as shown, without its colors
+        let mut screen = String::new();
+        let mut rest = machine.to_string();
+        while let Some(start) = rest.find('\x1b') {
+            screen.push_str(&rest[..start]);
+            let end = start + rest[start..].find('m').unwrap();
+            rest = rest[end + 1..].to_owned();
+        }
+        screen.push_str(&rest);
+        let columns: Vec<usize> = ['a', 'b', 'c'].iter()
+            .map(|&ch| screen.lines().find_map(|line| line.find(ch)).unwrap())
+            .collect();
+        assert!(columns.windows(2).all(|pair| pair[0] == pair[1]), "{:?}: {:?}", cell_size, screen);
+    }
+}
+
+#[test]
+fn pairs_nested_and_adjacent_loops() {
+    use Command::{JumpBackward as Close, JumpForward as Open};
+    let prog = parse("[][[]]").unwrap();
+    assert_eq!(prog.commands, [Open(1), Close(0), Open(5), Open(4), Close(3), Close(2)]);
+    assert!(prog.jumps_match());
+    let broken = Program { commands: vec![Open(1), Close(1)], ..Program::default() };
+    assert!(!broken.jumps_match());
+
+    // Every loop is entered once and left once, innermost first
+    let depth = 10_000;
+    let src = format!("+{}-{}", "[".repeat(depth), "]".repeat(depth));
+    let (machine, result) = run(&src, b"");
+    result.unwrap();
+    assert!(machine.prog.jumps_match());
+    assert_eq!(machine.steps, 2 * depth as u64 + 2);
+
+    // Loops side by side, each skipped or run on its own
+    let (machine, result) = run("[+][-]+[-][>+<-]>[[-]]+[>]", b"");
+    result.unwrap();
+    assert_eq!(machine.cells(..3), [0, 1, 0]);
+    assert_eq!(machine.data_ptr, 2);
+
+    // Editing one of a run of loops pairs the rest again
+    let old = "[-][-]\n[-]\n[-][-]";
+    let new = "[-][-]\n[[-]-]\n[-][-]";
+    let (prog, _) = reparse_with_extensions(&parse(old).unwrap(), old, new, |_| false).unwrap();
+    assert!(prog.jumps_match());
+    assert_eq!(prog.commands, parse(new).unwrap().commands);
+}
+
+// Build a pbrain machine for a program
+fn pbrain(program: &str) -> Machine {
+    Machine::builder()
+        .semantics(Semantics { dialect: Dialect::Pbrain, ..Semantics::default() })
+        .build(program.to_owned())
+        .unwrap()
+}
+
+#[test]
+fn runs_pbrain_procedures() {
+    // Procedure 1 counts in cell 1; procedure 2 calls it twice
+    let src = "+(>+<)+(-::+)::";
+    let mut machine = pbrain(src);
+    machine.call_tree = Some(CallTree::default());
+    machine.run().unwrap();
+    assert_eq!(machine.cells(..2), [2, 4]);
+    assert_eq!(machine.procedures.iter().collect::<Vec<_>>(), [(&1, &1), (&2, &7)]);
+    assert!(machine.calls.is_empty());
+    let tree = machine.call_tree.as_ref().unwrap();
+    let nodes: Vec<_> = tree.nodes.iter().map(|node| (node.procedure, node.calls, node.steps)).collect();
+    assert_eq!(nodes, [(None, 1, 0), (Some(2), 2, 28), (Some(1), 4, 20)]);
+    let lines = tree.lines(&machine.calls, machine.steps);
+    assert!(lines[0].starts_with("*top level") && lines[0].contains(" 32 steps"));
+    assert!(lines[2].starts_with("     procedure 1") && lines[2].contains(" 4 calls"));
+
+    // Plain brainfuck takes them for comments
+    let (machine, result) = run(src, b"");
+    result.unwrap();
+    assert_eq!(machine.cells(..2), [2, 1]);
+
+    // A call needs a definition
+    let mut machine = pbrain("+:");
+    let fault = machine.run().map_err(|err| machine.fault(err)).unwrap_err();
+    assert!(matches!(fault.error, RuntimeError::UndefinedProcedure(1)));
+    assert_eq!(fault.prog_ctr, 1);
+
+    // Stopping mid-call, a snapshot keeps the calls, and the tree follows
+    // them back in
+    let mut machine = pbrain(src);
+    machine.call_tree = Some(CallTree::default());
+    machine.run_for(8).unwrap();
+    let snapshot = machine.snapshot();
+    assert_eq!(snapshot.calls.iter().map(|call| call.procedure).collect::<Vec<_>>(), [2, 1]);
+    let mut restored = pbrain(src);
+    restored.call_tree = Some(CallTree::default());
+    restored.restore(&snapshot).unwrap();
+    assert_eq!(restored.calls, machine.calls);
+    assert_eq!(restored.call_tree.as_ref().unwrap().current(), 2);
+    restored.run().unwrap();
+    assert_eq!(restored.cells(..2), [2, 4]);
+    let (mut brainfuck, _) = run("", b"");
+    brainfuck.load(src.to_owned()).unwrap();
+    assert!(matches!(brainfuck.restore(&snapshot), Err(RestoreError::DialectMismatch)));
+}
+
+#[test]
+fn parses_pbrain() {
+    let prog = parse_dialect("(:[)]", Dialect::Pbrain, |_| false);
+    assert!(matches!(prog, Err(ParseError::UnmatchedProcEnd { offset: 3, .. })));
+    let prog = parse_dialect("[(])", Dialect::Pbrain, |_| false);
+    assert!(matches!(prog, Err(ParseError::UnmatchedClose { offset: 2, .. })));
+    let Err(err) = parse_dialect("+\n (", Dialect::Pbrain, |_| false) else { panic!("expected an unmatched `(`") };
+    assert_eq!((err.pos(), err.message()), ((1, 1), "unmatched `(`"));
+
+    let prog = parse_dialect("([]):", Dialect::Pbrain, |_| false).unwrap();
+    assert_eq!(prog.commands, [Command::ProcStart(3), Command::JumpForward(2), Command::JumpBackward(1),
+                               Command::ProcEnd(0), Command::Call]);
+    assert!(prog.jumps_match());
+    // The `:` of a label isn't a call
+    let prog = parse_dialect("@label:a :", Dialect::Pbrain, |_| false).unwrap();
+    assert_eq!(prog.commands, [Command::Call]);
+}
+
+#[test]
+fn debugger_steps_back_over_calls() {
+    let src = "+(>+<)+(-::+)::";
+    let mut machine = pbrain(src);
+    machine.call_tree = Some(CallTree::default());
+    let mut debugger = Debugger::new(machine);
+    let mut forward = vec![];
+    loop {
+        let machine = debugger.machine();
+        forward.push((machine.prog_ctr, machine.procedures.clone(), machine.calls.clone()));
+        if debugger.step().unwrap() == Stop::Halted {
+            break;
+        }
+    }
+    let tree = debugger.machine().call_tree.clone();
+
+    // Each step back undoes a definition, call or return
+    for (prog_ctr, procedures, calls) in forward.iter().rev() {
+        assert_eq!(debugger.step_back(), Stop::Step);
+        let machine = debugger.machine();
+        assert_eq!((&machine.prog_ctr, &machine.procedures, &machine.calls), (prog_ctr, procedures, calls));
+        assert_eq!(machine.call_tree.as_ref().unwrap().current(), match calls.len() {
+            0 => 0,
+            1 => 1,
+            _ => 2,
+        });
+    }
+    assert_eq!(debugger.step_back(), Stop::StartOfHistory);
+
+    // Replaying doesn't count the calls again
+    assert_eq!(debugger.resume().unwrap(), Stop::Halted);
+    assert_eq!(debugger.machine().cells(..2), [2, 4]);
+    assert_eq!(debugger.machine().call_tree, tree);
+}
+
+#[test]
+fn debugger_steps_over_and_out_of_loops_making_calls() {
+    // The second time round, the inner loop calls procedure 1, defined
+    // after it
+    let src = "++[>[:-]+(>+<)<-]";
+    let mut debugger = Debugger::new(pbrain(src));
+    debugger.set_breakpoint(5);  // The `:`
+    assert_eq!(debugger.resume().unwrap(), Stop::Breakpoint(5));
+    assert_eq!(debugger.step_out().unwrap(), Stop::Step);  // Not stopping in the procedure
+    assert_eq!((debugger.machine().prog_ctr, debugger.cell(2)), (8, Some(1)));
+    assert!(debugger.machine().calls.is_empty());
+
+    let mut debugger = Debugger::new(pbrain(src));
+    debugger.set_breakpoint(4);  // The inner `[`
+    assert_eq!(debugger.resume().unwrap(), Stop::Breakpoint(4));
+    assert_eq!(debugger.step_over().unwrap(), Stop::Step);  // Skipped
+    assert_eq!(debugger.machine().prog_ctr, 8);
+    assert_eq!(debugger.resume().unwrap(), Stop::Breakpoint(4));
+    assert_eq!(debugger.step_over().unwrap(), Stop::Step);  // Run, call and all
+    assert_eq!((debugger.machine().prog_ctr, debugger.cell(2)), (8, Some(1)));
+}